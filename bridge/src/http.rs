@@ -0,0 +1,61 @@
+//! A bare HTTP/1.1 server: one request per connection, then close.
+
+use std::{io::{BufRead, BufReader, Read, Write}, net::TcpStream};
+
+use anyhow::anyhow;
+
+/// Largest request body we'll read.
+pub const MAX_BODY: usize = 64 * 1024;
+
+pub struct Request {
+  pub method: String,
+  pub path: String,
+  pub body: Vec<u8>,
+}
+
+impl Request {
+  pub fn read(stream: &TcpStream) -> Result<Self, anyhow::Error> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+      (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+      _ => return Err(anyhow!("malformed request line {:?}", line.trim_end())),
+    };
+    let mut length = 0;
+    loop {
+      line.clear();
+      reader.read_line(&mut line)?;
+      let header = line.trim_end();
+      if header.is_empty() {break;}
+      if let Some((name, value)) = header.split_once(':') {
+        if name.eq_ignore_ascii_case("content-length") {
+          length = value.trim().parse()?;
+        }
+      }
+    }
+    if length > MAX_BODY {
+      return Err(anyhow!("body of {} bytes is too large", length));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Self { method, path, body })
+  }
+}
+
+/// Writes a whole response and closes the connection.
+pub fn respond(mut stream: &TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+  write!(
+    stream,
+    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status, content_type, body.len(), body,
+  )?;
+  stream.flush()
+}
+
+/// Starts a response whose body runs until the connection closes, for streaming.
+pub fn start_stream(mut stream: &TcpStream, content_type: &str) -> std::io::Result<()> {
+  write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n", content_type)?;
+  stream.flush()
+}
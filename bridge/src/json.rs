@@ -0,0 +1,51 @@
+//! Just enough JSON for what the bridge sends.
+
+use common::{packets::ServerMessage, UserInfo};
+
+/// `text` as a JSON string, quotes included.
+pub fn string(text: &str) -> String {
+  let mut out = String::with_capacity(text.len() + 2);
+  out.push('"');
+  for c in text.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+pub fn user(user: &UserInfo) -> String {
+  format!(
+    r#"{{"id":"{}","username":{},"role":"{}","muted":{},"deafened":{}}}"#,
+    user.id, string(&user.username), format!("{:?}", user.role).to_lowercase(), user.state.muted, user.state.deafened,
+  )
+}
+
+pub fn users<'a>(users: impl Iterator<Item = &'a UserInfo>) -> String {
+  format!("[{}]", users.map(user).collect::<Vec<_>>().join(","))
+}
+
+/// A server message as an event for `/events`, if it's one we pass on.
+pub fn event(msg: &ServerMessage) -> Option<String> {
+  Some(match msg {
+    ServerMessage::Connected(info) => format!(r#"{{"type":"joined","user":{}}}"#, user(info)),
+    ServerMessage::Disconnected(info, reason) => format!(
+      r#"{{"type":"left","user":{},"reason":"{}"}}"#,
+      user(info), format!("{:?}", reason).to_lowercase(),
+    ),
+    ServerMessage::StateChanged { user, state } => format!(
+      r#"{{"type":"state","user":"{}","muted":{},"deafened":{}}}"#,
+      user, state.muted, state.deafened,
+    ),
+    ServerMessage::Chat { from, text } => format!(r#"{{"type":"chat","from":{},"text":{}}}"#, user(from), string(text)),
+    ServerMessage::Announcement { text } => format!(r#"{{"type":"announcement","text":{}}}"#, string(text)),
+    _ => return None,
+  })
+}
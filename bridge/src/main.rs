@@ -0,0 +1,219 @@
+//! Bridges a voice server's roster and chat to HTTP and JSON, so bots and web
+//! services can follow along without speaking the voice protocol.
+//!
+//! - `GET /roster` lists everyone on the server.
+//! - `GET /events` streams what happens as JSON lines: joins, leaves, mutes, chat and announcements.
+//! - `POST /chat` says the request body in the bridge's room.
+//!
+//! The bridge joins the server as an audience member, so it shows up in the roster
+//! itself, and only hears chat from the room it's in.
+
+use std::{collections::HashMap, net::{SocketAddr, TcpListener, TcpStream}, sync::{mpsc::{self, Sender}, Arc, Mutex}, time::Duration};
+
+use clap::Parser;
+use client::{Client, ConnectionEvent};
+use common::{packets::{ClientMessage, ServerMessage, MAX_CHAT_LEN}, Role, UserInfo};
+use log::{info, warn};
+use uuid::Uuid;
+
+mod http;
+mod json;
+
+#[derive(Parser, Debug)]
+#[clap(name="Rust Voice Bridge")]
+struct Args {
+  /// Voice server to bridge, by host name, IPv4 or IPv6 address
+  #[clap(value_parser)]
+  address: String,
+  /// Port to connect on, instead of the server's SRV records or the default of 8080
+  #[clap(value_parser = clap::value_parser!(u16).range(1..), short='p', long="port")]
+  port: Option<u16>,
+  /// Password for the voice server, if it needs one
+  #[clap(value_parser, long="password")]
+  password: Option<String>,
+  /// Only connect to a server with this key, as the server logs it on start
+  #[clap(value_parser = parse_key, long="server-key")]
+  server_key: Option<common::crypto::PublicKeyBytes>,
+  /// File to remember servers' keys in, refusing any whose key has changed since
+  #[clap(value_parser, long="known-servers")]
+  known_servers: Option<std::path::PathBuf>,
+  /// Name the bridge joins as, and chat is sent from
+  #[clap(value_parser, long="name", default_value="bridge")]
+  name: String,
+  /// Room to follow the chat of, instead of the one the server puts us in
+  #[clap(value_parser, long="room")]
+  room: Option<String>,
+  /// Agree to the server's rules, if it has any, so chat we send goes through
+  #[clap(long="accept-rules")]
+  accept_rules: bool,
+  /// Address to serve HTTP on
+  #[clap(value_parser, long="listen", default_value="127.0.0.1:8081")]
+  listen: SocketAddr,
+}
+
+/// How long to wait between polls when the server has nothing for us.
+const IDLE_POLL: Duration = Duration::from_millis(5);
+
+/// What HTTP clients see, kept up to date from the server.
+#[derive(Default)]
+struct Shared {
+  roster: HashMap<Uuid, UserInfo>,
+  /// Streams from `/events`, dropped once their connection goes away.
+  subscribers: Vec<Sender<String>>,
+}
+
+impl Shared {
+  fn publish(&mut self, event: String) {
+    self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+  }
+}
+
+fn parse_key(fingerprint: &str) -> Result<common::crypto::PublicKeyBytes, String> {
+  common::crypto::parse_fingerprint(fingerprint).ok_or_else(|| "not a key, expected 64 hex digits".to_string())
+}
+
+fn main() -> Result<(), anyhow::Error> {
+  env_logger::init();
+  let args = Args::parse();
+
+  let mut client = Client::new(args.name.clone(), Role::Audience, mpsc::channel().1)?;
+  client.set_password(args.password.clone());
+  client.set_server_key(args.server_key);
+  client.set_known_servers(args.known_servers.clone())?;
+  client.set_auto_reconnect(true);
+  let users = client.connect_host(&args.address, args.port)?;
+  let roster = join(&mut client, &args, users)?;
+
+  let shared = Arc::new(Mutex::new(Shared {
+    roster,
+    ..Default::default()
+  }));
+  let (chat_tx, chat_rx) = mpsc::channel();
+
+  let listener = TcpListener::bind(args.listen)?;
+  info!("Serving on http://{}", args.listen);
+  {
+    let shared = shared.clone();
+    std::thread::spawn(move || {
+      for stream in listener.incoming().flatten() {
+        let shared = shared.clone();
+        let chat_tx = chat_tx.clone();
+        std::thread::spawn(move || {
+          if let Err(e) = handle(&stream, &shared, &chat_tx) {
+            warn!("Request from {:?} failed: {}", stream.peer_addr(), e);
+          }
+        });
+      }
+    });
+  }
+
+  loop {
+    while let Some(event) = client.take_connection_event() {
+      match event {
+        // whoever's there now, which is everyone if the server restarted
+        ConnectionEvent::Reconnected { users } => shared.lock().unwrap().roster = join(&mut client, &args, users)?,
+        ConnectionEvent::GaveUp { reason } => return Err(anyhow::anyhow!("Gave up reconnecting: {}", reason)),
+        _ => {},
+      }
+    }
+    // dropped rather than kept for later, while we're away
+    for text in chat_rx.try_iter() {
+      if client.is_connected() {
+        client.send(ClientMessage::Chat { text })?;
+      }
+    }
+    let msg = match client.poll()? {
+      Some(msg) => msg,
+      None => {
+        std::thread::sleep(IDLE_POLL);
+        continue;
+      },
+    };
+    let mut shared = shared.lock().unwrap();
+    match &msg {
+      ServerMessage::Connected(user) => {
+        shared.roster.insert(user.id, user.clone());
+      },
+      ServerMessage::Disconnected(user, _) => {
+        if Some(user.id) == client.id() {
+          return Err(anyhow::anyhow!("Disconnected by the server"));
+        }
+        shared.roster.remove(&user.id);
+      },
+      ServerMessage::StateChanged { user, state } => {
+        if let Some(info) = shared.roster.get_mut(user) {
+          info.state = *state;
+        }
+      },
+      // the rest of the roster, when it didn't fit in the handshake
+      ServerMessage::UserList { users, .. } => {
+        shared.roster.extend(users.iter().map(|user| (user.id, user.clone())));
+      },
+      _ => {},
+    }
+    if let Some(event) = json::event(&msg) {
+      shared.publish(event);
+    }
+  }
+}
+
+/// Settles in once connected: agrees to the rules if asked to and goes to our room,
+/// returning the roster from the handshake.
+fn join(client: &mut Client, args: &Args, mut users: Vec<UserInfo>) -> Result<HashMap<Uuid, UserInfo>, anyhow::Error> {
+  // the handshake lists everyone but us
+  if let Some(id) = client.id() {
+    users.push(UserInfo { id, username: args.name.clone(), role: Role::Audience, state: Default::default() });
+  }
+  if let Some(rules) = client.rules() {
+    if args.accept_rules {
+      info!("Accepting the server's rules: {}", rules);
+      client.accept_rules()?;
+    } else {
+      warn!("The server won't relay our chat until we accept its rules (--accept-rules): {}", rules);
+    }
+  }
+  if let Some(room) = &args.room {
+    client.send(ClientMessage::JoinRoom { room: room.clone() })?;
+  }
+  Ok(users.into_iter().map(|user| (user.id, user)).collect())
+}
+
+fn handle(stream: &TcpStream, shared: &Mutex<Shared>, chat_tx: &Sender<String>) -> Result<(), anyhow::Error> {
+  let request = match http::Request::read(stream) {
+    Ok(request) => request,
+    Err(e) => {
+      http::respond(stream, "400 Bad Request", "text/plain", &e.to_string())?;
+      return Ok(());
+    },
+  };
+  match (request.method.as_str(), request.path.as_str()) {
+    ("GET", "/roster") => {
+      let roster = json::users(shared.lock().unwrap().roster.values());
+      http::respond(stream, "200 OK", "application/json", &roster)?;
+    },
+    ("GET", "/events") => {
+      let (tx, rx) = mpsc::channel();
+      shared.lock().unwrap().subscribers.push(tx);
+      http::start_stream(stream, "application/x-ndjson")?;
+      let mut stream = stream;
+      for event in rx {
+        // the client hung up
+        if std::io::Write::write_all(&mut stream, format!("{}\n", event).as_bytes()).is_err() {break;}
+      }
+    },
+    ("POST", "/chat") => {
+      let text = match String::from_utf8(request.body) {
+        Ok(text) if !text.trim().is_empty() && text.len() <= MAX_CHAT_LEN => text,
+        _ => {
+          let reason = format!("chat must be 1 to {} bytes of UTF-8", MAX_CHAT_LEN);
+          http::respond(stream, "400 Bad Request", "text/plain", &reason)?;
+          return Ok(());
+        },
+      };
+      chat_tx.send(text)?;
+      http::respond(stream, "202 Accepted", "text/plain", "")?;
+    },
+    _ => http::respond(stream, "404 Not Found", "text/plain", "try GET /roster, GET /events or POST /chat")?,
+  }
+  Ok(())
+}
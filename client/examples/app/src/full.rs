@@ -1,22 +1,154 @@
-use std::{net::SocketAddr, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
 
 use clap::Parser;
-use client::App;
+use client::{App, AudioProfile, AudioThreadSettings, BitrateController, ChannelMap, ConnectionEvent, Nicknames, OpusConfig};
+use common::Role;
 
 #[derive(Parser, Debug)]
 #[clap(name="Rust Voice Server")]
 struct Args {
+  /// Server's host name, IPv4 or IPv6 address
   #[clap(value_parser)]
   address: String,
-  #[clap(value_parser = clap::value_parser!(u16).range(1..), short='p', long="port", default_value_t=8080)]
-  port: u16,
-  #[clap(value_parser, long="latency", default_value_t=150.)]
-  latency: f32,
+  /// Port to connect on, instead of the server's SRV records or the default of 8080
+  #[clap(value_parser = clap::value_parser!(u16).range(1..), short='p', long="port")]
+  port: Option<u16>,
+  #[clap(value_parser, long="latency")]
+  latency: Option<f32>,
+  /// Audio preset to start from: headset, speakers or studio
+  #[clap(value_parser, long="profile")]
+  profile: Option<String>,
+  /// Join as a listener, without opening the mic
+  #[clap(long="audience")]
+  audience: bool,
+  /// Name of the mic to record from
+  #[clap(value_parser, long="input")]
+  input: Option<String>,
+  /// Name of the speakers to play through
+  #[clap(value_parser, long="output")]
+  output: Option<String>,
+  /// Output channels to play on: stereo, swapped, mono, or a left,right pair like 2,3
+  #[clap(value_parser, long="channels", default_value="stereo")]
+  channels: ChannelMap,
+  /// Play your own voice back quietly, as others hear it
+  #[clap(long="monitor")]
+  monitor: bool,
+  /// Turn down background noise before sending
+  #[clap(long="denoise")]
+  denoise: bool,
+  /// Send in stereo if the mic supports it
+  #[clap(long="stereo")]
+  stereo: bool,
+  /// Bits per second to encode at, instead of letting opus pick
+  #[clap(value_parser, long="bitrate")]
+  bitrate: Option<i32>,
+  /// Encoder complexity, 0 (cheapest) to 10 (best)
+  #[clap(value_parser = clap::value_parser!(i32).range(0..=10), long="complexity")]
+  complexity: Option<i32>,
+  /// Stop sending during silence (opus DTX)
+  #[clap(long="dtx")]
+  dtx: bool,
+  /// Milliseconds of audio per packet: 10, 20, 40 or 60
+  #[clap(value_parser, long="frame-ms")]
+  frame_ms: Option<u64>,
+  /// Keep our own settings in rooms set up for music, gaming or meetings
+  #[clap(long="no-room-presets")]
+  no_room_presets: bool,
+  /// Lower the bitrate on congested networks, between 8 and 32 kbps
+  #[clap(long="adaptive-bitrate")]
+  adaptive_bitrate: bool,
+  /// Password for the server, if it needs one
+  #[clap(value_parser, long="password")]
+  password: Option<String>,
+  /// Only connect to a server with this key, as the server logs it on start
+  #[clap(value_parser = parse_key, long="server-key")]
+  server_key: Option<common::crypto::PublicKeyBytes>,
+  /// File to remember servers' keys in, refusing any whose key has changed since
+  #[clap(value_parser, long="known-servers")]
+  known_servers: Option<std::path::PathBuf>,
+  /// Encrypt voice end-to-end with this passphrase
+  #[clap(value_parser, long="passphrase")]
+  passphrase: Option<String>,
+  /// Run audio callbacks at realtime priority
+  #[clap(long="realtime")]
+  realtime: bool,
+  /// Pin audio callbacks to this CPU core
+  #[clap(value_parser, long="audio-core")]
+  audio_core: Option<usize>,
+  /// Seconds between pings while we have nothing else to send
+  #[clap(value_parser, long="keepalive", default_value_t=1.0)]
+  keepalive: f32,
+  /// Quit when the server goes away, instead of reconnecting
+  #[clap(long="no-reconnect")]
+  no_reconnect: bool,
+  /// Play dead silence while others aren't talking, instead of their background noise
+  #[clap(long="no-comfort-noise")]
+  no_comfort_noise: bool,
+  /// Log every voice packet received to this file, for the trace tool
+  #[clap(value_parser, long="trace")]
+  trace: Option<std::path::PathBuf>,
+  /// Show peers by the names in this file, a `username<TAB>nickname` per line
+  #[clap(value_parser, long="nicknames")]
+  nicknames: Option<std::path::PathBuf>,
+  /// Print what each stage of audio processing adds to the delay on exit
+  #[clap(long="latency-report")]
+  latency_report: bool,
+  /// Save who spoke when to this file on exit
+  #[clap(value_parser, long="speaking-log")]
+  speaking_log: Option<std::path::PathBuf>,
+  /// Keep the last this many seconds of the call, press Enter to save them
+  #[clap(value_parser, long="clip-secs")]
+  clip_secs: Option<f32>,
+  /// Play this WAV or Ogg file into the call alongside the mic
+  #[clap(value_parser, long="play")]
+  play: Option<std::path::PathBuf>,
+  /// With --play, send only the file while it plays
+  #[clap(long="play-only")]
+  play_only: bool,
+  /// Record the call to this WAV file
+  #[clap(value_parser, long="record")]
+  record: Option<std::path::PathBuf>,
+  /// With --record, also record each peer to their own file
+  #[clap(long="record-peers")]
+  record_peers: bool,
+  /// Print the available audio devices and exit
+  #[clap(long="list-devices")]
+  list_devices: bool,
+  /// Play our own voice back as peers would hear it, without connecting
+  #[clap(long="mic-test")]
+  mic_test: bool,
+  /// Check the audio devices, codec and server, print a report and exit
+  #[clap(long="doctor")]
+  doctor: bool,
+}
+
+fn parse_key(fingerprint: &str) -> Result<common::crypto::PublicKeyBytes, String> {
+  common::crypto::parse_fingerprint(fingerprint).ok_or_else(|| "not a key, expected 64 hex digits".to_string())
 }
 
 fn main() -> Result<(), anyhow::Error> {
   let args = Args::parse();
 
+  if args.list_devices {
+    for device in client::list_devices()? {
+      println!("{} (in: {:?}, out: {:?})", device.name, device.input, device.output);
+    }
+    return Ok(());
+  }
+
+  if args.doctor {
+    let server = client::resolve(&args.address, args.port).ok().and_then(|addrs| addrs.first().copied());
+    let report = client::run_diagnostics(server);
+    for diagnostic in &report {
+      println!("{}", diagnostic);
+    }
+    if report.iter().all(|d| d.passed()) {
+      println!("All checks passed");
+      return Ok(());
+    }
+    return Err(anyhow::anyhow!("{} of {} checks failed", report.iter().filter(|d| !d.passed()).count(), report.len()));
+  }
+
   let running = Arc::new(AtomicBool::new(true));
 
   {
@@ -26,14 +158,156 @@ fn main() -> Result<(), anyhow::Error> {
     })?;
   }
 
-  let mut app = App::new("test".to_string(), args.latency)?;
+  let role = if args.audience { Role::Audience } else { Role::Speaker };
+  let mut builder = App::builder("test".to_string());
+  if let Some(name) = &args.profile {
+    let profile = AudioProfile::preset(name).ok_or_else(|| anyhow::anyhow!("Unknown audio profile '{}'", name))?;
+    builder = builder.with_profile(profile);
+  }
+  // flags given on top of a profile override it
+  if let Some(latency) = args.latency {
+    builder = builder.with_latency(latency);
+  }
+  if args.input.is_some() {
+    builder = builder.with_input_device(args.input);
+  }
+  if args.output.is_some() {
+    builder = builder.with_output_device(args.output);
+  }
+  if args.denoise {
+    builder = builder.with_noise_suppression(true);
+  }
+  if args.stereo {
+    builder = builder.with_stereo(true);
+  }
+  // only pin the encoder if asked to, otherwise rooms' presets pick
+  if args.bitrate.is_some() || args.complexity.is_some() || args.dtx || args.frame_ms.is_some() {
+    builder = builder.with_opus_config(OpusConfig::default()
+      .with_fec(true)
+      .with_bitrate(args.bitrate)
+      .with_complexity(args.complexity)
+      .with_dtx(args.dtx)
+      .with_frame_duration(Duration::from_millis(args.frame_ms.unwrap_or(20))));
+  }
+  if let Some(path) = &args.nicknames {
+    builder = builder.with_nicknames(Nicknames::load(path)?);
+  }
+  let mut app = builder
+    .with_role(role)
+    .with_e2e_passphrase(args.passphrase)
+    .with_password(args.password)
+    .with_server_key(args.server_key)
+    .with_known_servers(args.known_servers)
+    .with_audio_threads(AudioThreadSettings { realtime: args.realtime, core: args.audio_core })
+    .with_channel_map(args.channels)
+    .with_room_presets(!args.no_room_presets)
+    .with_bitrate_controller(args.adaptive_bitrate.then(|| BitrateController::new(8000, 32000)))
+    .with_keepalive_interval(Duration::from_secs_f32(args.keepalive))
+    .with_auto_reconnect(!args.no_reconnect)
+    .with_comfort_noise(!args.no_comfort_noise)
+    .build()?;
+  app.on_server_unresponsive(Duration::from_secs(3), |silence| {
+    eprintln!("Server hasn't answered for {:.1}s, the connection may be lost", silence.as_secs_f32());
+  });
   
-  let addr: SocketAddr = format!("{}:{}", args.address, args.port).parse()?;
-  app.start(addr)?;
+  if args.mic_test {
+    app.enable_loopback(true)?;
+    println!("Testing mic, press Ctrl-C to stop");
+    while running.load(Ordering::Relaxed) {
+      app.poll()?;
+    }
+    return Ok(());
+  }
+
+  if let Some(path) = &args.trace {
+    app.set_packet_trace(Some(Box::new(std::io::BufWriter::new(std::fs::File::create(path)?))));
+  }
+
+  app.start_host(&args.address, args.port)?;
+  if let (Some(name), Some(addr)) = (app.server_name(), app.server_addr()) {
+    println!("Connected to {} at {}", name, addr);
+  }
+  if let Some(motd) = app.motd() {
+    println!("{}", motd);
+  }
+  if let Some(rules) = app.rules() {
+    println!("This server's rules:\n{}\nType 'yes' to accept them and join the call", rules);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("yes") {
+      app.stop();
+      return Ok(());
+    }
+    app.accept_rules()?;
+  }
+  if args.monitor {
+    app.set_monitor(true)?;
+  }
+  if let Some(path) = &args.play {
+    app.play_file(path, args.play_only)?;
+  }
+  if let Some(path) = &args.record {
+    app.start_recording(path, args.record_peers)?;
+  }
+  let (save_clip, clip_requests) = std::sync::mpsc::channel();
+  if let Some(secs) = args.clip_secs {
+    app.set_clip_duration(Some(Duration::from_secs_f32(secs)));
+    println!("Press Enter to save the last {} seconds", secs);
+    std::thread::spawn(move || {
+      for _ in std::io::stdin().lines() {
+        if save_clip.send(()).is_err() {break;}
+      }
+    });
+  }
   while running.load(Ordering::Relaxed) {
     app.poll()?;
+    while let Some(event) = app.take_connection_event() {
+      match event {
+        ConnectionEvent::Disconnected { reason } => eprintln!("Lost the server: {}", reason),
+        ConnectionEvent::Reconnecting { attempt, delay } => eprintln!("Reconnecting in {:.1}s (try {})", delay.as_secs_f32(), attempt),
+        ConnectionEvent::Reconnected { .. } => println!("Reconnected"),
+        ConnectionEvent::GaveUp { reason } => {
+          eprintln!("Gave up reconnecting: {}", reason);
+          running.store(false, Ordering::Relaxed);
+        },
+      }
+    }
+    if clip_requests.try_recv().is_ok() {
+      let path = format!("clip-{}.wav", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs());
+      match app.save_clip(&path) {
+        Ok(()) => println!("Saved {}", path),
+        Err(e) => eprintln!("Failed to save clip: {}", e),
+      }
+    }
+  }
+  if args.latency_report {
+    println!("Where the delay comes from:");
+    let mut total = Duration::ZERO;
+    for stage in app.latency() {
+      println!("  {:<10} {:>6.1} ms held, {:>5.2} ms processing", format!("{:?}", stage.stage), stage.delay.as_secs_f64() * 1000.0, stage.processing.as_secs_f64() * 1000.0);
+      total += stage.total();
+    }
+    if let Some(rtt) = app.rtt() {
+      println!("  {:<10} {:>6.1} ms, half the round trip", "Network", rtt.as_secs_f64() * 500.0);
+      total += rtt / 2;
+    }
+    println!("  {:<10} {:>6.1} ms", "Total", total.as_secs_f64() * 1000.0);
   }
   app.stop();
+  if app.is_recording() {
+    for path in app.stop_recording()? {
+      println!("Recorded {}", path.display());
+    }
+  }
+
+  let timeline = app.speaking_timeline();
+  if !timeline.segments().is_empty() {
+    println!("Who spoke when:");
+    print!("{}", timeline.render(Duration::ZERO, timeline.elapsed(), 80));
+  }
+  if let Some(path) = &args.speaking_log {
+    timeline.save(path)?;
+  }
   
   Ok(())
 }
\ No newline at end of file
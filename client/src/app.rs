@@ -1,12 +1,12 @@
-use std::{sync::{Arc, Mutex}, collections::HashMap, net::ToSocketAddrs};
+use std::{sync::{Arc, Mutex, mpsc::Receiver, atomic::{AtomicUsize, Ordering}}, collections::{HashMap, HashSet, VecDeque}, net::ToSocketAddrs, path::{Path, PathBuf}, time::{Duration, Instant}};
 
-use common::packets::ServerMessage;
-use kira::manager::{AudioManager, AudioManagerSettings};
+use common::{crypto::{Identity, PublicKeyBytes}, packets::{ServerMessage, ClientMessage, SeqNum, MAX_CHAT_LEN}, AdminCommand, JoinDefaults, Role, RoomPreset, UserInfo, UserState};
+use kira::{manager::{AudioManager, AudioManagerSettings}, tween::Tween, Volume};
 use log::{warn, info};
-use ringbuf::{Producer, RingBuffer};
+use ringbuf::{Consumer, Producer, RingBuffer};
 use uuid::Uuid;
 
-use crate::{voice::{VoiceSoundHandle, VoiceSoundData, VoiceSoundSettings}, decoder::OpusDecoder, mic::MicService, client::Client, cpal::CpalBackend};
+use crate::{bitrate::BitrateController, budget::{Stage, StageLatency, StageTimings}, voice::{VoiceMixer, VoiceMixerData, VoiceMixerHandle, VoiceSoundHandle, VoiceSoundData, VoiceSoundSettings}, decoder::{OpusDecoder, DECODED_CHANNELS}, encoder::{Bandwidth, OpusConfig}, mic::MicService, nicknames::Nicknames, client::{audio_level, Client, ConnectionEvent, MicPacket}, e2e::{GroupKey, ReplayWindow}, cpal::{CpalBackend, CpalBackendSettings, ChannelMap}, latency::Latency, preset::PresetSettings, profile::AudioProfile, quality::CallQuality, clip::ClipBuffer, recorder::Recorder, stats::NetworkStats, timeline::SpeakingTimeline, comfort::ComfortNoise, jitter::{JitterBuffer, Playout}, vad::{VoiceDetector, DEFAULT_VAD_THRESHOLD}, util::{opus::FRAME_DURATION, thread::AudioThreadSettings}};
 
 use anyhow::anyhow;
 
@@ -14,6 +14,75 @@ pub struct Peer {
   pub id: Uuid, 
 }
 
+/// Loudest a peer can be turned up to, as a multiple of their own level.
+pub const MAX_PEER_VOLUME: f32 = 2.0;
+
+/// Chat messages kept for [`App::chat`].
+const CHAT_HISTORY: usize = 100;
+
+/// A chat message from someone in our room.
+#[derive(Clone, Debug)]
+pub struct ChatMessage {
+  pub from: UserInfo,
+  pub text: String,
+  pub received: Instant,
+}
+
+/// Slowest and fastest a peer can be played back at.
+pub const PLAYBACK_RATE_RANGE: (f64, f64) = (0.75, 1.25);
+
+/// Frames of decoded audio kept queued for playback.
+const PLAYBACK_FRAMES: usize = 2;
+
+/// Peer id our own monitored voice plays under. The server only hands out random ids, so it's never a real peer.
+const MONITOR_ID: Uuid = Uuid::nil();
+
+/// Volume of our own voice while monitoring.
+const MONITOR_AMPLITUDE: f64 = 0.3;
+/// Volume of our own voice in a mic test, as loud as peers will hear it.
+const LOOPBACK_AMPLITUDE: f64 = 1.0;
+
+/// Mixed output buffered between polls while recording or keeping clips.
+const TAP_BUFFER: Duration = Duration::from_secs(2);
+
+/// How quickly a peer's recent loudness fades once they stop talking.
+const ACTIVITY_HALF_LIFE: Duration = Duration::from_millis(750);
+
+/// How long after a peer's last packet they still count as talking.
+/// Long enough that a late packet or two doesn't count as them stopping.
+const TALKING_TIMEOUT: Duration = Duration::from_millis(200);
+
+fn is_talking(jitter: &JitterBuffer) -> bool {
+  jitter.last_arrival().is_some_and(|time| time.elapsed() < TALKING_TIMEOUT)
+}
+
+/// A peer's recent loudness, used to pick who stays audible when speakers are limited.
+struct Activity {
+  level: f32,
+  updated: Instant,
+}
+
+impl Activity {
+  fn level(&self) -> f32 {
+    self.level * 0.5f32.powf(self.updated.elapsed().as_secs_f32() / ACTIVITY_HALF_LIFE.as_secs_f32())
+  }
+
+  fn update(&mut self, samples: &[f32]) {
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32).sqrt();
+    self.level = self.level().max(rms);
+    self.updated = Instant::now();
+  }
+}
+
+/// Which settings the user chose themselves, so room presets leave them alone.
+#[derive(Copy, Clone, Debug, Default)]
+struct PresetOverrides {
+  opus_config: bool,
+  latency: bool,
+  /// Noise suppression and voice detection.
+  processing: bool,
+}
+
 type AMutex<T> = Arc<Mutex<T>>;
 type ThreadMap<K,V> = AMutex<HashMap<K,V>>;
 
@@ -21,129 +90,1255 @@ pub struct App {
   sound_map: ThreadMap<Uuid, VoiceSoundHandle>,
   producer_map: ThreadMap<Uuid, Producer<f32>>,
   decoder_map: ThreadMap<Uuid, OpusDecoder>,
+  jitter_map: ThreadMap<Uuid, JitterBuffer>,
+  activity_map: ThreadMap<Uuid, Activity>,
+  comfort_map: ThreadMap<Uuid, ComfortNoise>,
+  replay_map: ThreadMap<Uuid, ReplayWindow>,
+
+  /// How many peers can be heard at full volume at once, the loudest win.
+  max_speakers: Option<usize>,
+  /// Whether to play noise while peers aren't sending, rather than dead silence.
+  comfort_noise: bool,
 
   audio_manager: AMutex<AudioManager<CpalBackend>>,
-  mic_service: MicService,
+  /// Where every voice is played, in a stable order, see [`crate::voice`].
+  mixer: Mutex<VoiceMixerHandle>,
+  /// Audience members don't have a mic.
+  mic_service: Option<MicService>,
   client: Client,
+  /// Our own encoded voice and how loud to play it, while monitoring or testing the mic.
+  monitor: Option<(Receiver<MicPacket>, f64)>,
+  monitor_seq: SeqNum,
+
+  /// Passphrase for end-to-end encrypted voice, if on.
+  e2e_passphrase: Option<String>,
+  /// Key for the room we're in, derived from the passphrase.
+  group_key: Option<(String, Arc<GroupKey>)>,
+  /// Most recent chat messages, oldest first.
+  chat: VecDeque<ChatMessage>,
+
+  /// Peers from a [`ServerMessage::UserList`] still being received.
+  roster: HashSet<Uuid>,
+  /// Names peers connected with, which nicknames are kept by.
+  usernames: HashMap<Uuid, String>,
+  /// Names we've given peers, shown instead of their own.
+  nicknames: Nicknames,
+
+  /// Our own mute/deafen state.
+  state: UserState,
+  /// The room we're in, as of the last [`ServerMessage::RoomState`].
+  room: Option<String>,
+  /// The room we're heading back to after reconnecting, past wherever the server puts us first.
+  rejoining: Option<String>,
+  /// Changes to the connection not yet taken by [`App::take_connection_event`].
+  connection_events: VecDeque<ConnectionEvent>,
+  /// The room only wants our mic while push to talk is held.
+  push_to_talk: bool,
+  /// Push to talk is held.
+  talking: bool,
+  /// Whether to tell the server (and so everyone else) when `state` changes.
+  announce_state: bool,
+  /// Whether to follow the room's [`RoomPreset`].
+  room_presets: bool,
+  /// The room's preset, as applied.
+  preset: Option<RoomPreset>,
+  /// What the app was built with, for rooms without a preset.
+  base_settings: PresetSettings,
+  /// Settings the user chose, which presets leave alone.
+  overrides: PresetOverrides,
+  /// Who spoke when, from peers' decoded voice.
+  speaking: Mutex<SpeakingTimeline>,
+  /// The call being recorded, if it is.
+  recorder: Mutex<Option<Recorder>>,
+  /// The last moments of what we heard, if kept.
+  clip: Option<ClipBuffer>,
+  /// Copy of the mixed output, while recording or keeping clips.
+  output_tap: Option<Consumer<f32>>,
+  /// Adapts the mic's bitrate to the network, if on.
+  bitrate_controller: Option<BitrateController>,
+  /// Voice packets dropped for failing to decrypt or being replayed.
+  rejected_packets: AtomicUsize,
+  /// Time spent decoding peers' voice.
+  timings: StageTimings,
 
   /// Sample rate of the playback device.
   sample_rate: u32,
+  /// Most audio buffered for each peer to ride out network jitter.
+  latency: Latency,
 }
 
 impl App {
 
   pub fn new(username: String, latency_ms: f32) -> Result<Self, anyhow::Error> {
+    Self::builder(username).with_latency(latency_ms).build()
+  }
+
+  /// Creates an app for the given role. [`Role::Audience`] never opens the mic.
+  pub fn with_role(username: String, latency_ms: f32, role: Role) -> Result<Self, anyhow::Error> {
+    Self::builder(username).with_latency(latency_ms).with_role(role).build()
+  }
+
+  pub fn builder(username: String) -> AppBuilder {
+    AppBuilder::new(username)
+  }
 
-    let mut audio_manager = AudioManager::<CpalBackend>::new(AudioManagerSettings::default())?;
+  fn from_builder(builder: AppBuilder) -> Result<Self, anyhow::Error> {
+    let AppBuilder { username, latency_ms, role, input_device, output_device, vad, noise_suppression, stereo, audio_threads, e2e_passphrase, announce_state, password, server_key, known_servers, keepalive_interval, auto_reconnect, comfort_noise, channel_map, bitrate_controller, opus_config, room_presets, overrides, nicknames } = builder;
+    let base_settings = PresetSettings { opus_config, latency_ms, noise_suppression, vad: vad.clone() };
+
+    let mut audio_manager = AudioManager::<CpalBackend>::new(AudioManagerSettings {
+      backend_settings: CpalBackendSettings { device: output_device, thread: audio_threads, channel_map },
+      ..Default::default()
+    })?;
     let sample_rate = audio_manager.backend_mut().sample_rate();
+    let (mixer, mixer_handle) = VoiceMixer::new();
+    audio_manager.play(VoiceMixerData(mixer))?;
 
-    let (mic_service, rx) = MicService::builder().with_latency(latency_ms).build()?;
+    let (mic_service, rx) = match role {
+      Role::Speaker => {
+        let (mic_service, rx) = MicService::builder()
+          .with_input_device(input_device)
+          .with_opus_config(opus_config)
+          .with_vad(vad)
+          .with_noise_suppression(noise_suppression)
+          .with_stereo(stereo)
+          .with_thread_settings(audio_threads)
+          .build()?;
+        if let Some(controller) = &bitrate_controller {
+          mic_service.follow_controller(controller);
+        }
+        (Some(mic_service), rx)
+      },
+      Role::Audience => (None, std::sync::mpsc::channel().1),
+    };
 
-    let mut client = Client::new(username, rx)?;
+    let mut client = Client::new(username, role, rx)?;
+    client.set_password(password);
+    client.set_server_key(server_key);
+    client.set_known_servers(known_servers)?;
+    if let Some(interval) = keepalive_interval {
+      client.set_keepalive_interval(interval);
+    }
+    client.set_auto_reconnect(auto_reconnect);
 
     Ok(Self {
       sound_map   : Arc::new(Mutex::new(HashMap::new())),
       producer_map: Arc::new(Mutex::new(HashMap::new())),
       decoder_map : Arc::new(Mutex::new(HashMap::new())),
+      jitter_map  : Arc::new(Mutex::new(HashMap::new())),
+      activity_map: Arc::new(Mutex::new(HashMap::new())),
+      comfort_map : Arc::new(Mutex::new(HashMap::new())),
+      replay_map  : Arc::new(Mutex::new(HashMap::new())),
+
+      max_speakers: None,
+      comfort_noise,
 
       audio_manager: Arc::new(Mutex::new(audio_manager)),
+      mixer: Mutex::new(mixer_handle),
       mic_service,
       client,
+      monitor: None,
+      monitor_seq: 0,
+
+      e2e_passphrase,
+      group_key: None,
+      speaking: Mutex::new(SpeakingTimeline::new()),
+      recorder: Mutex::new(None),
+      clip: None,
+      output_tap: None,
+      bitrate_controller,
+      rejected_packets: AtomicUsize::new(0),
+      timings: StageTimings::default(),
+      chat: VecDeque::new(),
+      roster: HashSet::new(),
+      usernames: HashMap::new(),
+      nicknames,
+      state: UserState::default(),
+      room: None,
+      rejoining: None,
+      connection_events: VecDeque::new(),
+      push_to_talk: false,
+      talking: false,
+      announce_state,
+      room_presets,
+      preset: None,
+      base_settings,
+      overrides,
 
       sample_rate,
+      // peers are buffered as interleaved samples at the output rate
+      latency: Latency::from_ms(latency_ms, sample_rate, DECODED_CHANNELS as u16),
     })
   }
 
   pub fn start<A>(&mut self, addr: A) -> Result<(), anyhow::Error> where A: ToSocketAddrs {
-    self.client.connect(addr)?;
-    self.mic_service.start()?;
+    let users = self.client.connect(addr)?;
+    self.joined(users)
+  }
+
+  /// Connects to a server by the host the user gave, see [`Client::connect_host`].
+  pub fn start_host(&mut self, host: &str, port: Option<u16>) -> Result<(), anyhow::Error> {
+    let users = self.client.connect_host(host, port)?;
+    self.joined(users)
+  }
+
+  /// Sets up for the server we just connected to, which already has `users`.
+  fn joined(&mut self, users: Vec<UserInfo>) -> Result<(), anyhow::Error> {
+    self.roster.extend(users.iter().map(|user| user.id));
+    self.learn_names(&users);
+    self.apply_join_defaults(self.client.join_defaults())?;
+    // we may have muted before connecting
+    if self.announce_state && self.state != UserState::default() {
+      self.client.send(ClientMessage::SetState(self.state))?;
+    }
+    // nothing we send is relayed until we accept the rules
+    if self.client.rules().is_none() {
+      self.start_mic()?;
+    }
+    Ok(())
+  }
+
+  /// Picks up where we left off after reconnecting: the roster afresh, and our room and state as they were.
+  fn rejoin(&mut self, users: &[UserInfo]) -> Result<(), anyhow::Error> {
+    // peers missing from it are dropped once the rest of it arrives
+    self.roster = users.iter().map(|user| user.id).collect();
+    self.learn_names(users);
+    if let Some(room) = self.room.clone() {
+      self.rejoining = Some(room.clone());
+      self.client.send(ClientMessage::JoinRoom { room })?;
+    }
+    if self.announce_state && self.state != UserState::default() {
+      self.client.send(ClientMessage::SetState(self.state))?;
+    }
+    Ok(())
+  }
+
+  /// The oldest change to the connection not yet taken, see [`AppBuilder::with_auto_reconnect`].
+  pub fn take_connection_event(&mut self) -> Option<ConnectionEvent> {
+    self.connection_events.pop_front()
+  }
+
+  fn start_mic(&mut self) -> Result<(), anyhow::Error> {
+    if let Some(mic_service) = self.mic_service.as_mut() {
+      // a mic test may have started it already
+      if !mic_service.is_running() {
+        mic_service.start()?;
+      }
+    }
     Ok(())
   }
 
   pub fn stop(&mut self) {
     self.client.disconnect();
-    self.mic_service.stop();
+    if let Some(mic_service) = self.mic_service.as_mut() {
+      mic_service.stop();
+    }
   }
 
   pub fn poll(&mut self) -> Result<Option<ServerMessage>, anyhow::Error> {
     let msg = self.client.poll()?;
-    match msg {
-      Some(ref msg) => {
-        match msg {
-          ServerMessage::Voice{user, samples} => {
-            self.handle_voice(*user, samples)?;
-          },
-          ServerMessage::Connected(user) => {
-            info!("'{}' has joined.", user.username);
-            self.create_peer(user.id)?;
-          },
-          ServerMessage::Disconnected(user, reason) => {
-            info!("'{}' has left ({:?}).", user.username, reason);
-            self.remove_peer(user.id)?;
-          },
-          ServerMessage::Pong => {},
+    // before the message, which may be the rest of a new roster
+    while let Some(event) = self.client.take_connection_event() {
+      if let ConnectionEvent::Reconnected { users } = &event {
+        self.rejoin(users)?;
+      }
+      self.connection_events.push_back(event);
+    }
+    if let Some(ref msg) = msg {
+      match msg {
+        ServerMessage::Voice{user, seq, samples} => {
+          // a stray voice packet (e.g. one that overtook its Connected) shouldn't end the session
+          match self.handle_voice(*user, *seq, samples) {
+            Ok(Some(bandwidth)) => self.client.record_bandwidth(*user, bandwidth),
+            Ok(None) => {},
+            Err(e) => warn!("Dropped voice packet from {}: {}", user, e),
+          }
+        },
+        ServerMessage::Connected(user) => {
+          info!("'{}' has joined ({:?}).", self.nicknames.display(&user.username), user.role);
+          self.learn_names(std::slice::from_ref(user));
+          self.create_peer(user.id)?;
+        },
+        ServerMessage::Disconnected(user, reason) => {
+          info!("'{}' has left ({:?}).", self.nicknames.display(&user.username), reason);
+          self.remove_peer(user.id)?;
+        },
+        ServerMessage::UserList { users, complete } => {
+          self.roster.extend(users.iter().map(|user| user.id));
+          self.learn_names(users);
+          if *complete {
+            self.sync_peers()?;
+          }
+        },
+        ServerMessage::Chat { from, text } => {
+          if self.chat.len() >= CHAT_HISTORY {
+            self.chat.pop_front();
+          }
+          self.chat.push_back(ChatMessage { from: from.clone(), text: text.clone(), received: Instant::now() });
+        },
+        ServerMessage::StateChanged { user, state } => {
+          info!("{} is now {:?}.", user, state);
+        },
+        ServerMessage::RoomState { room, users, defaults } => {
+          info!("In room '{}' with {} user(s).", room, users.len());
+          match &self.rejoining {
+            // only passing through on the way back
+            Some(rejoining) if rejoining != room => {},
+            _ => {
+              self.rejoining = None;
+              // sent whenever anyone comes or goes, but the defaults are only for when we arrive
+              if self.room.as_ref() != Some(room) {
+                self.room = Some(room.clone());
+                self.apply_join_defaults(*defaults)?;
+              }
+              self.update_group_key(Some(room));
+            },
+          }
+        },
+        ServerMessage::DeadAir { active: true, suppressed } => {
+          warn!("The server says our mic has been stuck on the same level, check it's working{}.",
+            if *suppressed {" (no one can hear us until it changes)"} else {""});
+        },
+        ServerMessage::DeadAir { active: false, .. } => {
+          info!("Our mic is sending live audio again.");
+        },
+        ServerMessage::ServerMuted { muted } => {
+          warn!("An operator has {} us.", if *muted {"muted"} else {"unmuted"});
+        },
+        ServerMessage::Announcement { text } => {
+          info!("Announcement: {}", text);
+        },
+        ServerMessage::AdminResult { ok, message } => {
+          if *ok {info!("Admin command done: {}", message);} else {warn!("Admin command failed: {}", message);}
+        },
+        ServerMessage::Pong { .. }
+        | ServerMessage::AdminUsers(_)
+        | ServerMessage::Reliable { .. }
+        | ServerMessage::Ack { .. }
+        | ServerMessage::ConnectAck { .. }
+        | ServerMessage::Handshake { .. }
+        | ServerMessage::Sealed { .. }
+        | ServerMessage::Fragment(_)
+        | ServerMessage::Challenge { .. }
+        | ServerMessage::RoomList(_) => {},
+      }
+    }
+    if let Some((monitor, _)) = &self.monitor {
+      for packet in monitor.try_iter() {
+        if let Err(e) = self.handle_voice(MONITOR_ID, self.monitor_seq, &packet.data) {
+          warn!("Dropped monitored packet: {}", e);
         }
-      },
-      None => {}
+        self.monitor_seq = self.monitor_seq.wrapping_add(1);
+      }
+    }
+    if self.play_out() {
+      self.update_ducking();
+    }
+    self.drain_output_tap()?;
+    if let (Some(controller), Some(mic_service)) = (self.bitrate_controller.as_mut(), self.mic_service.as_ref()) {
+      if let Some(bitrate) = controller.update(self.client.network_stats()) {
+        info!("Network conditions changed, sending at {} bps, up to {:?}", bitrate, controller.bandwidth());
+        mic_service.follow_controller(controller);
+      }
     }
     Ok(msg)
   }
 
+  /// Moves to another room. The server answers with a [`ServerMessage::RoomState`].
+  pub fn join_room(&self, room: String) -> Result<(), anyhow::Error> {
+    self.client.send(ClientMessage::JoinRoom { room })
+  }
+
+  pub fn leave_room(&mut self) -> Result<(), anyhow::Error> {
+    self.room = None;
+    self.push_to_talk = false;
+    self.update_mic();
+    self.apply_preset(None)?;
+    self.update_group_key(None);
+    self.client.send(ClientMessage::LeaveRoom)
+  }
+
+  /// Switches to the key for the room we're now in, if end-to-end encryption is on.
+  fn update_group_key(&mut self, room: Option<&str>) {
+    let passphrase = match &self.e2e_passphrase {
+      Some(passphrase) => passphrase,
+      None => return,
+    };
+    if self.group_key.as_ref().map(|(key_room, _)| key_room.as_str()) == room {return;}
+    self.group_key = room.map(|room| (room.to_string(), Arc::new(GroupKey::derive(passphrase, room))));
+    // senders' salts under the old key mean nothing under the new one
+    self.replay_map.lock().unwrap().clear();
+    self.client.set_group_key(self.group_key.as_ref().map(|(_, key)| key.clone()));
+  }
+
+  /// Asks for the server's rooms. The server answers with a [`ServerMessage::RoomList`].
+  pub fn list_rooms(&self) -> Result<(), anyhow::Error> {
+    self.client.send(ClientMessage::ListRooms)
+  }
+
+  /// Switches to another mic while connected. Does nothing for [`Role::Audience`].
+  pub fn set_input_device(&mut self, name: Option<String>) -> Result<(), anyhow::Error> {
+    match self.mic_service.as_mut() {
+      Some(mic_service) => mic_service.set_device(name),
+      None => Ok(()),
+    }
+  }
+
+  /// Switches to other speakers while connected, without interrupting any peers.
+  pub fn set_output_device(&self, name: Option<String>) {
+    self.audio_manager.lock().unwrap().backend_mut().set_output_device(name);
+  }
+
+  /// Plays our own voice back quietly, after the same encoding, jitter buffering and decoding that peers hear.
+  pub fn set_monitor(&mut self, enabled: bool) -> Result<(), anyhow::Error> {
+    self.play_own_voice(enabled.then_some(MONITOR_AMPLITUDE))
+  }
+
+  /// Mic test: plays our own voice back as loud as peers will hear it, codec artifacts and all.
+  /// Works without connecting, starting the mic if it isn't running. Takes over from [`App::set_monitor`].
+  pub fn enable_loopback(&mut self, enabled: bool) -> Result<(), anyhow::Error> {
+    self.play_own_voice(enabled.then_some(LOOPBACK_AMPLITUDE))?;
+    let connected = self.client.is_connected();
+    if let Some(mic_service) = self.mic_service.as_mut() {
+      if enabled && !mic_service.is_running() {
+        mic_service.start()?;
+      }
+      // the mic was only on for the test
+      if !enabled && !connected {
+        mic_service.stop();
+      }
+    }
+    Ok(())
+  }
+
+  /// Plays our own encoded voice back at `amplitude`, or stops with `None`.
+  fn play_own_voice(&mut self, amplitude: Option<f64>) -> Result<(), anyhow::Error> {
+    let mic_service = self.mic_service.as_ref().ok_or_else(|| anyhow!("Audience members have no mic to monitor"))?;
+    if amplitude == self.monitor.as_ref().map(|(_, amplitude)| *amplitude) {return Ok(());}
+    if self.monitor.take().is_some() {
+      mic_service.set_monitor(None);
+      self.remove_peer(MONITOR_ID)?;
+    }
+    if let Some(amplitude) = amplitude {
+      let (tx, rx) = std::sync::mpsc::channel();
+      self.create_sound(MONITOR_ID, VoiceSoundSettings {
+        volume: Volume::Amplitude(amplitude),
+        ..Default::default()
+      })?;
+      mic_service.set_monitor(Some(tx));
+      self.monitor = Some((rx, amplitude));
+    }
+    Ok(())
+  }
+
+  /// Plays a WAV or Ogg Vorbis file into the call, along with our mic or, with `replace_mic`, instead of it.
+  pub fn play_file(&self, path: impl AsRef<Path>, replace_mic: bool) -> Result<(), anyhow::Error> {
+    let mic_service = self.mic_service.as_ref().ok_or_else(|| anyhow!("The audience can't play files into the call"))?;
+    mic_service.play_file(path, replace_mic)
+  }
+
+  pub fn stop_file(&self) {
+    if let Some(mic_service) = &self.mic_service {
+      mic_service.stop_file();
+    }
+  }
+
+  /// Whether a file from [`App::play_file`] is still playing.
+  pub fn is_playing_file(&self) -> bool {
+    self.mic_service.as_ref().is_some_and(MicService::is_playing_file)
+  }
+
+  /// Changes voice activity detection while connected, see [`AppBuilder::with_vad`].
+  /// Room presets no longer change processing after this.
+  pub fn set_vad(&mut self, vad: Option<VoiceDetector>) {
+    self.overrides.processing = true;
+    if let Some(mic_service) = &self.mic_service {
+      mic_service.set_vad(vad);
+    }
+  }
+
+  pub fn state(&self) -> UserState {
+    self.state
+  }
+
+  /// Our id as the server knows us, once connected.
+  pub fn id(&self) -> Option<Uuid> {
+    self.client.id()
+  }
+
+  pub fn server_name(&self) -> Option<&str> {
+    self.client.server_name()
+  }
+
+  /// Which of the server's addresses we got through to.
+  pub fn server_addr(&self) -> Option<std::net::SocketAddr> {
+    self.client.server_addr()
+  }
+
+  /// Message of the day from the server, if it has one.
+  pub fn motd(&self) -> Option<&str> {
+    self.client.motd()
+  }
+
+  /// Rules the server wants us to agree to before anyone hears us, if there are any we haven't.
+  pub fn rules(&self) -> Option<&str> {
+    self.client.rules()
+  }
+
+  /// Agrees to the server's [`App::rules`], and starts sending the mic.
+  pub fn accept_rules(&mut self) -> Result<(), anyhow::Error> {
+    self.client.accept_rules()?;
+    self.start_mic()
+  }
+
+  /// Round trip time to the server, once measured.
+  pub fn rtt(&self) -> Option<Duration> {
+    self.client.rtt()
+  }
+
+  /// Round trip time to the server, and how much voice we're losing from each peer.
+  pub fn network_stats(&self) -> &NetworkStats {
+    self.client.network_stats()
+  }
+
+  /// What each stage adds to the delay between our mic and a peer's speakers, and theirs and ours, in order.
+  /// The network's own delay comes on top, see [`App::rtt`].
+  pub fn latency(&self) -> Vec<StageLatency> {
+    let mut stages = self.mic_service.as_ref().map(MicService::latency).unwrap_or_default();
+    // the peer held back longest is the one we hear latest
+    let jitter = self.jitter_map.lock().unwrap().iter()
+      .filter(|(id, _)| **id != MONITOR_ID)
+      .map(|(_, jitter)| jitter.delay())
+      .max()
+      .unwrap_or_default();
+    stages.push(self.timings.latency(Stage::Jitter, jitter));
+    stages.push(self.timings.latency(Stage::Decode, Duration::ZERO));
+    stages.push(self.timings.latency(Stage::Playback, FRAME_DURATION * PLAYBACK_FRAMES as u32));
+    stages
+  }
+
+  /// Bitrate the mic is sent at, if it's being adapted to the network.
+  pub fn bitrate(&self) -> Option<i32> {
+    self.bitrate_controller.as_ref().map(BitrateController::bitrate)
+  }
+
+  /// Widest band the mic is sent with, if it's being adapted to the network.
+  pub fn bandwidth(&self) -> Option<Bandwidth> {
+    self.bitrate_controller.as_ref().map(BitrateController::bandwidth)
+  }
+
+  /// Who spoke when since the app was built, see [`SpeakingTimeline::render`] and [`SpeakingTimeline::save`].
+  pub fn speaking_timeline(&self) -> SpeakingTimeline {
+    self.speaking.lock().unwrap().clone()
+  }
+
+  /// Records what we hear to a WAV file at `path`. With `per_peer`, each peer is
+  /// also recorded on their own, to `path` with their name added.
+  pub fn start_recording(&mut self, path: impl AsRef<Path>, per_peer: bool) -> Result<(), anyhow::Error> {
+    if self.is_recording() {
+      return Err(anyhow!("Already recording"));
+    }
+    // anything tapped so far was heard before the recording started
+    self.drain_output_tap()?;
+    let offset = self.speaking.lock().unwrap().elapsed();
+    *self.recorder.lock().unwrap() = Some(Recorder::start(path.as_ref(), self.sample_rate, per_peer, offset)?);
+    self.update_output_tap();
+    Ok(())
+  }
+
+  /// Finishes the recording, marking who spoke when, and returns the files written.
+  pub fn stop_recording(&mut self) -> Result<Vec<PathBuf>, anyhow::Error> {
+    self.drain_output_tap()?;
+    let recorder = self.recorder.lock().unwrap().take().ok_or_else(|| anyhow!("Not recording"))?;
+    self.update_output_tap();
+    recorder.finish(&self.speaking.lock().unwrap())
+  }
+
+  pub fn is_recording(&self) -> bool {
+    self.recorder.lock().unwrap().is_some()
+  }
+
+  /// Keeps the last `duration` of what we hear, for [`App::save_clip`]. `None` stops keeping it.
+  pub fn set_clip_duration(&mut self, duration: Option<Duration>) {
+    if duration == self.clip.as_ref().map(ClipBuffer::duration) {return;}
+    self.clip = duration.map(|duration| ClipBuffer::new(duration, self.sample_rate));
+    self.update_output_tap();
+  }
+
+  /// Saves the last moments of what we heard to a WAV file, see [`App::set_clip_duration`].
+  pub fn save_clip(&mut self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+    self.drain_output_tap()?;
+    self.clip.as_ref().ok_or_else(|| anyhow!("Clips aren't being kept"))?.save(path.as_ref())
+  }
+
+  /// Taps the output stream while something needs what we hear, and stops once nothing does.
+  fn update_output_tap(&mut self) {
+    let needed = self.clip.is_some() || self.is_recording();
+    if needed == self.output_tap.is_some() {return;}
+    let tap = needed.then(|| RingBuffer::new(Latency::from_duration(TAP_BUFFER, self.sample_rate, 2).samples()).split());
+    let (prod, cons) = tap.unzip();
+    self.audio_manager.lock().unwrap().backend_mut().set_output_tap(prod);
+    self.output_tap = cons;
+  }
+
+  /// Hands what the output stream played since the last call to the recorder and clip buffer.
+  fn drain_output_tap(&mut self) -> Result<(), anyhow::Error> {
+    let tap = match self.output_tap.as_mut() {
+      Some(tap) => tap,
+      None => return Ok(()),
+    };
+    let mut buf = [0.0; 1024];
+    let mut recorder = self.recorder.lock().unwrap();
+    loop {
+      let read = tap.pop_slice(&mut buf);
+      if read == 0 {break;}
+      if let Some(recorder) = recorder.as_mut() {
+        recorder.write_mix(&buf[..read])?;
+      }
+      if let Some(clip) = self.clip.as_mut() {
+        clip.push(&buf[..read]);
+      }
+    }
+    Ok(())
+  }
+
+  fn learn_names(&mut self, users: &[UserInfo]) {
+    let mut speaking = self.speaking.lock().unwrap();
+    for user in users {
+      speaking.set_name(user.id, self.nicknames.display(&user.username).to_string());
+      self.usernames.insert(user.id, user.username.clone());
+    }
+  }
+
+  /// What to call a peer: the nickname we gave them, or the name they connected with.
+  /// Use it wherever peers are listed, in place of [`UserInfo::username`].
+  pub fn display_name(&self, id: Uuid) -> Option<&str> {
+    self.usernames.get(&id).map(|username| self.nicknames.display(username))
+  }
+
+  /// Shows whoever connects as `username` as `nickname` instead, or as themselves again if `None`.
+  pub fn set_nickname(&mut self, username: &str, nickname: Option<String>) {
+    self.nicknames.set(username, nickname);
+    let mut speaking = self.speaking.lock().unwrap();
+    for (id, name) in self.usernames.iter().filter(|(_, name)| name.as_str() == username) {
+      speaking.set_name(*id, self.nicknames.display(name).to_string());
+    }
+  }
+
+  /// Every nickname we've given, to [`Nicknames::save`] for next time.
+  pub fn nicknames(&self) -> &Nicknames {
+    &self.nicknames
+  }
+
+  /// Logs every voice packet received to `trace`, for the `trace` tool to render.
+  pub fn set_packet_trace(&mut self, trace: Option<Box<dyn std::io::Write + Send>>) {
+    self.client.set_packet_trace(trace);
+  }
+
+  /// Estimated quality of the audio we get from a peer.
+  pub fn call_quality(&self, id: Uuid) -> Option<CallQuality> {
+    let jitter_map = self.jitter_map.lock().unwrap();
+    let jitter = jitter_map.get(&id)?;
+    // the peer's link to the server is unknown, so assume it's like ours: a round trip's worth
+    // of network for both hops, then buffering, then a frame of packetization
+    let delay = self.client.rtt().unwrap_or_default() + jitter.delay() + jitter.frame_duration();
+    Some(CallQuality::estimate(jitter.loss(), jitter.jitter(), delay))
+  }
+
+  /// Save this to resume the session after a restart, see [`App::set_resume_token`].
+  pub fn resume_token(&self) -> Option<u64> {
+    self.client.resume_token()
+  }
+
+  /// Takes back a previous session on [`App::start`], if the server still has it.
+  pub fn set_resume_token(&mut self, token: Option<u64>) {
+    self.client.set_resume_token(token);
+  }
+
+  /// Key of the server we last connected to, see [`Client::server_key`].
+  pub fn server_key(&self) -> Option<PublicKeyBytes> {
+    self.client.server_key()
+  }
+
+  /// Save this to be known as the same client after a restart, see [`App::set_identity`].
+  pub fn identity(&self) -> &Identity {
+    self.client.identity()
+  }
+
+  /// Connects as a saved identity on [`App::start`], see [`Client::set_identity`].
+  pub fn set_identity(&mut self, identity: Identity) {
+    self.client.set_identity(identity);
+  }
+
+  /// Calls `callback` once the server hasn't answered for `after`, see [`Client::on_server_unresponsive`].
+  pub fn on_server_unresponsive<F>(&mut self, after: Duration, callback: F) where F: FnMut(Duration) + Send + 'static {
+    self.client.on_server_unresponsive(after, callback);
+  }
+
+  /// Stops sending our mic.
+  pub fn set_mic_muted(&mut self, muted: bool) -> Result<(), anyhow::Error> {
+    self.set_state(UserState { muted, ..self.state })?;
+    self.update_mic();
+    Ok(())
+  }
+
+  /// Push to talk is held (or released). Only matters in rooms that require it, see [`App::push_to_talk_required`].
+  pub fn set_talking(&mut self, talking: bool) {
+    self.talking = talking;
+    self.update_mic();
+  }
+
+  /// Whether the room only wants our mic while push to talk is held.
+  pub fn push_to_talk_required(&self) -> bool {
+    self.push_to_talk
+  }
+
+  /// Starts out how the room (or server) wants: muted, deafened or push to talk.
+  /// Only ever mutes, it's up to the user to unmute.
+  fn apply_join_defaults(&mut self, defaults: JoinDefaults) -> Result<(), anyhow::Error> {
+    if defaults != JoinDefaults::default() {
+      info!("Joining with {:?}", defaults);
+    }
+    self.push_to_talk = defaults.push_to_talk;
+    if defaults.start_muted {
+      self.set_mic_muted(true)?;
+    }
+    if defaults.start_deafened {
+      self.set_deafened(true)?;
+    }
+    self.update_mic();
+    self.apply_preset(defaults.preset)
+  }
+
+  /// The preset of the room we're in, if it has one and we follow it.
+  pub fn room_preset(&self) -> Option<RoomPreset> {
+    self.preset
+  }
+
+  /// Tunes encoding, buffering and processing to what the room is for, leaving alone whatever
+  /// the user chose. Rooms without a preset get the settings the app was built with.
+  fn apply_preset(&mut self, preset: Option<RoomPreset>) -> Result<(), anyhow::Error> {
+    if !self.room_presets || preset == self.preset {return Ok(());}
+    self.preset = preset;
+    let settings = match preset {
+      Some(preset) => {
+        info!("Room is set up for {:?}", preset);
+        PresetSettings::of(preset)
+      },
+      None => self.base_settings.clone(),
+    };
+    if !self.overrides.latency {
+      self.latency = Latency::from_ms(settings.latency_ms, self.sample_rate, DECODED_CHANNELS as u16);
+      for jitter in self.jitter_map.lock().unwrap().values_mut() {
+        jitter.set_max_delay(self.latency.duration());
+      }
+    }
+    if let Some(mic_service) = self.mic_service.as_mut() {
+      if !self.overrides.opus_config {
+        mic_service.set_opus_config(settings.opus_config)?;
+        // the controller knows better than the preset what the network can take
+        if let Some(controller) = &self.bitrate_controller {
+          mic_service.follow_controller(controller);
+        }
+      }
+      if !self.overrides.processing {
+        mic_service.set_noise_suppression(settings.noise_suppression)?;
+        mic_service.set_vad(settings.vad);
+      }
+    }
+    Ok(())
+  }
+
+  /// Sends the mic unless we're muted, or the room needs push to talk and it isn't held.
+  fn update_mic(&mut self) {
+    self.client.set_mic_muted(self.state.muted || (self.push_to_talk && !self.talking));
+  }
+
+  /// Stops playing anyone, ourselves included when monitoring.
+  pub fn set_deafened(&mut self, deafened: bool) -> Result<(), anyhow::Error> {
+    let volume = if deafened { 0.0 } else { 1.0 };
+    self.audio_manager.lock().unwrap().main_track().set_volume(volume, Tween::default())?;
+    self.set_state(UserState { deafened, ..self.state })
+  }
+
+  fn set_state(&mut self, state: UserState) -> Result<(), anyhow::Error> {
+    if state == self.state {return Ok(());}
+    self.state = state;
+    if self.announce_state {
+      self.client.send(ClientMessage::SetState(state))?;
+    }
+    Ok(())
+  }
+
+  /// How many voice packets were dropped for not decrypting with the room's key, or being replays.
+  pub fn rejected_packets(&self) -> usize {
+    self.rejected_packets.load(Ordering::Relaxed)
+  }
+
+  /// Flags a peer for abuse. Reports go to the server's audit log.
+  pub fn report(&self, user: Uuid, reason: String) -> Result<(), anyhow::Error> {
+    self.client.send(ClientMessage::Report { user, reason })
+  }
+
+  /// Runs an operator command, if `token` is the server's admin token.
+  /// The server answers with a [`ServerMessage::AdminResult`] or [`ServerMessage::AdminUsers`].
+  pub fn admin(&self, token: String, command: AdminCommand) -> Result<(), anyhow::Error> {
+    self.client.send(ClientMessage::Admin { token, command })
+  }
+
+  /// Sends a message to everyone in our room.
+  pub fn send_chat(&self, text: String) -> Result<(), anyhow::Error> {
+    if text.len() > MAX_CHAT_LEN {
+      return Err(anyhow!("Chat messages can be at most {} bytes", MAX_CHAT_LEN));
+    }
+    self.client.send(ClientMessage::Chat { text })
+  }
+
+  /// Recent chat messages from our room, oldest first. Our own aren't echoed back.
+  pub fn chat(&self) -> impl Iterator<Item = &ChatMessage> {
+    self.chat.iter()
+  }
+
+  /// Asks the server who's connected, e.g. if we might have missed some joins or leaves.
+  pub fn list_users(&self) -> Result<(), anyhow::Error> {
+    self.client.send(ClientMessage::ListUsers)
+  }
+
+  /// Makes our peers match a complete roster from the server.
+  fn sync_peers(&mut self) -> Result<(), anyhow::Error> {
+    let roster = std::mem::take(&mut self.roster);
+    let current = self.sound_map.lock().unwrap().keys().copied().collect::<HashSet<_>>();
+    for id in current.difference(&roster).filter(|id| **id != MONITOR_ID) {
+      self.remove_peer(*id)?;
+    }
+    for id in roster.difference(&current) {
+      self.create_peer(*id)?;
+    }
+    Ok(())
+  }
+
+  pub fn playback_rate(&self, id: Uuid) -> Option<f64> {
+    self.sound_map.lock().unwrap().get(&id).map(|sound| sound.playback_rate())
+  }
+
+  /// Sets how fast a peer's voice is played back, within [`PLAYBACK_RATE_RANGE`].
+  ///
+  /// Slowing a peer down makes them easier to follow, at the cost of their audio falling further behind.
+  /// Their voice is resampled rather than time-stretched, so its pitch drops or rises with the rate.
+  pub fn set_playback_rate(&self, id: Uuid, playback_rate: f64) -> Result<(), anyhow::Error> {
+    let sound_map = self.sound_map.lock().unwrap();
+    let sound = sound_map.get(&id).ok_or_else(|| anyhow!("No such peer"))?;
+    sound.set_playback_rate(playback_rate.clamp(PLAYBACK_RATE_RANGE.0, PLAYBACK_RATE_RANGE.1));
+    Ok(())
+  }
+
+  pub fn volume(&self, id: Uuid) -> Option<f32> {
+    self.sound_map.lock().unwrap().get(&id).map(|sound| sound.gain())
+  }
+
+  /// Turns a peer up or down, from 0 (silent) to [`MAX_PEER_VOLUME`].
+  pub fn set_volume(&self, id: Uuid, volume: f32) -> Result<(), anyhow::Error> {
+    let sound_map = self.sound_map.lock().unwrap();
+    let sound = sound_map.get(&id).ok_or_else(|| anyhow!("No such peer"))?;
+    sound.set_gain(volume.clamp(0.0, MAX_PEER_VOLUME));
+    Ok(())
+  }
+
+  pub fn is_muted(&self, id: Uuid) -> Option<bool> {
+    self.sound_map.lock().unwrap().get(&id).map(|sound| sound.muted())
+  }
+
+  /// Mutes a peer for us only, keeping their volume for when they're unmuted.
+  pub fn set_muted(&self, id: Uuid, muted: bool) -> Result<(), anyhow::Error> {
+    let sound_map = self.sound_map.lock().unwrap();
+    let sound = sound_map.get(&id).ok_or_else(|| anyhow!("No such peer"))?;
+    sound.set_muted(muted);
+    Ok(())
+  }
+
+  /// Whether a peer is sending voice right now, going by when their packets last arrived.
+  pub fn is_talking(&self, id: Uuid) -> Option<bool> {
+    self.jitter_map.lock().unwrap().get(&id).map(is_talking)
+  }
+
+  /// Plays noise like a peer's background while they aren't sending, so they don't seem to have dropped out.
+  pub fn set_comfort_noise(&mut self, enabled: bool) {
+    self.comfort_noise = enabled;
+  }
+
+  /// Limits how many peers are heard at full volume at once, ducking everyone but the loudest.
+  pub fn set_max_speakers(&mut self, max_speakers: Option<usize>) {
+    self.max_speakers = max_speakers;
+    self.update_ducking();
+  }
+
+  fn update_ducking(&self) {
+    let activity_map = self.activity_map.lock().unwrap();
+    let sound_map = self.sound_map.lock().unwrap();
+    let mut levels = activity_map.iter()
+      // our own voice and muted peers shouldn't take anyone's place
+      .filter(|(id, _)| **id != MONITOR_ID && !sound_map.get(id).is_some_and(|sound| sound.muted()))
+      .map(|(id, activity)| (*id, activity.level()))
+      .collect::<Vec<_>>();
+    // ties (e.g. everyone silent) go by id, not by whatever order the map is in
+    levels.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    for (rank, (id, _)) in levels.iter().enumerate() {
+      if let Some(sound) = sound_map.get(id) {
+        sound.set_ducked(self.max_speakers.is_some_and(|max| rank >= max));
+      }
+    }
+  }
+
   fn remove_peer(&self, id: Uuid) -> Result<(), anyhow::Error> {
     let mut sound_map = self.sound_map.lock().unwrap();
     let mut producer_map = self.producer_map.lock().unwrap();
     let mut decoder_map = self.decoder_map.lock().unwrap();
+    let mut jitter_map = self.jitter_map.lock().unwrap();
+    let mut activity_map = self.activity_map.lock().unwrap();
 
-    if let Some(sound) = sound_map.remove(&id) {
-      // TODO: do something with the handle?
-    }
+    // TODO: do something with the handle?
+    sound_map.remove(&id);
     producer_map.remove(&id);
     decoder_map.remove(&id);
+    jitter_map.remove(&id);
+    activity_map.remove(&id);
+    self.comfort_map.lock().unwrap().remove(&id);
+    self.replay_map.lock().unwrap().remove(&id);
 
     Ok(())
   }
 
   fn create_peer(&self, id: Uuid) -> Result<(), anyhow::Error> {
-    let latency = self.mic_service.latency();
+    self.create_sound(id, VoiceSoundSettings::default())
+  }
+
+  /// Sets up everything needed to play a stream of voice packets under `id`.
+  fn create_sound(&self, id: Uuid, settings: VoiceSoundSettings) -> Result<(), anyhow::Error> {
     let mut sound_map = self.sound_map.lock().unwrap();
     if sound_map.contains_key(&id) {
       warn!("Peer already exists");
       return Ok(());
     }
-    let (mut prod, cons) = RingBuffer::new(latency.samples() * 2).split();
-    for _ in 0..latency.samples() {
-      prod.push(0.0).unwrap();
-    }
+    let decoder = OpusDecoder::new(self.sample_rate)?;
+    // the jitter buffer does the real buffering, this only needs to cover the gaps between polls
+    let (prod, cons) = RingBuffer::new(decoder.max_frame_size() * PLAYBACK_FRAMES * 2).split();
     let mut producer_map = self.producer_map.lock().unwrap();
     producer_map.insert(id, prod);
 
     let mut decoder_map = self.decoder_map.lock().unwrap();
-    decoder_map.insert(id, OpusDecoder::new(self.sample_rate)?);
+    decoder_map.insert(id, decoder);
 
-    let sound = VoiceSoundData::new(VoiceSoundSettings {
-      ..Default::default()
-    }, cons);
+    let mut jitter_map = self.jitter_map.lock().unwrap();
+    jitter_map.insert(id, JitterBuffer::new(FRAME_DURATION, self.latency.duration()));
+
+    self.comfort_map.lock().unwrap().insert(id, ComfortNoise::for_peer(&id));
 
-    let mut audio_manager = self.audio_manager.lock().unwrap();
-    sound_map.insert(id, audio_manager.play(sound)?);
+    let sound = VoiceSoundData::new(settings, cons);
+    sound_map.insert(id, self.mixer.lock().unwrap().add(id, sound)?);
 
     Ok(())
   }
 
-  fn handle_voice(&self, id: Uuid, data: &Vec<u8>) -> Result<(), anyhow::Error> {
-    let mut decoder_map = self.decoder_map.lock().unwrap();
-    let decoder = decoder_map.get_mut(&id).ok_or_else(|| anyhow!("No decoder for peer"))?;
-    match decoder.decode(data) {
-      Ok(data) => {
-        let mut producer_map = self.producer_map.lock().unwrap();
-        let producer = producer_map.get_mut(&id).ok_or_else(|| anyhow!("No producer for peer"))?;
-        producer.push_slice(&data);
+  /// Queues a peer's voice for playing, returning what it was encoded with.
+  fn handle_voice(&self, id: Uuid, seq: SeqNum, data: &[u8]) -> Result<Option<Bandwidth>, anyhow::Error> {
+    let decrypted;
+    let data = match &self.group_key {
+      // our own monitored voice never left the machine
+      Some((_, key)) if id != MONITOR_ID => {
+        let mut replay_map = self.replay_map.lock().unwrap();
+        decrypted = match key.open(seq, data, replay_map.entry(id).or_default()) {
+          Some(decrypted) => decrypted,
+          None => {
+            self.rejected_packets.fetch_add(1, Ordering::Relaxed);
+            return Err(anyhow!("Not sealed with this room's key, or replayed"));
+          }
+        };
+        &decrypted[..]
       },
-      Err(e) => {
-        warn!("Failed to decode voice data: {}", e);
+      _ => data,
+    };
+    let mut jitter_map = self.jitter_map.lock().unwrap();
+    let jitter = jitter_map.get_mut(&id).ok_or_else(|| anyhow!("No jitter buffer for peer"))?;
+    jitter.push(seq, data.to_vec());
+    Ok(Bandwidth::of_packet(data))
+  }
+
+  /// Runs `f`, counting how long it took against `stage`.
+  fn timed<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    self.timings.record(stage, start.elapsed());
+    result
+  }
+
+  /// Decodes frames from each peer's jitter buffer into their playback buffer as it drains.
+  /// Nothing is decoded ahead of a full playback buffer, packets wait in the jitter buffer instead.
+  ///
+  /// Returns whether anything was decoded.
+  fn play_out(&self) -> bool {
+    let mut jitter_map = self.jitter_map.lock().unwrap();
+    let mut decoder_map = self.decoder_map.lock().unwrap();
+    let mut producer_map = self.producer_map.lock().unwrap();
+    let mut activity_map = self.activity_map.lock().unwrap();
+    let mut comfort_map = self.comfort_map.lock().unwrap();
+    let mut decoded = false;
+    for (id, jitter) in jitter_map.iter_mut() {
+      let (decoder, producer, comfort) = match (decoder_map.get_mut(id), producer_map.get_mut(id), comfort_map.get_mut(id)) {
+        (Some(decoder), Some(producer), Some(comfort)) => (decoder, producer, comfort),
+        _ => continue,
+      };
+      while producer.len() < decoder.frame_size() * PLAYBACK_FRAMES {
+        let frame = match jitter.pop() {
+          Some(Playout::Packet(packet)) => match self.timed(Stage::Decode, || decoder.decode(&packet)) {
+            Ok(frame) => {
+              jitter.set_frame_duration(decoder.frame_duration());
+              comfort.update(&frame);
+              if *id != MONITOR_ID && audio_level(&frame) <= DEFAULT_VAD_THRESHOLD {
+                self.speaking.lock().unwrap().speaking(*id);
+              }
+              frame
+            },
+            Err(e) => {
+              warn!("Failed to decode voice data: {}", e);
+              vec![0.0; decoder.frame_size()]
+            }
+          },
+          Some(Playout::Lost) => match decoder.conceal(jitter.peek()) {
+            Ok(frame) => frame,
+            Err(e) => {
+              warn!("Failed to conceal lost voice data: {}", e);
+              vec![0.0; decoder.frame_size()]
+            }
+          },
+          None => break,
+        };
+        producer.push_slice(&frame);
+        if *id != MONITOR_ID {
+          if let Some(recorder) = self.recorder.lock().unwrap().as_mut() {
+            let speaking = self.speaking.lock().unwrap();
+            if let Err(e) = recorder.write_peer(*id, speaking.name(*id), &frame) {
+              warn!("Failed to record voice: {}", e);
+            }
+          }
+        }
+        activity_map.entry(*id)
+          .or_insert_with(|| Activity { level: 0.0, updated: Instant::now() })
+          .update(&frame);
+        decoded = true;
+      }
+      if producer.len() >= decoder.frame_size() * PLAYBACK_FRAMES {
+        jitter.hold();
+      } else if self.comfort_noise && *id != MONITOR_ID && !is_talking(jitter) {
+        // they stopped sending, not a late packet we're waiting on
+        while producer.len() < decoder.frame_size() * PLAYBACK_FRAMES {
+          producer.push_slice(&comfort.generate(decoder.frame_size()));
+        }
       }
     }
+    decoded
+  }
+}
 
-    Ok(())
+pub struct AppBuilder {
+  username: String,
+  latency_ms: f32,
+  role: Role,
+  input_device: Option<String>,
+  output_device: Option<String>,
+  vad: Option<VoiceDetector>,
+  noise_suppression: bool,
+  stereo: bool,
+  audio_threads: AudioThreadSettings,
+  e2e_passphrase: Option<String>,
+  announce_state: bool,
+  password: Option<String>,
+  server_key: Option<PublicKeyBytes>,
+  known_servers: Option<PathBuf>,
+  keepalive_interval: Option<Duration>,
+  auto_reconnect: bool,
+  comfort_noise: bool,
+  channel_map: ChannelMap,
+  bitrate_controller: Option<BitrateController>,
+  opus_config: OpusConfig,
+  room_presets: bool,
+  overrides: PresetOverrides,
+  nicknames: Nicknames,
+}
+
+impl AppBuilder {
+  pub fn new(username: String) -> Self {
+    Self {
+      username,
+      latency_ms: 150.0,
+      role: Role::Speaker,
+      input_device: None,
+      output_device: None,
+      vad: Some(VoiceDetector::default()),
+      noise_suppression: false,
+      stereo: false,
+      audio_threads: AudioThreadSettings::default(),
+      e2e_passphrase: None,
+      announce_state: true,
+      password: None,
+      server_key: None,
+      known_servers: None,
+      keepalive_interval: None,
+      auto_reconnect: false,
+      comfort_noise: true,
+      channel_map: ChannelMap::default(),
+      bitrate_controller: None,
+      opus_config: OpusConfig::default().with_fec(true),
+      room_presets: true,
+      overrides: PresetOverrides::default(),
+      nicknames: Nicknames::new(),
+    }
+  }
+
+  /// Most audio to buffer for each peer, to ride out network jitter.
+  pub fn with_latency(mut self, latency_ms: f32) -> Self {
+    self.latency_ms = latency_ms;
+    self.overrides.latency = true;
+    self
+  }
+
+  /// [`Role::Audience`] never opens the mic.
+  pub fn with_role(mut self, role: Role) -> Self {
+    self.role = role;
+    self
+  }
+
+  /// Mic to record from, by name (see [`crate::list_devices`]). Defaults to the system's default input.
+  pub fn with_input_device(mut self, name: Option<String>) -> Self {
+    self.input_device = name;
+    self
+  }
+
+  /// Speakers to play through, by name (see [`crate::list_devices`]). Defaults to the system's default output.
+  pub fn with_output_device(mut self, name: Option<String>) -> Self {
+    self.output_device = name;
+    self
+  }
+
+  /// Stops sending while the mic is silent. On by default, `None` sends every frame.
+  pub fn with_vad(mut self, vad: Option<VoiceDetector>) -> Self {
+    self.vad = vad;
+    self.overrides.processing = true;
+    self
+  }
+
+  /// Turns down steady background noise (fans, hum) before it's sent. Off by default.
+  pub fn with_noise_suppression(mut self, noise_suppression: bool) -> Self {
+    self.noise_suppression = noise_suppression;
+    self.overrides.processing = true;
+    self
+  }
+
+  /// How the mic is encoded. Defaults to opus' own choices with FEC on, in 20 ms frames.
+  /// A [`AppBuilder::with_bitrate_controller`] overrides the bitrate.
+  pub fn with_opus_config(mut self, opus_config: OpusConfig) -> Self {
+    self.opus_config = opus_config;
+    self.overrides.opus_config = true;
+    self
+  }
+
+  /// Steps the mic's bitrate down when the network is congested and back up once it clears. Off by default.
+  pub fn with_bitrate_controller(mut self, controller: Option<BitrateController>) -> Self {
+    self.bitrate_controller = controller;
+    self
+  }
+
+  /// Which output channels voice is played on. Defaults to the first two.
+  pub fn with_channel_map(mut self, channel_map: ChannelMap) -> Self {
+    self.channel_map = channel_map;
+    self
+  }
+
+  /// Sends in stereo if the mic supports it. Peers are always played back in stereo. Off by default.
+  pub fn with_stereo(mut self, stereo: bool) -> Self {
+    self.stereo = stereo;
+    self
+  }
+
+  /// Realtime priority and core pinning for the mic and playback threads. Both are off by default.
+  pub fn with_audio_threads(mut self, audio_threads: AudioThreadSettings) -> Self {
+    self.audio_threads = audio_threads;
+    self
+  }
+
+  /// Encrypts voice end-to-end with a key derived from this passphrase and the room name,
+  /// so the server can't listen in. Everyone in the room needs the same passphrase.
+  pub fn with_e2e_passphrase(mut self, passphrase: Option<String>) -> Self {
+    self.e2e_passphrase = passphrase;
+    self
+  }
+
+  /// Password for servers that need one.
+  pub fn with_password(mut self, password: Option<String>) -> Self {
+    self.password = password;
+    self
+  }
+
+  /// Key the server must have, see [`Client::set_server_key`].
+  pub fn with_server_key(mut self, key: Option<PublicKeyBytes>) -> Self {
+    self.server_key = key;
+    self
+  }
+
+  /// File to remember servers' keys in, see [`Client::set_known_servers`].
+  pub fn with_known_servers(mut self, path: Option<PathBuf>) -> Self {
+    self.known_servers = path;
+    self
+  }
+
+  /// How long we can go without sending before pinging the server, see [`Client::set_keepalive_interval`].
+  pub fn with_keepalive_interval(mut self, interval: Duration) -> Self {
+    self.keepalive_interval = Some(interval);
+    self
+  }
+
+  /// Reconnects on our own when the server goes away, then goes back to our room, see [`Client::set_auto_reconnect`].
+  pub fn with_auto_reconnect(mut self, enabled: bool) -> Self {
+    self.auto_reconnect = enabled;
+    self
+  }
+
+  /// Plays noise like a peer's background while they aren't sending, see [`App::set_comfort_noise`]. On by default.
+  pub fn with_comfort_noise(mut self, enabled: bool) -> Self {
+    self.comfort_noise = enabled;
+    self
+  }
+
+  /// Whether to tune encoding, buffering and processing to each room's [`RoomPreset`]. On by default.
+  /// Settings chosen on the builder (latency, opus config, VAD or noise suppression) are kept either way.
+  pub fn with_room_presets(mut self, room_presets: bool) -> Self {
+    self.room_presets = room_presets;
+    self
+  }
+
+  /// Whether others are told when we mute or deafen, so they can show it. On by default.
+  pub fn with_state_announcements(mut self, announce_state: bool) -> Self {
+    self.announce_state = announce_state;
+    self
+  }
+
+  /// Names to show peers by instead of their own, e.g. from [`Nicknames::load`].
+  pub fn with_nicknames(mut self, nicknames: Nicknames) -> Self {
+    self.nicknames = nicknames;
+    self
   }
-}
\ No newline at end of file
+
+  /// Takes the devices, latency and processing from a profile. Later calls can still override them.
+  pub fn with_profile(self, profile: AudioProfile) -> Self {
+    self
+      .with_input_device(profile.input_device)
+      .with_output_device(profile.output_device)
+      .with_latency(profile.latency_ms)
+      .with_noise_suppression(profile.noise_suppression)
+      .with_vad(profile.vad)
+      .with_stereo(profile.stereo)
+  }
+
+  pub fn build(self) -> Result<App, anyhow::Error> {
+    App::from_builder(self)
+  }
+}
@@ -0,0 +1,95 @@
+use std::time::{Duration, Instant};
+
+use crate::{encoder::Bandwidth, stats::NetworkStats};
+
+/// How often the bitrate is reconsidered.
+const ADAPT_INTERVAL: Duration = Duration::from_secs(2);
+/// Loss over an interval above which we back off.
+const CONGESTED_LOSS: f32 = 0.05;
+/// Loss over an interval below which the link looks clear.
+const CLEAR_LOSS: f32 = 0.01;
+/// Round trip time above which we back off, queues are probably filling up.
+const CONGESTED_RTT: Duration = Duration::from_millis(400);
+/// Clear intervals in a row needed before stepping back up.
+const CLEAR_INTERVALS: usize = 5;
+
+/// Picks an encoder bitrate from how the network is doing: backs off quickly
+/// when packets are lost or the round trip grows, and creeps back up once the
+/// link has been clear for a while. The band narrows along with the bitrate,
+/// see [`BitrateController::bandwidth`].
+///
+/// Loss is taken from unanswered pings and gaps in peers' voice, since the
+/// server doesn't tell us what it lost of ours, but a congested link usually
+/// loses in both directions.
+#[derive(Debug)]
+pub struct BitrateController {
+  min: i32,
+  max: i32,
+  bitrate: i32,
+  /// Totals from [`NetworkStats`] at the start of the interval, as (expected, lost).
+  last_totals: (u64, u64),
+  last_update: Instant,
+  clear_intervals: usize,
+}
+
+impl BitrateController {
+  /// Starts at `max`, in bits per second.
+  pub fn new(min: i32, max: i32) -> Self {
+    Self {
+      min,
+      max,
+      bitrate: max,
+      last_totals: (0, 0),
+      last_update: Instant::now(),
+      clear_intervals: 0,
+    }
+  }
+
+  pub fn bitrate(&self) -> i32 {
+    self.bitrate
+  }
+
+  /// Widest band worth sending at the current bitrate, so poor links fall back to wideband and then narrowband.
+  pub fn bandwidth(&self) -> Bandwidth {
+    Bandwidth::for_bitrate(self.bitrate)
+  }
+
+  /// Returns the new bitrate if it should change.
+  pub fn update(&mut self, stats: &NetworkStats) -> Option<i32> {
+    if self.last_update.elapsed() < ADAPT_INTERVAL {return None;}
+    self.last_update = Instant::now();
+
+    let totals = totals(stats);
+    let expected = totals.0.saturating_sub(self.last_totals.0);
+    let lost = totals.1.saturating_sub(self.last_totals.1);
+    self.last_totals = totals;
+    let loss = if expected == 0 { 0.0 } else { lost as f32 / expected as f32 };
+    let rtt = stats.rtt.unwrap_or_default();
+
+    let bitrate = if loss > CONGESTED_LOSS || rtt > CONGESTED_RTT {
+      self.clear_intervals = 0;
+      self.bitrate * 3 / 4
+    } else if loss < CLEAR_LOSS {
+      self.clear_intervals += 1;
+      if self.clear_intervals < CLEAR_INTERVALS {return None;}
+      self.clear_intervals = 0;
+      self.bitrate * 9 / 8
+    } else {
+      self.clear_intervals = 0;
+      return None;
+    };
+    let bitrate = bitrate.clamp(self.min, self.max);
+    if bitrate == self.bitrate {return None;}
+    self.bitrate = bitrate;
+    Some(bitrate)
+  }
+}
+
+/// Packets expected and lost so far, over pings and every peer's voice.
+fn totals(stats: &NetworkStats) -> (u64, u64) {
+  // the latest ping may still be on its way back
+  let pings_lost = stats.pings_sent.saturating_sub(1).saturating_sub(stats.pongs_received);
+  stats.peers.values().fold((stats.pings_sent, pings_lost), |(expected, lost), peer| {
+    (expected + peer.received + peer.lost, lost + peer.lost)
+  })
+}
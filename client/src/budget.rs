@@ -0,0 +1,66 @@
+use std::{sync::atomic::{AtomicU32, Ordering}, time::Duration};
+
+/// How many frames the processing time average mostly covers.
+const AVERAGE_FRAMES: f32 = 50.0;
+
+/// A step audio goes through between one person's mic and another's speakers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stage {
+  /// converting the mic's sample rate to one opus takes
+  Resample,
+  /// turning down background noise
+  Denoise,
+  /// waiting for a whole frame of audio to encode
+  Framing,
+  /// opus, which looks a few milliseconds ahead
+  Encode,
+  /// holding packets to smooth out uneven arrival
+  Jitter,
+  Decode,
+  /// decoded audio waiting for the output device
+  Playback,
+}
+
+/// What one stage adds to the delay.
+#[derive(Copy, Clone, Debug)]
+pub struct StageLatency {
+  pub stage: Stage,
+  /// Audio the stage holds on to before passing it on, fixed by how it works.
+  pub delay: Duration,
+  /// Recent time spent on each frame, measured as audio goes through.
+  pub processing: Duration,
+}
+
+impl StageLatency {
+  pub fn total(&self) -> Duration {
+    self.delay + self.processing
+  }
+}
+
+/// Processing time of each stage, averaged over recent frames.
+///
+/// Written from audio callbacks without locking. Each stage is only timed by
+/// one thread, so a plain load and store is enough to update its average.
+#[derive(Default)]
+pub(crate) struct StageTimings {
+  /// Seconds, as `f32` bits, by [`Stage`].
+  averages: [AtomicU32; 7],
+}
+
+impl StageTimings {
+  pub fn record(&self, stage: Stage, took: Duration) {
+    let average = &self.averages[stage as usize];
+    let old = f32::from_bits(average.load(Ordering::Relaxed));
+    let new = if old == 0.0 { took.as_secs_f32() } else { old + (took.as_secs_f32() - old) / AVERAGE_FRAMES };
+    average.store(new.to_bits(), Ordering::Relaxed);
+  }
+
+  pub fn average(&self, stage: Stage) -> Duration {
+    Duration::from_secs_f32(f32::from_bits(self.averages[stage as usize].load(Ordering::Relaxed)))
+  }
+
+  /// `stage` holding on to `delay` of audio, with the processing time measured so far.
+  pub fn latency(&self, stage: Stage, delay: Duration) -> StageLatency {
+    StageLatency { stage, delay, processing: self.average(stage) }
+  }
+}
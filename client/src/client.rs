@@ -1,84 +1,784 @@
-use std::{net::{UdpSocket, ToSocketAddrs}, sync::mpsc::Receiver, collections::VecDeque};
+use std::{collections::VecDeque, io::Write, net::{IpAddr, SocketAddr, UdpSocket, ToSocketAddrs}, path::PathBuf, sync::{Arc, Mutex, atomic::{AtomicU32, Ordering}, mpsc::Receiver}, time::{Duration, Instant}};
 
-use common::packets::{self, ServerMessage};
-use log::{debug, info, error};
+use common::{crypto::{self, Identity, KeyExchange, Password, PublicKeyBytes, Session, Side}, fragment::Reassembler, packets::{self, ClientMessage, ServerMessage, SeqNum, DEFAULT_PORT}, reliable::ReliableChannel, trace::TraceEvent, JoinDefaults, Role, UserInfo};
+use log::{debug, info, warn};
+use uuid::Uuid;
 
 use anyhow::anyhow;
-use ringbuf::Consumer;
-use uuid::Uuid;
+
+use crate::{e2e::GroupKey, encoder::Bandwidth, known_servers::KnownServers, srv, stats::NetworkStats};
+
+/// An encoded frame of mic audio.
+#[derive(Clone)]
+pub struct MicPacket {
+  pub data: Vec<u8>,
+  /// Loudness of the frame in -dBov, see [`audio_level`].
+  pub level: u8,
+}
+
+/// Loudness of some samples in -dBov, clamped to 0 (loudest) ..= 127 (silent).
+pub fn audio_level(samples: &[f32]) -> u8 {
+  let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32).sqrt();
+  (-20.0 * rms.log10()).clamp(0.0, 127.0) as u8
+}
+
+/// Finds the addresses a server might be at, in the order to try them, from a
+/// host and maybe a port. The host is a name, an IPv4 address or an IPv6
+/// address, with or without square brackets.
+///
+/// Without a port, a name is first looked up as SRV records under
+/// [`SRV_PREFIX`](crate::SRV_PREFIX), before falling back to the host itself on [`DEFAULT_PORT`].
+pub fn resolve(host: &str, port: Option<u16>) -> Result<Vec<SocketAddr>, anyhow::Error> {
+  let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+  if let Ok(ip) = host.parse::<IpAddr>() {
+    return Ok(vec![SocketAddr::new(ip, port.unwrap_or(DEFAULT_PORT))]);
+  }
+  let mut addrs = Vec::new();
+  if port.is_none() {
+    for record in srv::lookup_srv(host) {
+      match (record.target.as_str(), record.port).to_socket_addrs() {
+        Ok(found) => addrs.extend(found),
+        Err(e) => debug!("Failed to resolve {}, from the SRV records of {}: {}", record.target, host, e),
+      }
+    }
+  }
+  if addrs.is_empty() {
+    addrs.extend((host, port.unwrap_or(DEFAULT_PORT)).to_socket_addrs()?);
+  }
+  if addrs.is_empty() {
+    return Err(anyhow!("no addresses found for {}", host));
+  }
+  Ok(addrs)
+}
+
+/// What a server given as `host` and maybe `port` is kept under in [`KnownServers`]:
+/// the name as given, not where it resolves to now, since a name can be pointed
+/// somewhere else. An address is kept as it would be resolved.
+fn known_as(host: &str, port: Option<u16>) -> String {
+  let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+  if let Ok(ip) = host.parse::<IpAddr>() {
+    return SocketAddr::new(ip, port.unwrap_or(DEFAULT_PORT)).to_string();
+  }
+  let name = host.trim_end_matches('.').to_ascii_lowercase();
+  match port {
+    Some(port) => format!("{}:{}", name, port),
+    // without a port, SRV records can send it anywhere
+    None => name,
+  }
+}
+
+/// A socket on any local address of the same family as `server`, which it can reach.
+pub(crate) fn bind_for(server: SocketAddr) -> std::io::Result<UdpSocket> {
+  UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+}
 
 pub enum ClientState {
-  Invalid,
   Connecting,
   Connected,
   Disconnected,
+  /// Lost the server, and waiting to try it again.
+  Reconnecting,
+}
+
+/// A change in our connection to the server, see [`Client::take_connection_event`].
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum ConnectionEvent {
+  /// The server went away, or stopped answering.
+  Disconnected { reason: String },
+  /// Trying to reconnect for the `attempt`th time after `delay`.
+  Reconnecting { attempt: u32, delay: Duration },
+  /// Back on the server, with the users there now.
+  /// Any further users follow as [`ServerMessage::UserList`]s, as on [`Client::connect`].
+  Reconnected { users: Vec<UserInfo> },
+  /// The server turned us away when reconnecting, so we've stopped trying.
+  GaveUp { reason: String },
 }
 
-const PACKET_MAX_SIZE: usize = 1024;
 
-pub type OnVoiceCB = dyn Fn(Uuid, Vec<u8>) -> Result<(), anyhow::Error> + Send + Sync;
-pub type OnDisconnect = dyn FnMut(Uuid) -> Result<(), anyhow::Error> + Send + Sync;
+/// How long we can go without sending anything before pinging, so the server doesn't time us out.
+const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// Silence from the server after which it's considered unresponsive, the server's own timeout.
+const DEFAULT_UNRESPONSIVE_AFTER: Duration = Duration::from_secs(3);
+/// How often we ping to measure the round trip time, even while talking.
+const RTT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait for the server to answer a connect before asking again.
+const CONNECT_RETRY: Duration = Duration::from_millis(500);
+/// Times to ask before giving up on connecting.
+const CONNECT_ATTEMPTS: u32 = 6;
+/// Wait before first trying to reconnect, doubled each time it fails.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between tries to reconnect.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
 pub struct Client {
   username: String,
+  role: Role,
   socket: UdpSocket,
   state: ClientState,
-  mic_rx: Receiver<Vec<u8>>,
+  mic_rx: Receiver<MicPacket>,
+  /// Assigned by the server on connect, used to keep the session if our address changes.
+  connection_id: Option<u64>,
+  /// Our id as the server knows us.
+  id: Option<Uuid>,
+  server_name: Option<String>,
+  /// Message of the day from the server.
+  motd: Option<String>,
+  /// Rules the server wants us to accept before relaying us, until we do.
+  rules: Option<String>,
+  /// How the server wants us to start out in the room it put us in.
+  join_defaults: JoinDefaults,
+  /// Lets us take our session back if we restart, see [`Client::set_resume_token`].
+  resume_token: Option<u64>,
+  /// What the server knows us by across connections, see [`Client::set_identity`].
+  identity: Identity,
+  /// Key the server must have, see [`Client::set_server_key`].
+  pinned_key: Option<PublicKeyBytes>,
+  /// Keys servers had the first time, and the file they're kept in, see [`Client::set_known_servers`].
+  known_servers: Option<(PathBuf, KnownServers)>,
+  /// Key of the server we last connected to.
+  server_key: Option<PublicKeyBytes>,
+  /// What the server's key is kept under, if it was given by name, see [`Client::connect_host`].
+  server_host: Option<String>,
+  /// Transport encryption keys, agreed on connect.
+  session: Option<Session>,
+  /// Control messages in flight each way, see [`common::reliable`].
+  reliable: Mutex<ReliableChannel<ClientMessage, ServerMessage>>,
+  /// Messages received but not yet returned from [`Client::poll`].
+  inbox: VecDeque<ServerMessage>,
+  /// Messages from the server arriving in pieces, see [`common::fragment`].
+  fragments: Reassembler,
+  /// Numbers the next message we have to send in pieces.
+  next_fragment: AtomicU32,
+  /// For servers that need one, stretched once rather than on every connect.
+  password: Option<Password>,
+  /// Sequence number of the next voice packet we send.
+  voice_seq: SeqNum,
+  last_sent: Instant,
+  /// When our last ping went out.
+  last_ping: Instant,
+  /// Id of the last ping, its pong is the only one we time.
+  ping_id: u32,
+  /// Round trip time, and voice loss from each peer.
+  stats: NetworkStats,
+  /// Where to log voice packets as they arrive, and when logging started.
+  packet_trace: Option<(Box<dyn Write + Send>, Instant)>,
+  keepalive_interval: Duration,
+  /// When we last received anything from the server.
+  last_heard: Instant,
+  unresponsive_after: Duration,
+  /// Called once when the server goes quiet for `unresponsive_after`, with how long it's been.
+  on_unresponsive: Option<Box<dyn FnMut(Duration) + Send>>,
+  /// The callback has fired and the server hasn't answered since.
+  unresponsive: bool,
+  /// Encrypts our voice for the room, when end-to-end encryption is on.
+  group_key: Option<Arc<GroupKey>>,
+  /// Mic packets are thrown away instead of sent.
+  mic_muted: bool,
+  /// Where the server was, as of the last [`Client::connect`].
+  addrs: Vec<SocketAddr>,
+  auto_reconnect: bool,
+  /// Tries to reconnect that have failed since the connection was lost, and when to try next.
+  reconnect: Option<(u32, Instant)>,
+  /// Changes to the connection not yet taken by [`Client::take_connection_event`].
+  events: VecDeque<ConnectionEvent>,
 }
 
 impl Client {
 
-  pub fn new(username: String, mic_rx: Receiver<Vec<u8>>) -> Result<Self, anyhow::Error> {
+  pub fn new(username: String, role: Role, mic_rx: Receiver<MicPacket>) -> Result<Self, anyhow::Error> {
+    if !packets::is_valid_username(&username) {
+      return Err(anyhow!("Usernames must be 1 to {} bytes, without control characters", packets::MAX_USERNAME_LEN));
+    }
     let socket = UdpSocket::bind("0.0.0.0:0")?;
     Ok(Self {
       username,
+      role,
       socket,
       state: ClientState::Disconnected,
       mic_rx,
+      connection_id: None,
+      id: None,
+      server_name: None,
+      motd: None,
+      rules: None,
+      join_defaults: JoinDefaults::default(),
+      resume_token: None,
+      identity: Identity::generate(),
+      pinned_key: None,
+      known_servers: None,
+      server_key: None,
+      server_host: None,
+      session: None,
+      reliable: Mutex::new(ReliableChannel::new()),
+      inbox: VecDeque::new(),
+      fragments: Reassembler::default(),
+      next_fragment: AtomicU32::new(0),
+      password: None,
+      voice_seq: 0,
+      last_sent: Instant::now(),
+      last_ping: Instant::now(),
+      ping_id: 0,
+      stats: NetworkStats::default(),
+      packet_trace: None,
+      keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+      last_heard: Instant::now(),
+      unresponsive_after: DEFAULT_UNRESPONSIVE_AFTER,
+      on_unresponsive: None,
+      unresponsive: false,
+      group_key: None,
+      mic_muted: false,
+      addrs: Vec::new(),
+      auto_reconnect: false,
+      reconnect: None,
+      events: VecDeque::new(),
     })
   }
 
-  pub fn connect<A>(&mut self, addr: A) -> Result<(), anyhow::Error> where A: ToSocketAddrs {
-    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| anyhow!("invalid address"))?;
+  /// Connects to a server, returning the users already there.
+  /// Any further users follow as [`ServerMessage::UserList`]s, the last of which is `complete`.
+  ///
+  /// Each of the server's addresses is tried in turn until one answers, see [`Client::server_addr`].
+  pub fn connect<A>(&mut self, addr: A) -> Result<Vec<UserInfo>, anyhow::Error> where A: ToSocketAddrs {
+    let addrs = addr.to_socket_addrs()?.collect();
+    self.connect_addrs(None, addrs)
+  }
+
+  /// Connects to a server by the host, and maybe port, the user gave, see [`resolve`].
+  ///
+  /// Its key is remembered under that name rather than the address it resolved to,
+  /// so a name answered with a new address isn't taken for a server we've never met.
+  pub fn connect_host(&mut self, host: &str, port: Option<u16>) -> Result<Vec<UserInfo>, anyhow::Error> {
+    let addrs = resolve(host, port)?;
+    self.connect_addrs(Some(known_as(host, port)), addrs)
+  }
+
+  fn connect_addrs(&mut self, host: Option<String>, addrs: Vec<SocketAddr>) -> Result<Vec<UserInfo>, anyhow::Error> {
+    self.addrs = addrs;
+    self.server_host = host;
+    self.reconnect = None;
+    let addrs = self.addrs.clone();
+    self.connect_any(&addrs)?.inspect_err(|_| self.state = ClientState::Disconnected)
+  }
+
+  /// Tries each address in turn. `Ok(Err(_))` if none could be reached, or `Err` if a server turned us away.
+  fn connect_any(&mut self, addrs: &[SocketAddr]) -> Result<Result<Vec<UserInfo>, anyhow::Error>, anyhow::Error> {
+    let mut failure = anyhow!("invalid address");
+    for (i, addr) in addrs.iter().enumerate() {
+      match self.connect_to(*addr) {
+        Ok(Some(users)) => return Ok(Ok(users)),
+        Ok(None) => failure = anyhow!("Connection failed: no answer from the server after {} tries", CONNECT_ATTEMPTS),
+        // couldn't reach it, as opposed to it turning us away
+        Err(e) if e.downcast_ref::<std::io::Error>().is_some() => failure = e,
+        Err(e) => return Err(e),
+      }
+      if i + 1 < addrs.len() {
+        warn!("Failed to reach {}, trying the next address: {}", addr, failure);
+      }
+    }
+    Ok(Err(failure))
+  }
+
+  /// Reconnects on our own when the server goes away, or stops answering for as long as
+  /// [`Client::on_server_unresponsive`] waits, backing off between tries. Off by default.
+  ///
+  /// Our session is resumed if the server still has it. Tries happen in [`Client::poll`],
+  /// which blocks while each one waits for an answer.
+  pub fn set_auto_reconnect(&mut self, enabled: bool) {
+    self.auto_reconnect = enabled;
+  }
+
+  /// The oldest change to the connection not yet taken.
+  pub fn take_connection_event(&mut self) -> Option<ConnectionEvent> {
+    self.events.pop_front()
+  }
+
+  /// Gives up on the connection, reconnecting later if that's on.
+  fn lose_connection(&mut self, reason: String) {
+    warn!("Lost the connection to the server: {}", reason);
+    self.events.push_back(ConnectionEvent::Disconnected { reason });
+    if !self.auto_reconnect {
+      self.state = ClientState::Disconnected;
+      return;
+    }
+    self.state = ClientState::Reconnecting;
+    self.reconnect = Some((0, Instant::now() + RECONNECT_MIN_DELAY));
+    self.events.push_back(ConnectionEvent::Reconnecting { attempt: 1, delay: RECONNECT_MIN_DELAY });
+  }
+
+  /// Tries to reconnect once it's time to, waiting longer each time it fails.
+  fn try_reconnect(&mut self) {
+    let failures = match self.reconnect {
+      Some((failures, at)) if Instant::now() >= at => failures,
+      _ => return,
+    };
+    let addrs = self.addrs.clone();
+    match self.connect_any(&addrs) {
+      Ok(Ok(users)) => {
+        info!("Reconnected after {} failed tries", failures);
+        self.reconnect = None;
+        self.events.push_back(ConnectionEvent::Reconnected { users });
+      },
+      Ok(Err(e)) => {
+        let delay = RECONNECT_MIN_DELAY.saturating_mul(1 << (failures + 1).min(16)).min(RECONNECT_MAX_DELAY);
+        warn!("Failed to reconnect, trying again in {:?}: {}", delay, e);
+        self.state = ClientState::Reconnecting;
+        self.reconnect = Some((failures + 1, Instant::now() + delay));
+        self.events.push_back(ConnectionEvent::Reconnecting { attempt: failures + 2, delay });
+      },
+      Err(e) => {
+        warn!("Gave up reconnecting: {}", e);
+        self.state = ClientState::Disconnected;
+        self.reconnect = None;
+        self.events.push_back(ConnectionEvent::GaveUp { reason: e.to_string() });
+      },
+    }
+  }
+
+  /// Where we're connected to, out of the addresses [`Client::connect`] was given.
+  pub fn server_addr(&self) -> Option<SocketAddr> {
+    if !self.is_connected() {return None;}
+    self.socket.peer_addr().ok()
+  }
+
+  /// Connects to one address, or `None` if nothing there answered.
+  fn connect_to(&mut self, addr: SocketAddr) -> Result<Option<Vec<UserInfo>>, anyhow::Error> {
     info!("Connecting to {:?}...", addr);
     self.state = ClientState::Connecting;
+    // an IPv4 socket can't reach an IPv6 server, or the other way around
+    if self.socket.local_addr()?.is_ipv4() != addr.is_ipv4() {
+      self.socket = bind_for(addr)?;
+    }
     self.socket.connect(addr)?;
-    self.send(packets::ClientMessage::Connect { username: self.username.clone() })?;
-
-    let pack = self.recv_packet()?;
-    match pack {
-      // TODO: change to ack packet
-      Some(ServerMessage::Pong) => {
-        self.state = ClientState::Connected;
-        info!("Connected to {:?}", self.socket.peer_addr()?);
+    // keys and messages from a previous connection are no good to a new one
+    self.session = None;
+    *self.reliable.lock().unwrap() = ReliableChannel::new();
+    self.inbox.clear();
+    self.fragments = Reassembler::default();
+    let mut exchange = Some(KeyExchange::new());
+    let public_key = exchange.as_ref().unwrap().public_key();
+    // the same connect every time, so the server can tell a repeat from a new session
+    let connect = ClientMessage::Connect {
+      version: packets::PROTOCOL_VERSION,
+      username: self.username.clone(),
+      role: self.role,
+      resume_token: self.resume_token,
+      public_key,
+      identity: self.identity.public_key(),
+      credential: self.password.as_ref().map(|password| password.credential(public_key)),
+    }.to_bytes();
+
+    self.socket.set_nonblocking(false)?;
+    self.socket.set_read_timeout(Some(CONNECT_RETRY))?;
+    let mut buf = [0; packets::PACKET_MAX_SIZE];
+    let mut handshake = None;
+    'attempts: for attempt in 0..CONNECT_ATTEMPTS {
+      if attempt > 0 {
+        debug!("No answer from the server, asking again");
+      }
+      self.socket.send(&connect)?;
+      while handshake.is_none() {
+        let size = match self.socket.recv(&mut buf) {
+          Ok(size) => size,
+          Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue 'attempts,
+          Err(e) => {
+            self.state = ClientState::Disconnected;
+            return Err(e.into());
+          },
+        };
+        let message = match ServerMessage::from_bytes(&buf[..size]) {
+          Some(ServerMessage::Fragment(fragment)) => match ServerMessage::reassemble(&mut self.fragments, fragment) {
+            Some(message) => Some(message),
+            None => continue,
+          },
+          message => message,
+        };
+        match (message, &self.session) {
+          // a server that was stalled can answer the connects of earlier tries after this one's
+          (Some(ServerMessage::ConnectAck { accepted: true, client_key, .. }), _) if client_key != public_key => {
+            debug!("Ignoring an answer to an earlier connect");
+          },
+          (Some(ServerMessage::ConnectAck { accepted: true, public_key, identity, .. }), _) => {
+            // a repeated answer carries the same key
+            if let Some(exchange) = exchange.take() {
+              if let Err(e) = self.check_server_key(addr, identity) {
+                self.state = ClientState::Disconnected;
+                return Err(e);
+              }
+              let agreed = match (self.identity.agree(public_key), exchange.agree(identity)) {
+                (Some(ours), Some(theirs)) => [ours, theirs],
+                _ => {
+                  self.state = ClientState::Disconnected;
+                  return Err(anyhow!("Connection failed: the server's key is invalid"));
+                },
+              };
+              self.session = Some(exchange.finish(public_key, Side::Client, &agreed));
+              self.server_key = Some(identity);
+            }
+          },
+          (Some(ServerMessage::ConnectAck { accepted: false, reason, .. }), _) => {
+            self.state = ClientState::Disconnected;
+            return Err(anyhow!("Connection refused: {}", reason.unwrap_or_else(|| "no reason given".to_string())));
+          },
+          (Some(ServerMessage::Sealed { counter, payload }), Some(session)) => {
+            match ServerMessage::open(session, counter, &payload) {
+              Some(message @ ServerMessage::Handshake { .. }) => handshake = Some(message),
+              // the rest of the roster may overtake the handshake
+              Some(message) => self.accept(message)?,
+              None => debug!("Dropped a packet that failed to decrypt"),
+            }
+          },
+          (Some(_), _) => debug!("Ignoring a packet while connecting"),
+          (None, _) => {
+            self.state = ClientState::Disconnected;
+            return match ServerMessage::ack_version(&buf[..size]) {
+              Some(version) if version != packets::PROTOCOL_VERSION => Err(anyhow!(
+                "Connection failed: server speaks protocol version {}, we speak {}",
+                version, packets::PROTOCOL_VERSION,
+              )),
+              _ => Err(anyhow!("Connection failed: could not parse the server's reply")),
+            };
+          },
+        }
+      }
+      break;
+    }
+    let users = match handshake {
+      Some(ServerMessage::Handshake { your_id, users, server_name, motd, rules, connection_id, resume_token, join_defaults }) => {
+        info!("Connected to '{}' ({:?}) as {}", server_name, self.socket.peer_addr()?, your_id);
+        self.id = Some(your_id);
+        self.connection_id = Some(connection_id);
+        self.server_name = Some(server_name);
+        self.motd = motd;
+        self.rules = rules;
+        self.join_defaults = join_defaults;
+        self.resume_token = Some(resume_token);
+        // only now that it's opened the handshake do we know it holds the key
+        self.trust_server_key(addr);
+        users
+      },
+      _ => {
+        self.state = ClientState::Disconnected;
+        return Ok(None);
       },
-      None => {},
-      _ => error!("Connection failed: Unexpected packet received"),
     };
+    self.state = ClientState::Connected;
+    self.socket.set_read_timeout(None)?;
     self.socket.set_nonblocking(true)?;
+    self.last_heard = Instant::now();
+    self.unresponsive = false;
+    Ok(Some(users))
+  }
+
+  /// Our id as the server knows us, once connected.
+  pub fn id(&self) -> Option<Uuid> {
+    self.id
+  }
+
+  pub fn server_name(&self) -> Option<&str> {
+    self.server_name.as_deref()
+  }
+
+  pub fn motd(&self) -> Option<&str> {
+    self.motd.as_deref()
+  }
+
+  /// Rules to agree to with [`Client::accept_rules`] before our voice and chat are relayed, if the server has any we haven't.
+  pub fn rules(&self) -> Option<&str> {
+    self.rules.as_deref()
+  }
+
+  pub fn accept_rules(&mut self) -> Result<(), anyhow::Error> {
+    if self.rules.is_none() {return Ok(());}
+    self.send(ClientMessage::AcceptRules)?;
+    self.rules = None;
+    Ok(())
+  }
+
+  /// How the server wants us to start out in the room it put us in, as of the last connect.
+  pub fn join_defaults(&self) -> JoinDefaults {
+    self.join_defaults
+  }
+
+  /// Round trip time to the server, once measured.
+  pub fn rtt(&self) -> Option<Duration> {
+    self.stats.rtt
+  }
+
+  /// Round trip time to the server, and how much voice we're losing from each peer.
+  pub fn network_stats(&self) -> &NetworkStats {
+    &self.stats
+  }
+
+  /// Notes what a peer's voice was encoded with (see [`crate::Bandwidth::of_packet`]) in their stats.
+  /// Left to whoever plays the voice, as only they can open it when it's end-to-end encrypted.
+  pub fn record_bandwidth(&mut self, user: Uuid, bandwidth: Bandwidth) {
+    self.stats.peers.entry(user).or_default().bandwidth = Some(bandwidth);
+  }
+
+  /// Token for resuming this session, changes every time we connect.
+  pub fn resume_token(&self) -> Option<u64> {
+    self.resume_token
+  }
+
+  /// Password to give servers on [`Client::connect`].
+  pub fn set_password(&mut self, password: Option<String>) {
+    self.password = password.as_deref().map(Password::stretch);
+  }
+
+  /// Refuses to connect to a server without this key, see [`crypto::fingerprint`].
+  /// Takes precedence over [`Client::set_known_servers`].
+  pub fn set_server_key(&mut self, key: Option<PublicKeyBytes>) {
+    self.pinned_key = key;
+  }
+
+  /// Remembers each server's key the first time we connect to it, in the file at `path`,
+  /// and refuses to connect if it's changed since.
+  pub fn set_known_servers(&mut self, path: Option<PathBuf>) -> Result<(), anyhow::Error> {
+    self.known_servers = match path {
+      Some(path) => Some((path.clone(), KnownServers::load(path)?)),
+      None => None,
+    };
     Ok(())
   }
 
+  /// Key of the server we last connected to, to show or pin.
+  pub fn server_key(&self) -> Option<PublicKeyBytes> {
+    self.server_key
+  }
+
+  /// What the server at `addr` is kept under in our known servers.
+  fn known_as(&self, addr: SocketAddr) -> String {
+    self.server_host.clone().unwrap_or_else(|| addr.to_string())
+  }
+
+  /// Refuses a server whose key isn't the one we pinned, or the one it had last time.
+  fn check_server_key(&self, addr: SocketAddr, key: PublicKeyBytes) -> Result<(), anyhow::Error> {
+    let expected = self.pinned_key.or_else(|| self.known_servers.as_ref()?.1.get(&self.known_as(addr)));
+    match expected {
+      Some(expected) if expected != key => Err(anyhow!(
+        "Connection refused: the server's key is {}, not {} as expected, so it may not be the server it claims to be",
+        crypto::fingerprint(key), crypto::fingerprint(expected),
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  /// Saves the key of a server we're connecting to for the first time.
+  fn trust_server_key(&mut self, addr: SocketAddr) {
+    let server = self.known_as(addr);
+    let (key, (path, known)) = match (self.server_key, self.known_servers.as_mut()) {
+      (Some(key), Some(known)) => (key, known),
+      _ => return,
+    };
+    if known.get(&server).is_some() {return;}
+    info!("Trusting {}'s key {} from now on", server, crypto::fingerprint(key));
+    known.trust(&server, key);
+    if let Err(e) = known.save(&*path) {
+      warn!("Failed to save known servers to {:?}: {}", path, e);
+    }
+  }
+
+  /// Presents a token saved from a previous run on the next [`Client::connect`],
+  /// to get back our id, room and role instead of lingering as a ghost until we time out.
+  pub fn set_resume_token(&mut self, token: Option<u64>) {
+    self.resume_token = token;
+  }
+
+  /// What the server knows us by across connections, e.g. for operators' mutes.
+  /// Save [`Identity::to_bytes`] to be the same client after a restart.
+  pub fn identity(&self) -> &Identity {
+    &self.identity
+  }
+
+  /// Replaces the identity generated for us, with one saved from a previous run.
+  pub fn set_identity(&mut self, identity: Identity) {
+    self.identity = identity;
+  }
+
+  /// How long we can go without sending before pinging the server. Defaults to 1s,
+  /// and must stay below the server's timeout or idle clients get dropped.
+  pub fn set_keepalive_interval(&mut self, interval: Duration) {
+    self.keepalive_interval = interval;
+  }
+
+  /// Calls `callback` with how long it's been silent once the server hasn't answered for `after`,
+  /// then again each time it goes quiet after having answered.
+  pub fn on_server_unresponsive<F>(&mut self, after: Duration, callback: F) where F: FnMut(Duration) + Send + 'static {
+    self.unresponsive_after = after;
+    self.on_unresponsive = Some(Box::new(callback));
+  }
+
+  /// Logs every voice packet received to `trace`, see [`common::trace`].
+  pub fn set_packet_trace(&mut self, trace: Option<Box<dyn Write + Send>>) {
+    self.packet_trace = trace.map(|trace| (trace, Instant::now()));
+  }
+
   pub fn disconnect(&mut self) {
-    self.send(packets::ClientMessage::Disconnect).unwrap();
+    self.reconnect = None;
+    if !self.is_connected() {
+      self.state = ClientState::Disconnected;
+      return;
+    }
+    if let Err(e) = self.send(ClientMessage::Disconnect) {
+      warn!("Failed to notify server of disconnect: {}", e);
+    }
     self.state = ClientState::Disconnected;
   }
 
   pub fn poll(&mut self) -> Result<Option<ServerMessage>, anyhow::Error> {
-    let pack = self.recv_packet()?;
-    if let Ok(packet) = self.mic_rx.try_recv() {
-      self.send(packets::ClientMessage::Voice { samples: packet })?;
+    if matches!(self.state, ClientState::Reconnecting) {
+      self.try_reconnect();
+    }
+    if !self.is_connected() {
+      // nowhere to send the mic yet, and don't send a backlog once we connect
+      while self.mic_rx.try_recv().is_ok() {}
+      return Ok(None);
+    }
+    match self.poll_connected() {
+      // e.g. the socket refused, once the server's stopped
+      Err(e) if e.downcast_ref::<std::io::Error>().is_some() => {
+        self.lose_connection(e.to_string());
+        if self.auto_reconnect {Ok(None)} else {Err(e)}
+      },
+      result => result,
+    }
+  }
+
+  fn poll_connected(&mut self) -> Result<Option<ServerMessage>, anyhow::Error> {
+    if let Some(message) = self.recv_packet()? {
+      self.last_heard = Instant::now();
+      self.unresponsive = false;
+      self.accept(message)?;
+    }
+    let due = self.reliable.lock().unwrap().due();
+    for (seq, message) in due {
+      self.send_packet(ClientMessage::Reliable { seq, message: Box::new(message) })?;
+    }
+    self.fragments.expire();
+    if self.reliable.lock().unwrap().is_broken() {
+      return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "the server stopped acknowledging messages").into());
+    }
+    let pack = self.inbox.pop_front();
+    match &pack {
+      Some(ServerMessage::Pong { id }) => {
+        self.stats.pongs_received += 1;
+        // older pongs can't be timed, the ping they answer has been replaced
+        if *id == self.ping_id {
+          let sample = self.last_ping.elapsed();
+          self.stats.rtt = Some(match self.stats.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+          });
+        }
+      },
+      Some(ServerMessage::Voice { user, seq, samples }) => {
+        self.stats.peers.entry(*user).or_default().record(*seq);
+        if let Some((trace, started)) = self.packet_trace.as_mut() {
+          let event = TraceEvent { at_ms: started.elapsed().as_millis() as u64, user: *user, seq: *seq, size: samples.len() };
+          if let Err(e) = writeln!(trace, "{}", event) {
+            warn!("Failed to write packet trace, stopping it: {}", e);
+            self.packet_trace = None;
+          }
+        }
+      },
+      Some(ServerMessage::Disconnected(user, _)) => {
+        self.stats.peers.remove(&user.id);
+      },
+      _ => {},
+    }
+    if let Some(ServerMessage::Challenge { challenge }) = pack {
+      // the server no longer recognises our address (e.g. we switched networks)
+      if let Some(connection_id) = self.connection_id {
+        info!("Server challenged our address, migrating session");
+        self.send(ClientMessage::Migrate { connection_id, challenge })?;
+      }
+    }
+    let packet = if self.mic_muted {
+      // still drain the mic, so unmuting doesn't send a backlog
+      while self.mic_rx.try_recv().is_ok() {}
+      None
+    } else {
+      self.mic_rx.try_recv().ok()
+    };
+    if let Some(packet) = packet {
+      let samples = match &self.group_key {
+        Some(key) => key.seal(self.voice_seq, &packet.data)?,
+        None => packet.data,
+      };
+      self.send(ClientMessage::Voice { seq: self.voice_seq, samples, level: packet.level })?;
+      self.voice_seq = self.voice_seq.wrapping_add(1);
+      self.last_sent = Instant::now();
+    }
+    // audience members, and speakers while silent or muted, have nothing else to send
+    let idle = self.last_sent.elapsed() >= self.keepalive_interval;
+    if matches!(self.state, ClientState::Connected) && (idle || self.last_ping.elapsed() >= RTT_INTERVAL) {
+      self.ping_id = self.ping_id.wrapping_add(1);
+      self.send(ClientMessage::Ping { id: self.ping_id })?;
+      self.last_sent = Instant::now();
+      self.last_ping = Instant::now();
+      self.stats.pings_sent += 1;
+    }
+    let silence = self.last_heard.elapsed();
+    if matches!(self.state, ClientState::Connected) && !self.unresponsive && silence >= self.unresponsive_after {
+      self.unresponsive = true;
+      warn!("No reply from the server for {:?}", silence);
+      if let Some(callback) = self.on_unresponsive.as_mut() {
+        callback(silence);
+      }
+      // the server has likely timed us out by now
+      if self.auto_reconnect {
+        self.lose_connection(format!("no reply for {:?}", silence));
+      }
     }
     Ok(pack)
   }
 
-  fn recv_packet(&self) -> Result<Option<ServerMessage>, anyhow::Error> {
-    let mut buf = [0; 1024];
+  pub fn is_connected(&self) -> bool {
+    matches!(self.state, ClientState::Connected)
+  }
+
+  /// Stops sending our mic. Pings keep the session alive meanwhile.
+  pub fn set_mic_muted(&mut self, muted: bool) {
+    self.mic_muted = muted;
+  }
+
+  pub fn set_group_key(&mut self, group_key: Option<Arc<GroupKey>>) {
+    self.group_key = group_key;
+  }
+
+  fn recv_packet(&mut self) -> Result<Option<ServerMessage>, anyhow::Error> {
+    let mut buf = [0; packets::PACKET_MAX_SIZE];
     match self.socket.recv(&mut buf) {
       Ok(size) => {
         // debug!("Received {} bytes", size);
-        let packet = packets::ServerMessage::from_bytes(&buf[..size])
-          .ok_or_else(|| anyhow!("Failed to parse packet"))?;
-        Ok(Some(packet))
+        let packet = match packets::ServerMessage::from_bytes(&buf[..size]) {
+          Some(ServerMessage::Fragment(fragment)) => match ServerMessage::reassemble(&mut self.fragments, fragment) {
+            Some(packet) => Some(packet),
+            None => return Ok(None),
+          },
+          packet => packet,
+        };
+        let packet = match (packet, &self.session) {
+          (Some(ServerMessage::Sealed { counter, payload }), Some(session)) => {
+            let packet = ServerMessage::open(session, counter, &payload);
+            if packet.is_none() {
+              debug!("Dropped a packet that failed to decrypt");
+            }
+            packet
+          },
+          // a challenge goes to an address the server doesn't know, so it can't be sealed
+          (Some(packet @ ServerMessage::Challenge { .. }), _) => Some(packet),
+          (Some(_), _) => {
+            warn!("Ignoring unencrypted packet");
+            None
+          },
+          (None, _) => {
+            warn!("Failed to parse packet ({} bytes)", size);
+            None
+          },
+        };
+        Ok(packet)
       },
       Err(e) => {
         if e.kind() == std::io::ErrorKind::WouldBlock {
@@ -90,10 +790,190 @@ impl Client {
     }
   }
 
-  pub fn send(&self, command: packets::ClientMessage) -> Result<(), anyhow::Error> {
-    let packet = bincode::serialize(&command)?;
-    self.socket.send(&packet)?;
-    // debug!("-> {} bytes", packet.len());
+  /// Queues a message for [`Client::poll`] to return, unwrapping and acknowledging it if it came reliably.
+  fn accept(&mut self, message: ServerMessage) -> Result<(), anyhow::Error> {
+    match message {
+      ServerMessage::Reliable { seq, message } => {
+        let ready = match self.reliable.lock().unwrap().receive(seq, *message) {
+          Some(ready) => ready,
+          // not acked, so it's sent again once there's room
+          None => return Ok(()),
+        };
+        // acked even if we've seen it, our last ack may have been lost
+        self.send_packet(ClientMessage::Ack { seq })?;
+        self.inbox.extend(ready.into_iter().filter(|message| {
+          let reliable = message.is_reliable();
+          if !reliable {
+            warn!("Ignoring {:?}, it can't be sent reliably", message);
+          }
+          reliable
+        }));
+      },
+      ServerMessage::Ack { seq } => self.reliable.lock().unwrap().ack(seq),
+      message => self.inbox.push_back(message),
+    }
     Ok(())
   }
-}
\ No newline at end of file
+
+  /// Sealed once connected, see [`common::crypto`].
+  /// Control messages are sent until the server acknowledges them, see [`common::reliable`].
+  pub fn send(&self, command: ClientMessage) -> Result<(), anyhow::Error> {
+    let command = match (&self.session, command.is_reliable()) {
+      (Some(_), true) => {
+        let seq = self.reliable.lock().unwrap().send(command.clone());
+        ClientMessage::Reliable { seq, message: Box::new(command) }
+      },
+      _ => command,
+    };
+    self.send_packet(command)
+  }
+
+  /// Sends in pieces if the message is too big for one datagram, see [`common::fragment`].
+  fn send_packet(&self, command: ClientMessage) -> Result<(), anyhow::Error> {
+    let command = match &self.session {
+      Some(session) => command.seal(session),
+      None => command,
+    };
+    let datagrams = command.to_datagrams(self.next_fragment.fetch_add(1, Ordering::Relaxed));
+    if datagrams.is_empty() {
+      return Err(anyhow!("Message is too big to send"));
+    }
+    for packet in datagrams {
+      self.socket.send(&packet)?;
+      // debug!("-> {} bytes", packet.len());
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A client that's connected as far as it knows, and the socket it thinks is the server.
+  fn connected() -> (Client, UdpSocket) {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (_, mic_rx) = std::sync::mpsc::channel();
+    let mut client = Client::new("alice".to_string(), Role::Speaker, mic_rx).unwrap();
+    client.socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.socket.connect(server.local_addr().unwrap()).unwrap();
+    client.socket.set_nonblocking(true).unwrap();
+    client.state = ClientState::Connected;
+    (client, server)
+  }
+
+  #[test]
+  fn junk_is_dropped() {
+    let (mut client, server) = connected();
+    server.send_to(b"junk", client.socket.local_addr().unwrap()).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(matches!(client.poll(), Ok(None)));
+    assert!(client.is_connected());
+  }
+
+  #[test]
+  fn socket_errors_lose_the_connection() {
+    let (mut client, server) = connected();
+    drop(server);
+    // refused, which the socket reports on the next receive
+    client.send(ClientMessage::Ping { id: 1 }).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(client.poll().is_err());
+    assert!(!client.is_connected());
+    assert!(matches!(client.take_connection_event(), Some(ConnectionEvent::Disconnected { .. })));
+  }
+
+  #[test]
+  fn disconnects_even_if_the_server_cant_be_told() {
+    let (mut client, _server) = connected();
+    // unconnected, so there's nowhere to send
+    client.socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    assert!(client.send(ClientMessage::Disconnect).is_err());
+    client.disconnect();
+    assert!(!client.is_connected());
+  }
+
+  #[test]
+  fn resolves_addresses_without_looking_them_up() {
+    let v6: SocketAddr = "[::1]:4000".parse().unwrap();
+    assert_eq!(resolve("[::1]", Some(4000)).unwrap(), vec![v6]);
+    assert_eq!(resolve("::1", Some(4000)).unwrap(), vec![v6]);
+    assert_eq!(resolve("[::1]", None).unwrap(), vec![SocketAddr::new(v6.ip(), DEFAULT_PORT)]);
+    assert_eq!(resolve("127.0.0.1", Some(4000)).unwrap(), vec!["127.0.0.1:4000".parse().unwrap()]);
+  }
+
+  #[test]
+  fn resolves_names_on_the_given_port() {
+    let addrs = resolve("localhost", Some(4000)).unwrap();
+    assert!(!addrs.is_empty());
+    assert!(addrs.iter().all(|addr| addr.ip().is_loopback() && addr.port() == 4000));
+  }
+
+  #[test]
+  fn binds_the_servers_family() {
+    for server in ["127.0.0.1", "::1"] {
+      let listener = UdpSocket::bind((server, 0)).unwrap();
+      listener.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+      let server = listener.local_addr().unwrap();
+      let socket = bind_for(server).unwrap();
+      assert_eq!(socket.local_addr().unwrap().is_ipv4(), server.is_ipv4());
+      socket.send_to(&[1], server).unwrap();
+      listener.recv_from(&mut [0; 16]).unwrap();
+    }
+  }
+
+  #[test]
+  fn refuses_a_server_without_the_pinned_key() {
+    let (mut client, _server) = connected();
+    let addr = "127.0.0.1:4000".parse().unwrap();
+    assert!(client.check_server_key(addr, [1; 32]).is_ok());
+    client.set_server_key(Some([1; 32]));
+    assert!(client.check_server_key(addr, [1; 32]).is_ok());
+    assert!(client.check_server_key(addr, [2; 32]).is_err());
+  }
+
+  #[test]
+  fn refuses_a_server_whose_key_changed() {
+    let path = std::env::temp_dir().join(format!("rust-voice-known-servers-{}", std::process::id()));
+    let addr = "127.0.0.1:4000".parse().unwrap();
+    let (mut client, _server) = connected();
+    client.set_known_servers(Some(path.clone())).unwrap();
+    client.server_key = Some([1; 32]);
+    client.trust_server_key(addr);
+    // and still after a restart
+    let (mut restarted, _server) = connected();
+    restarted.set_known_servers(Some(path.clone())).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    for client in [client, restarted] {
+      assert!(client.check_server_key(addr, [1; 32]).is_ok());
+      assert!(client.check_server_key(addr, [2; 32]).is_err());
+      assert!(client.check_server_key("127.0.0.1:4001".parse().unwrap(), [2; 32]).is_ok());
+    }
+  }
+
+  #[test]
+  fn keeps_names_as_given() {
+    assert_eq!(known_as("Voice.Example.com.", None), "voice.example.com");
+    assert_eq!(known_as("voice.example.com", Some(4000)), "voice.example.com:4000");
+    assert_eq!(known_as("127.0.0.1", None), format!("127.0.0.1:{}", DEFAULT_PORT));
+    assert_eq!(known_as("[::1]", Some(4000)), "[::1]:4000");
+  }
+
+  #[test]
+  fn refuses_a_name_that_moved_to_another_key() {
+    let path = std::env::temp_dir().join(format!("rust-voice-known-names-{}", std::process::id()));
+    let (mut client, _server) = connected();
+    client.set_known_servers(Some(path.clone())).unwrap();
+    client.server_host = Some(known_as("voice.example.com", None));
+    client.server_key = Some([1; 32]);
+    client.trust_server_key("192.0.2.1:4000".parse().unwrap());
+    std::fs::remove_file(&path).unwrap();
+    // the name now answers from somewhere new
+    let moved = "198.51.100.1:4000".parse().unwrap();
+    assert!(client.check_server_key(moved, [1; 32]).is_ok());
+    assert!(client.check_server_key(moved, [2; 32]).is_err());
+    // but the address itself, given on its own, is another server
+    client.server_host = None;
+    assert!(client.check_server_key(moved, [2; 32]).is_ok());
+  }
+}
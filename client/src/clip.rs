@@ -0,0 +1,38 @@
+use std::{collections::VecDeque, path::Path, time::Duration};
+
+use crate::util::wav::WavWriter;
+
+/// The last few seconds of what we heard, to save a moment after it happened.
+pub(crate) struct ClipBuffer {
+  samples: VecDeque<f32>,
+  capacity: usize,
+  sample_rate: u32,
+}
+
+impl ClipBuffer {
+  /// Keeps `duration` of interleaved stereo at `sample_rate`.
+  pub fn new(duration: Duration, sample_rate: u32) -> Self {
+    let capacity = (duration.as_secs_f64() * sample_rate as f64) as usize * 2;
+    Self { samples: VecDeque::with_capacity(capacity), capacity, sample_rate }
+  }
+
+  pub fn duration(&self) -> Duration {
+    Duration::from_secs_f64(self.capacity as f64 / 2.0 / self.sample_rate as f64)
+  }
+
+  pub fn push(&mut self, samples: &[f32]) {
+    let samples = &samples[samples.len().saturating_sub(self.capacity)..];
+    let overflow = (self.samples.len() + samples.len()).saturating_sub(self.capacity);
+    self.samples.drain(..overflow);
+    self.samples.extend(samples);
+  }
+
+  /// Writes what's buffered to a WAV file, keeping it buffered.
+  pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+    let mut wav = WavWriter::create(path, self.sample_rate, 2)?;
+    let (front, back) = self.samples.as_slices();
+    wav.write(front)?;
+    wav.write(back)?;
+    wav.finish()
+  }
+}
@@ -0,0 +1,99 @@
+use uuid::Uuid;
+
+/// Loudest comfort noise played, so a peer who stopped mid-shout doesn't leave a roar behind.
+const MAX_NOISE_LEVEL: f32 = 0.01;
+/// How much the noise floor may rise each frame, so it tracks a room getting noisier
+/// without following speech up.
+const FLOOR_RISE: f32 = 1.01;
+
+/// Fills in a peer's silence with noise like their background, once they stop sending
+/// (with voice detection or DTX), so it doesn't sound like the call dropped.
+///
+/// The background's level is the quietest the peer's audio has recently been, as there's
+/// always some of it under their voice.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ComfortNoise {
+  /// RMS of the peer's background, `None` until we've heard from them
+  floor: Option<f32>,
+  /// xorshift state, never zero
+  state: u32,
+}
+
+/// Seed used in place of zero, which xorshift would never leave.
+const FALLBACK_SEED: u32 = 0x9e37_79b9;
+
+impl ComfortNoise {
+  pub fn new(seed: u32) -> Self {
+    let state = if seed == 0 { FALLBACK_SEED } else { seed };
+    Self { floor: None, state }
+  }
+
+  /// Noise seeded from the peer's id, so two peers going quiet at once don't play
+  /// the same samples and sum into one louder, correlated hiss.
+  pub fn for_peer(id: &Uuid) -> Self {
+    let id = id.as_u128();
+    Self::new((id ^ id >> 32 ^ id >> 64 ^ id >> 96) as u32)
+  }
+
+  /// Takes a frame decoded from one of the peer's packets.
+  pub fn update(&mut self, frame: &[f32]) {
+    let rms = (frame.iter().map(|s| s * s).sum::<f32>() / frame.len().max(1) as f32).sqrt();
+    self.floor = Some(match self.floor {
+      // after digital silence, still let it rise
+      Some(floor) => rms.min(floor.max(f32::EPSILON) * FLOOR_RISE),
+      None => rms,
+    });
+  }
+
+  /// Level of noise that would be played, `None` if we have nothing to go by yet.
+  pub fn level(&self) -> Option<f32> {
+    self.floor.map(|floor| floor.min(MAX_NOISE_LEVEL))
+  }
+
+  /// `len` samples of noise at the peer's background level, silence if we've not heard them.
+  pub fn generate(&mut self, len: usize) -> Vec<f32> {
+    // uniform noise from -1 to 1 has an RMS of 1/sqrt(3)
+    let scale = self.level().unwrap_or(0.0) * 3f32.sqrt();
+    (0..len).map(|_| {
+      self.state ^= self.state << 13;
+      self.state ^= self.state >> 17;
+      self.state ^= self.state << 5;
+      (self.state as f32 / u32::MAX as f32 * 2.0 - 1.0) * scale
+    }).collect()
+  }
+}
+
+impl Default for ComfortNoise {
+  fn default() -> Self {
+    Self::new(FALLBACK_SEED)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn heard(mut noise: ComfortNoise) -> Vec<f32> {
+    noise.update(&[0.005; 480]);
+    noise.generate(480)
+  }
+
+  #[test]
+  fn peers_get_different_noise() {
+    let a = heard(ComfortNoise::for_peer(&Uuid::from_u128(1)));
+    let b = heard(ComfortNoise::for_peer(&Uuid::from_u128(2)));
+    assert_ne!(a, b);
+  }
+
+  #[test]
+  fn zero_seed_still_makes_noise() {
+    assert!(heard(ComfortNoise::new(0)).iter().any(|s| *s != 0.0));
+    assert!(heard(ComfortNoise::for_peer(&Uuid::nil())).iter().any(|s| *s != 0.0));
+  }
+
+  #[test]
+  fn silent_until_heard() {
+    assert!(ComfortNoise::for_peer(&Uuid::from_u128(1)).generate(480).iter().all(|s| *s == 0.0));
+  }
+}
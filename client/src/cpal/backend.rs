@@ -1,11 +1,13 @@
-use cpal::{
-	traits::{DeviceTrait, HostTrait},
-	Device, StreamConfig,
-};
+use std::sync::{Arc, Mutex};
+
+use cpal::{traits::DeviceTrait, Device, StreamConfig};
 use kira::manager::backend::{Backend, cpal::Error, Renderer};
-use log::info;
+use log::{info, warn};
+use ringbuf::Producer;
+
+use crate::util::thread::AudioThreadSettings;
 
-use super::stream::{StreamManagerController, StreamManager};
+use super::{stream::{StreamManagerController, StreamManager, OutputTap, device_and_config}, ChannelMap};
 
 enum State {
 	Empty,
@@ -18,36 +20,74 @@ enum State {
 	},
 }
 
+/// Settings for a [`CpalBackend`].
+#[derive(Clone, Debug, Default)]
+pub struct CpalBackendSettings {
+	/// Name of the output device to play through. The default device is
+	/// used if this is `None`, or while the named device is unavailable.
+	pub device: Option<String>,
+	/// Scheduling for the thread rendering audio.
+	pub thread: AudioThreadSettings,
+	/// Which output channels the mix goes to.
+	pub channel_map: ChannelMap,
+}
+
 /// A backend that uses [cpal](https://crates.io/crates/cpal) to
 /// connect a [`Renderer`] to the operating system's audio driver.
 pub struct CpalBackend {
 	state: State,
 	sample_rate: u32,
+	preferred_device: Option<String>,
+	thread: AudioThreadSettings,
+	channel_map: ChannelMap,
+	tap: OutputTap,
 }
 
 impl CpalBackend {
 	pub fn sample_rate(&self) -> u32 {
 		self.sample_rate
 	}
+
+	/// Moves playback to another output device (or back to the default one)
+	/// without losing any sounds. Takes effect within a stream check interval.
+	pub fn set_output_device(&mut self, name: Option<String>) {
+		if let State::Initialized {
+			stream_manager_controller,
+		} = &self.state
+		{
+			stream_manager_controller.set_preferred_device(name.clone());
+		}
+		self.preferred_device = name;
+	}
+
+	/// Sends a copy of everything played, as interleaved stereo before
+	/// the channel map, to `tap`. `None` stops copying.
+	pub fn set_output_tap(&mut self, tap: Option<Producer<f32>>) {
+		*self.tap.lock().unwrap() = tap;
+	}
 }
 
 impl Backend for CpalBackend {
-	type Settings = ();
+	type Settings = CpalBackendSettings;
 
 	type Error = Error;
 
-	fn setup(_settings: Self::Settings) -> Result<(Self, u32), Self::Error> {
-		let host = cpal::default_host();
-		let device = host
-			.default_output_device()
-			.ok_or(Error::NoDefaultOutputDevice)?;
-		let config = device.default_output_config()?.config();
+	fn setup(settings: Self::Settings) -> Result<(Self, u32), Self::Error> {
+		let (device, config) = device_and_config(settings.device.as_deref())?;
 		let sample_rate = config.sample_rate.0;
-		info!("Cpal Backend started with sample rate {}hz", sample_rate);
+		let name = device.name().unwrap_or_default();
+		if settings.device.as_ref().is_some_and(|preferred| *preferred != name) {
+			warn!("Output device {:?} is unavailable, using '{}'", settings.device, name);
+		}
+		info!("Cpal Backend started on '{}' with sample rate {}hz", name, sample_rate);
 		Ok((
 			Self {
 				state: State::Uninitialized { device, config },
 				sample_rate,
+				preferred_device: settings.device,
+				thread: settings.thread,
+				channel_map: settings.channel_map,
+				tap: Arc::new(Mutex::new(None)),
 			},
 			sample_rate,
 		))
@@ -57,7 +97,7 @@ impl Backend for CpalBackend {
 		let state = std::mem::replace(&mut self.state, State::Empty);
 		if let State::Uninitialized { device, config } = state {
 			self.state = State::Initialized {
-				stream_manager_controller: StreamManager::start(renderer, device, config),
+				stream_manager_controller: StreamManager::start(renderer, device, config, self.preferred_device.clone(), self.thread, self.channel_map, self.tap.clone()),
 			};
 		} else {
 			panic!("Cannot initialize the backend multiple times")
@@ -0,0 +1,70 @@
+/// Which output channels the left and right of the mix go to.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ChannelMap {
+	/// Left and right to the first two channels, or mixed down on mono devices.
+	#[default]
+	Stereo,
+	/// Like `Stereo`, with left and right swapped.
+	Swapped,
+	/// Both sides mixed down and sent to every channel.
+	Mono,
+	/// Left and right to these channels (counting from 0), for multichannel
+	/// interfaces where voice should only reach one pair, like the headphones.
+	Channels { left: u16, right: u16 },
+}
+
+impl ChannelMap {
+	/// Whether the map can be used on a device with this many channels.
+	pub fn fits(&self, channels: u16) -> bool {
+		match self {
+			Self::Channels { left, right } => *left < channels && *right < channels,
+			_ => true,
+		}
+	}
+
+	/// Writes one frame of the mix, silencing channels the map doesn't use.
+	pub(super) fn write(&self, frame: &mut [f32], left: f32, right: f32) {
+		let mono = (left + right) / 2.0;
+		match (self, frame.len()) {
+			(Self::Mono, _) | (_, 1) => frame.fill(mono),
+			(Self::Stereo, _) => {
+				frame.fill(0.0);
+				frame[0] = left;
+				frame[1] = right;
+			}
+			(Self::Swapped, _) => {
+				frame.fill(0.0);
+				frame[0] = right;
+				frame[1] = left;
+			}
+			(Self::Channels { left: l, right: r }, _) => {
+				frame.fill(0.0);
+				// both sides to the same channel sums them, like a downmix
+				frame[*l as usize] += if l == r { mono } else { left };
+				if l != r {
+					frame[*r as usize] = right;
+				}
+			}
+		}
+	}
+}
+
+impl std::str::FromStr for ChannelMap {
+	type Err = String;
+
+	/// `stereo`, `swapped`, `mono`, or a `left,right` pair of channels.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"stereo" => Ok(Self::Stereo),
+			"swapped" => Ok(Self::Swapped),
+			"mono" => Ok(Self::Mono),
+			_ => {
+				let (left, right) = s.split_once(',').ok_or_else(|| format!("unknown channel map '{}'", s))?;
+				Ok(Self::Channels {
+					left: left.trim().parse().map_err(|e| format!("bad left channel: {}", e))?,
+					right: right.trim().parse().map_err(|e| format!("bad right channel: {}", e))?,
+				})
+			}
+		}
+	}
+}
@@ -1,8 +1,9 @@
 mod backend;
 pub use backend::*;
 
+mod channel_map;
+pub use channel_map::ChannelMap;
+
 mod stream;
-pub use stream::*;
 
-mod renderer_wrapper;
-use renderer_wrapper::*;
\ No newline at end of file
+mod renderer_wrapper;
\ No newline at end of file
@@ -1,7 +1,7 @@
 use std::{
 	sync::{
 		atomic::{AtomicBool, Ordering},
-		Arc,
+		Arc, Mutex,
 	},
 	time::Duration,
 };
@@ -11,12 +11,17 @@ use cpal::{
 	Device, Stream, StreamConfig, StreamError,
 };
 use kira::manager::backend::{Renderer, cpal::Error};
-use ringbuf::{Consumer, RingBuffer};
+use log::{error, warn};
+use ringbuf::{Consumer, Producer, RingBuffer};
 
-use super::renderer_wrapper::RendererWrapper;
+use super::{renderer_wrapper::RendererWrapper, ChannelMap};
+use crate::{devices::find_output_device, util::{alloc::RealtimeScope, thread::AudioThreadSettings}};
 
 const CHECK_STREAM_INTERVAL: Duration = Duration::from_millis(500);
 
+/// Where a copy of the mix goes, as interleaved stereo, if anywhere.
+pub(super) type OutputTap = Arc<Mutex<Option<Producer<f32>>>>;
+
 #[allow(clippy::large_enum_variant)]
 enum State {
 	Empty,
@@ -32,12 +37,18 @@ enum State {
 
 pub(super) struct StreamManagerController {
 	should_drop: Arc<AtomicBool>,
+	preferred_device: Arc<Mutex<Option<String>>>,
 }
 
 impl StreamManagerController {
 	pub fn stop(&self) {
 		self.should_drop.store(true, Ordering::SeqCst);
 	}
+
+	/// Switches to another device on the next stream check, keeping the renderer.
+	pub fn set_preferred_device(&self, name: Option<String>) {
+		*self.preferred_device.lock().unwrap() = name;
+	}
 }
 
 /// Starts a cpal stream and restarts it if needed
@@ -46,6 +57,11 @@ pub(super) struct StreamManager {
 	state: State,
 	device_name: String,
 	sample_rate: u32,
+	/// Device to use whenever it's available, instead of the default one.
+	preferred_device: Arc<Mutex<Option<String>>>,
+	thread: AudioThreadSettings,
+	channel_map: ChannelMap,
+	tap: OutputTap,
 }
 
 impl StreamManager {
@@ -53,16 +69,28 @@ impl StreamManager {
 		renderer: Renderer,
 		device: Device,
 		config: StreamConfig,
+		preferred_device: Option<String>,
+		thread: AudioThreadSettings,
+		channel_map: ChannelMap,
+		tap: OutputTap,
 	) -> StreamManagerController {
 		let should_drop = Arc::new(AtomicBool::new(false));
 		let should_drop_clone = should_drop.clone();
+		let preferred_device = Arc::new(Mutex::new(preferred_device));
+		let preferred_device_clone = preferred_device.clone();
 		std::thread::spawn(move || {
 			let mut stream_manager = StreamManager {
 				state: State::Idle { renderer },
 				device_name: device_name(&device),
 				sample_rate: config.sample_rate.0,
+				preferred_device,
+				thread,
+				channel_map,
+				tap,
 			};
-			stream_manager.start_stream(&device, &config).unwrap();
+			if let Err(e) = stream_manager.start_stream(&device, &config) {
+				error!("Failed to start output stream: {}", e);
+			}
 			loop {
 				std::thread::sleep(CHECK_STREAM_INTERVAL);
 				if should_drop.load(Ordering::SeqCst) {
@@ -73,33 +101,49 @@ impl StreamManager {
 		});
 		StreamManagerController {
 			should_drop: should_drop_clone,
+			preferred_device: preferred_device_clone,
 		}
 	}
 
-	/// Restarts the stream if the audio device gets disconnected.
+	/// Restarts the stream if the audio device gets disconnected,
+	/// or if a different device is now preferred.
 	fn check_stream(&mut self) {
-		if let State::Running {
-			stream_error_consumer,
-			..
-		} = &mut self.state
-		{
-			// check for device disconnection
-			if let Some(StreamError::DeviceNotAvailable) = stream_error_consumer.pop() {
-				self.stop_stream();
-				if let Ok((device, config)) = default_device_and_config() {
-					// TODO: gracefully handle errors that occur in this function
-					self.start_stream(&device, &config).unwrap();
+		let preferred_device = self.preferred_device.lock().unwrap().clone();
+		let device_and_config = || device_and_config(preferred_device.as_deref());
+		match &mut self.state {
+			State::Running {
+				stream_error_consumer,
+				..
+			} => {
+				// check for device disconnection
+				if let Some(StreamError::DeviceNotAvailable) = stream_error_consumer.pop() {
+					self.stop_stream();
+					if let Ok((device, config)) = device_and_config() {
+						if let Err(e) = self.start_stream(&device, &config) {
+							error!("Failed to restart output stream: {}", e);
+						}
+					}
+					return;
+				}
+				// check for device changes
+				if let Ok((device, config)) = device_and_config() {
+					let device_name = device_name(&device);
+					let sample_rate = config.sample_rate.0;
+					if device_name != self.device_name || sample_rate != self.sample_rate {
+						self.stop_stream();
+						if let Err(e) = self.start_stream(&device, &config) {
+							error!("Failed to switch output stream to '{}': {}", device_name, e);
+						}
+					}
 				}
 			}
-			// check for device changes
-			if let Ok((device, config)) = default_device_and_config() {
-				let device_name = device_name(&device);
-				let sample_rate = config.sample_rate.0;
-				if device_name != self.device_name || sample_rate != self.sample_rate {
-					self.stop_stream();
-					self.start_stream(&device, &config).unwrap();
+			// a previous (re)start failed, keep trying until a device shows up
+			State::Idle { .. } => {
+				if let Ok((device, config)) = device_and_config() {
+					let _ = self.start_stream(&device, &config);
 				}
 			}
+			State::Empty => {}
 		}
 	}
 
@@ -117,30 +161,58 @@ impl StreamManager {
 		}
 		self.device_name = device_name;
 		self.sample_rate = sample_rate;
-		let (mut renderer_wrapper, renderer_consumer) = RendererWrapper::new(renderer);
+		let (mut renderer_wrapper, mut renderer_consumer) = RendererWrapper::new(renderer);
 		let (mut stream_error_producer, stream_error_consumer) = RingBuffer::new(1).split();
 		let channels = config.channels;
+		let channel_map = if self.channel_map.fits(channels) {
+			self.channel_map
+		} else {
+			warn!("Channel map {:?} doesn't fit '{}' with {} channels, playing in stereo", self.channel_map, self.device_name, channels);
+			ChannelMap::Stereo
+		};
+		let thread = self.thread;
+		let tap = self.tap.clone();
+		let mut thread_ready = false;
 		let stream = device.build_output_stream(
 			config,
 			move |data: &mut [f32], _| {
+				let _realtime = RealtimeScope::enter("Output");
+				if !thread_ready {
+					thread.apply("Output");
+					thread_ready = true;
+				}
 				renderer_wrapper.on_start_processing();
+				// never wait on whoever is swapping the tap out, that chunk just goes unrecorded
+				let mut tap = tap.try_lock().ok();
+				let mut tap = tap.as_mut().and_then(|tap| tap.as_mut());
 				for frame in data.chunks_exact_mut(channels as usize) {
 					let out = renderer_wrapper.process();
-					if channels == 1 {
-						frame[0] = (out.left + out.right) / 2.0;
-					} else {
-						frame[0] = out.left;
-						frame[1] = out.right;
+					channel_map.write(frame, out.left, out.right);
+					if let Some(tap) = tap.as_mut() {
+						// a full tap means nobody is draining it, drop the audio rather than block
+						let _ = tap.push(out.left);
+						let _ = tap.push(out.right);
 					}
 				}
 			},
 			move |error| {
-				stream_error_producer
-					.push(error)
-					.expect("Stream error producer is full");
+				// if an error is already waiting to be handled, this one can be dropped
+				let _ = stream_error_producer.push(error);
 			},
-		)?;
-		stream.play()?;
+		);
+		let stream = match stream.map_err(Error::from).and_then(|stream| {
+			stream.play()?;
+			Ok(stream)
+		}) {
+			Ok(stream) => stream,
+			Err(e) => {
+				// dropping the callback sent the renderer back, so we can go idle and retry later
+				if let Some(renderer) = renderer_consumer.pop() {
+					self.state = State::Idle { renderer };
+				}
+				return Err(e);
+			}
+		};
 		self.state = State::Running {
 			stream,
 			stream_error_consumer,
@@ -167,11 +239,15 @@ impl StreamManager {
 	}
 }
 
-fn default_device_and_config() -> Result<(Device, StreamConfig), Error> {
+/// The preferred device if it's available, otherwise the default one.
+pub(super) fn device_and_config(preferred: Option<&str>) -> Result<(Device, StreamConfig), Error> {
 	let host = cpal::default_host();
-	let device = host
-		.default_output_device()
-		.ok_or(Error::NoDefaultOutputDevice)?;
+	let device = match preferred.and_then(|name| find_output_device(&host, name)) {
+		Some(device) => device,
+		None => host
+			.default_output_device()
+			.ok_or(Error::NoDefaultOutputDevice)?,
+	};
 	let config = device.default_output_config()?.config();
 	Ok((device, config))
 }
@@ -1,50 +1,81 @@
-use std::sync::{Mutex, Arc};
+use std::{sync::{Mutex, Arc}, time::Duration};
 use log::{info, warn};
 
-use crate::util::opus::nearest_opus_rate;
+use crate::util::{opus::{nearest_opus_rate, samples_in, FRAME_DURATION, MAX_FRAME_DURATION}, resampling::Resampler};
 
+/// Number of channels decoded audio has. Opus upmixes mono packets itself.
+pub const DECODED_CHANNELS: usize = 2;
+
+/// Decodes a peer's packets into interleaved stereo audio at the playback rate.
 pub struct OpusDecoder {
-  /// the real sample rate of the input
+  /// the sample rate audio is played back at
   sample_rate: u32,
-  /// the sample rate of the encoder
+  /// the sample rate opus decodes at, the closest one it supports
   opus_rate: u32,
   
   decoder: Arc<Mutex<opus::Decoder>>,
-  frame_size: usize,
+  resampler: Resampler,
+  /// length of the last frame decoded, the peer may change it at any time
+  frame_duration: Duration,
 }
 
 impl OpusDecoder {
   pub fn new(sample_rate: u32) -> Result<Self, anyhow::Error> {
     let opus_rate = nearest_opus_rate(sample_rate).unwrap();
-    let frame_size = (opus_rate * 20) as usize / 1000;
-    info!("Creating new OpusDecoder with frame size {} @ opus:{} hz (real:{} hz)", frame_size, opus_rate, sample_rate);
+    info!("Creating new OpusDecoder @ opus:{} hz (real:{} hz)", opus_rate, sample_rate);
     
     if opus_rate != sample_rate {
-      warn!("Audio Resampling is not yet supported! Your audio will likely be distorted/pitched.");
+      info!("Resampling output from {} hz to {} hz", opus_rate, sample_rate);
     }
 
-    let decoder = opus::Decoder::new(opus_rate, opus::Channels::Mono)?;
+    let decoder = opus::Decoder::new(opus_rate, opus::Channels::Stereo)?;
     Ok(Self {
       opus_rate,
       sample_rate,
       decoder: Arc::new(Mutex::new(decoder)),
-      frame_size,
+      resampler: Resampler::new(opus_rate, sample_rate, DECODED_CHANNELS),
+      frame_duration: FRAME_DURATION,
     })
   }
 
+  /// Roughly how many samples (of all channels) the last decoded frame had, at the playback rate.
   pub fn frame_size(&self) -> usize {
-    self.frame_size
+    samples_in(self.frame_duration, self.sample_rate) * DECODED_CHANNELS
+  }
+
+  /// Most samples (of all channels) a decoded frame can have, at the playback rate.
+  pub fn max_frame_size(&self) -> usize {
+    samples_in(MAX_FRAME_DURATION, self.sample_rate) * DECODED_CHANNELS
+  }
+
+  /// Length of the last frame decoded.
+  pub fn frame_duration(&self) -> Duration {
+    self.frame_duration
   }
 
   pub fn decode(&mut self, packet: &[u8]) -> Result<Vec<f32>, anyhow::Error> {
     let mut decoder = self.decoder.lock().unwrap();
-    let mut output = vec![0.0; self.frame_size];
-    decoder.decode_float(packet, &mut output[..], false)?;
-    Ok(output)
+    let mut output = vec![0.0; samples_in(MAX_FRAME_DURATION, self.opus_rate) * DECODED_CHANNELS];
+    let samples = decoder.decode_float(packet, &mut output[..], false)?;
+    output.truncate(samples * DECODED_CHANNELS);
+    self.frame_duration = Duration::from_micros(samples as u64 * 1_000_000 / self.opus_rate as u64);
+    Ok(self.resampler.process(&output))
   }
 
+  /// Fills in for a lost packet, recovering it from the in-band FEC of the
+  /// packet after it if we have that, otherwise extrapolating with PLC.
+  pub fn conceal(&mut self, next: Option<&[u8]>) -> Result<Vec<f32>, anyhow::Error> {
+    let mut decoder = self.decoder.lock().unwrap();
+    // assume the lost frame was as long as the last one
+    let mut output = vec![0.0; samples_in(self.frame_duration, self.opus_rate) * DECODED_CHANNELS];
+    // an empty packet tells opus it was lost
+    decoder.decode_float(next.unwrap_or(&[]), &mut output[..], next.is_some())?;
+    Ok(self.resampler.process(&output))
+  }
   pub fn reset(&self) {
     let mut decoder = self.decoder.lock().unwrap();
-    decoder.reset_state();
+    if let Err(e) = decoder.reset_state() {
+      warn!("Failed to reset decoder: {}", e);
+    }
   }
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+/// How fast the noise floor estimate creeps up each frame, so it follows noise that gets louder.
+const FLOOR_RISE: f32 = 1.005;
+/// Level above the noise floor at which frames pass untouched.
+const OPEN_RATIO: f32 = 4.0;
+/// Level above the noise floor below which frames are fully attenuated.
+const CLOSED_RATIO: f32 = 2.0;
+/// Gain applied to frames that are just noise.
+const CLOSED_GAIN: f32 = 0.1;
+
+/// Turns down steady background noise (fans, hum, hiss) in the gaps between words.
+///
+/// The noise floor is tracked as the quietest recent frame, and frames close to
+/// it are attenuated. Gain changes are ramped across each frame to avoid clicks.
+pub struct NoiseSuppressor {
+  floor: Option<f32>,
+  gain: f32,
+}
+
+impl NoiseSuppressor {
+  pub fn new() -> Self {
+    Self { floor: None, gain: 1.0 }
+  }
+
+  /// Processes a frame of mono samples in place.
+  pub fn process(&mut self, samples: &mut [f32]) {
+    if samples.is_empty() {return;}
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+    let floor = match self.floor {
+      Some(floor) if rms >= floor => floor * FLOOR_RISE,
+      _ => rms,
+    }.max(f32::EPSILON);
+    self.floor = Some(floor);
+
+    let ratio = rms / floor;
+    let target = if ratio >= OPEN_RATIO {
+      1.0
+    } else if ratio <= CLOSED_RATIO {
+      CLOSED_GAIN
+    } else {
+      CLOSED_GAIN + (1.0 - CLOSED_GAIN) * (ratio - CLOSED_RATIO) / (OPEN_RATIO - CLOSED_RATIO)
+    };
+
+    let step = (target - self.gain) / samples.len() as f32;
+    for sample in samples.iter_mut() {
+      self.gain += step;
+      *sample *= self.gain;
+    }
+    self.gain = target;
+  }
+}
@@ -0,0 +1,53 @@
+use anyhow::anyhow;
+use cpal::traits::{DeviceTrait, HostTrait};
+
+/// A range of stream formats a device supports.
+#[derive(Copy, Clone, Debug)]
+pub struct ConfigRange {
+  pub channels: u16,
+  pub min_sample_rate: u32,
+  pub max_sample_rate: u32,
+}
+
+impl From<cpal::SupportedStreamConfigRange> for ConfigRange {
+  fn from(config: cpal::SupportedStreamConfigRange) -> Self {
+    Self {
+      channels: config.channels(),
+      min_sample_rate: config.min_sample_rate().0,
+      max_sample_rate: config.max_sample_rate().0,
+    }
+  }
+}
+
+/// An audio device, as listed by [`list_devices`].
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+  pub name: String,
+  /// Formats the device can record in, empty if it isn't an input.
+  pub input: Vec<ConfigRange>,
+  /// Formats the device can play in, empty if it isn't an output.
+  pub output: Vec<ConfigRange>,
+}
+
+/// Lists the audio devices on the default host, for picking a mic and speakers by name.
+pub fn list_devices() -> Result<Vec<DeviceInfo>, anyhow::Error> {
+  let host = cpal::default_host();
+  Ok(host.devices()?.filter_map(|device| {
+    Some(DeviceInfo {
+      name: device.name().ok()?,
+      input: device.supported_input_configs().map(|c| c.map(Into::into).collect()).unwrap_or_default(),
+      output: device.supported_output_configs().map(|c| c.map(Into::into).collect()).unwrap_or_default(),
+    })
+  }).collect())
+}
+
+pub(crate) fn find_input_device(host: &cpal::Host, name: &str) -> Result<cpal::Device, anyhow::Error> {
+  host.input_devices()?
+    .find(|device| device.name().is_ok_and(|n| n == name))
+    .ok_or_else(|| anyhow!("no input device named '{}'", name))
+}
+
+pub(crate) fn find_output_device(host: &cpal::Host, name: &str) -> Option<cpal::Device> {
+  host.output_devices().ok()?
+    .find(|device| device.name().is_ok_and(|n| n == name))
+}
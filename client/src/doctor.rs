@@ -0,0 +1,136 @@
+//! Self checks for `--doctor`, to narrow down why audio or connecting doesn't work.
+
+use std::{net::SocketAddr, sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Duration};
+
+use anyhow::anyhow;
+use common::packets::{self, ServerMessage};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use crate::{decoder::OpusDecoder, devices::list_devices, encoder::{OpusConfig, OpusEncoder}, util::opus::{samples_in, FRAME_DURATION}};
+
+/// How long test streams run for.
+const STREAM_TEST: Duration = Duration::from_millis(300);
+/// How long to wait for the server to answer.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Outcome of one check.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+  pub name: &'static str,
+  /// What was found, or why it failed.
+  pub result: Result<String, String>,
+}
+
+impl Diagnostic {
+  fn new(name: &'static str, result: Result<String, anyhow::Error>) -> Self {
+    Self { name, result: result.map_err(|e| e.to_string()) }
+  }
+
+  pub fn passed(&self) -> bool {
+    self.result.is_ok()
+  }
+}
+
+impl std::fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.result {
+      Ok(detail) => write!(f, "[PASS] {}: {}", self.name, detail),
+      Err(reason) => write!(f, "[FAIL] {}: {}", self.name, reason),
+    }
+  }
+}
+
+/// Checks the audio devices, the codec, and if given, that `server` answers.
+pub fn run_diagnostics(server: Option<SocketAddr>) -> Vec<Diagnostic> {
+  let mut report = vec![
+    Diagnostic::new("Devices", check_devices()),
+    Diagnostic::new("Input stream", check_input()),
+    Diagnostic::new("Output stream", check_output()),
+    Diagnostic::new("Opus round trip", check_opus()),
+  ];
+  if let Some(server) = server {
+    report.push(Diagnostic::new("Server", check_server(server)));
+  }
+  report
+}
+
+fn check_devices() -> Result<String, anyhow::Error> {
+  let devices = list_devices()?;
+  let inputs = devices.iter().filter(|d| !d.input.is_empty()).count();
+  let outputs = devices.iter().filter(|d| !d.output.is_empty()).count();
+  if inputs == 0 || outputs == 0 {
+    return Err(anyhow!("{} inputs and {} outputs found, need at least one of each", inputs, outputs));
+  }
+  Ok(format!("{} inputs, {} outputs", inputs, outputs))
+}
+
+fn check_input() -> Result<String, anyhow::Error> {
+  let device = cpal::default_host().default_input_device().ok_or_else(|| anyhow!("no default input device"))?;
+  let config = device.default_input_config()?.config();
+  let callbacks = Arc::new(AtomicUsize::new(0));
+  let stream = {
+    let callbacks = callbacks.clone();
+    device.build_input_stream(&config, move |_: &[f32], _: &cpal::InputCallbackInfo| {
+      callbacks.fetch_add(1, Ordering::Relaxed);
+    }, |e| log::error!("Input stream error: {}", e))?
+  };
+  stream.play()?;
+  std::thread::sleep(STREAM_TEST);
+  drop(stream);
+  stream_result(device.name()?, &config, callbacks.load(Ordering::Relaxed))
+}
+
+fn check_output() -> Result<String, anyhow::Error> {
+  let device = cpal::default_host().default_output_device().ok_or_else(|| anyhow!("no default output device"))?;
+  let config = device.default_output_config()?.config();
+  let callbacks = Arc::new(AtomicUsize::new(0));
+  let stream = {
+    let callbacks = callbacks.clone();
+    device.build_output_stream(&config, move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+      data.fill(0.0);
+      callbacks.fetch_add(1, Ordering::Relaxed);
+    }, |e| log::error!("Output stream error: {}", e))?
+  };
+  stream.play()?;
+  std::thread::sleep(STREAM_TEST);
+  drop(stream);
+  stream_result(device.name()?, &config, callbacks.load(Ordering::Relaxed))
+}
+
+fn stream_result(name: String, config: &cpal::StreamConfig, callbacks: usize) -> Result<String, anyhow::Error> {
+  if callbacks == 0 {
+    return Err(anyhow!("'{}' opened but never called back", name));
+  }
+  Ok(format!("'{}' at {} hz, {} channels", name, config.sample_rate.0, config.channels))
+}
+
+fn check_opus() -> Result<String, anyhow::Error> {
+  let rate = 48000;
+  let tone: Vec<f32> = (0..samples_in(FRAME_DURATION, rate))
+    .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / rate as f32).sin() * 0.5)
+    .collect();
+  let mut encoder = OpusEncoder::new(rate, 1, OpusConfig::default())?;
+  let packet = encoder.encode(&tone, 4000)?.ok_or_else(|| anyhow!("encoder skipped the frame"))?;
+  let mut decoder = OpusDecoder::new(rate)?;
+  let decoded = decoder.decode(&packet)?;
+  if decoded.iter().all(|s| *s == 0.0) {
+    return Err(anyhow!("decoded frame is silent"));
+  }
+  Ok(format!("{} samples in {} bytes", tone.len(), packet.len()))
+}
+
+fn check_server(server: SocketAddr) -> Result<String, anyhow::Error> {
+  let socket = crate::client::bind_for(server)?;
+  socket.connect(server)?;
+  socket.set_read_timeout(Some(SERVER_TIMEOUT))?;
+  // just the start of a connect for a protocol version nobody speaks, which any
+  // version of the server refuses with its own version, without letting us in
+  socket.send(&bincode::serialize(&(0u32, 0u16))?)?;
+  let mut buf = [0; packets::PACKET_MAX_SIZE];
+  let size = socket.recv(&mut buf).map_err(|e| anyhow!("no answer from {}: {}", server, e))?;
+  match ServerMessage::ack_version(&buf[..size]) {
+    Some(version) if version == packets::PROTOCOL_VERSION => Ok(format!("{} answered, protocol version {}", server, version)),
+    Some(version) => Err(anyhow!("{} speaks protocol version {}, we speak {}", server, version, packets::PROTOCOL_VERSION)),
+    None => Err(anyhow!("{} answered with something other than a voice server reply", server)),
+  }
+}
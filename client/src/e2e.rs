@@ -0,0 +1,181 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chacha20poly1305::{aead::{Aead, KeyInit, OsRng, Payload, rand_core::RngCore}, ChaCha20Poly1305, Key, Nonce};
+use common::packets::SeqNum;
+use sha2::Sha256;
+
+/// PBKDF2 rounds when stretching a passphrase into a key.
+const KDF_ROUNDS: u32 = 100_000;
+const SALT_SIZE: usize = 4;
+const NONCE_SIZE: usize = 12;
+/// How far behind the newest packet an older one can arrive and still be accepted.
+const REPLAY_WINDOW: u64 = 64;
+/// How far into a new key its first packet we see can be, allowing for the ones before it being lost.
+/// Any further and it's an old key being replayed, not one the sender just made.
+const FRESH_KEY_PACKETS: u64 = 256;
+
+/// Key shared by everyone in a room, used to encrypt voice so the server can't listen in.
+///
+/// Derived from a passphrase agreed out of band, salted with the room name so
+/// each room gets its own key.
+pub struct GroupKey {
+  cipher: ChaCha20Poly1305,
+  /// Random prefix for our nonces, so senders sharing the key don't collide.
+  salt: [u8; SALT_SIZE],
+  /// Counts up through our packets, making up the rest of the nonce.
+  counter: AtomicU64,
+}
+
+impl GroupKey {
+  pub fn derive(passphrase: &str, room: &str) -> Self {
+    let salt = format!("rust-voice e2e room:{}", room);
+    let mut key = Key::default();
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt.as_bytes(), KDF_ROUNDS, &mut key);
+    let mut salt = [0; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    Self { cipher: ChaCha20Poly1305::new(&key), salt, counter: AtomicU64::new(0) }
+  }
+
+  /// Encrypts a voice payload, prefixing it with its nonce.
+  ///
+  /// The sequence number is authenticated too, so it can't be tampered with in transit.
+  pub fn seal(&self, seq: SeqNum, payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..SALT_SIZE].copy_from_slice(&self.salt);
+    nonce[SALT_SIZE..].copy_from_slice(&self.counter.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    let ciphertext = self.cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: payload, aad: &seq.to_be_bytes() })
+      .map_err(|_| anyhow::anyhow!("Failed to encrypt voice payload"))?;
+    let mut packet = nonce.to_vec();
+    packet.extend(ciphertext);
+    Ok(packet)
+  }
+
+  /// Decrypts a payload from [`GroupKey::seal`], or `None` if it wasn't sealed with this key
+  /// or was replayed, according to the sender's `window`.
+  pub fn open(&self, seq: SeqNum, packet: &[u8], window: &mut ReplayWindow) -> Option<Vec<u8>> {
+    if packet.len() < NONCE_SIZE {return None;}
+    let (nonce, ciphertext) = packet.split_at(NONCE_SIZE);
+    let payload = self.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &seq.to_be_bytes() }).ok()?;
+    // only trust the nonce once it's authenticated
+    let (salt, counter) = nonce.split_at(SALT_SIZE);
+    window.accept(salt.try_into().unwrap(), u64::from_be_bytes(counter.try_into().unwrap())).then_some(payload)
+  }
+}
+
+/// Remembers which of a sender's recent nonces we've seen, to drop replayed packets.
+///
+/// Should live as long as the [`GroupKey`] it's used with, as that's how long
+/// packets sealed with the sender's old salts can be replayed.
+#[derive(Default)]
+pub struct ReplayWindow {
+  salt: Option<[u8; SALT_SIZE]>,
+  highest: u64,
+  /// Bit `n` is set if we've seen `highest - n`.
+  seen: u64,
+  /// Salts the sender has moved on from, never accepted again.
+  retired: Vec<[u8; SALT_SIZE]>,
+}
+
+impl ReplayWindow {
+  fn accept(&mut self, salt: [u8; SALT_SIZE], counter: u64) -> bool {
+    if self.salt != Some(salt) {
+      // the sender derived a new key (e.g. they rejoined), which counts up from zero
+      if self.retired.contains(&salt) || (self.salt.is_some() && counter >= FRESH_KEY_PACKETS) {
+        return false;
+      }
+      self.retired.extend(self.salt);
+      self.salt = Some(salt);
+      self.highest = counter;
+      self.seen = 1;
+      return true;
+    }
+    if counter > self.highest {
+      let shift = counter - self.highest;
+      self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+      self.seen |= 1;
+      self.highest = counter;
+      return true;
+    }
+    let age = self.highest - counter;
+    if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+      return false;
+    }
+    self.seen |= 1 << age;
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const OLD: [u8; SALT_SIZE] = [1; SALT_SIZE];
+  const NEW: [u8; SALT_SIZE] = [2; SALT_SIZE];
+
+  #[test]
+  fn rejects_replays() {
+    let mut window = ReplayWindow::default();
+    assert!(window.accept(OLD, 10));
+    assert!(!window.accept(OLD, 10));
+    assert!(window.accept(OLD, 11));
+    assert!(!window.accept(OLD, 10));
+    assert!(!window.accept(OLD, 11));
+  }
+
+  #[test]
+  fn accepts_reordered_within_window() {
+    let mut window = ReplayWindow::default();
+    assert!(window.accept(OLD, 100));
+    assert!(window.accept(OLD, 98));
+    assert!(window.accept(OLD, 99));
+    assert!(!window.accept(OLD, 98));
+    assert!(window.accept(OLD, 100 - REPLAY_WINDOW + 1));
+    assert!(!window.accept(OLD, 100 - REPLAY_WINDOW));
+    // jumping ahead forgets everything older
+    assert!(window.accept(OLD, 100 + REPLAY_WINDOW * 2));
+    assert!(!window.accept(OLD, 100));
+  }
+
+  #[test]
+  fn switches_to_fresh_key() {
+    let mut window = ReplayWindow::default();
+    // we can join part way through a sender's key
+    assert!(window.accept(OLD, 5000));
+    assert!(window.accept(NEW, 0));
+    assert!(window.accept(NEW, 1));
+    assert!(!window.accept(NEW, 0));
+  }
+
+  #[test]
+  fn old_salt_is_never_accepted_again() {
+    let mut window = ReplayWindow::default();
+    assert!(window.accept(OLD, 0));
+    assert!(window.accept(NEW, 0));
+    // alternating salts used to start the window over each time
+    for counter in 0..4 {
+      assert!(!window.accept(OLD, counter));
+      assert!(!window.accept(NEW, 0));
+    }
+    assert!(!window.accept(OLD, 1));
+  }
+
+  #[test]
+  fn rejects_unknown_salt_far_into_its_key() {
+    let mut window = ReplayWindow::default();
+    assert!(window.accept(OLD, 0));
+    assert!(!window.accept(NEW, FRESH_KEY_PACKETS));
+    assert!(window.accept(OLD, 1));
+  }
+
+  #[test]
+  fn opens_only_once() {
+    let key = GroupKey::derive("hunter2", "lobby");
+    let mut window = ReplayWindow::default();
+    let packet = key.seal(7, b"hello").unwrap();
+    assert_eq!(key.open(7, &packet, &mut window).as_deref(), Some(&b"hello"[..]));
+    assert_eq!(key.open(7, &packet, &mut window), None);
+    // the sequence number is authenticated
+    let packet = key.seal(8, b"hello").unwrap();
+    assert_eq!(key.open(9, &packet, &mut window), None);
+  }
+}
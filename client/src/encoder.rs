@@ -0,0 +1,252 @@
+use std::{ffi::CStr, os::raw::c_int, time::Duration};
+
+use anyhow::anyhow;
+use audiopus_sys as ffi;
+use log::info;
+
+use crate::util::opus::{samples_in, FRAME_DURATION, FRAME_DURATIONS};
+
+/// Packet loss the encoder plans for when FEC is on, in percent.
+const FEC_EXPECTED_LOSS: i32 = 10;
+
+/// How much of the audio spectrum opus keeps, narrowest first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bandwidth {
+  /// 4 kHz, telephone quality
+  Narrowband,
+  /// 6 kHz
+  Mediumband,
+  /// 8 kHz, enough for clear speech
+  Wideband,
+  /// 12 kHz
+  SuperWideband,
+  /// 20 kHz, everything we can hear
+  Fullband,
+}
+
+impl Bandwidth {
+  pub const ALL: [Bandwidth; 5] = [Self::Narrowband, Self::Mediumband, Self::Wideband, Self::SuperWideband, Self::Fullband];
+
+  /// Widest bandwidth worth encoding at `bitrate` bits per second. Past this,
+  /// a starved encoder does better narrowing the band than smearing all of it.
+  pub fn for_bitrate(bitrate: i32) -> Self {
+    match bitrate {
+      i32::MIN..=11_999 => Self::Narrowband,
+      12_000..=15_999 => Self::Mediumband,
+      16_000..=23_999 => Self::Wideband,
+      24_000..=31_999 => Self::SuperWideband,
+      _ => Self::Fullband,
+    }
+  }
+
+  /// What an opus packet was encoded with, from its first byte.
+  pub fn of_packet(packet: &[u8]) -> Option<Self> {
+    // the top five bits pick the mode, bandwidth and frame length together
+    Some(match packet.first()? >> 3 {
+      0..=3 | 16..=19 => Self::Narrowband,
+      4..=7 => Self::Mediumband,
+      8..=11 | 20..=23 => Self::Wideband,
+      12..=13 | 24..=27 => Self::SuperWideband,
+      _ => Self::Fullband,
+    })
+  }
+
+  fn ctl_value(self) -> i32 {
+    match self {
+      Self::Narrowband => ffi::OPUS_BANDWIDTH_NARROWBAND,
+      Self::Mediumband => ffi::OPUS_BANDWIDTH_MEDIUMBAND,
+      Self::Wideband => ffi::OPUS_BANDWIDTH_WIDEBAND,
+      Self::SuperWideband => ffi::OPUS_BANDWIDTH_SUPERWIDEBAND,
+      Self::Fullband => ffi::OPUS_BANDWIDTH_FULLBAND,
+    }
+  }
+}
+
+/// How mic audio is encoded.
+#[derive(Copy, Clone, Debug)]
+pub struct OpusConfig {
+  /// Bits per second, or `None` to let opus pick from the sample rate and channels.
+  pub bitrate: Option<i32>,
+  /// 0 (cheapest) to 10 (best quality), or `None` for opus' default.
+  pub complexity: Option<i32>,
+  /// Embeds a low bitrate copy of each frame in the next packet, so receivers can recover single lost packets.
+  pub fec: bool,
+  /// Stops sending during silence, apart from the occasional comfort noise update.
+  pub dtx: bool,
+  /// Length of audio in each packet: 10, 20, 40 or 60 ms. Longer frames cost less overhead but add delay.
+  pub frame_duration: Duration,
+  /// Widest band to encode, or `None` to let opus pick from the bitrate.
+  pub max_bandwidth: Option<Bandwidth>,
+}
+
+impl Default for OpusConfig {
+  fn default() -> Self {
+    Self {
+      bitrate: None,
+      complexity: None,
+      fec: false,
+      dtx: false,
+      frame_duration: FRAME_DURATION,
+      max_bandwidth: None,
+    }
+  }
+}
+
+impl OpusConfig {
+  pub fn with_bitrate(mut self, bitrate: Option<i32>) -> Self {
+    self.bitrate = bitrate;
+    self
+  }
+
+  pub fn with_complexity(mut self, complexity: Option<i32>) -> Self {
+    self.complexity = complexity;
+    self
+  }
+
+  pub fn with_fec(mut self, fec: bool) -> Self {
+    self.fec = fec;
+    self
+  }
+
+  pub fn with_dtx(mut self, dtx: bool) -> Self {
+    self.dtx = dtx;
+    self
+  }
+
+  pub fn with_frame_duration(mut self, frame_duration: Duration) -> Self {
+    self.frame_duration = frame_duration;
+    self
+  }
+
+  pub fn with_max_bandwidth(mut self, max_bandwidth: Option<Bandwidth>) -> Self {
+    self.max_bandwidth = max_bandwidth;
+    self
+  }
+}
+
+/// Encodes frames of interleaved mic audio at an opus sample rate.
+///
+/// Talks to libopus directly, since the `opus` crate can't set complexity or DTX.
+pub struct OpusEncoder {
+  ptr: *mut ffi::OpusEncoder,
+  config: OpusConfig,
+  sample_rate: u32,
+  channels: usize,
+  /// Samples (of all channels) in each frame.
+  frame_size: usize,
+}
+
+// the encoder state is only touched through `&mut self`, and libopus keeps no thread-local state
+unsafe impl Send for OpusEncoder {}
+
+impl OpusEncoder {
+  pub fn new(opus_rate: u32, channels: usize, config: OpusConfig) -> Result<Self, anyhow::Error> {
+    if !FRAME_DURATIONS.contains(&config.frame_duration) {
+      return Err(anyhow!("opus can't encode {:?} frames, use one of {:?}", config.frame_duration, FRAME_DURATIONS));
+    }
+    info!("Creating new {} channel OpusEncoder @ {} hz with {:?}", channels, opus_rate, config);
+    let mut error = 0;
+    let ptr = unsafe { ffi::opus_encoder_create(opus_rate as i32, channels as c_int, ffi::OPUS_APPLICATION_VOIP, &mut error) };
+    if error != ffi::OPUS_OK || ptr.is_null() {
+      return Err(opus_error("opus_encoder_create", error));
+    }
+    let mut encoder = Self {
+      ptr,
+      config,
+      sample_rate: opus_rate,
+      channels,
+      frame_size: samples_in(config.frame_duration, opus_rate) * channels,
+    };
+    encoder.set_bitrate(config.bitrate)?;
+    encoder.set_max_bandwidth(config.max_bandwidth)?;
+    if let Some(complexity) = config.complexity {
+      encoder.ctl(ffi::OPUS_SET_COMPLEXITY_REQUEST, complexity)?;
+    }
+    if config.fec {
+      encoder.ctl(ffi::OPUS_SET_INBAND_FEC_REQUEST, 1)?;
+      encoder.ctl(ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST, FEC_EXPECTED_LOSS)?;
+    }
+    encoder.ctl(ffi::OPUS_SET_DTX_REQUEST, config.dtx as i32)?;
+    Ok(encoder)
+  }
+
+  pub fn config(&self) -> &OpusConfig {
+    &self.config
+  }
+
+  /// Samples (of all channels) each frame passed to [`OpusEncoder::encode`] needs.
+  pub fn frame_size(&self) -> usize {
+    self.frame_size
+  }
+
+  /// How far ahead of each frame the encoder looks, which delays everything it encodes by as much.
+  pub fn lookahead(&mut self) -> Result<Duration, anyhow::Error> {
+    let mut samples: i32 = 0;
+    let code = unsafe { ffi::opus_encoder_ctl(self.ptr, ffi::OPUS_GET_LOOKAHEAD_REQUEST, &mut samples as *mut i32) };
+    if code < 0 {
+      return Err(opus_error("opus_encoder_ctl", code));
+    }
+    Ok(Duration::from_secs_f64(samples as f64 / self.sample_rate as f64))
+  }
+
+  /// Encodes a frame, returning `None` if DTX decided it doesn't need sending.
+  pub fn encode(&mut self, frame: &[f32], max_size: usize) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let mut packet = vec![0; max_size];
+    Ok(self.encode_into(frame, &mut packet)?.map(|size| {
+      packet.truncate(size);
+      packet
+    }))
+  }
+
+  /// Encodes a frame into `packet`, returning how much of it was used, or `None` like [`OpusEncoder::encode`].
+  pub fn encode_into(&mut self, frame: &[f32], packet: &mut [u8]) -> Result<Option<usize>, anyhow::Error> {
+    if frame.len() != self.frame_size {
+      return Err(anyhow!("expected a frame of {} samples, got {}", self.frame_size, frame.len()));
+    }
+    let size = unsafe {
+      ffi::opus_encode_float(self.ptr, frame.as_ptr(), (frame.len() / self.channels) as c_int, packet.as_mut_ptr(), packet.len() as i32)
+    };
+    if size < 0 {
+      return Err(opus_error("opus_encode_float", size));
+    }
+    // with DTX, silence comes out as packets of a byte or two that are only meant to be dropped
+    if self.config.dtx && size <= 2 {
+      return Ok(None);
+    }
+    Ok(Some(size as usize))
+  }
+
+  /// Changes the bitrate without recreating the encoder, `None` lets opus pick.
+  pub fn set_bitrate(&mut self, bitrate: Option<i32>) -> Result<(), anyhow::Error> {
+    self.ctl(ffi::OPUS_SET_BITRATE_REQUEST, bitrate.unwrap_or(ffi::OPUS_AUTO))?;
+    self.config.bitrate = bitrate;
+    Ok(())
+  }
+
+  /// Changes the widest band encoded without recreating the encoder, `None` lets opus pick.
+  pub fn set_max_bandwidth(&mut self, max_bandwidth: Option<Bandwidth>) -> Result<(), anyhow::Error> {
+    // opus' default is fullband, narrowed as it sees fit for the bitrate
+    self.ctl(ffi::OPUS_SET_MAX_BANDWIDTH_REQUEST, max_bandwidth.unwrap_or(Bandwidth::Fullband).ctl_value())?;
+    self.config.max_bandwidth = max_bandwidth;
+    Ok(())
+  }
+
+  fn ctl(&mut self, request: i32, value: i32) -> Result<(), anyhow::Error> {
+    let code = unsafe { ffi::opus_encoder_ctl(self.ptr, request, value) };
+    if code < 0 {
+      return Err(opus_error("opus_encoder_ctl", code));
+    }
+    Ok(())
+  }
+}
+
+impl Drop for OpusEncoder {
+  fn drop(&mut self) {
+    unsafe { ffi::opus_encoder_destroy(self.ptr) }
+  }
+}
+
+fn opus_error(function: &str, code: c_int) -> anyhow::Error {
+  let description = unsafe { CStr::from_ptr(ffi::opus_strerror(code)) };
+  anyhow!("{} failed: {}", function, description.to_string_lossy())
+}
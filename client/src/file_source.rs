@@ -0,0 +1,117 @@
+use std::{collections::VecDeque, path::Path};
+
+use anyhow::anyhow;
+use log::warn;
+use symphonia::core::{audio::SampleBuffer, codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL}, errors::Error as DecodeError, formats::{FormatOptions, FormatReader}, io::MediaSourceStream, meta::MetadataOptions, probe::Hint};
+
+use crate::util::resampling::Resampler;
+
+/// Streams a WAV or Ogg Vorbis file as interleaved samples at the rate and
+/// channel count we encode at, for playing clips or music into a call.
+pub struct FileSource {
+  format: Box<dyn FormatReader>,
+  decoder: Box<dyn Decoder>,
+  track_id: u32,
+  /// Channels in the file.
+  source_channels: usize,
+  /// Channels we hand out.
+  channels: usize,
+  resampler: Resampler,
+  buffer: VecDeque<f32>,
+  finished: bool,
+}
+
+impl FileSource {
+  /// Opens `path`, to be read at `sample_rate` with `channels` channels.
+  pub fn open(path: impl AsRef<Path>, sample_rate: u32, channels: usize) -> Result<Self, anyhow::Error> {
+    let path = path.as_ref();
+    let stream = MediaSourceStream::new(Box::new(std::fs::File::open(path)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+      hint.with_extension(extension);
+    }
+    let format = symphonia::default::get_probe()
+      .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())?
+      .format;
+    let track = format.tracks().iter()
+      .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+      .ok_or_else(|| anyhow!("no audio in {}", path.display()))?;
+    let source_rate = track.codec_params.sample_rate.ok_or_else(|| anyhow!("unknown sample rate in {}", path.display()))?;
+    let source_channels = track.codec_params.channels.map_or(0, |c| c.count());
+    if source_channels == 0 {
+      return Err(anyhow!("unknown channel layout in {}", path.display()));
+    }
+    let decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    Ok(Self {
+      track_id: track.id,
+      format,
+      decoder,
+      source_channels,
+      channels,
+      resampler: Resampler::new(source_rate, sample_rate, channels),
+      buffer: VecDeque::new(),
+      finished: false,
+    })
+  }
+
+  /// Fills `out` with the next samples, returning how many there were.
+  /// Fewer than asked for means the file has ended.
+  pub fn read(&mut self, out: &mut [f32]) -> Result<usize, anyhow::Error> {
+    while self.buffer.len() < out.len() && !self.finished {
+      self.decode_packet()?;
+    }
+    let read = out.len().min(self.buffer.len());
+    for (out, sample) in out.iter_mut().zip(self.buffer.drain(..read)) {
+      *out = sample;
+    }
+    Ok(read)
+  }
+
+  /// Whether everything has been read.
+  pub fn is_finished(&self) -> bool {
+    self.finished && self.buffer.is_empty()
+  }
+
+  fn decode_packet(&mut self) -> Result<(), anyhow::Error> {
+    let packet = match self.format.next_packet() {
+      Ok(packet) => packet,
+      // the only way formats tell us they're done
+      Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+        self.finished = true;
+        return Ok(());
+      },
+      Err(e) => return Err(e.into()),
+    };
+    if packet.track_id() != self.track_id {return Ok(());}
+    let decoded = match self.decoder.decode(&packet) {
+      Ok(decoded) => decoded,
+      // a corrupt packet is a glitch, not the end of the clip
+      Err(DecodeError::DecodeError(e)) => {
+        warn!("Skipped undecodable packet: {}", e);
+        return Ok(());
+      },
+      Err(e) => return Err(e.into()),
+    };
+    let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+    samples.copy_interleaved_ref(decoded);
+    let remixed = remix(samples.samples(), self.source_channels, self.channels);
+    self.buffer.extend(self.resampler.process(&remixed));
+    Ok(())
+  }
+}
+
+/// Interleaved samples with `from` channels to `to` channels: averaged down to
+/// mono, copied up from mono, otherwise extra channels are dropped or left silent.
+fn remix(input: &[f32], from: usize, to: usize) -> Vec<f32> {
+  if from == to {
+    return input.to_vec();
+  }
+  input.chunks_exact(from).flat_map(|frame| {
+    let mono = frame.iter().sum::<f32>() / from as f32;
+    (0..to).map(move |channel| match (from, to) {
+      (_, 1) => mono,
+      (1, _) => frame[0],
+      _ => frame.get(channel).copied().unwrap_or(0.0),
+    })
+  }).collect()
+}
@@ -0,0 +1,379 @@
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+use common::packets::SeqNum;
+
+/// Consecutive frames we have to be over the target delay for before skipping ahead.
+const CATCH_UP_AFTER: usize = 25;
+/// Roughly how many frames the loss rate is averaged over.
+const LOSS_WINDOW: f32 = 250.0;
+
+/// How far `a` is ahead of `b`, accounting for wrap-around.
+pub fn seq_diff(a: SeqNum, b: SeqNum) -> i16 {
+  a.wrapping_sub(b) as i16
+}
+
+/// What to play next from a [`JitterBuffer`].
+pub enum Playout {
+  Packet(Vec<u8>),
+  /// The next packet never arrived (or arrived too late).
+  Lost,
+}
+
+/// Reorders a peer's voice packets and holds them back just long enough to
+/// ride out the network's jitter.
+///
+/// Jitter is estimated from packet inter-arrival times as in RFC 3550, and
+/// the playout delay grows and shrinks with it between one frame and
+/// `max_delay`.
+pub struct JitterBuffer {
+  /// Slots for the packets from `next_seq` onwards, `None` for ones not (yet) received.
+  slots: VecDeque<Option<Vec<u8>>>,
+  next_seq: Option<SeqNum>,
+  /// Whether we're playing out, as opposed to filling up to the target delay.
+  playing: bool,
+
+  frame_duration: Duration,
+  /// Most audio to hold, `max_delay_frames` is this in frames.
+  max_delay: Duration,
+  max_delay_frames: usize,
+  /// Target number of frames to hold.
+  target: usize,
+  /// How many frames in a row we've held more than we should.
+  over_target: usize,
+
+  /// Smoothed inter-arrival jitter, in seconds.
+  jitter: f64,
+  last_arrival: Option<(Instant, SeqNum)>,
+  /// Smoothed fraction of frames that were lost.
+  loss: f32,
+}
+
+impl JitterBuffer {
+  pub fn new(frame_duration: Duration, max_delay: Duration) -> Self {
+    let max_delay_frames = frames_in(max_delay, frame_duration);
+    Self {
+      slots: VecDeque::new(),
+      next_seq: None,
+      playing: false,
+
+      frame_duration,
+      max_delay,
+      max_delay_frames,
+      target: max_delay_frames.min(3),
+      over_target: 0,
+
+      jitter: 0.0,
+      last_arrival: None,
+      loss: 0.0,
+    }
+  }
+
+  pub fn push(&mut self, seq: SeqNum, packet: Vec<u8>) {
+    self.update_jitter(seq);
+
+    let next_seq = *self.next_seq.get_or_insert(seq);
+    let offset = seq_diff(seq, next_seq);
+    if offset < 0 {
+      // too late, we've already played past it
+      return;
+    }
+    let offset = offset as usize;
+    if offset >= self.max_delay_frames * 2 {
+      // way ahead of what we're playing, the peer probably restarted, so start over from here
+      self.slots.clear();
+      self.next_seq = Some(seq);
+      self.playing = false;
+      self.slots.push_back(Some(packet));
+      return;
+    }
+    if self.slots.len() <= offset {
+      self.slots.resize(offset + 1, None);
+    }
+    self.slots[offset] = Some(packet);
+  }
+
+  /// Takes the next frame to play, or `None` while buffering.
+  pub fn pop(&mut self) -> Option<Playout> {
+    if !self.playing {
+      if self.slots.len() < self.target {return None;}
+      self.playing = true;
+    }
+    // if we've been running behind for a while, skip a frame to catch up
+    if self.slots.len() > self.target + 2 {
+      self.over_target += 1;
+      if self.over_target >= CATCH_UP_AFTER {
+        self.over_target = 0;
+        self.advance();
+      }
+    } else {
+      self.over_target = 0;
+    }
+    let playout = match self.advance() {
+      Some(Some(packet)) => Playout::Packet(packet),
+      Some(None) => Playout::Lost,
+      None => {
+        // ran dry, build the buffer back up before playing again
+        self.playing = false;
+        return None;
+      }
+    };
+    let lost = if matches!(playout, Playout::Lost) {1.0} else {0.0};
+    self.loss += (lost - self.loss) / LOSS_WINDOW;
+    Some(playout)
+  }
+
+  /// Drops the oldest frames past the most it may hold, while playback can't take any more.
+  ///
+  /// Without this, packets pile up behind a stalled output until [`JitterBuffer::push`]
+  /// thinks the peer restarted and throws them all away, newest included.
+  pub fn hold(&mut self) {
+    while self.slots.len() > self.max_delay_frames {
+      self.advance();
+    }
+  }
+
+  /// Fraction of recent frames that never arrived in time.
+  pub fn loss(&self) -> f32 {
+    self.loss
+  }
+
+  /// Estimated variation in packet arrival times.
+  pub fn jitter(&self) -> Duration {
+    Duration::from_secs_f64(self.jitter)
+  }
+
+  /// Length of audio in each packet.
+  pub fn frame_duration(&self) -> Duration {
+    self.frame_duration
+  }
+
+  /// Changes the length of audio in each packet, when the peer starts sending a different length.
+  pub fn set_frame_duration(&mut self, frame_duration: Duration) {
+    if frame_duration == self.frame_duration {return;}
+    let held = self.frame_duration * self.target as u32;
+    self.frame_duration = frame_duration;
+    self.max_delay_frames = frames_in(self.max_delay, frame_duration);
+    // keep holding about as much audio as before
+    self.target = frames_in(held, frame_duration).min(self.max_delay_frames);
+  }
+
+  /// Changes the most audio to hold, e.g. when moving to a room that wants less delay.
+  pub fn set_max_delay(&mut self, max_delay: Duration) {
+    self.max_delay = max_delay;
+    self.max_delay_frames = frames_in(max_delay, self.frame_duration);
+    self.target = self.target.min(self.max_delay_frames);
+  }
+
+  /// How long packets are currently held before playing.
+  pub fn delay(&self) -> Duration {
+    self.frame_duration * self.target as u32
+  }
+
+  /// When the last packet arrived, `None` if none has yet.
+  pub fn last_arrival(&self) -> Option<Instant> {
+    self.last_arrival.map(|(time, _)| time)
+  }
+
+  /// The packet that will be played next, if it has arrived.
+  pub fn peek(&self) -> Option<&[u8]> {
+    self.slots.front()?.as_deref()
+  }
+
+  fn advance(&mut self) -> Option<Option<Vec<u8>>> {
+    let slot = self.slots.pop_front()?;
+    self.next_seq = self.next_seq.map(|seq| seq.wrapping_add(1));
+    Some(slot)
+  }
+
+  fn update_jitter(&mut self, seq: SeqNum) {
+    let now = Instant::now();
+    if let Some((last_time, last_seq)) = self.last_arrival {
+      let sent = seq_diff(seq, last_seq) as f64 * self.frame_duration.as_secs_f64();
+      let received = now.duration_since(last_time).as_secs_f64();
+      // a gap this long is the peer pausing (e.g. not sending silence), not network jitter
+      if received - sent > self.max_delay.as_secs_f64() {
+        self.last_arrival = Some((now, seq));
+        return;
+      }
+      self.jitter += ((received - sent).abs() - self.jitter) / 16.0;
+      // hold enough to cover a few deviations, plus the frame being played
+      let frames = (4.0 * self.jitter / self.frame_duration.as_secs_f64()).ceil() as usize + 1;
+      self.target = frames.clamp(1, self.max_delay_frames);
+    }
+    self.last_arrival = Some((now, seq));
+  }
+}
+
+/// Whole frames in `duration`, at least one.
+fn frames_in(duration: Duration, frame_duration: Duration) -> usize {
+  ((duration.as_secs_f64() / frame_duration.as_secs_f64()) as usize).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FRAME: Duration = Duration::from_millis(20);
+
+  /// Holds at most 10 frames, and waits for 3 before playing.
+  fn buffer() -> JitterBuffer {
+    JitterBuffer::new(FRAME, FRAME * 10)
+  }
+
+  /// Pushes a packet holding the low byte of `seq`, as if it arrived right on time,
+  /// so the target delay stays put.
+  fn push(buffer: &mut JitterBuffer, seq: SeqNum) {
+    buffer.last_arrival = None;
+    buffer.push(seq, vec![seq as u8]);
+  }
+
+  /// The next frame's low byte of `seq`, `Some(None)` if it was lost.
+  fn pop(buffer: &mut JitterBuffer) -> Option<Option<u8>> {
+    buffer.pop().map(|playout| match playout {
+      Playout::Packet(packet) => Some(packet[0]),
+      Playout::Lost => None,
+    })
+  }
+
+  #[test]
+  fn waits_for_the_target_then_plays_in_order() {
+    let mut buffer = buffer();
+    push(&mut buffer, 0);
+    assert_eq!(pop(&mut buffer), None);
+    push(&mut buffer, 2);
+    push(&mut buffer, 1);
+    assert_eq!(pop(&mut buffer), Some(Some(0)));
+    assert_eq!(pop(&mut buffer), Some(Some(1)));
+    assert_eq!(pop(&mut buffer), Some(Some(2)));
+    // ran dry, so it fills back up before playing again
+    assert_eq!(pop(&mut buffer), None);
+    push(&mut buffer, 3);
+    assert_eq!(pop(&mut buffer), None);
+  }
+
+  #[test]
+  fn reorders_across_wrap_around() {
+    let mut buffer = buffer();
+    push(&mut buffer, SeqNum::MAX);
+    push(&mut buffer, 1);
+    push(&mut buffer, 0);
+    assert_eq!(pop(&mut buffer), Some(Some(SeqNum::MAX as u8)));
+    assert_eq!(pop(&mut buffer), Some(Some(0)));
+    assert_eq!(pop(&mut buffer), Some(Some(1)));
+  }
+
+  #[test]
+  fn missing_frames_are_lost() {
+    let mut buffer = buffer();
+    push(&mut buffer, 0);
+    push(&mut buffer, 2);
+    push(&mut buffer, 3);
+    assert_eq!(pop(&mut buffer), Some(Some(0)));
+    assert_eq!(pop(&mut buffer), Some(None));
+    assert_eq!(pop(&mut buffer), Some(Some(2)));
+    assert!(buffer.loss() > 0.0);
+  }
+
+  #[test]
+  fn late_frames_are_dropped() {
+    let mut buffer = buffer();
+    push(&mut buffer, 0);
+    push(&mut buffer, 2);
+    push(&mut buffer, 3);
+    assert_eq!(pop(&mut buffer), Some(Some(0)));
+    assert_eq!(pop(&mut buffer), Some(None));
+    // 1 turns up after we've played past it, and 0 again
+    push(&mut buffer, 1);
+    push(&mut buffer, 0);
+    assert_eq!(buffer.slots.len(), 2);
+    assert_eq!(pop(&mut buffer), Some(Some(2)));
+    assert_eq!(pop(&mut buffer), Some(Some(3)));
+  }
+
+  #[test]
+  fn starts_over_when_the_peer_jumps_ahead() {
+    let mut near = buffer();
+    push(&mut near, 0);
+    // still within twice the most it holds
+    push(&mut near, 19);
+    assert_eq!(near.slots.len(), 20);
+
+    let mut buffer = buffer();
+    for seq in 0..3 {
+      push(&mut buffer, seq);
+    }
+    assert_eq!(pop(&mut buffer), Some(Some(0)));
+    push(&mut buffer, 1 + 20);
+    assert_eq!(buffer.slots.len(), 1);
+    assert_eq!(pop(&mut buffer), None);
+    push(&mut buffer, 22);
+    push(&mut buffer, 23);
+    assert_eq!(pop(&mut buffer), Some(Some(21)));
+    assert_eq!(pop(&mut buffer), Some(Some(22)));
+  }
+
+  #[test]
+  fn skips_a_frame_after_running_behind_for_a_while() {
+    let mut buffer = buffer();
+    for seq in 0..7 {
+      push(&mut buffer, seq);
+    }
+    let mut played = vec![];
+    for seq in 7..7 + CATCH_UP_AFTER as SeqNum {
+      push(&mut buffer, seq);
+      played.push(pop(&mut buffer).unwrap().unwrap());
+    }
+    let expected: Vec<u8> = (0..CATCH_UP_AFTER as u8 - 1).chain([CATCH_UP_AFTER as u8]).collect();
+    assert_eq!(played, expected);
+    assert_eq!(buffer.over_target, 0);
+  }
+
+  #[test]
+  fn doesnt_skip_when_only_briefly_behind() {
+    let mut buffer = buffer();
+    for seq in 0..7 {
+      push(&mut buffer, seq);
+    }
+    for expected in 0..7 {
+      assert_eq!(pop(&mut buffer), Some(Some(expected)));
+    }
+  }
+
+  #[test]
+  fn hold_drops_the_oldest_past_the_most_it_holds() {
+    let mut buffer = buffer();
+    for seq in 0..16 {
+      push(&mut buffer, seq);
+    }
+    buffer.hold();
+    assert_eq!(buffer.slots.len(), 10);
+    assert_eq!(buffer.peek(), Some(&[6][..]));
+    // newer packets still fit rather than looking like a restart
+    push(&mut buffer, 16);
+    assert_eq!(pop(&mut buffer), Some(Some(6)));
+    buffer.hold();
+    assert_eq!(buffer.slots.len(), 10);
+  }
+
+  #[test]
+  fn frame_duration_changes_keep_the_delay() {
+    let mut buffer = buffer();
+    assert_eq!(buffer.delay(), FRAME * 3);
+
+    buffer.set_frame_duration(FRAME / 2);
+    assert_eq!(buffer.frame_duration(), FRAME / 2);
+    assert_eq!(buffer.target, 6);
+    assert_eq!(buffer.max_delay_frames, 20);
+    assert_eq!(buffer.delay(), FRAME * 3);
+    // the jump that counts as a restart follows the new frame length
+    push(&mut buffer, 0);
+    push(&mut buffer, 39);
+    assert_eq!(buffer.slots.len(), 40);
+
+    // longer frames than the target delay still hold at least one
+    buffer.set_frame_duration(FRAME * 4);
+    assert_eq!(buffer.target, 1);
+    assert_eq!(buffer.max_delay_frames, 2);
+    assert_eq!(buffer.delay(), FRAME * 4);
+  }
+}
@@ -0,0 +1,85 @@
+use std::{collections::HashMap, fmt::Write as _, path::Path};
+
+use common::crypto::{fingerprint, parse_fingerprint, PublicKeyBytes};
+
+/// The keys servers had when we first connected to them, so we notice if
+/// someone else answers in their place later (trust on first use).
+#[derive(Debug, Default)]
+#[derive(Clone)]
+pub struct KnownServers {
+  keys: HashMap<String, PublicKeyBytes>,
+}
+
+impl KnownServers {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Reads servers saved with [`KnownServers::save`], an `address<TAB>fingerprint` per line.
+  /// Nothing is known yet if the file doesn't exist.
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+    let text = match std::fs::read_to_string(path) {
+      Ok(text) => text,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+      Err(e) => return Err(e.into()),
+    };
+    let mut known = Self::new();
+    for (number, line) in text.lines().enumerate() {
+      if line.trim().is_empty() {continue;}
+      let key = line.split_once('\t').and_then(|(server, key)| Some((server, parse_fingerprint(key)?)));
+      let (server, key) = key.ok_or_else(|| anyhow::anyhow!("line {} isn't an address and key separated by a tab", number + 1))?;
+      known.trust(server, key);
+    }
+    Ok(known)
+  }
+
+  pub fn save(&self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+    let mut keys = self.keys.iter().collect::<Vec<_>>();
+    keys.sort();
+    let mut out = String::new();
+    for (server, key) in keys {
+      writeln!(out, "{}\t{}", server, fingerprint(*key))?;
+    }
+    std::fs::write(path, out)?;
+    Ok(())
+  }
+
+  /// Expects `server` to have `key` from now on.
+  pub fn trust(&mut self, server: &str, key: PublicKeyBytes) {
+    self.keys.insert(server.to_string(), key);
+  }
+
+  /// Stops expecting anything of `server`, for when it has really changed its key.
+  pub fn forget(&mut self, server: &str) {
+    self.keys.remove(server);
+  }
+
+  pub fn get(&self, server: &str) -> Option<PublicKeyBytes> {
+    self.keys.get(server).copied()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn saved_servers_load_back() {
+    let path = std::env::temp_dir().join(format!("rust-voice-known-{}", std::process::id()));
+    let mut known = KnownServers::new();
+    known.trust("127.0.0.1:4000", [1; 32]);
+    known.trust("[::1]:4000", [2; 32]);
+    known.save(&path).unwrap();
+    let loaded = KnownServers::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.get("127.0.0.1:4000"), Some([1; 32]));
+    assert_eq!(loaded.get("[::1]:4000"), Some([2; 32]));
+    assert_eq!(loaded.get("[::1]:4001"), None);
+  }
+
+  #[test]
+  fn missing_file_knows_nothing() {
+    let known = KnownServers::load(std::env::temp_dir().join("rust-voice-no-such-file")).unwrap();
+    assert_eq!(known.get("127.0.0.1:4000"), None);
+  }
+}
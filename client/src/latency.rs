@@ -1,23 +1,138 @@
-#[derive(Copy, Clone)]
+use std::time::Duration;
+
+/// A buffering delay on a stream with a given sample rate and channel count.
+///
+/// The delay is stored in frames (one sample per channel), so a latency is
+/// only meaningful for the stream format it was created for. Use
+/// [`Latency::convert`] to express the same delay for another stream.
+#[derive(Copy, Clone, Debug)]
 pub struct Latency {
-  ms: f32,
   frames: usize,
-  samples: usize,
+  sample_rate: u32,
+  channels: u16,
 }
 
-impl Latency {
-  pub fn new(latency_ms: f32, sample_rate: u32, channels: u16) -> Self {
-    let frames = ((latency_ms * sample_rate as f32) / 1000.0) as usize;
-    let samples = frames * channels as usize;
+/// Sample rates any audio device could plausibly run at; anything else is
+/// most likely a channel count or frame size passed in the wrong place.
+const SAMPLE_RATES: std::ops::RangeInclusive<u32> = 1_000..=768_000;
 
+impl Latency {
+  pub fn from_frames(frames: usize, sample_rate: u32, channels: u16) -> Self {
+    debug_assert!(SAMPLE_RATES.contains(&sample_rate), "latency given an implausible sample rate of {} Hz", sample_rate);
+    debug_assert!(channels > 0, "latency needs at least one channel");
     Self {
-      ms: latency_ms,
       frames,
-      samples,
+      sample_rate,
+      channels,
     }
   }
 
+  /// `samples` counts every channel, so it must be a whole number of frames.
+  pub fn from_samples(samples: usize, sample_rate: u32, channels: u16) -> Self {
+    debug_assert!(channels > 0, "latency needs at least one channel");
+    debug_assert_eq!(samples % channels as usize, 0, "{} samples is not a whole number of {} channel frames", samples, channels);
+    Self::from_frames(samples / channels as usize, sample_rate, channels)
+  }
+
+  pub fn from_ms(latency_ms: f32, sample_rate: u32, channels: u16) -> Self {
+    debug_assert!(latency_ms.is_finite() && latency_ms >= 0.0, "invalid latency of {} ms", latency_ms);
+    let frames = (latency_ms as f64 * sample_rate as f64 / 1000.0).round() as usize;
+    Self::from_frames(frames, sample_rate, channels)
+  }
+
+  pub fn from_duration(duration: Duration, sample_rate: u32, channels: u16) -> Self {
+    let frames = (duration.as_secs_f64() * sample_rate as f64).round() as usize;
+    Self::from_frames(frames, sample_rate, channels)
+  }
+
+  /// The same delay on a stream with a different format.
+  pub fn convert(&self, sample_rate: u32, channels: u16) -> Self {
+    debug_assert!(SAMPLE_RATES.contains(&sample_rate), "can't convert a {} Hz latency to {} Hz", self.sample_rate, sample_rate);
+    Self::from_duration(self.duration(), sample_rate, channels)
+  }
+
+  pub fn frames(&self) -> usize {
+    self.frames
+  }
+
   pub fn samples(&self) -> usize {
-    self.samples
+    self.frames * self.channels as usize
+  }
+
+  pub fn ms(&self) -> f32 {
+    self.frames as f32 * 1000.0 / self.sample_rate as f32
   }
-}
\ No newline at end of file
+
+  pub fn duration(&self) -> Duration {
+    Duration::from_secs_f64(self.frames as f64 / self.sample_rate as f64)
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  pub fn channels(&self) -> u16 {
+    self.channels
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ms_round_trip() {
+    for rate in [8_000, 44_100, 48_000, 96_000] {
+      for ms in [0.0, 10.0, 20.0, 150.0] {
+        let latency = Latency::from_ms(ms, rate, 2);
+        assert_eq!(latency.ms(), ms, "{} ms at {} Hz", ms, rate);
+        assert_eq!(Latency::from_duration(latency.duration(), rate, 2).frames(), latency.frames());
+      }
+    }
+  }
+
+  #[test]
+  fn samples_round_trip() {
+    let latency = Latency::from_samples(960, 48_000, 2);
+    assert_eq!(latency.frames(), 480);
+    assert_eq!(latency.samples(), 960);
+    assert_eq!(latency.ms(), 10.0);
+  }
+
+  #[test]
+  fn convert_round_trip() {
+    let latency = Latency::from_ms(150.0, 48_000, 2);
+    let converted = latency.convert(44_100, 1);
+    assert_eq!(converted.frames(), 6_615);
+    assert_eq!(converted.samples(), 6_615);
+    assert_eq!(converted.convert(48_000, 2).frames(), latency.frames());
+  }
+
+  #[test]
+  fn convert_stays_within_a_frame() {
+    let latency = Latency::from_frames(1_001, 48_000, 2);
+    let back = latency.convert(44_100, 2).convert(48_000, 2);
+    assert!(back.frames().abs_diff(latency.frames()) <= 1);
+  }
+
+  #[test]
+  #[cfg(debug_assertions)]
+  #[should_panic(expected = "implausible sample rate")]
+  fn channels_passed_as_rate() {
+    Latency::from_ms(10.0, 2, 48_000);
+  }
+
+  #[test]
+  #[cfg(debug_assertions)]
+  #[should_panic(expected = "can't convert")]
+  fn convert_to_no_rate() {
+    Latency::from_ms(10.0, 48_000, 2).convert(0, 2);
+  }
+
+  #[test]
+  #[cfg(debug_assertions)]
+  #[should_panic(expected = "whole number")]
+  fn partial_frames() {
+    Latency::from_samples(961, 48_000, 2);
+  }
+}
@@ -1,10 +1,78 @@
+//! Voice chat client.
+//!
+//! The `audio` feature (on by default) adds `App`, which records, plays and
+//! mixes through the system's audio devices. Without it only the codec and
+//! network core is built, for bots and other headless uses.
+
+#[cfg(feature = "audio")]
 mod app;
+#[cfg(feature = "audio")]
 pub use app::*;
 
+mod bitrate;
+pub use bitrate::BitrateController;
+#[cfg(feature = "audio")]
+mod budget;
+#[cfg(feature = "audio")]
+pub use budget::{Stage, StageLatency};
+#[cfg(feature = "audio")]
+mod clip;
 mod client;
+pub use client::{Client, ClientState, ConnectionEvent, MicPacket, audio_level, resolve};
+mod comfort;
+pub use comfort::ComfortNoise;
 mod decoder;
+pub use decoder::OpusDecoder;
+#[cfg(feature = "audio")]
+mod denoise;
+#[cfg(feature = "audio")]
+mod devices;
+#[cfg(feature = "audio")]
+mod doctor;
+#[cfg(feature = "audio")]
+pub use doctor::{run_diagnostics, Diagnostic};
+#[cfg(feature = "audio")]
+pub use devices::{list_devices, DeviceInfo, ConfigRange};
+mod encoder;
+pub use encoder::{Bandwidth, OpusConfig, OpusEncoder};
+mod file_source;
+pub use file_source::FileSource;
+mod e2e;
+pub use e2e::{GroupKey, ReplayWindow};
+mod jitter;
+pub use jitter::{JitterBuffer, Playout};
+mod known_servers;
+pub use known_servers::KnownServers;
 mod latency;
+pub use latency::Latency;
+#[cfg(feature = "audio")]
 mod mic;
+mod nicknames;
+pub use nicknames::Nicknames;
+mod preset;
+pub use preset::PresetSettings;
+#[cfg(feature = "audio")]
+mod profile;
+#[cfg(feature = "audio")]
+pub use profile::AudioProfile;
+mod quality;
+pub use quality::CallQuality;
+#[cfg(feature = "audio")]
+mod recorder;
+mod srv;
+pub use srv::{lookup_srv, SrvRecord, SRV_PREFIX};
+mod stats;
+pub use stats::{NetworkStats, PeerStats};
+mod timeline;
+pub use timeline::{SpeakingSegment, SpeakingTimeline};
+mod vad;
+pub use vad::{VoiceDetector, DEFAULT_VAD_THRESHOLD, DEFAULT_VAD_HANGOVER};
+#[cfg(feature = "audio")]
 mod voice;
 mod util;
-mod cpal;
\ No newline at end of file
+#[cfg(feature = "audio")]
+pub use util::{alloc::realtime_allocations, thread::AudioThreadSettings};
+#[cfg(feature = "audio")]
+mod cpal;
+#[cfg(feature = "audio")]
+pub use cpal::ChannelMap;
@@ -1,26 +1,216 @@
-use std::{borrow::BorrowMut, sync::{Mutex, Arc, mpsc::{Sender, Receiver}}, collections::VecDeque};
+use std::{sync::{Mutex, Arc, mpsc::{Sender, Receiver}, atomic::{AtomicBool, AtomicI32, AtomicU8, Ordering}}, collections::VecDeque, path::Path, time::{Duration, Instant}};
 
 use anyhow::anyhow;
 use common::packets;
 use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
 use log::{info, error, warn};
-use ringbuf::{Producer, Consumer, RingBuffer};
+use ringbuf::{Consumer, RingBuffer};
 
-use crate::{util::{opus::{OPUS_SAMPLE_RATES, nearest_opus_rate}, resampling::resample_audio}, latency::Latency};
+use crate::{bitrate::BitrateController, budget::{Stage, StageLatency, StageTimings}, client::{audio_level, MicPacket}, denoise::NoiseSuppressor, devices::find_input_device, encoder::{Bandwidth, OpusConfig, OpusEncoder}, file_source::FileSource, vad::VoiceDetector, util::{opus::{OPUS_SAMPLE_RATES, nearest_opus_rate}, resampling::Resampler, thread::AudioThreadSettings, alloc::RealtimeScope}};
+
+/// Audio from a file queued to go out with (or instead of) the mic.
+struct FilePlayback {
+  consumer: Consumer<f32>,
+  /// Send only the file, not the mic, while it plays.
+  replace_mic: bool,
+  /// Set by the thread reading the file once it has queued all of it.
+  done: Arc<AtomicBool>,
+  /// Tells the thread reading the file to give up.
+  stop: Arc<AtomicBool>,
+}
+
+impl FilePlayback {
+  fn playing(&self) -> bool {
+    !(self.done.load(Ordering::Relaxed) && self.consumer.is_empty())
+  }
+}
+
+impl Drop for FilePlayback {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
+  }
+}
+
+/// Audio read ahead from a file.
+const FILE_BUFFER: Duration = Duration::from_secs(1);
+/// How long the file reader waits for room in the buffer.
+const FILE_POLL: Duration = Duration::from_millis(20);
+/// Most audio the input callback expects at once, which its buffers are made to hold.
+const INPUT_CHUNK: Duration = Duration::from_millis(100);
+
+/// What the input callback does with the mic's audio, kept apart from the stream so it
+/// can be run without a device. Everything it needs is allocated up front, see [`crate::util::alloc`].
+struct InputPipeline {
+  device_channels: usize,
+  /// Channels we encode, the first of the device's.
+  channels: usize,
+  frame_size: usize,
+  resampler: Resampler,
+  /// The channels we encode, out of what the device gave us.
+  input: Vec<f32>,
+  resampled: Vec<f32>,
+  /// Resampled audio waiting for a whole frame.
+  buffer: VecDeque<f32>,
+  frame: Vec<f32>,
+  packet: Vec<u8>,
+  denoise: Option<NoiseSuppressor>,
+  encoder: Arc<Mutex<OpusEncoder>>,
+  bitrate: Arc<AtomicI32>,
+  max_bandwidth: Arc<AtomicU8>,
+  // the encoder may be new, so the bitrate and bandwidth are set on the first frame
+  applied_bitrate: Option<i32>,
+  applied_bandwidth: Option<u8>,
+  timings: Arc<StageTimings>,
+  tx: Sender<MicPacket>,
+  monitor: Arc<Mutex<Option<Sender<MicPacket>>>>,
+  vad: Arc<Mutex<Option<VoiceDetector>>>,
+  file: Arc<Mutex<Option<FilePlayback>>>,
+}
+
+impl InputPipeline {
+  /// Takes the device's audio as `(sample rate, channels)` and encodes it as `opus`, with nothing
+  /// else going on: no denoising, VAD, monitor or file, and the encoder's own bitrate and bandwidth.
+  fn new(device: (u32, usize), opus: (u32, usize), frame_size: usize, encoder: Arc<Mutex<OpusEncoder>>, tx: Sender<MicPacket>) -> Self {
+    let ((device_rate, device_channels), (opus_rate, channels)) = (device, opus);
+    // far more than any device hands over at once, so they never have to grow
+    let input = INPUT_CHUNK.as_millis() as usize * device_rate as usize / 1000 * channels;
+    let resampled = INPUT_CHUNK.as_millis() as usize * opus_rate as usize / 1000 * channels + channels;
+    Self {
+      device_channels,
+      channels,
+      frame_size,
+      resampler: Resampler::new(device_rate, opus_rate, channels),
+      input: Vec::with_capacity(input),
+      resampled: Vec::with_capacity(resampled),
+      buffer: VecDeque::with_capacity(frame_size + resampled),
+      frame: Vec::with_capacity(frame_size),
+      packet: vec![0; packets::MAX_VOICE_PAYLOAD],
+      denoise: None,
+      encoder,
+      bitrate: Arc::default(),
+      max_bandwidth: Arc::default(),
+      applied_bitrate: None,
+      applied_bandwidth: None,
+      timings: Arc::default(),
+      tx,
+      monitor: Arc::default(),
+      vad: Arc::default(),
+      file: Arc::default(),
+    }
+  }
+
+  /// Takes a chunk of interleaved audio from the device, sending every frame it completes.
+  fn process(&mut self, data: &[f32], realtime: &RealtimeScope) {
+    let channels = self.channels;
+    self.input.clear();
+    // keep the channels we encode, dropping the rest
+    self.input.extend(data.chunks_exact(self.device_channels).flat_map(|frame| &frame[..channels]));
+    let start = Instant::now();
+    self.resampled.clear();
+    self.resampler.process_into(&self.input, &mut self.resampled);
+    self.buffer.extend(&self.resampled);
+    self.timings.record(Stage::Resample, start.elapsed());
+    while self.buffer.len() >= self.frame_size {
+      self.frame.clear();
+      self.frame.extend(self.buffer.drain(..self.frame_size));
+      self.send_frame(realtime);
+    }
+  }
+
+  fn send_frame(&mut self, realtime: &RealtimeScope) {
+    let frame = &mut self.frame;
+    if let Some(denoise) = self.denoise.as_mut() {
+      let start = Instant::now();
+      denoise.process(frame);
+      self.timings.record(Stage::Denoise, start.elapsed());
+    }
+    // mixed in after denoising, which would mangle music
+    if let Ok(Some(file)) = self.file.try_lock().as_deref_mut() {
+      if file.replace_mic && file.playing() {
+        frame.fill(0.0);
+      }
+      for (sample, from_file) in frame.iter_mut().zip(std::iter::from_fn(|| file.consumer.pop())) {
+        *sample += from_file;
+      }
+    }
+    let level = audio_level(frame);
+    // if the app is changing the settings right now, send the frame rather than wait
+    if self.vad.try_lock().is_ok_and(|mut vad| vad.as_mut().is_some_and(|vad| !vad.is_voice(level))) {
+      return;
+    }
+    let mut encoder = match self.encoder.try_lock() {
+      Ok(encoder) => encoder,
+      Err(_) => return,
+    };
+    let target = self.bitrate.load(Ordering::Relaxed);
+    if self.applied_bitrate != Some(target) {
+      if let Err(e) = encoder.set_bitrate((target > 0).then_some(target)) {
+        warn!("Failed to set bitrate to {}: {}", target, e);
+      }
+      self.applied_bitrate = Some(target);
+    }
+    let target = self.max_bandwidth.load(Ordering::Relaxed);
+    if self.applied_bandwidth != Some(target) {
+      if let Err(e) = encoder.set_max_bandwidth(unpack_bandwidth(target)) {
+        warn!("Failed to set bandwidth to {:?}: {}", unpack_bandwidth(target), e);
+      }
+      self.applied_bandwidth = Some(target);
+    }
+    let start = Instant::now();
+    let encoded = encoder.encode_into(frame, &mut self.packet);
+    self.timings.record(Stage::Encode, start.elapsed());
+    match encoded {
+      // DTX says there's nothing worth sending
+      Ok(None) => {},
+      Ok(Some(size)) => {
+        // a packet for another thread needs memory of its own
+        let _handoff = realtime.suspend();
+        let packet = MicPacket { data: self.packet[..size].to_vec(), level };
+        if let Ok(Some(monitor)) = self.monitor.try_lock().as_deref() {
+          let _ = monitor.send(packet.clone());
+        }
+        if self.tx.send(packet).is_err() {
+          warn!("Dropped encoded packet: client is gone");
+        }
+      },
+      Err(e) => {
+        warn!("Failed to encode audio: {}", e);
+      }
+    }
+  }
+}
 
 pub struct MicService {
-  host: cpal::Host,
   device: cpal::Device,
   config: cpal::StreamConfig,
   stream: Option<cpal::Stream>,
-  latency: Latency,
 
   opus_rate: u32,
+  opus_config: OpusConfig,
+  stereo: bool,
+  /// Channels we encode, 2 when recording in stereo.
+  channels: usize,
   
   frame_size: usize,
-  tx: Arc<Mutex<Sender<Vec<u8>>>>,
-  encoder: Arc<Mutex<opus::Encoder>>,
-  buffer: Arc<Mutex<VecDeque<f32>>>,
+  /// How far ahead the encoder looks, see [`OpusEncoder::lookahead`].
+  lookahead: Duration,
+  /// Processing time of each stage, measured by the callback.
+  timings: Arc<StageTimings>,
+  tx: Sender<MicPacket>,
+  /// Also gets a copy of every packet, to hear ourselves as peers do.
+  monitor: Arc<Mutex<Option<Sender<MicPacket>>>>,
+  /// Frames it doesn't think are speech aren't sent at all.
+  vad: Arc<Mutex<Option<VoiceDetector>>>,
+  /// A file being played into the call, if any.
+  file: Arc<Mutex<Option<FilePlayback>>>,
+  thread: AudioThreadSettings,
+  noise_suppression: bool,
+  encoder: Arc<Mutex<OpusEncoder>>,
+  /// Bitrate for the encoder in bits per second, 0 to let opus pick.
+  /// Applied by the callback, so changing it never holds up a frame.
+  bitrate: Arc<AtomicI32>,
+  /// Widest band for the encoder, see [`pack_bandwidth`]. Applied like the bitrate.
+  max_bandwidth: Arc<AtomicU8>,
 }
 
 fn error(err: cpal::StreamError) {
@@ -32,41 +222,36 @@ impl MicService {
     MicServiceBuilder::new()
   }
 
-  pub fn latency(&self) -> Latency {
-    self.latency
-  }
-
+  /// Starts recording.
+  ///
+  /// The callback never blocks on a lock: state shared with the app is only
+  /// `try_lock`ed, and the encoder is only ever locked elsewhere while stopped.
   pub fn start(&mut self) -> Result<(), anyhow::Error> {
-    // let producer = self.producer.clone();
-    let encoder = self.encoder.clone();
-    let buffer = self.buffer.clone();
-    let frame_size = self.frame_size;
-    let tx = self.tx.clone();
-
-    let opus_rate = self.opus_rate;
-    let channels = self.config.channels as usize;
-    let real_rate = self.config.sample_rate.0;
+    let mut pipeline = InputPipeline {
+      denoise: self.noise_suppression.then(NoiseSuppressor::new),
+      bitrate: self.bitrate.clone(),
+      max_bandwidth: self.max_bandwidth.clone(),
+      timings: self.timings.clone(),
+      monitor: self.monitor.clone(),
+      vad: self.vad.clone(),
+      file: self.file.clone(),
+      ..InputPipeline::new(
+        (self.config.sample_rate.0, self.config.channels as usize),
+        (self.opus_rate, self.channels),
+        self.frame_size,
+        self.encoder.clone(),
+        self.tx.clone(),
+      )
+    };
+    let thread = self.thread;
+    let mut thread_ready = false;
     self.stream = Some(self.device.build_input_stream(&self.config, move |data: &[f32], _: &cpal::InputCallbackInfo| {
-      let mut buffer = buffer.lock().unwrap();
-      for sample in data.iter().step_by(channels as usize) {
-        buffer.push_back(*sample);
-      }
-      if buffer.len() >= frame_size {
-        let mut encoder = encoder.lock().unwrap();
-        let mut input = buffer.drain(..frame_size).collect::<Vec<f32>>();
-        if opus_rate != real_rate {
-          input = resample_audio(&input, real_rate, opus_rate);
-        }
-        match encoder.encode_vec_float(&input, packets::PACKET_MAX_SIZE/2) {
-          Ok(packet) => {
-            let tx = tx.lock().unwrap();
-            tx.send(packet);
-          },
-          Err(e) => {
-            warn!("Failed to encode audio: {}", e);
-          }
-        }
+      let realtime = RealtimeScope::enter("Input");
+      if !thread_ready {
+        thread.apply("Input");
+        thread_ready = true;
       }
+      pipeline.process(data, &realtime);
     }, error)?);
     self.stream.as_ref().unwrap().play()?;
     Ok(())
@@ -75,82 +260,347 @@ impl MicService {
   pub fn stop(&mut self) {
     drop(self.stream.take());
   }
-}
 
+  pub fn is_running(&self) -> bool {
+    self.stream.is_some()
+  }
 
+  /// Stops sending silence, or goes back to sending every frame with `None`.
+  pub fn set_vad(&self, vad: Option<VoiceDetector>) {
+    *self.vad.lock().unwrap() = vad;
+  }
 
-pub struct MicServiceBuilder {
-  host: cpal::Host,
-  device: Option<cpal::Device>,
-  latency_ms: f32,
-}
+  /// Bitrate to encode at in bits per second, or `None` to let opus pick. Takes effect from the next frame.
+  pub fn set_bitrate(&self, bitrate: Option<i32>) {
+    self.bitrate.store(bitrate.unwrap_or(0), Ordering::Relaxed);
+  }
 
-impl MicServiceBuilder {
-  pub fn new() -> Self {
-    Self { host: cpal::default_host(), device: None, latency_ms: 150.0 }
+  /// Widest band to encode, or `None` to let opus pick. Takes effect from the next frame.
+  pub fn set_max_bandwidth(&self, max_bandwidth: Option<Bandwidth>) {
+    self.max_bandwidth.store(pack_bandwidth(max_bandwidth), Ordering::Relaxed);
   }
-  pub fn with_latency(mut self, latency_ms: f32) -> Self {
-    self.latency_ms = latency_ms;
-    self
+
+  /// Encodes at what the controller picked for the network, keeping to a narrower band if the config asks for one.
+  pub fn follow_controller(&self, controller: &BitrateController) {
+    self.set_bitrate(Some(controller.bitrate()));
+    let widest = self.opus_config.max_bandwidth.unwrap_or(Bandwidth::Fullband);
+    self.set_max_bandwidth(Some(controller.bandwidth().min(widest)));
   }
-  pub fn build(self) -> Result<(MicService, Receiver<Vec<u8>>), anyhow::Error> {
-    let device = self.device.unwrap_or(
-      self.host.default_input_device().ok_or_else(|| anyhow!("no input device available"))?
-    );
-    info!("Input device: {:?}", device.name()?);
-    let config: cpal::StreamConfig = match device.supported_input_configs() {
-      Result::Ok(configs) => {
-        let mut out = None;
-        for config in configs {
-          if out.is_some() { break; }
-          for rate in OPUS_SAMPLE_RATES {
-            if config.max_sample_rate().0 >= rate && config.min_sample_rate().0 <= rate {
-              out = Some(config.with_sample_rate(cpal::SampleRate(rate)).into());
+
+  /// Switches to another encoder config, restarting the stream if it was running.
+  /// The bitrate and bandwidth go back to the config's.
+  pub fn set_opus_config(&mut self, opus_config: OpusConfig) -> Result<(), anyhow::Error> {
+    let running = self.stream.is_some();
+    self.stop();
+    let mut encoder = OpusEncoder::new(self.opus_rate, self.channels, opus_config)?;
+    self.frame_size = encoder.frame_size();
+    self.lookahead = encoder.lookahead()?;
+    *self.encoder.lock().unwrap() = encoder;
+    self.opus_config = opus_config;
+    self.set_bitrate(opus_config.bitrate);
+    self.set_max_bandwidth(opus_config.max_bandwidth);
+    if running {
+      self.start()?;
+    }
+    Ok(())
+  }
+
+  /// Turns noise suppression on or off, restarting the stream if it was running.
+  pub fn set_noise_suppression(&mut self, noise_suppression: bool) -> Result<(), anyhow::Error> {
+    if noise_suppression == self.noise_suppression {return Ok(());}
+    let running = self.stream.is_some();
+    self.stop();
+    self.noise_suppression = noise_suppression;
+    if running {
+      self.start()?;
+    }
+    Ok(())
+  }
+
+  /// Plays a WAV or Ogg Vorbis file into the call, along with the mic or, with `replace_mic`, instead of it.
+  /// Replaces any file already playing.
+  pub fn play_file(&self, path: impl AsRef<Path>, replace_mic: bool) -> Result<(), anyhow::Error> {
+    let mut source = FileSource::open(path, self.opus_rate, self.channels)?;
+    let buffer = (FILE_BUFFER.as_secs_f64() * self.opus_rate as f64) as usize * self.channels;
+    let (mut producer, consumer) = RingBuffer::new(buffer).split();
+    let done = Arc::new(AtomicBool::new(false));
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+      let done = done.clone();
+      let stop = stop.clone();
+      // decoding can be slow and allocates, so it's kept off the mic's thread
+      std::thread::spawn(move || {
+        let mut chunk = vec![0.0; buffer / 4];
+        while !stop.load(Ordering::Relaxed) {
+          if producer.remaining() < chunk.len() {
+            std::thread::sleep(FILE_POLL);
+            continue;
+          }
+          match source.read(&mut chunk) {
+            Ok(read) => {
+              producer.push_slice(&chunk[..read]);
+              if source.is_finished() {break;}
+            },
+            Err(e) => {
+              warn!("Stopped playing file: {}", e);
               break;
-            }
+            },
           }
         }
-        out
-      }
-      Err(_) => None
-    }.unwrap_or(device.default_input_config()?.into());
+        done.store(true, Ordering::Relaxed);
+      });
+    }
+    *self.file.lock().unwrap() = Some(FilePlayback { consumer, replace_mic, done, stop });
+    Ok(())
+  }
+
+  /// Stops playing a file, see [`MicService::play_file`].
+  pub fn stop_file(&self) {
+    self.file.lock().unwrap().take();
+  }
 
-    let latency = Latency::new(self.latency_ms, config.sample_rate.0, config.channels);
-    
-    info!("Input:");
-    info!(" - Channels: {}", config.channels);
-    info!(" - Sample Rate: {}", config.sample_rate.0);
+  /// Whether a file is still playing.
+  pub fn is_playing_file(&self) -> bool {
+    self.file.lock().unwrap().as_ref().is_some_and(FilePlayback::playing)
+  }
 
-    let ring = RingBuffer::new(latency.samples() * 2);
-    let (mut producer, consumer) = ring.split();
-    for _ in 0..latency.samples() {
-      producer.push(0).unwrap();
+  /// What each stage between the mic and the network adds to the delay, in the order audio goes through them.
+  pub fn latency(&self) -> Vec<StageLatency> {
+    let mut stages = Vec::new();
+    if self.config.sample_rate.0 != self.opus_rate {
+      // interpolating holds on to the last frame of each chunk for the next
+      stages.push(self.timings.latency(Stage::Resample, Duration::from_secs_f64(1.0 / self.config.sample_rate.0 as f64)));
+    }
+    if self.noise_suppression {
+      stages.push(self.timings.latency(Stage::Denoise, Duration::ZERO));
     }
+    stages.push(self.timings.latency(Stage::Framing, self.opus_config.frame_duration));
+    stages.push(self.timings.latency(Stage::Encode, self.lookahead));
+    stages
+  }
+
+  /// Sends a copy of every encoded packet to `tx` as well, or stops doing so.
+  pub fn set_monitor(&self, tx: Option<Sender<MicPacket>>) {
+    *self.monitor.lock().unwrap() = tx;
+  }
+
+  /// Switches to another mic (or back to the default one), restarting the stream if it was running.
+  /// The encoder is kept unless the new mic needs a different opus rate or channel count.
+  pub fn set_device(&mut self, name: Option<String>) -> Result<(), anyhow::Error> {
+    let (device, config) = open_input(&cpal::default_host(), name.as_deref(), self.stereo)?;
+    let running = self.stream.is_some();
+    self.stop();
 
     let opus_rate = nearest_opus_rate(config.sample_rate.0).unwrap();
-    let frame_size = (config.sample_rate.0 * 20) as usize / 1000;
-    info!("Creating new OpusEncoder with frame size {} @ opus:{} hz (real:{} hz)", frame_size, opus_rate, config.sample_rate.0);
-    
-    if opus_rate != config.sample_rate.0 {
-      warn!("Audio Resampling enabled.");
+    let channels = encoded_channels(&config, self.stereo);
+    if opus_rate != self.opus_rate || channels != self.channels {
+      *self.encoder.lock().unwrap() = OpusEncoder::new(opus_rate, channels, self.opus_config)?;
+      // it was being read for the old format
+      if self.file.lock().unwrap().take().is_some() {
+        warn!("Stopped playing file: the new mic needs a different format");
+      }
     }
-    let encoder = opus::Encoder::new(opus_rate, opus::Channels::Mono, opus::Application::Voip)?;
+    self.opus_rate = opus_rate;
+    self.channels = channels;
+    let mut encoder = self.encoder.lock().unwrap();
+    self.frame_size = encoder.frame_size();
+    self.lookahead = encoder.lookahead()?;
+    drop(encoder);
+    self.device = device;
+    self.config = config;
+
+    if running {
+      self.start()?;
+    }
+    Ok(())
+  }
+}
+
+
+
+pub struct MicServiceBuilder {
+  host: cpal::Host,
+  device: Option<String>,
+  opus_config: OpusConfig,
+  vad: Option<VoiceDetector>,
+  thread: AudioThreadSettings,
+  noise_suppression: bool,
+  stereo: bool,
+}
+
+impl MicServiceBuilder {
+  pub fn new() -> Self {
+    Self { host: cpal::default_host(), device: None, opus_config: OpusConfig::default(), vad: None, thread: AudioThreadSettings::default(), noise_suppression: false, stereo: false }
+  }
+  /// Records from the input device with this name (see [`crate::list_devices`]) instead of the default one.
+  pub fn with_input_device(mut self, name: Option<String>) -> Self {
+    self.device = name;
+    self
+  }
+  /// Bitrate, complexity, DTX and frame length for the encoder.
+  pub fn with_opus_config(mut self, opus_config: OpusConfig) -> Self {
+    self.opus_config = opus_config;
+    self
+  }
+  /// Only sends frames the detector thinks are speech.
+  pub fn with_vad(mut self, vad: Option<VoiceDetector>) -> Self {
+    self.vad = vad;
+    self
+  }
+  /// Turns down steady background noise before encoding.
+  pub fn with_noise_suppression(mut self, noise_suppression: bool) -> Self {
+    self.noise_suppression = noise_suppression;
+    self
+  }
+  /// Records and encodes in stereo, if the mic has more than one channel.
+  pub fn with_stereo(mut self, stereo: bool) -> Self {
+    self.stereo = stereo;
+    self
+  }
+  /// Scheduling for the thread recording from the mic.
+  pub fn with_thread_settings(mut self, thread: AudioThreadSettings) -> Self {
+    self.thread = thread;
+    self
+  }
+  pub fn build(self) -> Result<(MicService, Receiver<MicPacket>), anyhow::Error> {
+    let (device, config) = open_input(&self.host, self.device.as_deref(), self.stereo)?;
+
+    let opus_rate = nearest_opus_rate(config.sample_rate.0).unwrap();
+    let channels = encoded_channels(&config, self.stereo);
+    let mut encoder = OpusEncoder::new(opus_rate, channels, self.opus_config)?;
+    let frame_size = encoder.frame_size();
+    let lookahead = encoder.lookahead()?;
 
     let (tx, rx) = std::sync::mpsc::channel();
 
     Ok((MicService {
-      host: self.host,
       device,
       config,
       stream: None,
-      latency,
 
       opus_rate,
+      opus_config: self.opus_config,
+      stereo: self.stereo,
+      channels,
 
-      tx: Arc::new(Mutex::new(tx)),
-      buffer: Arc::new(Mutex::new(VecDeque::new())),
+      tx,
+      monitor: Arc::new(Mutex::new(None)),
+      vad: Arc::new(Mutex::new(self.vad)),
+      file: Arc::new(Mutex::new(None)),
+      thread: self.thread,
+      noise_suppression: self.noise_suppression,
       encoder: Arc::new(Mutex::new(encoder)),
+      bitrate: Arc::new(AtomicI32::new(self.opus_config.bitrate.unwrap_or(0))),
+      max_bandwidth: Arc::new(AtomicU8::new(pack_bandwidth(self.opus_config.max_bandwidth))),
       frame_size,
+      lookahead,
+      timings: Arc::default(),
     }, rx))
   }
-}
\ No newline at end of file
+}
+
+/// A bandwidth as it's kept in an atomic, 0 for `None`.
+fn pack_bandwidth(bandwidth: Option<Bandwidth>) -> u8 {
+  bandwidth.map_or(0, |bandwidth| Bandwidth::ALL.iter().position(|b| *b == bandwidth).unwrap() as u8 + 1)
+}
+
+fn unpack_bandwidth(packed: u8) -> Option<Bandwidth> {
+  Bandwidth::ALL.get((packed as usize).checked_sub(1)?).copied()
+}
+
+/// Finds an input device, and the config closest to what opus wants.
+fn open_input(host: &cpal::Host, name: Option<&str>, stereo: bool) -> Result<(cpal::Device, cpal::StreamConfig), anyhow::Error> {
+  let device = match name {
+    Some(name) => find_input_device(host, name)?,
+    None => host.default_input_device().ok_or_else(|| anyhow!("no input device available"))?,
+  };
+  info!("Input device: {:?}", device.name()?);
+  let config: cpal::StreamConfig = match device.supported_input_configs() {
+    Result::Ok(configs) => {
+      let mut configs = configs.collect::<Vec<_>>();
+      // try multichannel configs first if we want stereo
+      configs.sort_by_key(|config| !(stereo && config.channels() >= 2));
+      let mut out = None;
+      for config in configs {
+        if out.is_some() { break; }
+        for rate in OPUS_SAMPLE_RATES {
+          if config.max_sample_rate().0 >= rate && config.min_sample_rate().0 <= rate {
+            out = Some(config.with_sample_rate(cpal::SampleRate(rate)).into());
+            break;
+          }
+        }
+      }
+      out
+    }
+    Err(_) => None
+  }.unwrap_or(device.default_input_config()?.into());
+
+  info!("Input:");
+  info!(" - Channels: {}", config.channels);
+  info!(" - Sample Rate: {}", config.sample_rate.0);
+  if let Some(opus_rate) = nearest_opus_rate(config.sample_rate.0).filter(|rate| *rate != config.sample_rate.0) {
+    info!("Resampling input from {} hz to {} hz", config.sample_rate.0, opus_rate);
+  }
+  Ok((device, config))
+}
+
+/// Stereo if we want it and the device has it, otherwise mono.
+fn encoded_channels(config: &cpal::StreamConfig, stereo: bool) -> usize {
+  if stereo && config.channels >= 2 { 2 } else { 1 }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::mpsc;
+
+  use super::*;
+
+  /// From a 44.1kHz stereo mic to 48kHz mono opus, denoised, and where its packets go.
+  fn pipeline() -> (InputPipeline, Receiver<MicPacket>) {
+    let encoder = OpusEncoder::new(48_000, 1, OpusConfig::default()).unwrap();
+    let frame_size = encoder.frame_size();
+    let (tx, rx) = mpsc::channel();
+    let pipeline = InputPipeline {
+      denoise: Some(NoiseSuppressor::new()),
+      ..InputPipeline::new((44_100, 2), (48_000, 1), frame_size, Arc::new(Mutex::new(encoder)), tx)
+    };
+    (pipeline, rx)
+  }
+
+  /// 10ms of a tone, as the mic's callback would get it.
+  fn chunk() -> Vec<f32> {
+    (0..441).flat_map(|i| [(i as f32 * 0.05).sin() * 0.5; 2]).collect()
+  }
+
+  #[test]
+  fn frames_and_encodes_the_mic() {
+    let (mut pipeline, rx) = pipeline();
+    let chunk = chunk();
+    {
+      let realtime = RealtimeScope::enter("Test");
+      for _ in 0..10 {
+        pipeline.process(&chunk, &realtime);
+      }
+    }
+    // 100ms in 20ms frames, the last short of the sample the resampler holds back
+    assert_eq!(rx.try_iter().count(), 4);
+  }
+
+  /// What the input callback does with the mic's audio mustn't allocate, short of handing over packets.
+  #[cfg(feature = "alloc-check")]
+  #[test]
+  fn framing_and_encoding_the_mic_doesnt_allocate() {
+    use crate::util::alloc::realtime_allocations;
+
+    let (mut pipeline, rx) = pipeline();
+    let chunk = chunk();
+    {
+      let realtime = RealtimeScope::enter("Test");
+      for _ in 0..50 {
+        pipeline.process(&chunk, &realtime);
+      }
+    }
+    assert_eq!(realtime_allocations(), 0);
+    assert!(rx.try_iter().count() > 0);
+  }
+}
@@ -0,0 +1,57 @@
+use std::{collections::HashMap, fmt::Write as _, path::Path};
+
+/// Names we've given peers ourselves, shown instead of the ones they chose.
+///
+/// Kept by the username a peer connects with, the closest thing to an identity
+/// the server gives us, so they carry over between sessions.
+#[derive(Debug, Default)]
+#[derive(Clone)]
+pub struct Nicknames {
+  names: HashMap<String, String>,
+}
+
+impl Nicknames {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Reads nicknames saved with [`Nicknames::save`], a `username<TAB>nickname` per line.
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+    let mut nicknames = Self::new();
+    for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+      if line.trim().is_empty() {continue;}
+      let (username, nickname) = line.split_once('\t')
+        .ok_or_else(|| anyhow::anyhow!("line {} isn't a username and nickname separated by a tab", number + 1))?;
+      nicknames.set(username, Some(nickname.to_string()));
+    }
+    Ok(nicknames)
+  }
+
+  pub fn save(&self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+    let mut names = self.names.iter().collect::<Vec<_>>();
+    names.sort();
+    let mut out = String::new();
+    for (username, nickname) in names {
+      writeln!(out, "{}\t{}", username, nickname)?;
+    }
+    std::fs::write(path, out)?;
+    Ok(())
+  }
+
+  /// Shows `username` as `nickname`, or as themselves again if `None`.
+  pub fn set(&mut self, username: &str, nickname: Option<String>) {
+    match nickname {
+      Some(nickname) => self.names.insert(username.to_string(), nickname),
+      None => self.names.remove(username),
+    };
+  }
+
+  pub fn get(&self, username: &str) -> Option<&str> {
+    self.names.get(username).map(String::as_str)
+  }
+
+  /// What to call `username`: their nickname if we gave them one.
+  pub fn display<'a>(&'a self, username: &'a str) -> &'a str {
+    self.get(username).unwrap_or(username)
+  }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use common::RoomPreset;
+
+use crate::{encoder::OpusConfig, vad::VoiceDetector};
+
+/// Settings a [`RoomPreset`] stands for.
+#[derive(Clone, Debug)]
+pub struct PresetSettings {
+  pub opus_config: OpusConfig,
+  /// Most audio to buffer for each peer, in milliseconds.
+  pub latency_ms: f32,
+  pub noise_suppression: bool,
+  pub vad: Option<VoiceDetector>,
+}
+
+impl PresetSettings {
+  pub fn of(preset: RoomPreset) -> Self {
+    match preset {
+      RoomPreset::Music => Self {
+        opus_config: OpusConfig::default()
+          .with_bitrate(Some(96_000))
+          .with_complexity(Some(10)),
+        latency_ms: 200.0,
+        // quiet passages aren't silence, and hiss is part of the recording
+        noise_suppression: false,
+        vad: None,
+      },
+      RoomPreset::LowLatency => Self {
+        opus_config: OpusConfig::default()
+          .with_fec(true)
+          .with_complexity(Some(5))
+          .with_frame_duration(Duration::from_millis(10)),
+        latency_ms: 40.0,
+        noise_suppression: false,
+        vad: Some(VoiceDetector::default()),
+      },
+      RoomPreset::Conference => Self {
+        opus_config: OpusConfig::default()
+          .with_fec(true)
+          .with_dtx(true)
+          .with_bitrate(Some(24_000)),
+        latency_ms: 120.0,
+        noise_suppression: true,
+        vad: Some(VoiceDetector::default()),
+      },
+    }
+  }
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use crate::vad::{VoiceDetector, DEFAULT_VAD_HANGOVER, DEFAULT_VAD_THRESHOLD};
+
+/// A named bundle of audio settings for a kind of setup, applied with [`crate::AppBuilder::with_profile`].
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct AudioProfile {
+  pub name: String,
+  /// Devices to use, `None` for the system default.
+  pub input_device: Option<String>,
+  pub output_device: Option<String>,
+  /// Most audio to buffer for each peer, in milliseconds.
+  pub latency_ms: f32,
+  pub noise_suppression: bool,
+  pub vad: Option<VoiceDetector>,
+  pub stereo: bool,
+}
+
+impl AudioProfile {
+  /// A mic close to the mouth and no speaker bleed, so we can keep latency low.
+  pub fn headset() -> Self {
+    Self {
+      name: "headset".to_string(),
+      input_device: None,
+      output_device: None,
+      latency_ms: 100.0,
+      noise_suppression: false,
+      vad: Some(VoiceDetector::default()),
+      stereo: false,
+    }
+  }
+
+  /// A distant mic that hears the room and the speakers, so only send clearly louder speech.
+  pub fn speakers() -> Self {
+    Self {
+      name: "speakers".to_string(),
+      input_device: None,
+      output_device: None,
+      latency_ms: 150.0,
+      noise_suppression: true,
+      vad: Some(VoiceDetector::new(DEFAULT_VAD_THRESHOLD - 10, DEFAULT_VAD_HANGOVER + Duration::from_millis(200))),
+      stereo: false,
+    }
+  }
+
+  /// A treated room and a good mic, send everything as captured.
+  pub fn studio() -> Self {
+    Self {
+      name: "studio".to_string(),
+      input_device: None,
+      output_device: None,
+      latency_ms: 80.0,
+      noise_suppression: false,
+      vad: None,
+      stereo: true,
+    }
+  }
+
+  pub fn presets() -> Vec<Self> {
+    vec![Self::headset(), Self::speakers(), Self::studio()]
+  }
+
+  /// A preset by name, ignoring case.
+  pub fn preset(name: &str) -> Option<Self> {
+    Self::presets().into_iter().find(|profile| profile.name.eq_ignore_ascii_case(name))
+  }
+}
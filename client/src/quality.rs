@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+/// Equipment impairment of the codec, Opus is close to transparent at voice bitrates.
+const CODEC_IMPAIRMENT: f32 = 0.0;
+/// How well the codec copes with random loss, Opus with concealment and FEC copes well.
+const LOSS_ROBUSTNESS: f32 = 20.0;
+
+/// Estimated quality of the audio from a peer.
+#[derive(Copy, Clone, Debug)]
+pub struct CallQuality {
+  /// Fraction of frames lost, 0 to 1.
+  pub loss: f32,
+  pub jitter: Duration,
+  /// Estimated mouth to ear delay.
+  pub delay: Duration,
+  /// Mean opinion score, from 1 (bad) to 4.5 (as good as it gets).
+  pub mos: f32,
+}
+
+impl CallQuality {
+  /// Scores a call with a simplified ITU-T G.107 E-model.
+  pub fn estimate(loss: f32, jitter: Duration, delay: Duration) -> Self {
+    // jitter costs about as much as twice the delay it causes
+    let delay_ms = (delay + jitter * 2).as_secs_f32() * 1000.0;
+    let delay_impairment = 0.024 * delay_ms + 0.11 * (delay_ms - 177.3).max(0.0);
+    let loss_pct = loss * 100.0;
+    let loss_impairment = CODEC_IMPAIRMENT + (95.0 - CODEC_IMPAIRMENT) * loss_pct / (loss_pct + LOSS_ROBUSTNESS);
+    let r = 93.2 - delay_impairment - loss_impairment;
+    Self {
+      loss,
+      jitter,
+      delay,
+      mos: r_to_mos(r),
+    }
+  }
+}
+
+fn r_to_mos(r: f32) -> f32 {
+  if r <= 0.0 {return 1.0;}
+  if r >= 100.0 {return 4.5;}
+  (1.0 + 0.035 * r + 7e-6 * r * (r - 60.0) * (100.0 - r)).clamp(1.0, 4.5)
+}
@@ -0,0 +1,87 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use uuid::Uuid;
+
+use crate::{decoder::DECODED_CHANNELS, timeline::SpeakingTimeline, util::wav::WavWriter};
+
+/// Records a call to WAV files: the mix as heard, and if asked, each peer on their own
+/// track. Speaking turns from the [`SpeakingTimeline`] are marked as cue points.
+pub(crate) struct Recorder {
+  path: PathBuf,
+  sample_rate: u32,
+  /// Stereo output, tapped from the output stream.
+  mix: WavWriter,
+  /// Peers' tracks, if recording them separately.
+  peers: Option<HashMap<Uuid, (PathBuf, WavWriter)>>,
+  started: Instant,
+  /// Where the speaking timeline was when recording started.
+  timeline_offset: Duration,
+}
+
+impl Recorder {
+  pub fn start(path: &Path, sample_rate: u32, per_peer: bool, timeline_offset: Duration) -> Result<Self, anyhow::Error> {
+    Ok(Self {
+      path: path.to_path_buf(),
+      sample_rate,
+      mix: WavWriter::create(path, sample_rate, 2)?,
+      peers: per_peer.then(HashMap::new),
+      started: Instant::now(),
+      timeline_offset,
+    })
+  }
+
+  /// Adds what the output stream played.
+  pub fn write_mix(&mut self, samples: &[f32]) -> Result<(), anyhow::Error> {
+    self.mix.write(samples)
+  }
+
+  /// Adds a decoded frame to `user`'s track, if recording peers separately.
+  pub fn write_peer(&mut self, user: Uuid, name: Option<&str>, frame: &[f32]) -> Result<(), anyhow::Error> {
+    let peers = match self.peers.as_mut() {
+      Some(peers) => peers,
+      None => return Ok(()),
+    };
+    let (_, track) = match peers.entry(user) {
+      std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+      std::collections::hash_map::Entry::Vacant(entry) => {
+        let path = track_path(&self.path, &name.map_or_else(|| user.to_string(), str::to_string));
+        let track = WavWriter::create(&path, self.sample_rate, DECODED_CHANNELS as u16)?;
+        entry.insert((path, track))
+      },
+    };
+    // peers only send while talking, so fill in the silence to keep tracks lined up with the mix
+    let due = (self.started.elapsed().as_secs_f64() * self.sample_rate as f64) as u64 * DECODED_CHANNELS as u64;
+    let behind = due.saturating_sub(track.samples() + frame.len() as u64);
+    if behind > 0 {
+      track.write_silence(behind as usize)?;
+    }
+    track.write(frame)
+  }
+
+  /// Marks speaking turns and closes every file, returning their paths.
+  pub fn finish(mut self, timeline: &SpeakingTimeline) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut peers = self.peers.take().unwrap_or_default();
+    for segment in timeline.segments().iter().filter(|s| s.start >= self.timeline_offset) {
+      let frame = ((segment.start - self.timeline_offset).as_secs_f64() * self.sample_rate as f64) as u32;
+      let label = timeline.name(segment.user).map_or_else(|| segment.user.to_string(), str::to_string);
+      if let Some((_, track)) = peers.get_mut(&segment.user) {
+        track.add_cue(frame, label.clone());
+      }
+      self.mix.add_cue(frame, label);
+    }
+    let mut paths = vec![self.path];
+    self.mix.finish()?;
+    for (_, (path, track)) in peers {
+      track.finish()?;
+      paths.push(path);
+    }
+    Ok(paths)
+  }
+}
+
+/// `call.wav` with `name` becomes `call-name.wav`, next to it.
+fn track_path(path: &Path, name: &str) -> PathBuf {
+  let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+  let name: String = name.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+  path.with_file_name(format!("{}-{}.wav", stem, name))
+}
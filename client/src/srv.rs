@@ -0,0 +1,152 @@
+//! Just enough DNS to find a server from its SRV records, so a domain can
+//! point at servers on any host and port without clients being told.
+//!
+//! Asks the nameservers in `/etc/resolv.conf` directly, as the standard
+//! library only resolves A and AAAA records. Where there's no such file,
+//! lookups find nothing and clients fall back to the host itself.
+
+use std::{net::{IpAddr, SocketAddr}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use anyhow::anyhow;
+
+/// Service and protocol a server's SRV records are published under.
+pub const SRV_PREFIX: &str = "_rustvoice._udp";
+/// How long to wait for each nameserver to answer.
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+/// Largest answer a nameserver sends over UDP, without us asking for more.
+const DNS_MAX_SIZE: usize = 512;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// Where an SRV record says a server is.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct SrvRecord {
+  /// lower is tried first
+  pub priority: u16,
+  /// share of connections among records of the same priority
+  pub weight: u16,
+  pub port: u16,
+  pub target: String,
+}
+
+/// Servers published for `domain`, in the order to try them.
+/// Empty if there are none, or no nameserver answered.
+pub fn lookup_srv(domain: &str) -> Vec<SrvRecord> {
+  let name = format!("{}.{}", SRV_PREFIX, domain.trim_end_matches('.'));
+  for nameserver in nameservers() {
+    match query(nameserver, &name) {
+      Ok(mut records) => {
+        // heavier first, instead of the weighted shuffle the RFC asks for, so
+        // every client tries the same order
+        records.sort_by_key(|record| (record.priority, u16::MAX - record.weight));
+        return records;
+      },
+      Err(e) => log::debug!("SRV lookup of {} through {} failed: {}", name, nameserver, e),
+    }
+  }
+  Vec::new()
+}
+
+fn nameservers() -> Vec<SocketAddr> {
+  let conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+  conf.lines()
+    .filter_map(|line| line.trim().strip_prefix("nameserver"))
+    // a scoped IPv6 nameserver like `fe80::1%eth0` can't be parsed without its scope, so it's skipped
+    .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+    .map(|ip| SocketAddr::new(ip, 53))
+    .collect()
+}
+
+fn query(nameserver: SocketAddr, name: &str) -> Result<Vec<SrvRecord>, anyhow::Error> {
+  let id = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos() as u16;
+  let mut request = Vec::with_capacity(DNS_MAX_SIZE);
+  request.extend_from_slice(&id.to_be_bytes());
+  // recursion desired, one question
+  request.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+  for label in name.split('.') {
+    if label.is_empty() || label.len() > 63 {
+      return Err(anyhow!("invalid domain name"));
+    }
+    request.push(label.len() as u8);
+    request.extend_from_slice(label.as_bytes());
+  }
+  request.push(0);
+  request.extend_from_slice(&TYPE_SRV.to_be_bytes());
+  request.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+  let socket = crate::client::bind_for(nameserver)?;
+  socket.connect(nameserver)?;
+  socket.set_read_timeout(Some(DNS_TIMEOUT))?;
+  socket.send(&request)?;
+  let mut buf = [0; DNS_MAX_SIZE];
+  loop {
+    let size = socket.recv(&mut buf)?;
+    // a late answer to an earlier question
+    if size < 12 || buf[..2] != id.to_be_bytes() {continue;}
+    return parse(&buf[..size]);
+  }
+}
+
+fn parse(answer: &[u8]) -> Result<Vec<SrvRecord>, anyhow::Error> {
+  let malformed = || anyhow!("malformed answer");
+  let u16_at = |at: usize| answer.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(malformed);
+  match answer[3] & 0x0f {
+    0 => {},
+    // no such name, which just means there are no records
+    3 => return Ok(Vec::new()),
+    code => return Err(anyhow!("nameserver answered with error {}", code)),
+  }
+  let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+  let mut at = 12;
+  for _ in 0..questions {
+    at = read_name(answer, at)?.1 + 4;
+  }
+  let mut records = Vec::new();
+  for _ in 0..answers {
+    at = read_name(answer, at)?.1;
+    let (kind, len) = (u16_at(at)?, u16_at(at + 8)? as usize);
+    let data = at + 10;
+    at = data + len;
+    if at > answer.len() {return Err(malformed());}
+    // CNAMEs the nameserver followed to get here
+    if kind != TYPE_SRV {continue;}
+    records.push(SrvRecord {
+      priority: u16_at(data)?,
+      weight: u16_at(data + 2)?,
+      port: u16_at(data + 4)?,
+      target: read_name(answer, data + 6)?.0,
+    });
+  }
+  // a target of `.` says there's no server here
+  records.retain(|record| !record.target.is_empty());
+  Ok(records)
+}
+
+/// Reads a name starting at `at`, following compression pointers,
+/// returning it and where the data after it starts.
+fn read_name(packet: &[u8], mut at: usize) -> Result<(String, usize), anyhow::Error> {
+  let mut labels = Vec::new();
+  let mut end = None;
+  // pointers only go backwards in a well formed packet, this stops loops in others
+  for _ in 0..packet.len() {
+    let len = *packet.get(at).ok_or_else(|| anyhow!("name runs past the end"))? as usize;
+    match len {
+      0 => {
+        let name = labels.join(".");
+        return Ok((name, end.unwrap_or(at + 1)));
+      },
+      len if len & 0xc0 == 0xc0 => {
+        let low = *packet.get(at + 1).ok_or_else(|| anyhow!("name runs past the end"))? as usize;
+        end.get_or_insert(at + 2);
+        at = (len & 0x3f) << 8 | low;
+      },
+      len => {
+        let label = packet.get(at + 1..at + 1 + len).ok_or_else(|| anyhow!("name runs past the end"))?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        at += 1 + len;
+      },
+    }
+  }
+  Err(anyhow!("name compression loops"))
+}
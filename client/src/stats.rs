@@ -0,0 +1,71 @@
+use std::{collections::HashMap, time::Duration};
+
+use common::packets::SeqNum;
+use uuid::Uuid;
+
+use crate::{encoder::Bandwidth, jitter::seq_diff};
+
+/// Jumps in sequence numbers bigger than this are the peer restarting, not loss.
+const MAX_GAP: i16 = 1000;
+
+/// How the connection to the server and each peer is doing, see [`crate::Client::network_stats`].
+#[derive(Clone, Debug, Default)]
+pub struct NetworkStats {
+  /// Smoothed round trip time to the server, once measured.
+  pub rtt: Option<Duration>,
+  pub pings_sent: u64,
+  pub pongs_received: u64,
+  pub peers: HashMap<Uuid, PeerStats>,
+}
+
+impl NetworkStats {
+  /// Fraction of pings the server never answered, 0 to 1.
+  pub fn ping_loss(&self) -> f32 {
+    if self.pings_sent == 0 {return 0.0;}
+    1.0 - (self.pongs_received as f32 / self.pings_sent as f32).min(1.0)
+  }
+}
+
+/// Voice packets from one peer, counted from the gaps in their sequence numbers.
+#[derive(Clone, Debug, Default)]
+pub struct PeerStats {
+  pub received: u64,
+  /// Packets skipped over and not (yet) arrived late.
+  pub lost: u64,
+  /// What their latest voice was encoded with, once we've read any, to see who's fallen back on a poor link.
+  pub bandwidth: Option<Bandwidth>,
+  /// Highest sequence number seen.
+  last_seq: Option<SeqNum>,
+}
+
+impl PeerStats {
+  /// Fraction of packets lost, 0 to 1.
+  pub fn loss(&self) -> f32 {
+    let expected = self.received + self.lost;
+    if expected == 0 {return 0.0;}
+    self.lost as f32 / expected as f32
+  }
+
+  pub(crate) fn record(&mut self, seq: SeqNum) {
+    self.received += 1;
+    let last = match self.last_seq {
+      Some(last) => last,
+      None => {
+        self.last_seq = Some(seq);
+        return;
+      }
+    };
+    let gap = seq_diff(seq, last);
+    if gap > MAX_GAP {
+      self.last_seq = Some(seq);
+    } else if gap > 0 {
+      self.lost += gap as u64 - 1;
+      self.last_seq = Some(seq);
+    } else if gap > -MAX_GAP {
+      // a late packet filling a gap we already counted
+      self.lost = self.lost.saturating_sub(1);
+    } else {
+      self.last_seq = Some(seq);
+    }
+  }
+}
@@ -0,0 +1,122 @@
+use std::{collections::HashMap, fmt::Write as _, io::Write, path::Path, time::{Duration, Instant}};
+
+use uuid::Uuid;
+
+/// Pauses shorter than this are breaths, not the end of a turn.
+const MERGE_GAP: Duration = Duration::from_millis(500);
+
+/// A stretch of time someone was talking, from the start of the call.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SpeakingSegment {
+  pub user: Uuid,
+  pub start: Duration,
+  pub end: Duration,
+}
+
+/// Who spoke when during a call, for reviewing it afterwards.
+#[derive(Clone, Debug)]
+pub struct SpeakingTimeline {
+  started: Instant,
+  segments: Vec<SpeakingSegment>,
+  /// Each user's latest segment, which may still grow.
+  latest: HashMap<Uuid, usize>,
+  names: HashMap<Uuid, String>,
+}
+
+impl SpeakingTimeline {
+  pub fn new() -> Self {
+    Self {
+      started: Instant::now(),
+      segments: Vec::new(),
+      latest: HashMap::new(),
+      names: HashMap::new(),
+    }
+  }
+
+  /// Shows `user` as `name` in [`SpeakingTimeline::render`] and saved timelines.
+  pub fn set_name(&mut self, user: Uuid, name: String) {
+    self.names.insert(user, name);
+  }
+
+  pub fn name(&self, user: Uuid) -> Option<&str> {
+    self.names.get(&user).map(String::as_str)
+  }
+
+  /// Notes that `user` was talking just now.
+  pub fn speaking(&mut self, user: Uuid) {
+    let now = self.started.elapsed();
+    if let Some(segment) = self.latest.get(&user).map(|index| &mut self.segments[*index]) {
+      if now.saturating_sub(segment.end) <= MERGE_GAP {
+        segment.end = now;
+        return;
+      }
+    }
+    self.latest.insert(user, self.segments.len());
+    self.segments.push(SpeakingSegment { user, start: now, end: now });
+  }
+
+  /// Every segment so far, in the order they started.
+  pub fn segments(&self) -> &[SpeakingSegment] {
+    &self.segments
+  }
+
+  /// How long since the call started.
+  pub fn elapsed(&self) -> Duration {
+    self.started.elapsed()
+  }
+
+  /// Users in the order they first spoke.
+  pub fn speakers(&self) -> Vec<Uuid> {
+    let mut speakers = Vec::new();
+    for segment in &self.segments {
+      if !speakers.contains(&segment.user) {
+        speakers.push(segment.user);
+      }
+    }
+    speakers
+  }
+
+  /// Draws the part of the call from `from` to `to` as a row per speaker, `width` characters wide.
+  /// Move the window to scroll through a long call.
+  pub fn render(&self, from: Duration, to: Duration, width: usize) -> String {
+    let width = width.max(1);
+    let span = to.saturating_sub(from).max(Duration::from_millis(1));
+    let column = |at: Duration| ((at.saturating_sub(from).as_secs_f64() / span.as_secs_f64()) * width as f64) as usize;
+    let speakers = self.speakers();
+    let label = |user: &Uuid| self.name(*user).map_or_else(|| user.to_string(), str::to_string);
+    let label_width = speakers.iter().map(|user| label(user).chars().count()).max().unwrap_or(0);
+    let mut out = String::new();
+    for user in &speakers {
+      let mut row = vec![' '; width];
+      for segment in self.segments.iter().filter(|s| s.user == *user && s.end >= from && s.start <= to) {
+        for cell in row.iter_mut().take((column(segment.end) + 1).min(width)).skip(column(segment.start)) {
+          *cell = '#';
+        }
+      }
+      writeln!(out, "{:>label_width$} |{}|", label(user), row.into_iter().collect::<String>()).unwrap();
+    }
+    writeln!(out, "{:>label_width$}  {:<half$}{:>half$}", "", format_time(from), format_time(to), half = width / 2).unwrap();
+    out
+  }
+
+  /// Writes the segments to a file, a line each: start and end in ms, the user's id, and their name if known.
+  pub fn save(&self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for segment in &self.segments {
+      writeln!(file, "{} {} {} {}", segment.start.as_millis(), segment.end.as_millis(), segment.user, self.name(segment.user).unwrap_or(""))?;
+    }
+    file.flush()?;
+    Ok(())
+  }
+}
+
+impl Default for SpeakingTimeline {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// `m:ss` from the start of the call.
+fn format_time(at: Duration) -> String {
+  format!("{}:{:02}", at.as_secs() / 60, at.as_secs() % 60)
+}
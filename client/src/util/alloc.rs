@@ -0,0 +1,106 @@
+//! Counts heap allocations made inside the realtime audio callbacks, which
+//! can stall them. Only active with the `alloc-check` feature, which replaces
+//! the global allocator. `cargo test --features alloc-check` fails if mixing
+//! voices for the output callback, or framing and encoding the mic's audio in
+//! the input callback, allocates.
+//!
+//! Handing an encoded packet to the client's thread does allocate, so the input
+//! callback steps off the realtime path for just that, see [`RealtimeScope::suspend`].
+
+#[cfg(feature = "alloc-check")]
+mod imp {
+  use std::{alloc::{GlobalAlloc, Layout, System}, cell::Cell, sync::atomic::{AtomicUsize, Ordering}};
+
+  thread_local! {
+    static IN_REALTIME: Cell<bool> = const { Cell::new(false) };
+  }
+
+  pub static REALTIME_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+  struct CountingAllocator;
+
+  unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+      // during thread teardown the flag may be gone already, which isn't realtime anyway
+      if IN_REALTIME.try_with(Cell::get).unwrap_or(false) {
+        REALTIME_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+      }
+      System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+      System.dealloc(ptr, layout)
+    }
+  }
+
+  #[global_allocator]
+  static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+  pub struct RealtimeScope {
+    before: usize,
+    name: &'static str,
+  }
+
+  impl RealtimeScope {
+    pub fn enter(name: &'static str) -> Self {
+      IN_REALTIME.with(|flag| flag.set(true));
+      Self { before: REALTIME_ALLOCATIONS.load(Ordering::Relaxed), name }
+    }
+  }
+
+  impl RealtimeScope {
+    pub fn suspend(&self) -> Suspended {
+      IN_REALTIME.with(|flag| flag.set(false));
+      Suspended
+    }
+  }
+
+  pub struct Suspended;
+
+  impl Drop for Suspended {
+    fn drop(&mut self) {
+      IN_REALTIME.with(|flag| flag.set(true));
+    }
+  }
+
+  impl Drop for RealtimeScope {
+    fn drop(&mut self) {
+      IN_REALTIME.with(|flag| flag.set(false));
+      // logging allocates, so only after leaving the scope, and only the first time
+      if self.before == 0 && REALTIME_ALLOCATIONS.load(Ordering::Relaxed) > 0 {
+        log::error!("{} callback allocated on the realtime path", self.name);
+      }
+    }
+  }
+}
+
+/// Marks the rest of a block as running on a realtime audio thread.
+/// [`RealtimeScope::suspend`] steps off it until the guard it returns is dropped,
+/// for what has to allocate and is known to.
+#[cfg(feature = "alloc-check")]
+pub use imp::RealtimeScope;
+
+#[cfg(not(feature = "alloc-check"))]
+pub struct RealtimeScope;
+
+#[cfg(not(feature = "alloc-check"))]
+pub struct Suspended;
+
+#[cfg(not(feature = "alloc-check"))]
+impl RealtimeScope {
+  pub fn enter(_name: &'static str) -> Self {
+    Self
+  }
+
+  pub fn suspend(&self) -> Suspended {
+    Suspended
+  }
+}
+
+/// Allocations made inside realtime audio callbacks since startup, always 0 without the `alloc-check` feature.
+pub fn realtime_allocations() -> usize {
+  #[cfg(feature = "alloc-check")]
+  return imp::REALTIME_ALLOCATIONS.load(std::sync::atomic::Ordering::Relaxed);
+  #[cfg(not(feature = "alloc-check"))]
+  0
+}
@@ -1,2 +1,8 @@
 pub mod opus;
-pub mod resampling;
\ No newline at end of file
+pub mod resampling;
+#[cfg(feature = "audio")]
+pub mod alloc;
+#[cfg(feature = "audio")]
+pub mod thread;
+#[cfg(feature = "audio")]
+pub mod wav;
@@ -1,3 +1,24 @@
+use std::time::Duration;
+
+/// Length of audio in each Opus packet, unless configured otherwise (see [`crate::OpusConfig`]).
+pub const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+/// Frame lengths we can encode, and so need to be able to decode.
+pub const FRAME_DURATIONS: [Duration; 4] = [
+  Duration::from_millis(10),
+  Duration::from_millis(20),
+  Duration::from_millis(40),
+  Duration::from_millis(60),
+];
+
+/// Longest frame a peer might send.
+pub const MAX_FRAME_DURATION: Duration = Duration::from_millis(60);
+
+/// Samples (of one channel) in `duration` at the given rate.
+pub fn samples_in(duration: Duration, sample_rate: u32) -> usize {
+  (sample_rate as u128 * duration.as_micros() / 1_000_000) as usize
+}
+
 pub const OPUS_SAMPLE_RATES: [u32; 5] = [
   48000,
   24000,
@@ -1,16 +1,51 @@
-use log::debug;
+/// Linear resampler for a continuous stream of interleaved samples.
+///
+/// The position between input frames carries over from one chunk to the
+/// next, so chunks join up without clicks and the long-run rate is exact.
+pub struct Resampler {
+  source_rate: u32,
+  dest_rate: u32,
+  channels: usize,
+  /// Position of the next output frame, in input frames from the start of the next chunk.
+  /// Between -1 and 0 it lies between the previous chunk's last frame and the next chunk's first.
+  pos: f64,
+  last: Vec<f32>,
+}
 
-pub fn resample_audio(source: &[f32], source_rate: u32, dest_rate: u32) -> Vec<f32> {
-  let dst_size = (source.len() as f32 * (dest_rate as f32 / source_rate as f32)) as usize;
-  let last_pos = source.len() - 1;
-  let mut dst = vec![0.0; dst_size];
-  for i in 0..dst_size {
-    let pos = ((i as u32 * source_rate) as f32 / dest_rate as f32);
-    let p1 = pos as usize;
-    let coef = pos - (p1 as f32);
-    let p2 = if p1 == last_pos { last_pos } else { p1 + 1 };
-    dst[i] = (1. - coef) * source[p1] + coef * source[p2];
+impl Resampler {
+  pub fn new(source_rate: u32, dest_rate: u32, channels: usize) -> Self {
+    Self { source_rate, dest_rate, channels, pos: 0.0, last: vec![0.0; channels] }
   }
-  // debug!("Resampled {} samples -> {} samples from {} hz -> {} hz", source.len(), dst_size, source_rate, dest_rate);
-  dst
-}
\ No newline at end of file
+
+  /// Takes whole frames of interleaved samples.
+  pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+    let frames = input.len() / self.channels.max(1);
+    let mut output = Vec::with_capacity(((frames as u64 * self.dest_rate as u64 / self.source_rate as u64) as usize + 1) * self.channels);
+    self.process_into(input, &mut output);
+    output
+  }
+
+  /// Like [`Resampler::process`], adding to the end of `output`, which only allocates if it's out of room.
+  pub fn process_into(&mut self, input: &[f32], output: &mut Vec<f32>) {
+    if self.source_rate == self.dest_rate || input.is_empty() {
+      output.extend_from_slice(input);
+      return;
+    }
+    let channels = self.channels;
+    let frames = input.len() / channels;
+    let step = self.source_rate as f64 / self.dest_rate as f64;
+    while self.pos < (frames - 1) as f64 {
+      let index = self.pos.floor();
+      let coef = (self.pos - index) as f32;
+      let next = (index + 1.0) as usize * channels;
+      for channel in 0..channels {
+        let a = if index < 0.0 { self.last[channel] } else { input[index as usize * channels + channel] };
+        let b = input[next + channel];
+        output.push(a + (b - a) * coef);
+      }
+      self.pos += step;
+    }
+    self.pos -= frames as f64;
+    self.last.copy_from_slice(&input[(frames - 1) * channels..frames * channels]);
+  }
+}
@@ -0,0 +1,52 @@
+use log::{info, warn};
+use thread_priority::ThreadPriority;
+
+/// How the threads running audio callbacks are scheduled.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AudioThreadSettings {
+  /// Ask the OS for realtime scheduling, so a loaded system doesn't starve the callbacks.
+  pub realtime: bool,
+  /// Pin the callbacks to this CPU core.
+  pub core: Option<usize>,
+}
+
+impl AudioThreadSettings {
+  /// Applies the settings to the calling thread, logging whether it worked.
+  ///
+  /// Failing isn't fatal, audio just runs at normal priority.
+  pub fn apply(&self, thread: &str) {
+    if self.realtime {
+      match promote_to_realtime() {
+        Ok(()) => info!("{} thread promoted to realtime priority", thread),
+        Err(e) => warn!("Couldn't promote {} thread to realtime priority: {}", thread, e),
+      }
+    }
+    if let Some(core) = self.core {
+      let pinned = core_affinity::get_core_ids()
+        .and_then(|ids| ids.into_iter().find(|id| id.id == core))
+        .is_some_and(core_affinity::set_for_current);
+      if pinned {
+        info!("{} thread pinned to core {}", thread, core);
+      } else {
+        warn!("Couldn't pin {} thread to core {}", thread, core);
+      }
+    }
+  }
+}
+
+#[cfg(unix)]
+fn promote_to_realtime() -> Result<(), thread_priority::Error> {
+  use thread_priority::unix::{
+    RealtimeThreadSchedulePolicy, ThreadSchedulePolicy, set_thread_priority_and_policy, thread_native_id,
+  };
+  set_thread_priority_and_policy(
+    thread_native_id(),
+    ThreadPriority::Max,
+    ThreadSchedulePolicy::Realtime(RealtimeThreadSchedulePolicy::Fifo),
+  )
+}
+
+#[cfg(not(unix))]
+fn promote_to_realtime() -> Result<(), thread_priority::Error> {
+  thread_priority::set_current_thread_priority(ThreadPriority::Max)
+}
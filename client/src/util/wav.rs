@@ -0,0 +1,135 @@
+use std::{fs::File, io::{BufWriter, Seek, SeekFrom, Write}, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+/// Writes 16 bit PCM WAV files, with optional cue points (markers most editors show) and a creation date.
+pub struct WavWriter {
+  file: BufWriter<File>,
+  samples: u64,
+  /// Markers as (frame, label).
+  cues: Vec<(u32, String)>,
+  /// Where the data chunk's size goes.
+  data_size_at: u64,
+}
+
+impl WavWriter {
+  pub fn create(path: &Path, sample_rate: u32, channels: u16) -> Result<Self, anyhow::Error> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let block_align = channels * 2;
+    file.write_all(b"RIFF")?;
+    // sizes are filled in by `finish`
+    file.write_all(&0u32.to_le_bytes())?;
+    file.write_all(b"WAVE")?;
+    file.write_all(b"fmt ")?;
+    file.write_all(&16u32.to_le_bytes())?;
+    file.write_all(&1u16.to_le_bytes())?;
+    file.write_all(&channels.to_le_bytes())?;
+    file.write_all(&sample_rate.to_le_bytes())?;
+    file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    file.write_all(&block_align.to_le_bytes())?;
+    file.write_all(&16u16.to_le_bytes())?;
+    write_chunk(&mut file, b"LIST", &info_list(&creation_date()))?;
+    file.write_all(b"data")?;
+    let data_size_at = file.stream_position()?;
+    file.write_all(&0u32.to_le_bytes())?;
+    Ok(Self { file, samples: 0, cues: Vec::new(), data_size_at })
+  }
+
+  /// Appends interleaved samples.
+  pub fn write(&mut self, samples: &[f32]) -> Result<(), anyhow::Error> {
+    for sample in samples {
+      let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+      self.file.write_all(&sample.to_le_bytes())?;
+    }
+    self.samples += samples.len() as u64;
+    Ok(())
+  }
+
+  /// Appends silence.
+  pub fn write_silence(&mut self, samples: usize) -> Result<(), anyhow::Error> {
+    for _ in 0..samples {
+      self.file.write_all(&0i16.to_le_bytes())?;
+    }
+    self.samples += samples as u64;
+    Ok(())
+  }
+
+  /// Samples (of all channels) written so far.
+  pub fn samples(&self) -> u64 {
+    self.samples
+  }
+
+  /// Marks a point in the recording, `frame` frames from the start.
+  pub fn add_cue(&mut self, frame: u32, label: String) {
+    self.cues.push((frame, label));
+  }
+
+  /// Writes the cue points and fills in the sizes.
+  pub fn finish(mut self) -> Result<(), anyhow::Error> {
+    if !self.cues.is_empty() {
+      let mut cue = (self.cues.len() as u32).to_le_bytes().to_vec();
+      let mut labels = b"adtl".to_vec();
+      for (id, (frame, label)) in self.cues.iter().enumerate() {
+        let id = id as u32 + 1;
+        cue.extend_from_slice(&id.to_le_bytes());
+        cue.extend_from_slice(&frame.to_le_bytes());
+        cue.extend_from_slice(b"data");
+        cue.extend_from_slice(&0u32.to_le_bytes());
+        cue.extend_from_slice(&0u32.to_le_bytes());
+        cue.extend_from_slice(&frame.to_le_bytes());
+        let mut labl = id.to_le_bytes().to_vec();
+        labl.extend_from_slice(label.as_bytes());
+        labl.push(0);
+        append_chunk(&mut labels, b"labl", &labl);
+      }
+      write_chunk(&mut self.file, b"cue ", &cue)?;
+      write_chunk(&mut self.file, b"LIST", &labels)?;
+    }
+    let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+    let end = file.stream_position()?;
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&((end - 8) as u32).to_le_bytes())?;
+    // samples are 16 bit, so the data never needs padding
+    file.seek(SeekFrom::Start(self.data_size_at))?;
+    file.write_all(&((self.samples * 2) as u32).to_le_bytes())?;
+    Ok(())
+  }
+}
+
+fn write_chunk(file: &mut impl Write, id: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+  file.write_all(id)?;
+  file.write_all(&(data.len() as u32).to_le_bytes())?;
+  file.write_all(data)?;
+  if data.len() % 2 == 1 {
+    file.write_all(&[0])?;
+  }
+  Ok(())
+}
+
+fn append_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+  // can't fail writing to a vec
+  write_chunk(out, id, data).unwrap();
+}
+
+/// A LIST chunk with the creation date, as editors expect it.
+fn info_list(date: &str) -> Vec<u8> {
+  let mut list = b"INFO".to_vec();
+  let mut icrd = date.as_bytes().to_vec();
+  icrd.push(0);
+  append_chunk(&mut list, b"ICRD", &icrd);
+  list
+}
+
+/// Today's date in UTC, as `YYYY-MM-DD`.
+fn creation_date() -> String {
+  let days = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 86400) as i64;
+  // days since 1970-01-01 to a civil date, from Howard Hinnant's `civil_from_days`
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z - era * 146097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  format!("{:04}-{:02}-{:02}", year, month, day)
+}
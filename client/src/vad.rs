@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use crate::util::opus::FRAME_DURATION;
+
+/// Quietest level treated as speech by default, in -dBov.
+pub const DEFAULT_VAD_THRESHOLD: u8 = 50;
+/// How long to keep sending after speech stops by default, so word endings aren't clipped.
+pub const DEFAULT_VAD_HANGOVER: Duration = Duration::from_millis(300);
+
+/// Energy based voice activity detection, to stop sending frames of silence.
+#[derive(Clone, Debug)]
+pub struct VoiceDetector {
+  /// Quietest level that counts as speech, in -dBov.
+  threshold: u8,
+  /// Frames to keep sending once the level drops below the threshold.
+  hangover: usize,
+  remaining: usize,
+}
+
+impl VoiceDetector {
+  pub fn new(threshold: u8, hangover: Duration) -> Self {
+    Self {
+      threshold,
+      hangover: (hangover.as_secs_f64() / FRAME_DURATION.as_secs_f64()).ceil() as usize,
+      remaining: 0,
+    }
+  }
+
+  /// Takes the level of a frame in -dBov (as from [`crate::audio_level`]) and decides whether to send it.
+  pub fn is_voice(&mut self, level: u8) -> bool {
+    if level <= self.threshold {
+      self.remaining = self.hangover;
+      return true;
+    }
+    if self.remaining > 0 {
+      self.remaining -= 1;
+      return true;
+    }
+    false
+  }
+}
+
+impl Default for VoiceDetector {
+  fn default() -> Self {
+    Self::new(DEFAULT_VAD_THRESHOLD, DEFAULT_VAD_HANGOVER)
+  }
+}
@@ -1,17 +1,38 @@
-use std::sync::Arc;
+//! Playing peers' voices, each from the buffer their decoded audio is pushed into.
+//!
+//! All voices go through one [`VoiceMixer`], which sums them itself rather than
+//! leaving it to kira, so that the order they're added up in (and so how the
+//! floats round) doesn't depend on when each peer joined. For each output frame:
+//!
+//! 1. each voice interpolates its next frame at its playback rate, then is scaled
+//!    by ducking, gain and volume, all in `f32`, or is exactly zero while muted
+//! 2. voices are summed in `f32`, starting from zero, in ascending order of peer id
+//! 3. kira applies the main track's volume and the backend maps the result to
+//!    the device's channels, neither of which depend on order
+//!
+//! The same audio from the same peers is mixed to the same bits every time, so
+//! recordings of the mix can be reproduced.
+
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}};
 
 use kira::{Volume, sound::{Sound, SoundData}, dsp::Frame, track::TrackId, tween::Tweener};
-use ringbuf::Consumer;
+use ringbuf::{Consumer, Producer, RingBuffer};
+use uuid::Uuid;
+
+/// Amplitude a ducked voice is played at.
+const DUCKED_AMPLITUDE: f32 = 0.2;
+/// Voices the mixer has room for without allocating on the audio thread.
+const MIXER_CAPACITY: usize = 64;
 
 pub struct VoiceSoundSettings {
   pub volume: Volume,
-  pub track: TrackId,
-  pub pitch: f64,
+  /// Speed the voice is played back at, which also shifts its pitch.
+  pub playback_rate: f64,
 }
 
 impl Default for VoiceSoundSettings {
   fn default() -> Self {
-    Self { volume: Volume::Amplitude(1.0), track: TrackId::Main, pitch: 1.0 }
+    Self { volume: Volume::Amplitude(1.0), playback_rate: 1.0 }
   }
 }
 
@@ -26,61 +47,322 @@ impl VoiceSoundData {
   }
 
   pub(crate) fn split(self) -> Result<(VoiceSound, VoiceSoundHandle), anyhow::Error> {
+    let shared = Arc::new(Shared {
+      playback_rate: AtomicU64::new(self.settings.playback_rate.to_bits()),
+      ducked: AtomicBool::new(false),
+      gain: AtomicU32::new(1.0f32.to_bits()),
+      muted: AtomicBool::new(false),
+    });
     let sound = VoiceSound {
       volume: Tweener::new(self.settings.volume),
-      pitch: self.settings.pitch,
       consumer: self.consumer,
-      shared: Arc::new(Shared {
-      }),
-      time: 0.0,
+      shared: shared.clone(),
+      position: 0.0,
+      previous: Frame::ZERO,
+      current: Frame::ZERO,
     };
-    let handle = VoiceSoundHandle {};
+    let handle = VoiceSoundHandle { shared };
     Ok((sound, handle))
   }
 }
 
-impl SoundData for VoiceSoundData {
-  type Error = anyhow::Error;
-  type Handle = VoiceSoundHandle;
-  
-  fn into_sound(self) -> Result<(Box<dyn Sound>, Self::Handle), Self::Error> {
-      let (sound, handle) = self.split()?;
-      Ok((Box::new(sound), handle))
-  }
+pub struct VoiceSoundHandle {
+  shared: Arc<Shared>,
 }
 
-pub struct VoiceSoundHandle {
+impl VoiceSoundHandle {
+  pub fn playback_rate(&self) -> f64 {
+    self.shared.playback_rate()
+  }
 
-}
+  /// Plays the sound at `playback_rate` times its speed, resampling it, so its pitch
+  /// changes by the same factor.
+  pub fn set_playback_rate(&self, playback_rate: f64) {
+    self.shared.playback_rate.store(playback_rate.to_bits(), Ordering::Relaxed);
+  }
+
+  pub fn gain(&self) -> f32 {
+    self.shared.gain()
+  }
+
+  /// Scales the voice's amplitude, on top of its settings' volume.
+  pub fn set_gain(&self, gain: f32) {
+    self.shared.gain.store(gain.to_bits(), Ordering::Relaxed);
+  }
 
+  pub fn muted(&self) -> bool {
+    self.shared.muted.load(Ordering::Relaxed)
+  }
+
+  pub fn set_muted(&self, muted: bool) {
+    self.shared.muted.store(muted, Ordering::Relaxed);
+  }
+
+  /// Pushes the voice into the background, e.g. while too many others are talking.
+  pub fn set_ducked(&self, ducked: bool) {
+    self.shared.ducked.store(ducked, Ordering::Relaxed);
+  }
+}
 
+/// State shared between a [`VoiceSound`] and its handle.
 pub(crate) struct Shared {
+  playback_rate: AtomicU64,
+  ducked: AtomicBool,
+  /// f32 bits
+  gain: AtomicU32,
+  muted: AtomicBool,
+}
 
+impl Shared {
+  fn playback_rate(&self) -> f64 {
+    f64::from_bits(self.playback_rate.load(Ordering::Relaxed))
+  }
+
+  fn gain(&self) -> f32 {
+    f32::from_bits(self.gain.load(Ordering::Relaxed))
+  }
 }
 
 pub(crate) struct VoiceSound {
-  time: f64,
   volume: Tweener<Volume>,
   shared: Arc<Shared>,
-  pitch: f64,
   consumer: Consumer<f32>,
+  /// How far we are between `previous` and `current`.
+  position: f64,
+  previous: Frame,
+  current: Frame,
+}
+
+impl VoiceSound {
+  fn next_frame(&mut self) -> Frame {
+    // step through the buffered samples at the playback rate, interpolating between them
+    self.position += self.shared.playback_rate();
+    while self.position >= 1.0 {
+      self.position -= 1.0;
+      self.previous = self.current;
+      // samples are interleaved stereo, only take whole frames so channels can't swap
+      self.current = if self.consumer.len() >= 2 {
+        Frame::new(self.consumer.pop().unwrap(), self.consumer.pop().unwrap())
+      } else {
+        Frame::ZERO
+      };
+    }
+    let mut frame = self.previous + (self.current - self.previous) * self.position as f32;
+    // keep consuming while muted, so unmuting doesn't play stale audio
+    if self.shared.muted.load(Ordering::Relaxed) {
+      return Frame::ZERO;
+    }
+    if self.shared.ducked.load(Ordering::Relaxed) {
+      frame *= DUCKED_AMPLITUDE;
+    }
+    frame * self.shared.gain() * self.volume.value().as_amplitude() as f32
+  }
+
+  /// Whether its handle is gone, so nobody can hear it any more.
+  fn is_orphaned(&self) -> bool {
+    Arc::strong_count(&self.shared) == 1
+  }
+}
+
+/// Plays every peer's voice as one sound, see the [module docs](self) for how they're mixed.
+pub(crate) struct VoiceMixer {
+  /// In ascending order of id.
+  voices: Vec<(Uuid, VoiceSound)>,
+  added: Consumer<(Uuid, VoiceSound)>,
+  /// Voices whose handles were dropped, sent back to be freed off the audio thread.
+  retired: Producer<VoiceSound>,
 }
 
-impl Sound for VoiceSound {
-  fn track(&mut self) -> kira::track::TrackId {
-    kira::track::TrackId::Main
+impl VoiceMixer {
+  pub fn new() -> (Self, VoiceMixerHandle) {
+    let (added_tx, added_rx) = RingBuffer::new(MIXER_CAPACITY).split();
+    let (retired_tx, retired_rx) = RingBuffer::new(MIXER_CAPACITY).split();
+    let mixer = Self { voices: Vec::with_capacity(MIXER_CAPACITY), added: added_rx, retired: retired_tx };
+    (mixer, VoiceMixerHandle { added: added_tx, retired: retired_rx })
   }
 
-  fn process(&mut self, dt: f64, clock_info_provider: &kira::clock::clock_info::ClockInfoProvider) -> kira::dsp::Frame {
-    self.time += dt;
-    if let Some(sample) = self.consumer.pop() {
-      Frame::from_mono(sample)
-    } else {
-      Frame::from_mono(0.0)
+  /// The next frame of every voice, added up.
+  pub fn next_frame(&mut self) -> Frame {
+    while let Some((id, voice)) = self.added.pop() {
+      let at = self.voices.partition_point(|(other, _)| *other <= id);
+      self.voices.insert(at, (id, voice));
     }
+    let mut mix = Frame::ZERO;
+    let mut i = 0;
+    while i < self.voices.len() {
+      if self.voices[i].1.is_orphaned() {
+        let (_, voice) = self.voices.remove(i);
+        // if the main thread is behind on freeing them, free it here rather than keep playing it
+        let _ = self.retired.push(voice);
+        continue;
+      }
+      mix += self.voices[i].1.next_frame();
+      i += 1;
+    }
+    mix
+  }
+}
+
+impl Sound for VoiceMixer {
+  fn track(&mut self) -> TrackId {
+    TrackId::Main
+  }
+
+  fn process(&mut self, _dt: f64, _clock_info_provider: &kira::clock::clock_info::ClockInfoProvider) -> Frame {
+    self.next_frame()
   }
 
   fn finished(&self) -> bool {
     false
   }
-}
\ No newline at end of file
+}
+
+/// Plays the mixer through kira.
+pub(crate) struct VoiceMixerData(pub VoiceMixer);
+
+impl SoundData for VoiceMixerData {
+  type Error = anyhow::Error;
+  type Handle = ();
+
+  fn into_sound(self) -> Result<(Box<dyn Sound>, Self::Handle), Self::Error> {
+    Ok((Box::new(self.0), ()))
+  }
+}
+
+/// Adds voices to a [`VoiceMixer`] from outside the audio thread.
+/// A voice is taken out of the mix once its [`VoiceSoundHandle`] is dropped.
+pub struct VoiceMixerHandle {
+  added: Producer<(Uuid, VoiceSound)>,
+  retired: Consumer<VoiceSound>,
+}
+
+impl VoiceMixerHandle {
+  pub fn add(&mut self, id: Uuid, data: VoiceSoundData) -> Result<VoiceSoundHandle, anyhow::Error> {
+    // free what the mixer is done with while we're here
+    while self.retired.pop().is_some() {}
+    let (sound, handle) = data.split()?;
+    self.added.push((id, sound)).map_err(|_| anyhow::anyhow!("Too many voices waiting to be mixed"))?;
+    Ok(handle)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A voice that will play `frames` of stereo samples.
+  fn voice(frames: &[(f32, f32)], settings: VoiceSoundSettings) -> VoiceSoundData {
+    let (mut producer, consumer) = RingBuffer::new(frames.len() * 2).split();
+    for (left, right) in frames {
+      producer.push(*left).unwrap();
+      producer.push(*right).unwrap();
+    }
+    VoiceSoundData::new(settings, consumer)
+  }
+
+  fn id(n: u128) -> Uuid {
+    Uuid::from_u128(n)
+  }
+
+  fn mix(mixer: &mut VoiceMixer, frames: usize) -> Vec<(f32, f32)> {
+    (0..frames).map(|_| mixer.next_frame()).map(|frame| (frame.left, frame.right)).collect()
+  }
+
+  #[test]
+  fn golden_mix() {
+    let (mut mixer, mut handle) = VoiceMixer::new();
+    let _a = handle.add(id(1), voice(&[(0.5, 0.25), (-0.5, 1.0)], VoiceSoundSettings::default())).unwrap();
+    let _b = handle.add(id(2), voice(&[(0.75, -0.125), (0.25, 0.25)], VoiceSoundSettings {
+      volume: Volume::Amplitude(0.5),
+      ..Default::default()
+    })).unwrap();
+    // a frame behind, as each voice interpolates towards the sample it just took
+    assert_eq!(mix(&mut mixer, 4), vec![(0.0, 0.0), (0.875, 0.1875), (-0.375, 1.125), (0.0, 0.0)]);
+  }
+
+  #[test]
+  fn sums_in_id_order() {
+    // 1e8 + 1 rounds back to 1e8 in f32, so the order these are added up in shows in the result
+    let voices = [(id(1), 1e8), (id(2), 1.0), (id(3), -1e8)];
+    for order in [[0, 1, 2], [2, 1, 0], [1, 2, 0], [0, 2, 1]] {
+      let (mut mixer, mut handle) = VoiceMixer::new();
+      let _handles = order.iter()
+        .map(|&i| handle.add(voices[i].0, voice(&[(voices[i].1, voices[i].1)], VoiceSoundSettings::default())).unwrap())
+        .collect::<Vec<_>>();
+      assert_eq!(mix(&mut mixer, 2), vec![(0.0, 0.0), (0.0, 0.0)], "added in order {:?}", order);
+    }
+  }
+
+  #[test]
+  fn interpolates_at_playback_rate() {
+    let (mut mixer, mut handle) = VoiceMixer::new();
+    let _voice = handle.add(id(1), voice(&[(1.0, -1.0), (0.0, 0.0)], VoiceSoundSettings {
+      playback_rate: 0.5,
+      ..Default::default()
+    })).unwrap();
+    // a sample is taken every other frame, with the frames between halfway from the last
+    assert_eq!(mix(&mut mixer, 6), vec![(0.0, 0.0), (0.0, 0.0), (0.5, -0.5), (1.0, -1.0), (0.5, -0.5), (0.0, 0.0)]);
+  }
+
+  #[test]
+  fn muted_and_ducked() {
+    let (mut mixer, mut handle) = VoiceMixer::new();
+    let frames = [(1.0, 1.0); 3];
+    let muted = handle.add(id(1), voice(&frames, VoiceSoundSettings::default())).unwrap();
+    let ducked = handle.add(id(2), voice(&frames, VoiceSoundSettings::default())).unwrap();
+    muted.set_muted(true);
+    ducked.set_ducked(true);
+    assert_eq!(mix(&mut mixer, 2), vec![(0.0, 0.0), (DUCKED_AMPLITUDE, DUCKED_AMPLITUDE)]);
+    // muted voices keep being consumed, so unmuting doesn't play stale audio
+    muted.set_muted(false);
+    ducked.set_ducked(false);
+    assert_eq!(mix(&mut mixer, 3), vec![(2.0, 2.0), (2.0, 2.0), (0.0, 0.0)]);
+  }
+
+  #[test]
+  fn dropped_voices_leave_the_mix() {
+    let (mut mixer, mut handle) = VoiceMixer::new();
+    let kept = handle.add(id(1), voice(&[(0.5, 0.5); 3], VoiceSoundSettings::default())).unwrap();
+    let dropped = handle.add(id(2), voice(&[(0.25, 0.25); 3], VoiceSoundSettings::default())).unwrap();
+    assert_eq!(mix(&mut mixer, 2), vec![(0.0, 0.0), (0.75, 0.75)]);
+    drop(dropped);
+    assert_eq!(mix(&mut mixer, 1), vec![(0.5, 0.5)]);
+    assert_eq!(mixer.voices.len(), 1);
+    drop(kept);
+  }
+
+  /// What the output callback does with decoded voices mustn't allocate, see [`crate::util::alloc`].
+  #[cfg(feature = "alloc-check")]
+  #[test]
+  fn mixing_decoded_voice_doesnt_allocate() {
+    use crate::{decoder::OpusDecoder, encoder::{OpusConfig, OpusEncoder}, util::alloc::{realtime_allocations, RealtimeScope}};
+
+    let mut encoder = OpusEncoder::new(48000, 2, OpusConfig::default()).unwrap();
+    let mut decoder = OpusDecoder::new(48000).unwrap();
+    let (mut mixer, mut handle) = VoiceMixer::new();
+    let mut voices = Vec::new();
+    for n in 0..4 {
+      let (mut producer, consumer) = RingBuffer::new(decoder.max_frame_size() * 8).split();
+      for frame in 0..4 {
+        let tone = (0..encoder.frame_size()).map(|i| ((i + frame * 960) as f32 * 0.05 * (n + 1) as f32).sin() * 0.5).collect::<Vec<_>>();
+        let packet = encoder.encode(&tone, 4000).unwrap().unwrap();
+        producer.push_slice(&decoder.decode(&packet).unwrap());
+      }
+      voices.push(handle.add(id(n as u128), VoiceSoundData::new(VoiceSoundSettings::default(), consumer)).unwrap());
+    }
+    voices[1].set_ducked(true);
+    voices[2].set_muted(true);
+    {
+      let _realtime = RealtimeScope::enter("Test");
+      // past the end of what was decoded, and with a voice leaving part way
+      for frame in 0..4 * 960 + 100 {
+        std::hint::black_box(mixer.next_frame());
+        if frame == 960 {
+          // its handle is freed here, the mixer only hands the voice back
+          drop(voices.pop());
+        }
+      }
+    }
+    assert_eq!(realtime_allocations(), 0);
+  }
+}
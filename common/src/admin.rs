@@ -0,0 +1,48 @@
+use std::net::SocketAddr;
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::{Role, UserInfo};
+
+/// What a server's operators can ask of it, with the server's admin token.
+#[derive(Clone)]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminCommand {
+  /// answered with a [`crate::packets::ServerMessage::AdminUsers`]
+  ListUsers,
+  Kick { user: Uuid },
+  /// stop relaying a user's voice, or start again
+  Mute { user: Uuid, muted: bool },
+  /// tell everyone on the server something
+  Announce { text: String },
+  /// stop listing a saved room once it's empty, see the server's `state_file`
+  ForgetRoom { room: String },
+  /// keep listing a room once it's empty and after a restart, see the server's `state_file`
+  SaveRoom { room: String },
+  /// make a user a speaker or audience, whatever they connect as from now on, see the server's `state_file`
+  SetRole { user: Uuid, role: Role },
+}
+
+/// A user as operators see them.
+#[derive(Clone)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminUserInfo {
+  pub info: UserInfo,
+  pub addr: SocketAddr,
+  pub room: Option<String>,
+  pub server_muted: bool,
+  pub downlink: DownlinkStats,
+}
+
+/// What the server failed to get through to a user, since they connected.
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownlinkStats {
+  /// voice packets dropped because their queue was full
+  pub queue_drops: u64,
+  /// packets the socket refused to send
+  pub send_failures: u64,
+  /// control messages sent again because they went unacknowledged
+  pub retransmits: u64,
+}
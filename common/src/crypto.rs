@@ -0,0 +1,330 @@
+//! Transport encryption between a client and the server.
+//!
+//! Each side sends an ephemeral X25519 public key during connect (see
+//! [`crate::packets::ClientMessage::Connect`]), from which both derive a
+//! ChaCha20-Poly1305 key per direction. Every later packet travels sealed
+//! under those keys, with a counter nonce so replays are dropped.
+//!
+//! Both sides also have a long-term [`Identity`], which names them across
+//! connections. The session keys mix in a secret agreed between each side's
+//! identity and the other's ephemeral key, so neither can use an identity it
+//! doesn't hold. Clients pin the server's identity, configured or trusted on
+//! first use, so a man in the middle can't stand in for the server without
+//! the client noticing.
+
+use std::sync::{Mutex, atomic::{AtomicU64, Ordering}};
+
+use chacha20poly1305::{aead::{Aead, KeyInit}, ChaCha20Poly1305, Key, Nonce};
+use rand_core::OsRng;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, ReusableSecret, SharedSecret, StaticSecret};
+
+pub type PublicKeyBytes = [u8; 32];
+
+/// How many packets back a late one can arrive and still be accepted.
+const REPLAY_WINDOW: u64 = 64;
+/// PBKDF2 rounds when stretching a server password, so guessing it from a credential is slow.
+const PASSWORD_ROUNDS: u32 = 100_000;
+
+/// Which end of the connection we are, so each side picks the right key for each direction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Side {
+  Client,
+  Server,
+}
+
+/// Our half of a key exchange.
+pub struct KeyExchange {
+  secret: ReusableSecret,
+  public: PublicKey,
+}
+
+impl KeyExchange {
+  pub fn new() -> Self {
+    let secret = ReusableSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    Self { secret, public }
+  }
+
+  pub fn public_key(&self) -> PublicKeyBytes {
+    self.public.to_bytes()
+  }
+
+  /// Agrees a secret with the other side's [`Identity`], that only its holder can agree too.
+  /// `None` if the key is one that agrees the same secret with everyone.
+  pub fn agree(&self, identity: PublicKeyBytes) -> Option<[u8; 32]> {
+    contributory(self.secret.diffie_hellman(&PublicKey::from(identity)))
+  }
+
+  /// Combines our secret with the other side's public key, and the secrets agreed
+  /// with long-term keys (see [`KeyExchange::agree`]): the client's identity's, then the server's.
+  pub fn finish(self, theirs: PublicKeyBytes, side: Side, agreed: &[[u8; 32]]) -> Session {
+    let ours = self.public.to_bytes();
+    let shared = self.secret.diffie_hellman(&PublicKey::from(theirs));
+    let (client, server) = match side {
+      Side::Client => (ours, theirs),
+      Side::Server => (theirs, ours),
+    };
+    let derive = |label: &[u8]| -> [u8; 32] {
+      let hash = Sha256::new()
+        .chain_update(label)
+        .chain_update(shared.as_bytes())
+        .chain_update(client)
+        .chain_update(server);
+      agreed.iter().fold(hash, |hash, secret| hash.chain_update(secret)).finalize().into()
+    };
+    let to_server = derive(b"rust-voice client to server");
+    let to_client = derive(b"rust-voice server to client");
+    let (send, recv) = match side {
+      Side::Client => (to_server, to_client),
+      Side::Server => (to_client, to_server),
+    };
+    let id = derive(b"rust-voice session id");
+    Session {
+      id: u64::from_le_bytes(id[..8].try_into().unwrap()),
+      send: ChaCha20Poly1305::new(Key::from_slice(&send)),
+      recv: ChaCha20Poly1305::new(Key::from_slice(&recv)),
+      counter: AtomicU64::new(0),
+      replay: Mutex::new(ReplayWindow::default()),
+    }
+  }
+}
+
+impl Default for KeyExchange {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// A long-term key, which a client or server is known by across connections.
+/// Keep [`Identity::to_bytes`] to be the same one next time.
+#[derive(Clone)]
+pub struct Identity {
+  secret: StaticSecret,
+}
+
+impl Identity {
+  pub fn generate() -> Self {
+    Self { secret: StaticSecret::random_from_rng(OsRng) }
+  }
+
+  pub fn from_bytes(secret: [u8; 32]) -> Self {
+    Self { secret: StaticSecret::from(secret) }
+  }
+
+  /// The secret half, to save and give to [`Identity::from_bytes`].
+  pub fn to_bytes(&self) -> [u8; 32] {
+    self.secret.to_bytes()
+  }
+
+  pub fn public_key(&self) -> PublicKeyBytes {
+    PublicKey::from(&self.secret).to_bytes()
+  }
+
+  /// The other side of [`KeyExchange::agree`], with their ephemeral key.
+  pub fn agree(&self, ephemeral: PublicKeyBytes) -> Option<[u8; 32]> {
+    contributory(self.secret.diffie_hellman(&PublicKey::from(ephemeral)))
+  }
+}
+
+impl std::fmt::Debug for Identity {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_tuple("Identity").field(&fingerprint(self.public_key())).finish()
+  }
+}
+
+/// A public key written out, for people and files.
+pub fn fingerprint(key: PublicKeyBytes) -> String {
+  key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Reads back a [`fingerprint`].
+pub fn parse_fingerprint(fingerprint: &str) -> Option<PublicKeyBytes> {
+  if fingerprint.len() != 64 || !fingerprint.is_ascii() {return None;}
+  let mut key = [0; 32];
+  for (byte, hex) in key.iter_mut().zip(fingerprint.as_bytes().chunks(2)) {
+    *byte = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+  }
+  Some(key)
+}
+
+fn contributory(shared: SharedSecret) -> Option<[u8; 32]> {
+  shared.was_contributory().then(|| shared.to_bytes())
+}
+
+/// Keys for one connection, in both directions.
+pub struct Session {
+  /// Public name for the session, so the server can find its keys after the client's address changes.
+  id: u64,
+  send: ChaCha20Poly1305,
+  recv: ChaCha20Poly1305,
+  /// Nonce of the next packet we seal.
+  counter: AtomicU64,
+  replay: Mutex<ReplayWindow>,
+}
+
+impl Session {
+  pub fn id(&self) -> u64 {
+    self.id
+  }
+
+  /// Encrypts a packet, returning the counter it needs to be opened with.
+  pub fn seal(&self, plaintext: &[u8]) -> (u64, Vec<u8>) {
+    let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+    let ciphertext = self.send.encrypt(&nonce(counter), plaintext).expect("packet too large to encrypt");
+    (counter, ciphertext)
+  }
+
+  /// Decrypts a packet from the other side, or `None` if it's forged, corrupt or a replay.
+  pub fn open(&self, counter: u64, ciphertext: &[u8]) -> Option<Vec<u8>> {
+    let plaintext = self.recv.decrypt(&nonce(counter), ciphertext).ok()?;
+    // only trust the counter once it's authenticated
+    self.replay.lock().unwrap().accept(counter).then_some(plaintext)
+  }
+}
+
+impl std::fmt::Debug for Session {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Session").field("id", &self.id).finish_non_exhaustive()
+  }
+}
+
+/// A server password, stretched with a slow KDF so that a credential made with it
+/// (see [`Password::credential`]) takes as long to guess from as the KDF.
+///
+/// The salt is fixed, so the server stretches its password once rather than on every connect.
+#[derive(Clone)]
+pub struct Password([u8; 32]);
+
+impl Password {
+  pub fn stretch(password: &str) -> Self {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), b"rust-voice password", PASSWORD_ROUNDS, &mut key);
+    Self(key)
+  }
+
+  /// Proof of the password for [`crate::packets::ClientMessage::Connect`], tied to our
+  /// public key so it can't be replayed with anyone else's (and the password isn't sent).
+  pub fn credential(&self, public_key: PublicKeyBytes) -> [u8; 32] {
+    self.mac(public_key).finalize().into_bytes().into()
+  }
+
+  /// Checks a [`Password::credential`], in time that doesn't depend on where it's wrong.
+  pub fn verify(&self, public_key: PublicKeyBytes, credential: &[u8; 32]) -> bool {
+    self.mac(public_key).verify_slice(credential).is_ok()
+  }
+
+  fn mac(&self, public_key: PublicKeyBytes) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.0).expect("HMAC takes any key size");
+    mac.update(&public_key);
+    mac
+  }
+}
+
+impl std::fmt::Debug for Password {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("Password(..)")
+  }
+}
+
+fn nonce(counter: u64) -> Nonce {
+  let mut nonce = [0; 12];
+  nonce[4..].copy_from_slice(&counter.to_le_bytes());
+  *Nonce::from_slice(&nonce)
+}
+
+/// Remembers which recent counters we've seen.
+#[derive(Default)]
+struct ReplayWindow {
+  highest: Option<u64>,
+  /// Bit `n` is set if we've seen `highest - n`.
+  seen: u64,
+}
+
+impl ReplayWindow {
+  fn accept(&mut self, counter: u64) -> bool {
+    let highest = match self.highest {
+      Some(highest) => highest,
+      None => {
+        self.highest = Some(counter);
+        self.seen = 1;
+        return true;
+      }
+    };
+    if counter > highest {
+      let shift = counter - highest;
+      self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+      self.seen |= 1;
+      self.highest = Some(counter);
+      return true;
+    }
+    let age = highest - counter;
+    if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+      return false;
+    }
+    self.seen |= 1 << age;
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Sessions for a client and server with the identities given, each claiming to be someone.
+  fn connect(client: &Identity, client_claims: PublicKeyBytes, server: &Identity, server_claims: PublicKeyBytes) -> (Session, Session) {
+    let (ours, theirs) = (KeyExchange::new(), KeyExchange::new());
+    let (our_key, their_key) = (ours.public_key(), theirs.public_key());
+    let client_agreed = [client.agree(their_key).unwrap(), ours.agree(server_claims).unwrap()];
+    let server_agreed = [theirs.agree(client_claims).unwrap(), server.agree(our_key).unwrap()];
+    (ours.finish(their_key, Side::Client, &client_agreed), theirs.finish(our_key, Side::Server, &server_agreed))
+  }
+
+  fn can_talk((client, server): (Session, Session)) -> bool {
+    let (counter, sealed) = client.seal(b"hello");
+    server.open(counter, &sealed).as_deref() == Some(&b"hello"[..])
+  }
+
+  #[test]
+  fn holders_of_their_identities_can_talk() {
+    let (client, server) = (Identity::generate(), Identity::generate());
+    assert!(can_talk(connect(&client, client.public_key(), &server, server.public_key())));
+  }
+
+  #[test]
+  fn someone_elses_identity_cant_be_used() {
+    let (client, server, someone) = (Identity::generate(), Identity::generate(), Identity::generate());
+    assert!(!can_talk(connect(&client, someone.public_key(), &server, server.public_key())));
+    // a man in the middle claiming to be the server the client pinned
+    assert!(!can_talk(connect(&client, client.public_key(), &someone, server.public_key())));
+  }
+
+  #[test]
+  fn identity_survives_saving() {
+    let identity = Identity::generate();
+    assert_eq!(Identity::from_bytes(identity.to_bytes()).public_key(), identity.public_key());
+  }
+
+  #[test]
+  fn fingerprints_read_back() {
+    let key = Identity::generate().public_key();
+    assert_eq!(parse_fingerprint(&fingerprint(key)), Some(key));
+    assert_eq!(parse_fingerprint("not a key"), None);
+  }
+
+  #[test]
+  fn credentials_prove_the_password() {
+    let password = Password::stretch("hunter2");
+    let (ours, someone_elses) = ([1; 32], [2; 32]);
+    let credential = password.credential(ours);
+    assert!(password.verify(ours, &credential));
+    assert!(!password.verify(someone_elses, &credential));
+    assert!(!Password::stretch("hunter3").verify(ours, &credential));
+  }
+
+  #[test]
+  fn low_order_keys_are_refused() {
+    assert_eq!(KeyExchange::new().agree([0; 32]), None);
+  }
+}
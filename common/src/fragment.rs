@@ -0,0 +1,207 @@
+//! Splitting messages too big for one datagram. Paths across the internet
+//! drop or fragment datagrams much past [`MTU`], so bigger messages go as
+//! numbered pieces and are put back together on the other end. Voice is
+//! kept small enough never to need this, see [`crate::packets::MAX_VOICE_PAYLOAD`].
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+
+/// Largest datagram we send.
+pub const MTU: usize = 1200;
+/// Most of a message carried by one fragment, leaving room for the fragment's own header.
+pub const FRAGMENT_DATA: usize = MTU - 32;
+/// Largest message that can be sent in fragments.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Most fragments a message can be split into.
+pub const MAX_FRAGMENTS: usize = MAX_MESSAGE_SIZE.div_ceil(FRAGMENT_DATA);
+/// How long to wait for the rest of a message before giving up on it.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most messages being put back together at once, per sender.
+const MAX_PENDING: usize = 8;
+
+/// Numbers the messages split by a sender, wrapping around.
+pub type FragmentId = u32;
+
+/// One piece of a message that didn't fit in a datagram.
+#[derive(Clone)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fragment {
+  pub id: FragmentId,
+  pub index: u16,
+  pub count: u16,
+  pub data: Vec<u8>,
+}
+
+/// Splits the bytes of a message into fragments numbered `id`, or `None` if it's too big to send at all.
+pub fn split(id: FragmentId, bytes: &[u8]) -> Option<Vec<Fragment>> {
+  if bytes.len() > MAX_MESSAGE_SIZE {return None;}
+  let count = bytes.len().div_ceil(FRAGMENT_DATA) as u16;
+  Some(bytes.chunks(FRAGMENT_DATA).enumerate().map(|(index, data)| Fragment {
+    id,
+    index: index as u16,
+    count,
+    data: data.to_vec(),
+  }).collect())
+}
+
+struct Pending {
+  parts: Vec<Option<Vec<u8>>>,
+  missing: usize,
+  started: Instant,
+}
+
+/// Puts fragments from one sender back together.
+#[derive(Default)]
+pub struct Reassembler {
+  pending: HashMap<FragmentId, Pending>,
+}
+
+impl Reassembler {
+  /// Takes in a fragment, returning the whole message once the last of it arrives.
+  pub fn add(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+    let count = fragment.count as usize;
+    if count == 0 || count > MAX_FRAGMENTS || fragment.index as usize >= count || fragment.data.len() > FRAGMENT_DATA {
+      return None;
+    }
+    if !self.pending.contains_key(&fragment.id) && self.pending.len() >= MAX_PENDING {
+      // whatever's been waiting longest has probably lost a piece
+      let oldest = self.pending.iter().min_by_key(|(_, pending)| pending.started).map(|(id, _)| *id);
+      self.pending.remove(&oldest?);
+    }
+    let pending = self.pending.entry(fragment.id).or_insert_with(|| Pending {
+      parts: vec![None; count],
+      missing: count,
+      started: Instant::now(),
+    });
+    // a different message reusing the id, after the first lost a piece
+    if pending.parts.len() != count {
+      *pending = Pending { parts: vec![None; count], missing: count, started: Instant::now() };
+    }
+    let part = &mut pending.parts[fragment.index as usize];
+    if part.is_none() {
+      *part = Some(fragment.data);
+      pending.missing -= 1;
+    }
+    if pending.missing > 0 {return None;}
+    let pending = self.pending.remove(&fragment.id)?;
+    Some(pending.parts.into_iter().flatten().flatten().collect())
+  }
+
+  /// Gives up on messages still missing pieces after [`REASSEMBLY_TIMEOUT`].
+  pub fn expire(&mut self) {
+    self.pending.retain(|_, pending| pending.started.elapsed() < REASSEMBLY_TIMEOUT);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::packets::ServerMessage;
+
+  use super::*;
+
+  /// Bytes that differ from one position to the next, so pieces out of place would show.
+  fn message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+  }
+
+  #[test]
+  fn reassembles_out_of_order() {
+    let bytes = message(FRAGMENT_DATA * 3 + 10);
+    let fragments = split(1, &bytes).unwrap();
+    assert_eq!(fragments.len(), 4);
+    let mut reassembler = Reassembler::default();
+    let (last, rest) = fragments.split_last().unwrap();
+    assert!(reassembler.add(last.clone()).is_none());
+    for fragment in rest.iter().rev() {
+      assert_eq!(reassembler.add(fragment.clone()).is_some(), fragment.index == 0);
+    }
+    assert!(reassembler.pending.is_empty());
+    let mut reassembler = Reassembler::default();
+    let mut whole = None;
+    for fragment in [&fragments[2], &fragments[0], &fragments[3], &fragments[1]] {
+      whole = reassembler.add(fragment.clone());
+    }
+    assert_eq!(whole, Some(bytes));
+  }
+
+  #[test]
+  fn duplicates_dont_count_twice() {
+    let bytes = message(FRAGMENT_DATA * 2);
+    let fragments = split(1, &bytes).unwrap();
+    let mut reassembler = Reassembler::default();
+    assert!(reassembler.add(fragments[0].clone()).is_none());
+    assert!(reassembler.add(fragments[0].clone()).is_none());
+    assert_eq!(reassembler.add(fragments[1].clone()), Some(bytes));
+  }
+
+  #[test]
+  fn a_reused_id_starts_over() {
+    let first = split(1, &message(FRAGMENT_DATA * 3)).unwrap();
+    let second = message(FRAGMENT_DATA + 1);
+    let mut reassembler = Reassembler::default();
+    assert!(reassembler.add(first[0].clone()).is_none());
+    let mut whole = None;
+    for fragment in split(1, &second).unwrap() {
+      whole = reassembler.add(fragment);
+    }
+    assert_eq!(whole, Some(second));
+    // the first message's piece went with it
+    assert!(reassembler.add(first[1].clone()).is_none());
+    assert_eq!(reassembler.pending[&1].missing, 2);
+  }
+
+  #[test]
+  fn the_oldest_goes_once_too_many_are_pending() {
+    let mut reassembler = Reassembler::default();
+    let fragments = (0..=MAX_PENDING as FragmentId).map(|id| split(id, &message(FRAGMENT_DATA * 2)).unwrap()).collect::<Vec<_>>();
+    for pieces in &fragments[..MAX_PENDING] {
+      assert!(reassembler.add(pieces[0].clone()).is_none());
+    }
+    reassembler.pending.get_mut(&0).unwrap().started -= Duration::from_secs(1);
+    assert!(reassembler.add(fragments[MAX_PENDING][0].clone()).is_none());
+    assert_eq!(reassembler.pending.len(), MAX_PENDING);
+    assert!(!reassembler.pending.contains_key(&0));
+    assert!(reassembler.add(fragments[0][1].clone()).is_none());
+    assert!(reassembler.add(fragments[MAX_PENDING][1].clone()).is_some());
+  }
+
+  #[test]
+  fn expire_gives_up_on_stale_messages() {
+    let mut reassembler = Reassembler::default();
+    for id in 0..2 {
+      reassembler.add(split(id, &message(FRAGMENT_DATA * 2)).unwrap().remove(0));
+    }
+    reassembler.pending.get_mut(&0).unwrap().started -= REASSEMBLY_TIMEOUT;
+    reassembler.expire();
+    assert_eq!(reassembler.pending.keys().collect::<Vec<_>>(), [&1]);
+  }
+
+  #[test]
+  fn malformed_fragments_are_ignored() {
+    let mut reassembler = Reassembler::default();
+    for (index, count, len) in [(0, 0, 1), (2, 2, 1), (0, MAX_FRAGMENTS as u16 + 1, 1), (0, 2, FRAGMENT_DATA + 1)] {
+      assert!(reassembler.add(Fragment { id: 1, index, count, data: vec![0; len] }).is_none());
+    }
+    assert!(reassembler.pending.is_empty());
+    assert!(split(1, &message(MAX_MESSAGE_SIZE + 1)).is_none());
+  }
+
+  #[test]
+  fn big_messages_go_in_datagrams_that_fit() {
+    let announcement = ServerMessage::Announcement { text: "a".repeat(5000) };
+    let datagrams = announcement.to_datagrams(7);
+    assert!(datagrams.len() > 1);
+    assert!(datagrams.iter().all(|datagram| datagram.len() <= MTU));
+    let mut reassembler = Reassembler::default();
+    let mut whole = None;
+    for datagram in datagrams.iter().rev() {
+      let fragment = match ServerMessage::from_bytes(datagram) {
+        Some(ServerMessage::Fragment(fragment)) => fragment,
+        other => panic!("expected a fragment, got {:?}", other),
+      };
+      whole = ServerMessage::reassemble(&mut reassembler, fragment);
+    }
+    assert!(matches!(whole, Some(ServerMessage::Announcement { text }) if text.len() == 5000));
+  }
+}
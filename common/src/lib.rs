@@ -1,4 +1,15 @@
+pub mod crypto;
+pub mod fragment;
 pub mod packets;
+pub mod reliable;
+pub mod trace;
+pub mod wire;
 
 mod user;
-pub use user::*;
\ No newline at end of file
+pub use user::*;
+
+mod room;
+pub use room::*;
+
+mod admin;
+pub use admin::*;
\ No newline at end of file
@@ -1,27 +1,133 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::UserInfo;
+use crate::{crypto::{PublicKeyBytes, Session}, fragment::{self, Fragment, FragmentId, Reassembler, MTU}, reliable::ReliableSeq, wire::{ClientVoice, ServerVoice}, UserInfo, UserState, Role, RoomInfo, JoinDefaults, AdminCommand, AdminUserInfo};
 
+/// Largest datagram we'll receive, anything we send is at most [`MTU`].
 pub const PACKET_MAX_SIZE: usize = 4000;
 
+/// Bumped whenever messages change in a way older peers can't parse.
+pub const PROTOCOL_VERSION: u16 = 20;
+/// Port servers listen on, and clients connect to, unless told otherwise.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Per-sender sequence number of a voice packet, wrapping around.
+pub type SeqNum = u16;
+
+/// Longest chat message, in bytes, so it always fits in a packet.
+pub const MAX_CHAT_LEN: usize = 1000;
+
+/// Longest username, in bytes.
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// Whether `username` can be connected with: something other than whitespace, at most
+/// [`MAX_USERNAME_LEN`] bytes, and nothing that would break a line or a log.
+pub fn is_valid_username(username: &str) -> bool {
+  !username.trim().is_empty() && username.len() <= MAX_USERNAME_LEN && !username.chars().any(char::is_control)
+}
+
+/// Largest encoded voice frame, so a voice packet always fits in one datagram.
+pub const MAX_VOICE_PAYLOAD: usize = 1000;
+
 #[derive(Clone)]
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
-  /// request to connect to a server
-  Connect { username: String },
+  /// request to connect to a server.
+  /// Must stay the first variant with `version` first, so any version can read it (see [`ClientMessage::connect_version`]).
+  /// `resume_token` is from a previous session's [`ServerMessage::Handshake`], to pick up where it left off.
+  /// `public_key` starts the key exchange for the session, see [`crate::crypto`].
+  /// `identity` is the client's [`crate::crypto::Identity`], which the key exchange proves it holds.
+  /// `credential` is needed if the server has a password, see [`crate::crypto::Password`].
+  Connect { version: u16, username: String, role: Role, resume_token: Option<u64>, public_key: PublicKeyBytes, identity: PublicKeyBytes, credential: Option<[u8; 32]> },
   Disconnect,
-  Ping,
-  /// send voice to the server
-  Voice { samples: Vec<u8> },
+  /// answered with a [`ServerMessage::Pong`] carrying the same `id`, to measure the round trip time
+  Ping { id: u32 },
+  /// send voice to the server, `level` is the frame's loudness in -dBov (0 loudest, 127 silent)
+  Voice { seq: SeqNum, samples: Vec<u8>, level: u8 },
+  /// answer to a [`ServerMessage::Challenge`], moving an existing session to the address this was sent from
+  Migrate { connection_id: u64, challenge: u64 },
+  /// move to a room, creating it if it doesn't exist
+  JoinRoom { room: String },
+  /// leave the current room, without joining another
+  LeaveRoom,
+  /// request a [`ServerMessage::RoomList`]
+  ListRooms,
+  /// flag a user for abuse, to be reviewed by the server's operators
+  Report { user: Uuid, reason: String },
+  /// share our mute/deafen state with everyone else
+  SetState(UserState),
+  /// request a [`ServerMessage::UserList`]
+  ListUsers,
+  /// text message to everyone in our room, at most [`MAX_CHAT_LEN`] bytes
+  Chat { text: String },
+  /// agree to the rules from the [`ServerMessage::Handshake`], after which our voice and chat are relayed
+  AcceptRules,
+  /// an operator's command, refused unless `token` is the server's admin token.
+  /// Answered with a [`ServerMessage::AdminResult`], or [`ServerMessage::AdminUsers`] for a listing.
+  Admin { token: String, command: AdminCommand },
+  /// a control message that must arrive, to be answered with an [`ClientMessage::Ack`] (see [`crate::reliable`])
+  Reliable { seq: ReliableSeq, message: Box<ClientMessage> },
+  /// we got the [`ServerMessage::Reliable`] numbered `seq`
+  Ack { seq: ReliableSeq },
+  /// any other message, encrypted for the session named by `session` (see [`crate::crypto::Session`])
+  Sealed { session: u64, counter: u64, payload: Vec<u8> },
+  /// a piece of a message too big for one datagram (see [`crate::fragment`])
+  Fragment(Fragment),
 }
 
 impl ClientMessage {
+  /// Voice has an encoding of its own, see [`crate::wire`].
   pub fn to_bytes(&self) -> Vec<u8> {
-    bincode::serialize(self).unwrap()
+    match self {
+      Self::Voice { seq, samples, level } => ClientVoice { seq: *seq, level: *level, samples }.encode(),
+      _ => bincode::serialize(self).unwrap(),
+    }
   }
   pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-    bincode::deserialize(bytes).ok()
+    match ClientVoice::decode(bytes) {
+      Some(voice) => Some(voice.into()),
+      None => bincode::deserialize(bytes).ok(),
+    }
+  }
+  /// The protocol version of a [`ClientMessage::Connect`], even from a version whose messages we can't otherwise parse.
+  pub fn connect_version(bytes: &[u8]) -> Option<u16> {
+    match bincode::deserialize::<(u32, u16)>(bytes) {
+      Ok((0, version)) => Some(version),
+      _ => None,
+    }
+  }
+  /// The message as datagrams to send, split into [`ClientMessage::Fragment`]s numbered `id` if it's bigger than [`MTU`].
+  /// Empty if it's too big to send at all.
+  pub fn to_datagrams(&self, id: FragmentId) -> Vec<Vec<u8>> {
+    let bytes = self.to_bytes();
+    if bytes.len() <= MTU {return vec![bytes];}
+    fragment::split(id, &bytes).unwrap_or_default().into_iter().map(|f| Self::Fragment(f).to_bytes()).collect()
+  }
+  /// The message `fragment` completes, once the last piece of it arrives.
+  pub fn reassemble(fragments: &mut Reassembler, fragment: Fragment) -> Option<Self> {
+    match Self::from_bytes(&fragments.add(fragment)?)? {
+      Self::Fragment(_) => None,
+      message => Some(message),
+    }
+  }
+  /// Wraps the message in a [`ClientMessage::Sealed`] for the session.
+  pub fn seal(&self, session: &Session) -> Self {
+    let (counter, payload) = session.seal(&self.to_bytes());
+    Self::Sealed { session: session.id(), counter, payload }
+  }
+  /// Whether the message changes state the server must not miss, so goes over the reliable channel once connected.
+  pub fn is_reliable(&self) -> bool {
+    matches!(self,
+      Self::Disconnect | Self::JoinRoom { .. } | Self::LeaveRoom | Self::ListRooms | Self::Report { .. }
+      | Self::SetState(_) | Self::ListUsers | Self::Chat { .. } | Self::AcceptRules | Self::Admin { .. }
+    )
+  }
+  /// The message inside a [`ClientMessage::Sealed`], if it's genuine.
+  pub fn open(session: &Session, counter: u64, payload: &[u8]) -> Option<Self> {
+    match Self::from_bytes(&session.open(counter, payload)?)? {
+      Self::Sealed { .. } => None,
+      message => Some(message),
+    }
   }
 }
 
@@ -32,25 +138,126 @@ pub enum LeaveReason {
   Disconnect,
   Kicked,
   Timeout,
+  /// the user connected again, replacing their previous session
+  Rejoin,
 }
 
 #[derive(Clone)]
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
-  Pong,
+  /// answer to a [`ClientMessage::Ping`]
+  Pong { id: u32 },
+  /// answer to a [`ClientMessage::Connect`], with `reason` set if it was refused.
+  /// `public_key` is the server's half of the key exchange, everything after this is [`ServerMessage::Sealed`].
+  /// `client_key` echoes the connect's, to tell it from answers to earlier connects, or is zeros for a refusal.
+  /// `identity` is the server's long-term key, which clients pin and the key exchange proves it holds.
+  /// Must stay the second variant with `server_version` first (see [`ServerMessage::ack_version`]).
+  ConnectAck { server_version: u16, accepted: bool, reason: Option<String>, public_key: PublicKeyBytes, client_key: PublicKeyBytes, identity: PublicKeyBytes },
+  /// a packet came from an unknown address, echo this back in a [`ClientMessage::Migrate`] to resume a session
+  Challenge { challenge: u64 },
   /// a user connected
   Connected (UserInfo),
   /// a user disconnected
   Disconnected (UserInfo, LeaveReason),
   /// voice packet from a user
-  Voice { user: Uuid, samples: Vec<u8> },
+  Voice { user: Uuid, seq: SeqNum, samples: Vec<u8> },
+  /// the users in a room, sent to its members whenever someone joins or leaves it.
+  /// `defaults` is how the room wants clients to start out when they join it.
+  RoomState { room: String, users: Vec<UserInfo>, defaults: JoinDefaults },
+  /// rooms on the server
+  RoomList(Vec<RoomInfo>),
+  /// a user muted/unmuted or deafened/undeafened
+  StateChanged { user: Uuid, state: UserState },
+  /// everyone else on the server, sent on join and on request.
+  /// Large rosters are split over several packets, the last of which is `complete`.
+  UserList { users: Vec<UserInfo>, complete: bool },
+  /// text message from someone in our room
+  Chat { from: UserInfo, text: String },
+  /// follows an accepting [`ServerMessage::ConnectAck`], telling the client who it is and who's here.
+  /// `users` is the start of the roster, the rest follows as [`ServerMessage::UserList`]s.
+  /// `connection_id` identifies the session if the client's address changes.
+  /// `resume_token` lets a restarted client take this session back (see [`ClientMessage::Connect`]).
+  /// `join_defaults` is how the client should start out in the room it was put in.
+  /// `rules` must be agreed to with a [`ClientMessage::AcceptRules`] before our voice or chat goes anywhere.
+  Handshake { your_id: Uuid, users: Vec<UserInfo>, server_name: String, motd: Option<String>, rules: Option<String>, connection_id: u64, resume_token: u64, join_defaults: JoinDefaults },
+  /// our voice has been `active`ly stuck at the same level, digital silence or a broken mic,
+  /// and `suppressed` if the server has stopped relaying it until it changes
+  DeadAir { active: bool, suppressed: bool },
+  /// how an [`ClientMessage::Admin`] command went, `message` saying why if it didn't
+  AdminResult { ok: bool, message: String },
+  /// everyone on the server, for an [`AdminCommand::ListUsers`]
+  AdminUsers(Vec<AdminUserInfo>),
+  /// an operator has stopped relaying our voice, or started again
+  ServerMuted { muted: bool },
+  /// an operator telling everyone on the server something
+  Announcement { text: String },
+  /// a control message that must arrive, to be answered with a [`ClientMessage::Ack`] (see [`crate::reliable`])
+  Reliable { seq: ReliableSeq, message: Box<ServerMessage> },
+  /// the server got the [`ClientMessage::Reliable`] numbered `seq`
+  Ack { seq: ReliableSeq },
+  /// any other message, encrypted for the session (see [`crate::crypto::Session`])
+  Sealed { counter: u64, payload: Vec<u8> },
+  /// a piece of a message too big for one datagram (see [`crate::fragment`])
+  Fragment(Fragment),
 }
 
 impl ServerMessage {
+  /// Voice has an encoding of its own, see [`crate::wire`].
   pub fn to_bytes(&self) -> Vec<u8> {
-    bincode::serialize(self).unwrap()
+    match self {
+      Self::Voice { user, seq, samples } => ServerVoice { user: *user, seq: *seq, samples }.encode(),
+      _ => bincode::serialize(self).unwrap(),
+    }
   }
   pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-    bincode::deserialize(bytes).ok()
+    match ServerVoice::decode(bytes) {
+      Some(voice) => Some(voice.into()),
+      None => bincode::deserialize(bytes).ok(),
+    }
+  }
+  /// The server version in a [`ServerMessage::ConnectAck`], even from a version whose messages we can't otherwise parse.
+  pub fn ack_version(bytes: &[u8]) -> Option<u16> {
+    match bincode::deserialize::<(u32, u16)>(bytes) {
+      Ok((1, version)) => Some(version),
+      _ => None,
+    }
+  }
+  /// The message as datagrams to send, split into [`ServerMessage::Fragment`]s numbered `id` if it's bigger than [`MTU`].
+  /// Empty if it's too big to send at all.
+  pub fn to_datagrams(&self, id: FragmentId) -> Vec<Vec<u8>> {
+    let bytes = self.to_bytes();
+    if bytes.len() <= MTU {return vec![bytes];}
+    fragment::split(id, &bytes).unwrap_or_default().into_iter().map(|f| Self::Fragment(f).to_bytes()).collect()
+  }
+  /// The message `fragment` completes, once the last piece of it arrives.
+  pub fn reassemble(fragments: &mut Reassembler, fragment: Fragment) -> Option<Self> {
+    match Self::from_bytes(&fragments.add(fragment)?)? {
+      Self::Fragment(_) => None,
+      message => Some(message),
+    }
+  }
+  /// Wraps the message in a [`ServerMessage::Sealed`] for the session.
+  pub fn seal(&self, session: &Session) -> Self {
+    Self::seal_bytes(session, &self.to_bytes())
+  }
+  /// Wraps an already encoded message in a [`ServerMessage::Sealed`], to encode it once for many sessions.
+  pub fn seal_bytes(session: &Session, bytes: &[u8]) -> Self {
+    let (counter, payload) = session.seal(bytes);
+    Self::Sealed { counter, payload }
+  }
+  /// Whether the message changes state the client must not miss, so goes over the reliable channel.
+  pub fn is_reliable(&self) -> bool {
+    matches!(self,
+      Self::Connected(_) | Self::Disconnected(..) | Self::RoomState { .. } | Self::RoomList(_) | Self::StateChanged { .. }
+      | Self::UserList { .. } | Self::Chat { .. } | Self::DeadAir { .. } | Self::AdminResult { .. } | Self::AdminUsers(_)
+      | Self::ServerMuted { .. } | Self::Announcement { .. }
+    )
+  }
+  /// The message inside a [`ServerMessage::Sealed`], if it's genuine.
+  pub fn open(session: &Session, counter: u64, payload: &[u8]) -> Option<Self> {
+    match Self::from_bytes(&session.open(counter, payload)?)? {
+      Self::Sealed { .. } => None,
+      message => Some(message),
+    }
   }
 }
\ No newline at end of file
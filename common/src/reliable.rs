@@ -0,0 +1,205 @@
+//! Delivery of control messages over UDP: each is numbered, acknowledged by
+//! the other end, and sent again until it is. The receiving end hands them
+//! on in order, once each. Voice doesn't go through here, a late frame is
+//! worse than a lost one.
+
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+/// Numbers reliable messages in the order they were sent, per direction.
+pub type ReliableSeq = u32;
+
+/// How long to wait for an ack before sending again, at first.
+pub const INITIAL_RETRANSMIT: Duration = Duration::from_millis(200);
+/// Longest wait between sends of an unacknowledged message.
+pub const MAX_RETRANSMIT: Duration = Duration::from_secs(2);
+/// Times a message is sent without an ack before the channel is given up on, about half a minute's worth.
+pub const MAX_SENDS: u32 = 20;
+/// Most messages held on either end: sent and unacknowledged, or received ahead of one still missing.
+pub const WINDOW: ReliableSeq = 64;
+
+#[derive(Debug)]
+struct Unacked<T> {
+  message: T,
+  sent: Instant,
+  /// How long to wait before sending it again, doubled every time.
+  wait: Duration,
+  sends: u32,
+}
+
+/// One end of a reliable channel, sending `Out` and receiving `In`.
+#[derive(Debug)]
+pub struct ReliableChannel<Out, In> {
+  next_seq: ReliableSeq,
+  unacked: BTreeMap<ReliableSeq, Unacked<Out>>,
+  /// The next message to hand on.
+  expected: ReliableSeq,
+  /// Messages that overtook one we're still waiting for.
+  early: BTreeMap<ReliableSeq, In>,
+  /// Whether the other end stopped acknowledging, see [`ReliableChannel::is_broken`].
+  broken: bool,
+}
+
+impl<Out: Clone, In> ReliableChannel<Out, In> {
+  pub fn new() -> Self {
+    Self { next_seq: 0, unacked: BTreeMap::new(), expected: 0, early: BTreeMap::new(), broken: false }
+  }
+
+  /// Numbers a message for sending, keeping it until it's acknowledged.
+  pub fn send(&mut self, message: Out) -> ReliableSeq {
+    let seq = self.next_seq;
+    self.next_seq = self.next_seq.wrapping_add(1);
+    self.unacked.insert(seq, Unacked { message, sent: Instant::now(), wait: INITIAL_RETRANSMIT, sends: 1 });
+    // the other end can only take so many ahead of one it's missing
+    if self.unacked.len() > WINDOW as usize * 4 {
+      self.broken = true;
+    }
+    seq
+  }
+
+  /// The other end got `seq`, stop sending it.
+  pub fn ack(&mut self, seq: ReliableSeq) {
+    self.unacked.remove(&seq);
+  }
+
+  /// Messages that have waited too long for an ack, to send again now.
+  /// Nothing once the channel [is broken](ReliableChannel::is_broken).
+  pub fn due(&mut self) -> Vec<(ReliableSeq, Out)> {
+    let now = Instant::now();
+    let due = self.unacked.iter_mut()
+      .filter(|(_, unacked)| now.duration_since(unacked.sent) >= unacked.wait)
+      .map(|(seq, unacked)| {
+        unacked.sent = now;
+        unacked.wait = (unacked.wait * 2).min(MAX_RETRANSMIT);
+        unacked.sends += 1;
+        (*seq, unacked.message.clone())
+      })
+      .collect();
+    if self.unacked.values().any(|unacked| unacked.sends > MAX_SENDS) {
+      self.broken = true;
+    }
+    if self.broken {
+      self.unacked.clear();
+      return Vec::new();
+    }
+    due
+  }
+
+  /// Whether the other end has stopped acknowledging what we send, or fallen too far behind.
+  /// Messages can't be handed on past one that was given up on, so the connection should be dropped.
+  pub fn is_broken(&self) -> bool {
+    self.broken
+  }
+
+  /// Messages sent that the other end hasn't acknowledged yet.
+  pub fn unacked(&self) -> usize {
+    self.unacked.len()
+  }
+
+  /// Takes in message `seq`, returning whatever can now be handed on in order.
+  ///
+  /// Acknowledge `seq` unless this is `None`, even if nothing comes back: it may be a repeat whose ack was lost.
+  /// `None` means it's too far ahead of what we're missing to hold on to, and should be sent again later.
+  pub fn receive(&mut self, seq: ReliableSeq, message: In) -> Option<Vec<In>> {
+    let ahead = seq.wrapping_sub(self.expected);
+    // already handed on
+    if ahead > ReliableSeq::MAX / 2 {return Some(Vec::new());}
+    if ahead >= WINDOW {return None;}
+    self.early.insert(seq, message);
+    let mut ready = Vec::new();
+    while let Some(message) = self.early.remove(&self.expected) {
+      ready.push(message);
+      self.expected = self.expected.wrapping_add(1);
+    }
+    Some(ready)
+  }
+}
+
+impl<Out: Clone, In> Default for ReliableChannel<Out, In> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  type Channel = ReliableChannel<u32, u32>;
+
+  #[test]
+  fn hands_on_in_order() {
+    let mut channel = Channel::new();
+    assert_eq!(channel.receive(1, 1), Some(vec![]));
+    assert_eq!(channel.receive(2, 2), Some(vec![]));
+    assert_eq!(channel.receive(0, 0), Some(vec![0, 1, 2]));
+    assert_eq!(channel.receive(3, 3), Some(vec![3]));
+  }
+
+  #[test]
+  fn hands_on_once() {
+    let mut channel = Channel::new();
+    assert_eq!(channel.receive(0, 0), Some(vec![0]));
+    assert_eq!(channel.receive(0, 0), Some(vec![]));
+    assert_eq!(channel.receive(2, 2), Some(vec![]));
+    assert_eq!(channel.receive(2, 2), Some(vec![]));
+    assert_eq!(channel.receive(1, 1), Some(vec![1, 2]));
+    assert_eq!(channel.receive(1, 1), Some(vec![]));
+  }
+
+  #[test]
+  fn refuses_too_far_ahead() {
+    let mut channel = Channel::new();
+    assert_eq!(channel.receive(WINDOW, 0), None);
+    assert_eq!(channel.receive(ReliableSeq::MAX / 2, 0), None);
+    assert_eq!(channel.receive(WINDOW - 1, 0), Some(vec![]));
+    assert!(channel.early.len() <= WINDOW as usize);
+    // once the gap is filled, what was refused gets in when it's sent again
+    for seq in 0..WINDOW - 1 {
+      channel.receive(seq, seq);
+    }
+    assert_eq!(channel.receive(WINDOW, WINDOW), Some(vec![WINDOW]));
+  }
+
+  #[test]
+  fn wraps_around() {
+    let mut channel = Channel { expected: ReliableSeq::MAX, ..Channel::new() };
+    assert_eq!(channel.receive(0, 0), Some(vec![]));
+    assert_eq!(channel.receive(ReliableSeq::MAX, 1), Some(vec![1, 0]));
+    assert_eq!(channel.receive(ReliableSeq::MAX, 1), Some(vec![]));
+  }
+
+  #[test]
+  fn acked_messages_are_not_sent_again() {
+    let mut channel = Channel::new();
+    let seq = channel.send(7);
+    channel.ack(seq);
+    assert_eq!(channel.unacked(), 0);
+    assert!(channel.due().is_empty());
+  }
+
+  #[test]
+  fn gives_up_without_acks() {
+    let mut channel = Channel::new();
+    channel.send(7);
+    for _ in 0..MAX_SENDS {
+      assert!(!channel.is_broken());
+      // as if it's been waiting long enough every time
+      channel.unacked.values_mut().for_each(|unacked| unacked.wait = Duration::ZERO);
+      channel.due();
+    }
+    assert!(channel.is_broken());
+    assert!(channel.due().is_empty());
+    assert_eq!(channel.unacked(), 0);
+  }
+
+  #[test]
+  fn gives_up_on_too_many_unacked() {
+    let mut channel = Channel::new();
+    for message in 0..WINDOW * 4 {
+      channel.send(message);
+    }
+    assert!(!channel.is_broken());
+    channel.send(0);
+    assert!(channel.is_broken());
+  }
+}
@@ -0,0 +1,39 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomInfo {
+  pub name: String,
+  /// number of users in the room
+  pub users: usize,
+}
+
+/// How clients should start out when they join a room, so a big room isn't
+/// disrupted by a hot mic every time someone walks in.
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JoinDefaults {
+  /// join with the mic muted
+  pub start_muted: bool,
+  /// join without hearing anyone
+  pub start_deafened: bool,
+  /// only send the mic while push to talk is held
+  pub push_to_talk: bool,
+  /// what the room is for, so clients can tune their audio to it
+  pub preset: Option<RoomPreset>,
+}
+
+/// What a room is for. Clients pick codec, buffering and processing
+/// settings to suit it, unless their user chose their own.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoomPreset {
+  /// full band audio, untouched, with room to buffer
+  Music,
+  /// as little delay as possible, for talking over a game
+  LowLatency,
+  /// speech from many mics, cleaned up and cheap to send
+  Conference,
+}
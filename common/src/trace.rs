@@ -0,0 +1,43 @@
+//! Packet traces: one line per voice packet received, as `<ms> <user> <seq> <bytes>`,
+//! where `ms` counts from the start of the trace. Written by the client, read by the `trace` tool.
+
+use std::{fmt, str::FromStr};
+
+use uuid::Uuid;
+
+use crate::packets::SeqNum;
+
+/// A voice packet arriving.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+  /// Milliseconds since the trace started.
+  pub at_ms: u64,
+  pub user: Uuid,
+  pub seq: SeqNum,
+  pub size: usize,
+}
+
+impl fmt::Display for TraceEvent {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} {} {} {}", self.at_ms, self.user, self.seq, self.size)
+  }
+}
+
+impl FromStr for TraceEvent {
+  type Err = String;
+
+  fn from_str(line: &str) -> Result<Self, Self::Err> {
+    let mut fields = line.split_whitespace();
+    let mut next = |name: &str| fields.next().ok_or_else(|| format!("missing {}", name));
+    let event = Self {
+      at_ms: next("time")?.parse().map_err(|e| format!("bad time: {}", e))?,
+      user: next("user")?.parse().map_err(|e| format!("bad user: {}", e))?,
+      seq: next("sequence number")?.parse().map_err(|e| format!("bad sequence number: {}", e))?,
+      size: next("size")?.parse().map_err(|e| format!("bad size: {}", e))?,
+    };
+    if fields.next().is_some() {
+      return Err("trailing fields".to_string());
+    }
+    Ok(event)
+  }
+}
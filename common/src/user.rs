@@ -1,9 +1,30 @@
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Role {
+  /// can talk and listen
+  Speaker,
+  /// can only listen, the server won't relay their voice
+  Audience,
+}
+
+/// What a user has chosen to share about their audio, for others to display.
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserState {
+  /// not sending their mic
+  pub muted: bool,
+  /// not hearing anyone
+  pub deafened: bool,
+}
+
 #[derive(Clone)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserInfo {
   pub id: Uuid,
   pub username: String,
+  pub role: Role,
+  pub state: UserState,
 }
\ No newline at end of file
@@ -0,0 +1,183 @@
+//! A compact encoding for voice, which everyone talking sends every 20 ms.
+//! Other messages go through bincode, whose enum tags and length prefixes
+//! cost more than voice needs. Voice starts with a tag byte bincode never
+//! starts a message with, as it would be the low byte of a variant index
+//! far past the last, so the two can share a socket.
+//!
+//! Decoding borrows the samples from the packet, so voice can be relayed
+//! without copying them out.
+
+use uuid::Uuid;
+
+use crate::packets::{ClientMessage, ServerMessage, SeqNum};
+
+const CLIENT_VOICE: u8 = 0xf0;
+const SERVER_VOICE: u8 = 0xf1;
+
+/// A [`ClientMessage::Voice`]: tag, `seq`, `level`, the samples' length, then the samples.
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+pub struct ClientVoice<'a> {
+  pub seq: SeqNum,
+  pub level: u8,
+  pub samples: &'a [u8],
+}
+
+impl<'a> ClientVoice<'a> {
+  const HEADER: usize = 6;
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut out = Vec::with_capacity(Self::HEADER + self.samples.len());
+    out.push(CLIENT_VOICE);
+    out.extend_from_slice(&self.seq.to_le_bytes());
+    out.push(self.level);
+    out.extend_from_slice(&samples_len(self.samples));
+    out.extend_from_slice(self.samples);
+    out
+  }
+
+  /// `None` if `bytes` aren't a client's voice packet.
+  pub fn decode(bytes: &'a [u8]) -> Option<Self> {
+    let (header, samples) = bytes.split_at_checked(Self::HEADER)?;
+    if header[0] != CLIENT_VOICE || u16::from_le_bytes([header[4], header[5]]) as usize != samples.len() {
+      return None;
+    }
+    Some(Self { seq: u16::from_le_bytes([header[1], header[2]]), level: header[3], samples })
+  }
+}
+
+impl From<ClientVoice<'_>> for ClientMessage {
+  fn from(voice: ClientVoice) -> Self {
+    ClientMessage::Voice { seq: voice.seq, samples: voice.samples.to_vec(), level: voice.level }
+  }
+}
+
+/// A [`ServerMessage::Voice`]: tag, `seq`, `user`, the samples' length, then the samples.
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+pub struct ServerVoice<'a> {
+  pub user: Uuid,
+  pub seq: SeqNum,
+  pub samples: &'a [u8],
+}
+
+impl<'a> ServerVoice<'a> {
+  const HEADER: usize = 21;
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut out = Vec::with_capacity(Self::HEADER + self.samples.len());
+    out.push(SERVER_VOICE);
+    out.extend_from_slice(&self.seq.to_le_bytes());
+    out.extend_from_slice(self.user.as_bytes());
+    out.extend_from_slice(&samples_len(self.samples));
+    out.extend_from_slice(self.samples);
+    out
+  }
+
+  /// `None` if `bytes` aren't a server's voice packet.
+  pub fn decode(bytes: &'a [u8]) -> Option<Self> {
+    let (header, samples) = bytes.split_at_checked(Self::HEADER)?;
+    if header[0] != SERVER_VOICE || u16::from_le_bytes([header[19], header[20]]) as usize != samples.len() {
+      return None;
+    }
+    Some(Self {
+      user: Uuid::from_slice(&header[3..19]).ok()?,
+      seq: u16::from_le_bytes([header[1], header[2]]),
+      samples,
+    })
+  }
+}
+
+impl From<ServerVoice<'_>> for ServerMessage {
+  fn from(voice: ServerVoice) -> Self {
+    ServerMessage::Voice { user: voice.user, seq: voice.seq, samples: voice.samples.to_vec() }
+  }
+}
+
+/// Voice is clamped to [`crate::packets::MAX_VOICE_PAYLOAD`], far below what the length can hold.
+fn samples_len(samples: &[u8]) -> [u8; 2] {
+  u16::try_from(samples.len()).expect("voice payload too large to encode").to_le_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn client_voice_round_trips() {
+    let voice = ClientVoice { seq: 0xbeef, level: 42, samples: &[1, 2, 3, 4, 5] };
+    let bytes = voice.encode();
+    assert_eq!(bytes.len(), ClientVoice::HEADER + 5);
+    let decoded = ClientVoice::decode(&bytes).unwrap();
+    assert_eq!((decoded.seq, decoded.level, decoded.samples), (0xbeef, 42, &[1, 2, 3, 4, 5][..]));
+  }
+
+  #[test]
+  fn server_voice_round_trips() {
+    let user = Uuid::new_v4();
+    let voice = ServerVoice { user, seq: 7, samples: &[9; 300] };
+    let bytes = voice.encode();
+    assert_eq!(bytes.len(), ServerVoice::HEADER + 300);
+    let decoded = ServerVoice::decode(&bytes).unwrap();
+    assert_eq!((decoded.user, decoded.seq, decoded.samples), (user, 7, &[9; 300][..]));
+  }
+
+  #[test]
+  fn empty_voice_round_trips() {
+    let bytes = ClientVoice { seq: 0, level: 127, samples: &[] }.encode();
+    assert!(ClientVoice::decode(&bytes).unwrap().samples.is_empty());
+    let bytes = ServerVoice { user: Uuid::nil(), seq: 0, samples: &[] }.encode();
+    assert!(ServerVoice::decode(&bytes).unwrap().samples.is_empty());
+  }
+
+  #[test]
+  fn truncated_voice_is_refused() {
+    let client = ClientVoice { seq: 1, level: 0, samples: &[1, 2, 3] }.encode();
+    let server = ServerVoice { user: Uuid::new_v4(), seq: 1, samples: &[1, 2, 3] }.encode();
+    for len in 0..client.len() {
+      assert!(ClientVoice::decode(&client[..len]).is_none(), "{len} bytes");
+    }
+    for len in 0..server.len() {
+      assert!(ServerVoice::decode(&server[..len]).is_none(), "{len} bytes");
+    }
+  }
+
+  #[test]
+  fn lengths_that_dont_match_are_refused() {
+    let mut client = ClientVoice { seq: 1, level: 0, samples: &[1, 2, 3] }.encode();
+    client.push(4);
+    assert!(ClientVoice::decode(&client).is_none());
+    client[4..6].copy_from_slice(&u16::MAX.to_le_bytes());
+    assert!(ClientVoice::decode(&client).is_none());
+
+    let mut server = ServerVoice { user: Uuid::new_v4(), seq: 1, samples: &[1, 2, 3] }.encode();
+    server.push(4);
+    assert!(ServerVoice::decode(&server).is_none());
+    server[19..21].copy_from_slice(&u16::MAX.to_le_bytes());
+    assert!(ServerVoice::decode(&server).is_none());
+  }
+
+  #[test]
+  fn tags_arent_mixed_up() {
+    let client = ClientVoice { seq: 1, level: 0, samples: &[0; 20] }.encode();
+    let server = ServerVoice { user: Uuid::new_v4(), seq: 1, samples: &[0; 5] }.encode();
+    assert!(ServerVoice::decode(&client).is_none());
+    assert!(ClientVoice::decode(&server).is_none());
+  }
+
+  #[test]
+  fn other_messages_arent_taken_for_voice() {
+    let ping = ClientMessage::Ping { id: u32::MAX }.to_bytes();
+    assert!(ClientVoice::decode(&ping).is_none());
+    assert!(matches!(ClientMessage::from_bytes(&ping), Some(ClientMessage::Ping { id: u32::MAX })));
+    let pong = ServerMessage::Pong { id: u32::MAX }.to_bytes();
+    assert!(ServerVoice::decode(&pong).is_none());
+    assert!(matches!(ServerMessage::from_bytes(&pong), Some(ServerMessage::Pong { id: u32::MAX })));
+  }
+
+  #[test]
+  #[should_panic(expected = "voice payload too large")]
+  fn oversized_voice_isnt_encoded() {
+    ClientVoice { seq: 0, level: 0, samples: &vec![0; usize::from(u16::MAX) + 1] }.encode();
+  }
+}
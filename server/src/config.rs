@@ -1,21 +1,287 @@
-use std::time::Duration;
+use std::{collections::HashMap, net::{IpAddr, Ipv6Addr, SocketAddr}, path::{Path, PathBuf}, time::Duration};
 
-use crate::server::Server;
+use common::JoinDefaults;
+use serde::Deserialize;
 
+#[derive(Clone)]
 pub struct ServerConfig {
+  /// Address to listen on. The default, `::`, takes both IPv6 and IPv4 where the system allows it.
+  pub bind: IpAddr,
   pub port: u16,
+  /// Shown to clients when they connect.
+  pub name: String,
+  /// Message of the day, shown to clients when they connect.
+  pub motd: Option<String>,
+  /// Users must agree to these before they can talk or chat, the first time they connect.
+  pub rules: Option<String>,
+  /// File of the identities that have agreed to the rules, so they aren't asked again after a restart.
+  pub rules_accepted_file: Option<PathBuf>,
+  /// File saved rooms and who operators mute are kept in, and restored from on start.
+  pub state_file: Option<PathBuf>,
+  /// Whether rooms any user makes are saved, rather than only those an operator saves.
+  pub save_user_rooms: bool,
+  /// File the server's long-term key is kept in, made if it doesn't exist, and read on start.
+  /// Without one the key changes every start, and clients that pinned it will refuse to connect.
+  pub key_file: Option<PathBuf>,
+  /// Needed to connect, if set.
+  pub password: Option<String>,
+  /// Lets whoever has it kick and mute users, and make announcements. Nobody can if unset.
+  pub admin_token: Option<String>,
+  /// Most users connected at once, new ones are refused beyond this.
+  pub max_users: usize,
   /// Time before a user is disconnected.
   pub timeout: Duration,
   /// Interval between heartbeat checks.
   pub heartbeat_interval: Duration,
+  /// Voice packets kept per recipient before the oldest are dropped.
+  pub outbound_queue_len: usize,
+  /// Number of users in a room above which only the loudest speakers are relayed.
+  pub large_room_threshold: usize,
+  /// How many speakers are relayed at once in a large room.
+  pub max_relayed_speakers: usize,
+  /// How long a timed-out session can still be resumed.
+  pub resume_window: Duration,
+  /// Most packets a client can send per second, on average.
+  pub max_packets_per_sec: f32,
+  /// Most bytes a client can send per second, on average.
+  pub max_bytes_per_sec: f32,
+  /// How long a client's voice is dropped for after going over the limits.
+  pub flood_mute: Duration,
+  /// Times a client can be muted for flooding before they're kicked.
+  pub max_flood_mutes: usize,
+  /// How long a user's voice can stay at the same level before they're told their mic looks broken.
+  pub dead_air: Duration,
+  /// Whether to stop relaying a user's voice while it's dead air.
+  pub suppress_dead_air: bool,
+  /// Rooms that always exist. Users start in the first one.
+  pub rooms: Vec<String>,
+  /// How clients should start out when they join a room.
+  pub join_defaults: JoinDefaults,
+  /// Rooms that want clients to start out differently, by name.
+  pub room_defaults: HashMap<String, JoinDefaults>,
+  /// Where to serve Prometheus metrics, if anywhere. Needs the `metrics` feature.
+  pub metrics_addr: Option<SocketAddr>,
 }
 
 impl ServerConfig {
   pub fn new() -> Self {
     Self {
-      port: 8080,
+      bind: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+      port: common::packets::DEFAULT_PORT,
+      name: "Rust Voice Server".to_string(),
+      motd: None,
+      rules: None,
+      rules_accepted_file: None,
+      state_file: None,
+      save_user_rooms: false,
+      key_file: None,
+      password: None,
+      admin_token: None,
+      max_users: 64,
       timeout: Duration::from_secs(100),
       heartbeat_interval: Duration::from_secs(1),
+      outbound_queue_len: 16,
+      large_room_threshold: 16,
+      max_relayed_speakers: 4,
+      resume_window: Duration::from_secs(60),
+      // 100 voice frames a second for low latency rooms' 10ms frames, plus room for control messages
+      max_packets_per_sec: 150.0,
+      max_bytes_per_sec: 64_000.0,
+      flood_mute: Duration::from_secs(10),
+      max_flood_mutes: 3,
+      dead_air: Duration::from_secs(30),
+      suppress_dead_air: false,
+      rooms: vec!["Lobby".to_string()],
+      join_defaults: JoinDefaults::default(),
+      room_defaults: HashMap::new(),
+      metrics_addr: None,
     }
   }
-}
\ No newline at end of file
+
+  /// How clients should start out in a room.
+  pub fn join_defaults(&self, room: Option<&str>) -> JoinDefaults {
+    room.and_then(|room| self.room_defaults.get(room)).copied().unwrap_or(self.join_defaults)
+  }
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Settings from a config file or the command line, each replacing the default if set.
+///
+/// ```toml
+/// bind = "::"
+/// port = 8080
+/// name = "My Server"
+/// motd = "Be nice"
+/// rules = "No music in the Lobby"
+/// rules_accepted_file = "accepted.txt"
+/// state_file = "state.toml"
+/// save_user_rooms = false
+/// key_file = "server.key"
+/// password = "hunter2"
+/// admin_token = "correct horse battery staple"
+/// max_users = 32
+/// timeout_secs = 10
+/// heartbeat_secs = 1
+/// dead_air_secs = 30
+/// suppress_dead_air = true
+/// rooms = ["Lobby", "Music"]
+/// metrics_addr = "127.0.0.1:9100"
+///
+/// [join_defaults]
+/// start_muted = true
+///
+/// [room_defaults.Stage]
+/// start_muted = true
+/// push_to_talk = true
+///
+/// [room_defaults.Music]
+/// preset = "music"
+/// ```
+#[derive(Debug, Default)]
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartialConfig {
+  pub bind: Option<IpAddr>,
+  pub port: Option<u16>,
+  pub name: Option<String>,
+  pub motd: Option<String>,
+  pub rules: Option<String>,
+  pub rules_accepted_file: Option<PathBuf>,
+  pub state_file: Option<PathBuf>,
+  pub save_user_rooms: Option<bool>,
+  pub key_file: Option<PathBuf>,
+  pub password: Option<String>,
+  pub admin_token: Option<String>,
+  pub max_users: Option<usize>,
+  pub timeout_secs: Option<f32>,
+  pub heartbeat_secs: Option<f32>,
+  pub max_packets_per_sec: Option<f32>,
+  pub max_bytes_per_sec: Option<f32>,
+  pub dead_air_secs: Option<f32>,
+  pub suppress_dead_air: Option<bool>,
+  pub rooms: Option<Vec<String>>,
+  pub join_defaults: Option<JoinDefaults>,
+  pub room_defaults: Option<HashMap<String, JoinDefaults>>,
+  pub metrics_addr: Option<SocketAddr>,
+}
+
+impl PartialConfig {
+  pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+    Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+  }
+
+  /// Sets everything given on `config`, failing without touching it if anything is out of range.
+  pub fn apply(&self, config: &mut ServerConfig) -> Result<(), anyhow::Error> {
+    let timeout = self.timeout_secs.map(|secs| duration("timeout_secs", secs)).transpose()?;
+    let heartbeat = self.heartbeat_secs.map(|secs| duration("heartbeat_secs", secs)).transpose()?;
+    let dead_air = self.dead_air_secs.map(|secs| duration("dead_air_secs", secs)).transpose()?;
+    for (name, rate) in [("max_packets_per_sec", self.max_packets_per_sec), ("max_bytes_per_sec", self.max_bytes_per_sec)] {
+      if let Some(rate) = rate {
+        anyhow::ensure!(rate.is_finite() && rate > 0.0, "{} = {} isn't a positive number", name, rate);
+      }
+    }
+
+    if let Some(bind) = self.bind {config.bind = bind;}
+    if let Some(port) = self.port {config.port = port;}
+    if let Some(name) = &self.name {config.name = name.clone();}
+    if let Some(motd) = &self.motd {config.motd = Some(motd.clone());}
+    if let Some(rules) = &self.rules {config.rules = Some(rules.clone());}
+    if let Some(path) = &self.rules_accepted_file {config.rules_accepted_file = Some(path.clone());}
+    if let Some(path) = &self.state_file {config.state_file = Some(path.clone());}
+    if let Some(save) = self.save_user_rooms {config.save_user_rooms = save;}
+    if let Some(path) = &self.key_file {config.key_file = Some(path.clone());}
+    if let Some(password) = &self.password {config.password = Some(password.clone());}
+    if let Some(token) = &self.admin_token {config.admin_token = Some(token.clone());}
+    if let Some(max_users) = self.max_users {config.max_users = max_users;}
+    if let Some(timeout) = timeout {config.timeout = timeout;}
+    if let Some(heartbeat) = heartbeat {config.heartbeat_interval = heartbeat;}
+    if let Some(rate) = self.max_packets_per_sec {config.max_packets_per_sec = rate;}
+    if let Some(rate) = self.max_bytes_per_sec {config.max_bytes_per_sec = rate;}
+    if let Some(dead_air) = dead_air {config.dead_air = dead_air;}
+    if let Some(suppress) = self.suppress_dead_air {config.suppress_dead_air = suppress;}
+    if let Some(rooms) = &self.rooms {config.rooms = rooms.clone();}
+    if let Some(defaults) = self.join_defaults {config.join_defaults = defaults;}
+    if let Some(defaults) = &self.room_defaults {config.room_defaults = defaults.clone();}
+    if let Some(addr) = self.metrics_addr {config.metrics_addr = Some(addr);}
+    Ok(())
+  }
+}
+
+/// `secs` as a duration, if it is one.
+fn duration(name: &str, secs: f32) -> Result<Duration, anyhow::Error> {
+  Duration::try_from_secs_f32(secs).map_err(|e| anyhow::anyhow!("{} = {} isn't a duration: {}", name, secs, e))
+}
+
+/// Where the config came from, so it can be rebuilt when the file changes.
+pub struct ConfigSource {
+  /// Settings before the file is applied.
+  pub base: ServerConfig,
+  pub path: Option<std::path::PathBuf>,
+  /// Settings from the command line, which win over the file.
+  pub overrides: PartialConfig,
+}
+
+impl ConfigSource {
+  pub fn load(&self) -> Result<ServerConfig, anyhow::Error> {
+    let mut config = self.base.clone();
+    if let Some(path) = &self.path {
+      PartialConfig::load(path)?.apply(&mut config)?;
+    }
+    self.overrides.apply(&mut config)?;
+    if config.rooms.is_empty() {
+      anyhow::bail!("at least one room is needed");
+    }
+    Ok(config)
+  }
+
+  /// When the file was last changed, if there is one.
+  pub fn modified(&self) -> Option<std::time::SystemTime> {
+    std::fs::metadata(self.path.as_ref()?).ok()?.modified().ok()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn apply(toml: &str) -> Result<ServerConfig, anyhow::Error> {
+    let mut config = ServerConfig::new();
+    toml::from_str::<PartialConfig>(toml)?.apply(&mut config)?;
+    Ok(config)
+  }
+
+  #[test]
+  fn durations_are_applied() {
+    let config = apply("timeout_secs = 2.5\nheartbeat_secs = 0.5\ndead_air_secs = 10").unwrap();
+    assert_eq!(config.timeout, Duration::from_millis(2500));
+    assert_eq!(config.heartbeat_interval, Duration::from_millis(500));
+    assert_eq!(config.dead_air, Duration::from_secs(10));
+  }
+
+  #[test]
+  fn bad_durations_are_errors() {
+    for toml in ["timeout_secs = -1", "heartbeat_secs = nan", "dead_air_secs = inf"] {
+      assert!(apply(toml).is_err(), "{}", toml);
+    }
+  }
+
+  #[test]
+  fn bad_rates_are_errors() {
+    for toml in ["max_packets_per_sec = 0", "max_bytes_per_sec = -5", "max_packets_per_sec = nan"] {
+      assert!(apply(toml).is_err(), "{}", toml);
+    }
+  }
+
+  #[test]
+  fn nothing_is_applied_if_anything_is_wrong() {
+    let mut config = ServerConfig::new();
+    let partial = PartialConfig { name: Some("Changed".to_string()), timeout_secs: Some(-1.0), ..Default::default() };
+    assert!(partial.apply(&mut config).is_err());
+    assert_eq!(config.name, ServerConfig::new().name);
+  }
+}
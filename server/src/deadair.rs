@@ -0,0 +1,45 @@
+use std::time::{Duration, Instant};
+
+/// Levels closer together than this, in dB, count as not changing.
+const STUCK_RANGE: u8 = 1;
+
+/// What a voice packet said about a user's uplink.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+  None,
+  /// Their levels have stopped changing: digital silence, or a stuck mic.
+  Dead,
+  /// They've come back to life.
+  Alive,
+}
+
+/// Notices a user sending the same level for too long, which no real mic does.
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct DeadAirDetector {
+  /// Quietest and loudest level since `since`, in -dBov.
+  min: u8,
+  max: u8,
+  since: Instant,
+  pub dead: bool,
+}
+
+impl DeadAirDetector {
+  pub fn new() -> Self {
+    Self { min: u8::MAX, max: 0, since: Instant::now(), dead: false }
+  }
+
+  /// Takes in a reported level in -dBov, declaring the uplink dead once it hasn't moved for `after`.
+  pub fn update(&mut self, level: u8, after: Duration) -> Change {
+    let (min, max) = (self.min.min(level), self.max.max(level));
+    if max - min > STUCK_RANGE {
+      let was_dead = self.dead;
+      *self = Self { min: level, max: level, ..Self::new() };
+      return if was_dead {Change::Alive} else {Change::None};
+    }
+    (self.min, self.max) = (min, max);
+    if self.dead || self.since.elapsed() < after {return Change::None;}
+    self.dead = true;
+    Change::Dead
+  }
+}
@@ -0,0 +1,35 @@
+use std::net::SocketAddr;
+
+use common::{packets::LeaveReason, AdminCommand, UserInfo};
+use uuid::Uuid;
+
+/// Something that happened on the server, for embedders to react to without parsing logs.
+/// See [`crate::Server::subscribe`].
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum ServerEvent {
+  /// Someone connected, or `resumed` a session that had timed out.
+  UserJoined { user: UserInfo, addr: SocketAddr, resumed: bool },
+  /// Someone disconnected, was kicked, or connected again from elsewhere.
+  UserLeft { user: UserInfo, reason: LeaveReason },
+  /// Someone stopped answering. They can still resume their session for a while.
+  UserTimedOut { user: UserInfo },
+  /// Someone moved between rooms, or into or out of one.
+  UserMoved { user: UserInfo, from: Option<String>, to: Option<String> },
+  /// Someone's address changed, e.g. their network did.
+  UserMigrated { user: UserInfo, from: SocketAddr, to: SocketAddr },
+  /// A connection was refused before it got a session.
+  Refused { addr: SocketAddr, reason: String },
+  /// A sender went over their rate limit and is muted for a while.
+  RateLimited { addr: SocketAddr, user: Option<UserInfo> },
+  /// Someone's voice got stuck on the same level, or came back from it.
+  DeadAir { user: UserInfo, active: bool },
+  /// Someone agreed to the server's rules.
+  RulesAccepted { user: UserInfo },
+  /// An operator ran a command.
+  Admin { by: UserInfo, command: AdminCommand },
+  /// Someone flagged a user for abuse.
+  Reported { by: UserInfo, user: Uuid, reason: String },
+  /// The config file changed and was applied.
+  ConfigReloaded,
+}
@@ -0,0 +1,15 @@
+//! Voice chat server.
+//!
+//! Run by the `server` binary, or embedded: make a [`Server`], [`Server::subscribe`]
+//! to its [`ServerEvent`]s, then [`Server::start`] it.
+
+pub mod config;
+mod deadair;
+mod events;
+pub use events::ServerEvent;
+mod metrics;
+mod ratelimit;
+mod server;
+pub use server::Server;
+pub mod snapshot;
+mod speakers;
@@ -1,26 +1,82 @@
 use clap::Parser;
 use env_logger::Env;
-
-mod config;
-mod server;
+use server::{config, snapshot::Snapshot, Server};
 
 #[derive(Parser, Debug)]
 #[clap(name="Rust Voice Server")]
 struct Args {
-  #[clap(value_parser = clap::value_parser!(u16).range(1..), short='p', long="port", default_value_t=8080)]
-  port: u16,
+  /// TOML file to read settings from, reloaded when it changes
+  #[clap(short='c', long="config")]
+  config: Option<std::path::PathBuf>,
+  /// Address to listen on, `::` for IPv6 and IPv4 or `0.0.0.0` for just IPv4
+  #[clap(short='b', long="bind")]
+  bind: Option<std::net::IpAddr>,
+  #[clap(value_parser = clap::value_parser!(u16).range(1..), short='p', long="port")]
+  port: Option<u16>,
+  /// Name shown to clients when they connect
+  #[clap(short='n', long="name")]
+  name: Option<String>,
+  /// Most users connected at once
+  #[clap(long="max-users")]
+  max_users: Option<usize>,
+  /// Password clients need to connect
+  #[clap(long="password")]
+  password: Option<String>,
+  /// Token operators need to kick, mute and make announcements
+  #[clap(long="admin-token")]
+  admin_token: Option<String>,
+  /// File to keep the server's key in, so clients that pinned it still trust it after a restart
+  #[clap(long="key-file")]
+  key_file: Option<std::path::PathBuf>,
+  /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9100
+  #[clap(long="metrics")]
+  metrics: Option<std::net::SocketAddr>,
+  /// Write this server's rooms and mutes to a file, to set up another with --import, and exit
+  #[clap(long="export")]
+  export: Option<std::path::PathBuf>,
+  /// Replace the rooms and mutes in the state file with ones from --export, and exit
+  #[clap(long="import")]
+  import: Option<std::path::PathBuf>,
 }
 
-fn main() {
+fn main() -> Result<(), anyhow::Error> {
   env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
   let args = Args::parse();
 
-  let config = config::ServerConfig {
-    port: args.port,
-    heartbeat_interval: std::time::Duration::from_secs(1),
-    timeout: std::time::Duration::from_secs(3),
+  let source = config::ConfigSource {
+    base: config::ServerConfig {
+      timeout: std::time::Duration::from_secs(3),
+      ..config::ServerConfig::new()
+    },
+    path: args.config,
+    overrides: config::PartialConfig {
+      bind: args.bind,
+      port: args.port,
+      name: args.name,
+      password: args.password,
+      admin_token: args.admin_token,
+      key_file: args.key_file,
+      max_users: args.max_users,
+      metrics_addr: args.metrics,
+      ..Default::default()
+    },
   };
-  let mut server = server::Server::new(config);
+  let config = source.load()?;
+  if let Some(path) = args.export {
+    Snapshot::export(&config)?.save(&path)?;
+    log::info!("Exported rooms and mutes to {:?}", path);
+    return Ok(());
+  }
+  if let Some(path) = args.import {
+    // loading treats a missing file as nothing saved yet
+    anyhow::ensure!(path.exists(), "{:?} doesn't exist", path);
+    Snapshot::load(&path)?.import(&config)?;
+    log::info!("Imported rooms and mutes from {:?}", path);
+    return Ok(());
+  }
+  let mut server = Server::new(config);
+  server.watch_config(source);
   server.start();
+  Ok(())
 }
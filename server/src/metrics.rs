@@ -0,0 +1,229 @@
+//! Counters for operators. With the `metrics` feature, they're served in
+//! Prometheus' text format on `/metrics`.
+
+use std::{collections::HashMap, sync::{Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+#[cfg(feature = "metrics")]
+use std::{fmt::Write as _, io::{BufRead, BufReader, Write}, net::{SocketAddr, TcpListener, TcpStream}, sync::Arc};
+
+use common::packets::SeqNum;
+#[cfg(feature = "metrics")]
+use log::{info, warn};
+use uuid::Uuid;
+
+#[cfg(feature = "metrics")]
+use crate::server::User;
+
+/// Upper bounds of the fan-out time histogram's buckets, in seconds.
+const FANOUT_BUCKETS: [f64; 8] = [0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01];
+
+/// Gaps in a user's sequence numbers bigger than this are a restart, not loss.
+const MAX_GAP: u16 = 1000;
+
+/// How long one message took to send to everyone it was for.
+#[derive(Default)]
+struct Histogram {
+  buckets: [AtomicU64; FANOUT_BUCKETS.len()],
+  count: AtomicU64,
+  /// Total, in nanoseconds.
+  sum: AtomicU64,
+}
+
+impl Histogram {
+  fn observe(&self, took: Duration) {
+    let secs = took.as_secs_f64();
+    for (bucket, bound) in self.buckets.iter().zip(FANOUT_BUCKETS) {
+      if secs <= bound {
+        bucket.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self.sum.fetch_add(took.as_nanos() as u64, Ordering::Relaxed);
+  }
+
+  #[cfg(feature = "metrics")]
+  fn render(&self, out: &mut String, name: &str, labels: &str) {
+    for (bucket, bound) in self.buckets.iter().zip(FANOUT_BUCKETS) {
+      writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, bucket.load(Ordering::Relaxed)).unwrap();
+    }
+    let count = self.count.load(Ordering::Relaxed);
+    writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count).unwrap();
+    writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum.load(Ordering::Relaxed) as f64 / 1e9).unwrap();
+    writeln!(out, "{}_count{{{}}} {}", name, labels, count).unwrap();
+  }
+}
+
+/// Voice packets from one user, and how many never made it to us.
+#[derive(Default)]
+struct VoiceLoss {
+  received: u64,
+  lost: u64,
+  last_seq: Option<SeqNum>,
+}
+
+/// Counted as the server goes, cheap enough to keep with or without the endpoint.
+#[derive(Default)]
+pub struct Metrics {
+  packets_received: AtomicU64,
+  bytes_received: AtomicU64,
+  packets_sent: AtomicU64,
+  bytes_sent: AtomicU64,
+  send_failures: AtomicU64,
+  /// Voice packets queued for recipients.
+  voice_relayed: AtomicU64,
+  /// Voice packets dropped because a recipient's queue was full.
+  voice_dropped: AtomicU64,
+  /// Packets dropped for going over a rate limit.
+  rate_limited: AtomicU64,
+  /// Times clients were muted for flooding.
+  flood_mutes: AtomicU64,
+  relay_time: Histogram,
+  broadcast_time: Histogram,
+  loss: Mutex<HashMap<Uuid, VoiceLoss>>,
+}
+
+impl Metrics {
+  pub fn received(&self, bytes: usize) {
+    self.packets_received.fetch_add(1, Ordering::Relaxed);
+    self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  pub fn sent(&self, bytes: usize) {
+    self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  pub fn send_failed(&self) {
+    self.send_failures.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn relayed(&self, recipients: usize, dropped: usize, took: Duration) {
+    self.voice_relayed.fetch_add(recipients as u64, Ordering::Relaxed);
+    self.voice_dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+    self.relay_time.observe(took);
+  }
+
+  pub fn rate_limited(&self) {
+    self.rate_limited.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn flood_muted(&self) {
+    self.flood_mutes.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn broadcast(&self, took: Duration) {
+    self.broadcast_time.observe(took);
+  }
+
+  /// Counts a voice packet from `user`, noting any skipped sequence numbers as lost.
+  pub fn voice(&self, user: Uuid, seq: SeqNum) {
+    let mut loss = self.loss.lock().unwrap();
+    let entry = loss.entry(user).or_default();
+    entry.received += 1;
+    if let Some(last) = entry.last_seq {
+      let gap = seq.wrapping_sub(last);
+      // late or duplicated, already counted as lost if it was skipped over
+      if gap == 0 || gap > u16::MAX / 2 {return;}
+      // a bigger jump is a restarted client, not loss
+      if gap <= MAX_GAP {
+        entry.lost += gap as u64 - 1;
+      }
+    }
+    entry.last_seq = Some(seq);
+  }
+
+  /// Forgets the loss of users who are no longer connected.
+  pub fn retain_users(&self, connected: impl Fn(&Uuid) -> bool) {
+    self.loss.lock().unwrap().retain(|id, _| connected(id));
+  }
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+  /// Everything, in Prometheus' text exposition format.
+  pub fn render(&self, users: &HashMap<SocketAddr, User>) -> String {
+    let mut out = String::new();
+    let mut rooms = users.values().filter_map(|u| u.room.as_deref()).collect::<Vec<_>>();
+    rooms.sort_unstable();
+    rooms.dedup();
+    gauge(&mut out, "voice_users", "Users connected", users.len() as u64);
+    gauge(&mut out, "voice_rooms", "Rooms with anyone in them", rooms.len() as u64);
+    counter(&mut out, "voice_packets_received_total", "Packets received", &self.packets_received);
+    counter(&mut out, "voice_bytes_received_total", "Bytes received", &self.bytes_received);
+    counter(&mut out, "voice_packets_sent_total", "Packets sent", &self.packets_sent);
+    counter(&mut out, "voice_bytes_sent_total", "Bytes sent", &self.bytes_sent);
+    counter(&mut out, "voice_send_failures_total", "Packets that could not be sent", &self.send_failures);
+    counter(&mut out, "voice_relayed_total", "Voice packets queued for recipients", &self.voice_relayed);
+    counter(&mut out, "voice_relay_dropped_total", "Voice packets dropped for recipients that fell behind", &self.voice_dropped);
+    counter(&mut out, "voice_rate_limited_total", "Packets dropped for going over a rate limit", &self.rate_limited);
+    counter(&mut out, "voice_flood_mutes_total", "Times clients were muted for flooding", &self.flood_mutes);
+
+    writeln!(out, "# HELP voice_fanout_seconds Time to send one message to everyone it was for").unwrap();
+    writeln!(out, "# TYPE voice_fanout_seconds histogram").unwrap();
+    self.relay_time.render(&mut out, "voice_fanout_seconds", "kind=\"relay\"");
+    self.broadcast_time.render(&mut out, "voice_fanout_seconds", "kind=\"broadcast\"");
+
+    let loss = self.loss.lock().unwrap();
+    let labelled = users.values()
+      .filter_map(|user| Some((format!("id=\"{}\",user=\"{}\"", user.id, escape(&user.username)), loss.get(&user.id)?)))
+      .collect::<Vec<_>>();
+    writeln!(out, "# HELP voice_user_packets_received_total Voice packets received from each user").unwrap();
+    writeln!(out, "# TYPE voice_user_packets_received_total counter").unwrap();
+    for (labels, loss) in &labelled {
+      writeln!(out, "voice_user_packets_received_total{{{}}} {}", labels, loss.received).unwrap();
+    }
+    writeln!(out, "# HELP voice_user_packets_lost_total Voice packets from each user that never arrived").unwrap();
+    writeln!(out, "# TYPE voice_user_packets_lost_total counter").unwrap();
+    for (labels, loss) in &labelled {
+      writeln!(out, "voice_user_packets_lost_total{{{}}} {}", labels, loss.lost).unwrap();
+    }
+    out
+  }
+}
+
+#[cfg(feature = "metrics")]
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+  writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value).unwrap();
+}
+
+#[cfg(feature = "metrics")]
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+  writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value.load(Ordering::Relaxed)).unwrap();
+}
+
+#[cfg(feature = "metrics")]
+/// A label value, escaped as the text format wants.
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(feature = "metrics")]
+/// Serves `/metrics` on `addr` from a thread of its own.
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>, users: Arc<Mutex<HashMap<SocketAddr, User>>>) -> std::io::Result<()> {
+  let listener = TcpListener::bind(addr)?;
+  info!("Serving metrics on http://{}/metrics", addr);
+  std::thread::spawn(move || {
+    for stream in listener.incoming().flatten() {
+      if let Err(e) = respond(&stream, &metrics, &users) {
+        warn!("Failed to serve metrics to {:?}: {}", stream.peer_addr(), e);
+      }
+    }
+  });
+  Ok(())
+}
+
+#[cfg(feature = "metrics")]
+fn respond(mut stream: &TcpStream, metrics: &Metrics, users: &Mutex<HashMap<SocketAddr, User>>) -> std::io::Result<()> {
+  stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+  let mut request = String::new();
+  BufReader::new(stream).read_line(&mut request)?;
+  let (status, body) = match request.split_whitespace().nth(1) {
+    Some("/metrics") => ("200 OK", metrics.render(&users.lock().unwrap())),
+    _ => ("404 Not Found", "try /metrics\n".to_string()),
+  };
+  write!(
+    stream,
+    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status, body.len(), body,
+  )?;
+  stream.flush()
+}
@@ -0,0 +1,315 @@
+use std::{collections::HashMap, net::IpAddr, time::{Duration, Instant}};
+
+/// Most sources of unauthenticated packets tracked at once, so spoofed addresses can't
+/// grow [`SourceLimiter`] without bound.
+const MAX_SOURCES: usize = 4096;
+/// How long a client has to stay within its limits for one time it was muted to be forgiven,
+/// so only one that keeps flooding is kicked, not one that's been connected a long time.
+const MUTE_FORGIVEN_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// Allows `rate` per second on average, in bursts of up to `capacity`.
+#[derive(Debug)]
+pub struct TokenBucket {
+  capacity: f32,
+  rate: f32,
+  tokens: f32,
+  updated: Instant,
+}
+
+impl TokenBucket {
+  /// A full bucket, holding a second's worth of tokens.
+  pub fn new(rate: f32) -> Self {
+    Self { capacity: rate, rate, tokens: rate, updated: Instant::now() }
+  }
+
+  /// Takes `amount` tokens if there are enough.
+  pub fn take(&mut self, amount: f32) -> bool {
+    self.refill();
+    if self.tokens < amount {return false;}
+    self.tokens -= amount;
+    true
+  }
+
+  /// Allows `rate` per second from now on, keeping however many tokens it has up to the new capacity.
+  pub fn set_rate(&mut self, rate: f32) {
+    self.refill();
+    self.capacity = rate;
+    self.rate = rate;
+    self.tokens = self.tokens.min(rate);
+  }
+
+  /// Whether it has filled back up, so it's no different from a new one.
+  pub fn is_full(&mut self) -> bool {
+    self.refill();
+    self.tokens >= self.capacity
+  }
+
+  fn refill(&mut self) {
+    let now = Instant::now();
+    self.tokens = (self.tokens + self.rate * now.duration_since(self.updated).as_secs_f32()).min(self.capacity);
+    self.updated = now;
+  }
+}
+
+/// Limits packets we can't tie to a session yet, per source, so one address flooding
+/// (or spoofing) them can't lock everyone else out of connecting. A limit on them all
+/// together backs that up, for when they come from too many sources to track.
+#[derive(Debug)]
+pub struct SourceLimiter {
+  per_source: f32,
+  sources: HashMap<IpAddr, TokenBucket>,
+  total: TokenBucket,
+}
+
+impl SourceLimiter {
+  pub fn new(per_source: f32, total: f32) -> Self {
+    Self { per_source, sources: HashMap::new(), total: TokenBucket::new(total) }
+  }
+
+  /// Counts a packet from `ip`, returning whether it's within the limits.
+  pub fn check(&mut self, ip: IpAddr) -> bool {
+    let source = source(ip);
+    // past so many, only the total limits new ones until some go quiet
+    let tracked = self.sources.len() < MAX_SOURCES || self.sources.contains_key(&source);
+    let per_source = self.per_source;
+    let allowed = !tracked || self.sources.entry(source).or_insert_with(|| TokenBucket::new(per_source)).take(1.0);
+    allowed && self.total.take(1.0)
+  }
+
+  /// Forgets sources that have stayed within their limit long enough to be back where they started.
+  pub fn forget_idle(&mut self) {
+    self.sources.retain(|_, bucket| !bucket.is_full());
+  }
+}
+
+/// What unauthenticated packets from `ip` are counted against: the address itself for IPv4,
+/// and its /64 for IPv6, which one host can usually pick any address in.
+fn source(ip: IpAddr) -> IpAddr {
+  match ip.to_canonical() {
+    IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !(u64::MAX as u128)).into()),
+    ip => ip,
+  }
+}
+
+/// What to do with a packet from a client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+  Allow,
+  /// Over the limit, drop it.
+  Drop,
+  /// Over the limit after behaving for a while, drop it and mute them.
+  Mute,
+  /// Flooding again and again, kick them.
+  Kick,
+}
+
+/// Packet and byte limits for one address, and how it's been behaving.
+#[derive(Debug)]
+pub struct RateLimiter {
+  packets: TokenBucket,
+  bytes: TokenBucket,
+  /// Voice is dropped until then, after going over the limit.
+  muted_until: Option<Instant>,
+  /// Times they've been muted for flooding.
+  pub mutes: usize,
+  /// Packets dropped for going over the limit.
+  pub dropped: usize,
+  /// Value of `dropped` when it was last logged.
+  pub reported: usize,
+  pub last_packet: Instant,
+}
+
+impl RateLimiter {
+  pub fn new(packets_per_sec: f32, bytes_per_sec: f32) -> Self {
+    Self {
+      packets: TokenBucket::new(packets_per_sec),
+      bytes: TokenBucket::new(bytes_per_sec),
+      muted_until: None,
+      mutes: 0,
+      dropped: 0,
+      reported: 0,
+      last_packet: Instant::now(),
+    }
+  }
+
+  /// Changes the limits, keeping how they've been behaving.
+  pub fn set_rates(&mut self, packets_per_sec: f32, bytes_per_sec: f32) {
+    self.packets.set_rate(packets_per_sec);
+    self.bytes.set_rate(bytes_per_sec);
+  }
+
+  /// Counts a packet of `size` bytes against the limits.
+  /// Going over mutes them for `mute`, and doing so more than `max_mutes` times gets them kicked.
+  pub fn check(&mut self, size: usize, mute: Duration, max_mutes: usize) -> Verdict {
+    self.last_packet = Instant::now();
+    if self.packets.take(1.0) && self.bytes.take(size as f32) {
+      return Verdict::Allow;
+    }
+    self.dropped += 1;
+    if !self.is_muted() {
+      if let Some(until) = self.muted_until {
+        let forgiven = Instant::now().saturating_duration_since(until).as_secs() / MUTE_FORGIVEN_AFTER.as_secs();
+        self.mutes = self.mutes.saturating_sub(forgiven as usize);
+      }
+      self.mutes += 1;
+      self.muted_until = Some(Instant::now() + mute);
+      if self.mutes > max_mutes {
+        return Verdict::Kick;
+      }
+      return Verdict::Mute;
+    }
+    Verdict::Drop
+  }
+
+  pub fn is_muted(&self) -> bool {
+    self.muted_until.is_some_and(|until| Instant::now() < until)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MUTE: Duration = Duration::from_secs(10);
+
+  #[test]
+  fn buckets_refill_at_their_rate() {
+    let mut bucket = TokenBucket::new(10.0);
+    assert!(bucket.take(10.0));
+    assert!(!bucket.take(1.0));
+    bucket.updated -= Duration::from_millis(500);
+    assert!(bucket.take(5.0));
+    assert!(!bucket.take(1.0));
+    // but never past a second's worth
+    bucket.updated -= Duration::from_secs(10);
+    assert!(bucket.is_full());
+    assert!(!bucket.take(11.0));
+  }
+
+  /// Sends until the limiter stops allowing packets, returning what it said then.
+  fn burst(limiter: &mut RateLimiter) -> Verdict {
+    loop {
+      match limiter.check(10, MUTE, 2) {
+        Verdict::Allow => continue,
+        verdict => return verdict,
+      }
+    }
+  }
+
+  /// As if the mute ended `ago`, and the buckets have filled back up since.
+  fn unmute(limiter: &mut RateLimiter, ago: Duration) {
+    limiter.muted_until = Some(Instant::now() - ago);
+    limiter.packets.updated -= Duration::from_secs(1);
+    limiter.bytes.updated -= Duration::from_secs(1);
+  }
+
+  #[test]
+  fn flooding_mutes_then_drops() {
+    let mut limiter = RateLimiter::new(10.0, 1000.0);
+    assert_eq!(burst(&mut limiter), Verdict::Mute);
+    assert!(limiter.is_muted());
+    assert_eq!(limiter.check(10, MUTE, 2), Verdict::Drop);
+    assert_eq!(limiter.mutes, 1);
+    assert_eq!(limiter.dropped, 2);
+  }
+
+  #[test]
+  fn bytes_are_limited_too() {
+    let mut limiter = RateLimiter::new(10.0, 100.0);
+    assert_eq!(limiter.check(60, MUTE, 2), Verdict::Allow);
+    assert_eq!(limiter.check(60, MUTE, 2), Verdict::Mute);
+  }
+
+  #[test]
+  fn flooding_again_and_again_kicks() {
+    let mut limiter = RateLimiter::new(10.0, 1000.0);
+    assert_eq!(burst(&mut limiter), Verdict::Mute);
+    unmute(&mut limiter, Duration::ZERO);
+    assert!(!limiter.is_muted());
+    assert_eq!(burst(&mut limiter), Verdict::Mute);
+    unmute(&mut limiter, Duration::ZERO);
+    assert_eq!(burst(&mut limiter), Verdict::Kick);
+  }
+
+  #[test]
+  fn changing_rates_keeps_the_mute() {
+    let mut limiter = RateLimiter::new(10.0, 1000.0);
+    assert_eq!(burst(&mut limiter), Verdict::Mute);
+    limiter.set_rates(20.0, 2000.0);
+    assert!(limiter.is_muted());
+    assert_eq!(limiter.mutes, 1);
+    // the new rate, but starting from the empty bucket
+    assert_eq!(limiter.check(10, MUTE, 2), Verdict::Drop);
+    limiter.packets.updated -= Duration::from_millis(500);
+    limiter.bytes.updated -= Duration::from_millis(500);
+    assert!((0..10).all(|_| limiter.check(10, MUTE, 2) == Verdict::Allow));
+  }
+
+  #[test]
+  fn old_mutes_are_forgiven() {
+    let mut limiter = RateLimiter::new(10.0, 1000.0);
+    assert_eq!(burst(&mut limiter), Verdict::Mute);
+    unmute(&mut limiter, Duration::ZERO);
+    assert_eq!(burst(&mut limiter), Verdict::Mute);
+    unmute(&mut limiter, MUTE_FORGIVEN_AFTER);
+    assert_eq!(burst(&mut limiter), Verdict::Mute);
+    assert_eq!(limiter.mutes, 2);
+  }
+
+  fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+  }
+
+  /// Takes everything `source` is allowed right now.
+  fn flood(limiter: &mut SourceLimiter, source: IpAddr) {
+    while limiter.check(source) {}
+  }
+
+  #[test]
+  fn one_source_flooding_leaves_others_alone() {
+    let mut limiter = SourceLimiter::new(10.0, 100.0);
+    flood(&mut limiter, ip("192.0.2.1"));
+    assert!(!limiter.check(ip("192.0.2.1")));
+    assert!(limiter.check(ip("192.0.2.2")));
+  }
+
+  #[test]
+  fn ipv6_is_limited_by_prefix() {
+    let mut limiter = SourceLimiter::new(10.0, 100.0);
+    flood(&mut limiter, ip("2001:db8::1"));
+    assert!(!limiter.check(ip("2001:db8::2")));
+    assert!(limiter.check(ip("2001:db8:0:1::1")));
+    // an IPv4 client on a dual-stack socket is still limited by its IPv4 address
+    flood(&mut limiter, ip("192.0.2.1"));
+    assert!(!limiter.check(ip("::ffff:192.0.2.1")));
+  }
+
+  #[test]
+  fn the_total_backs_them_up() {
+    let mut limiter = SourceLimiter::new(10.0, 25.0);
+    let allowed = (0..30).filter(|n| limiter.check(IpAddr::from([192, 0, 2, *n]))).count();
+    assert_eq!(allowed, 25);
+  }
+
+  #[test]
+  fn untracked_sources_still_get_the_total() {
+    let mut limiter = SourceLimiter::new(1.0, 1_000_000.0);
+    for n in 0..MAX_SOURCES as u32 {
+      limiter.check(IpAddr::from((0x0a00_0000 + n).to_be_bytes()));
+    }
+    assert!(limiter.check(ip("192.0.2.1")));
+    assert!(limiter.check(ip("192.0.2.1")));
+    assert_eq!(limiter.sources.len(), MAX_SOURCES);
+  }
+
+  #[test]
+  fn idle_sources_are_forgotten() {
+    let mut limiter = SourceLimiter::new(10.0, 100.0);
+    flood(&mut limiter, ip("192.0.2.1"));
+    limiter.check(ip("192.0.2.2"));
+    // as if a second has gone by for the one that only sent a packet
+    limiter.sources.get_mut(&ip("192.0.2.2")).unwrap().updated -= Duration::from_secs(1);
+    limiter.forget_idle();
+    assert_eq!(limiter.sources.keys().collect::<Vec<_>>(), [&ip("192.0.2.1")]);
+  }
+}
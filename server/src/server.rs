@@ -1,18 +1,82 @@
-use std::{net::{UdpSocket, SocketAddr}, collections::{LinkedList, HashMap}, sync::{Arc, Mutex}, time::Instant};
+use std::{net::{UdpSocket, SocketAddr}, collections::{BTreeMap, HashMap, HashSet, VecDeque}, io::Write, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicU32, AtomicUsize, Ordering}, mpsc::{self, Receiver, Sender}}, time::{Duration, Instant, SystemTime}};
 
-use common::{packets::{self, ClientMessage, ServerMessage, LeaveReason}, UserInfo};
+use common::{crypto::{self, Identity, KeyExchange, Password, PublicKeyBytes, Session, Side}, fragment::{Fragment, Reassembler}, packets::{self, ClientMessage, ServerMessage, LeaveReason}, reliable::ReliableChannel, wire::{ClientVoice, ServerVoice}, UserInfo, UserState, Role, RoomInfo, AdminCommand, AdminUserInfo, DownlinkStats};
 use log::{info, debug, error, warn};
+use socket2::{Domain, Protocol, Socket, Type};
 use uuid::Uuid;
 
-use crate::config::ServerConfig;
+use crate::{config::{ConfigSource, ServerConfig}, deadair::{Change, DeadAirDetector}, events::ServerEvent, metrics::Metrics, ratelimit::{RateLimiter, SourceLimiter, Verdict}, snapshot::{Snapshot, MAX_SAVED_ROOMS}, speakers::LoudestSpeakers};
+
+/// How fast a user's loudness falls off once they stop talking, in dB per second.
+const LOUDNESS_DECAY: f32 = 40.0;
+
+/// A user's recent loudness in dBov, from the levels their client reports.
+#[derive(Debug)]
+#[derive(Copy, Clone)]
+pub struct Loudness {
+  db: f32,
+  updated: Instant,
+}
+
+impl Loudness {
+  pub fn silent() -> Self {
+    Self { db: -127.0, updated: Instant::now() }
+  }
+
+  /// When the user last sent voice, or connected if they haven't yet.
+  pub fn updated(&self) -> Instant {
+    self.updated
+  }
+
+  pub fn current(&self) -> f32 {
+    self.db - LOUDNESS_DECAY * self.updated.elapsed().as_secs_f32()
+  }
+
+  /// Takes in a reported level in -dBov.
+  pub fn update(&mut self, level: u8) {
+    self.db = self.current().max(-(level as f32));
+    self.updated = Instant::now();
+  }
+}
+
+/// Most users sent in a single [`ServerMessage::UserList`] packet.
+const USER_LIST_CHUNK: usize = 16;
+
+/// Packets per second taken from each source without a session, plenty to connect,
+/// resume or move with, but not to flood.
+const UNKNOWN_PACKETS_PER_SOURCE: f32 = 20.0;
+/// Packets per second taken from senders without a session, all of them together,
+/// in case they come from more sources than can be tracked.
+const UNKNOWN_PACKETS_PER_SEC: f32 = 2000.0;
+
+/// How often to look for reliable messages that need sending again.
+const RETRANSMIT_CHECK: Duration = Duration::from_millis(20);
 
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct User {
   pub id: Uuid,
   pub username: String,
+  pub role: Role,
   pub addr: SocketAddr,
+  /// Secret used to move the session to a new address.
+  pub connection_id: u64,
+  /// Secret a restarted client uses to take the session back.
+  pub resume_token: u64,
   pub last_reply: Instant,
+  pub loudness: Loudness,
+  pub dead_air: DeadAirDetector,
+  /// Room the user is talking in, if any.
+  pub room: Option<String>,
+  pub state: UserState,
+  /// An operator has stopped relaying their voice.
+  pub server_muted: bool,
+  /// Whether they've agreed to the rules, or there aren't any. Their voice and chat go nowhere until then.
+  pub accepted_rules: bool,
+  /// Our half and theirs of the key exchange, to answer a repeated connect the same way.
+  pub public_keys: (PublicKeyBytes, PublicKeyBytes),
+  /// Long-term key the client proved it holds, which names it across connections.
+  pub identity: PublicKeyBytes,
 }
 
 impl User {
@@ -20,27 +84,161 @@ impl User {
     UserInfo {
       id: self.id,
       username: self.username.clone(),
+      role: self.role,
+      state: self.state,
     }
   }
 }
 
+/// Voice packets waiting to be sent to a single recipient, and what didn't get to them.
+#[derive(Default)]
+struct OutboundQueue {
+  packets: VecDeque<Vec<u8>>,
+  stats: DownlinkStats,
+  /// Value of `stats` when it was last logged.
+  reported: DownlinkStats,
+}
+
 pub struct Server {
   pub config: ServerConfig,
+  /// Our long-term key, which clients pin, see [`ServerConfig::key_file`].
+  identity: Identity,
+  /// The configured password, stretched once for checking credentials with.
+  password: Option<Password>,
   socket: Option<UdpSocket>,
   users: Arc<Mutex<HashMap<SocketAddr,User>>>,
+  /// Transport encryption keys, by the address of the user they belong to.
+  sessions: Mutex<HashMap<SocketAddr, Session>>,
+  /// Control messages in flight each way, by the address of the user they're with.
+  links: Mutex<HashMap<SocketAddr, ReliableChannel<ServerMessage, ClientMessage>>>,
+  /// Outstanding migration challenges, by the unknown address they were sent to.
+  challenges: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+  /// Sessions that timed out, by resume token, kept a while in case the client comes back.
+  suspended: Mutex<HashMap<u64, (User, Instant)>>,
+  /// Flood protection, per sender, counting only packets that opened under their session key
+  /// so nobody can get someone else muted by spoofing their address.
+  limits: Mutex<HashMap<SocketAddr, RateLimiter>>,
+  /// Flood protection for everything else, kept apart so spoofed addresses can't grow [`Server::limits`].
+  unknown_limits: Mutex<SourceLimiter>,
+  /// Voice packets waiting to be sent, per recipient, so one stalled link can't hold up the room.
+  outbound: Mutex<HashMap<SocketAddr, OutboundQueue>>,
+  /// Who's relayed in large rooms.
+  speakers: Mutex<LoudestSpeakers>,
+  /// Messages arriving in pieces, by the address of the user sending them.
+  fragments: Mutex<HashMap<SocketAddr, Reassembler>>,
+  /// Numbers the next message we have to send in pieces.
+  next_fragment: AtomicU32,
+  /// Counts passes over the outbound queues, to start each at a different recipient.
+  next_flush: AtomicUsize,
   running: bool,
+  /// Where to reload the config from, and the file's last change we've applied.
+  config_source: Option<(ConfigSource, Option<SystemTime>)>,
+  /// Traffic and timings since the server started.
+  metrics: Arc<Metrics>,
+  /// Everyone following [`ServerEvent`]s, dropped once they stop listening.
+  subscribers: Mutex<Vec<Sender<ServerEvent>>>,
+  /// Usernames that have agreed to the rules.
+  accepted_rules: Mutex<HashSet<String>>,
+  /// Saved rooms and who's muted, kept in the state file.
+  saved: Mutex<Snapshot>,
+  /// Writes the state file in the background, so saving doesn't hold up packets.
+  saver: Option<Sender<Snapshot>>,
 }
 
 impl Server {
   pub fn new(config: ServerConfig) -> Self {
+    let accepted_rules = config.rules_accepted_file.as_deref().map(load_accepted_rules).unwrap_or_default();
+    let saved = match config.state_file.as_deref().map(Snapshot::load) {
+      Some(Ok(saved)) => saved,
+      Some(Err(e)) => {
+        error!("Failed to read the state file, starting with no saved rooms or mutes: {}", e);
+        Snapshot::default()
+      },
+      None => Snapshot::default(),
+    };
+    let identity = match config.key_file.as_deref().map(load_identity) {
+      Some(Ok(identity)) => identity,
+      Some(Err(e)) => {
+        error!("Failed to read the key file, clients that pinned our key will refuse this one: {}", e);
+        Identity::generate()
+      },
+      None => Identity::generate(),
+    };
+    let password = config.password.as_deref().map(Password::stretch);
+    let saver = config.state_file.clone().map(spawn_saver);
     Server {
       config,
+      identity,
+      password,
       socket: None,
       users: Arc::new(Mutex::new(HashMap::new())),
+      sessions: Mutex::new(HashMap::new()),
+      links: Mutex::new(HashMap::new()),
+      challenges: Mutex::new(HashMap::new()),
+      suspended: Mutex::new(HashMap::new()),
+      limits: Mutex::new(HashMap::new()),
+      unknown_limits: Mutex::new(SourceLimiter::new(UNKNOWN_PACKETS_PER_SOURCE, UNKNOWN_PACKETS_PER_SEC)),
+      outbound: Mutex::new(HashMap::new()),
+      speakers: Mutex::new(LoudestSpeakers::default()),
+      fragments: Mutex::new(HashMap::new()),
+      next_fragment: AtomicU32::new(0),
+      next_flush: AtomicUsize::new(0),
       running: false,
+      config_source: None,
+      metrics: Arc::new(Metrics::default()),
+      subscribers: Mutex::new(Vec::new()),
+      accepted_rules: Mutex::new(accepted_rules),
+      saved: Mutex::new(saved),
+      saver,
     }
   }
 
+  /// Follows what happens on the server from here on.
+  /// Call before [`Server::start`], which doesn't return.
+  pub fn subscribe(&self) -> Receiver<ServerEvent> {
+    let (tx, rx) = mpsc::channel();
+    self.subscribers.lock().unwrap().push(tx);
+    rx
+  }
+
+  fn emit(&self, event: ServerEvent) {
+    self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+  }
+
+
+  /// Reloads the config whenever its file changes.
+  pub fn watch_config(&mut self, source: ConfigSource) {
+    let modified = source.modified();
+    self.config_source = Some((source, modified));
+  }
+
+  fn reload_config(&mut self) {
+    let (source, applied) = match &mut self.config_source {
+      Some(source) => source,
+      None => return,
+    };
+    let modified = source.modified();
+    if modified == *applied {return;}
+    *applied = modified;
+    match source.load() {
+      Ok(config) => {
+        if config.port != self.config.port || config.bind != self.config.bind {
+          warn!("Changing the address from {} to {} needs a restart",
+            SocketAddr::new(self.config.bind, self.config.port), SocketAddr::new(config.bind, config.port));
+        }
+        if config.password != self.config.password {
+          self.password = config.password.as_deref().map(Password::stretch);
+        }
+        self.config = ServerConfig { bind: self.config.bind, port: self.config.port, ..config };
+        for limit in self.limits.lock().unwrap().values_mut() {
+          limit.set_rates(self.config.max_packets_per_sec, self.config.max_bytes_per_sec);
+        }
+        info!("Reloaded config");
+        self.emit(ServerEvent::ConfigReloaded);
+      },
+      Err(e) => error!("Failed to reload config, keeping the old one: {}", e),
+    }
+  }
 
   pub fn start(&mut self) {
     if self.running {
@@ -52,38 +250,145 @@ impl Server {
     self.service();
   }
   
+  /// The user at `addr`, if any, noting that they're still there.
+  fn heard_from(&self, addr: SocketAddr) -> Option<User> {
+    let mut users = self.users.lock().unwrap();
+    let mut user = users.get_mut(&addr);
+    if let Some(user) = user.as_mut() {
+      user.last_reply = Instant::now();
+    }
+    user.cloned()
+  }
+
   fn handle_command(&self, addr: SocketAddr, command: ClientMessage) {
-    let user = {
-      let mut users = self.users.lock().unwrap();
-      let mut user = users.get_mut(&addr);
-      if let Some(user) = user.as_mut() {
-        user.last_reply = Instant::now();
-      }
-      user.cloned()
-    };
+    let user = self.heard_from(addr);
     match command {
-      ClientMessage::Connect { username } => {
-        if user.is_some() {
+      ClientMessage::Connect { version, username, role, resume_token, public_key, identity, credential } => {
+        if version != packets::PROTOCOL_VERSION {
+          self.reject_version(addr, version);
+          return;
+        }
+        if !packets::is_valid_username(&username) {
+          info!("Refusing {}: invalid username {:?}", addr, username);
+          self.reject(addr, &format!("usernames must be 1 to {} bytes, without control characters", packets::MAX_USERNAME_LEN));
+          return;
+        }
+        if let Some(password) = &self.password {
+          if !credential.is_some_and(|credential| password.verify(public_key, &credential)) {
+            info!("Refusing {}: wrong password", addr);
+            self.reject(addr, if credential.is_some() {"wrong password"} else {"this server needs a password"});
+            return;
+          }
+        }
+        // the client didn't hear our answer, so give it again rather than starting over
+        if let Some(user) = user.as_ref().filter(|user| user.public_keys.1 == public_key && user.identity == identity) {
+          debug!("Answering a repeated connect from {}", addr);
+          self.send_plain(addr, &ServerMessage::ConnectAck {
+            server_version: packets::PROTOCOL_VERSION,
+            accepted: true,
+            reason: None,
+            public_key: user.public_keys.0,
+            client_key: user.public_keys.1,
+            identity: self.identity.public_key(),
+          });
+          self.send_handshake(user, &self.users.lock().unwrap());
+          return;
+        }
+        // a client that crashed and came back (likely from a new port) takes over its stale session
+        // with its resume token, anyone else under the same name and address has to wait for it to time out
+        let resumed = resume_token.and_then(|token| self.take_session(token, identity));
+        // everyone else still thinks a live session is here, so there's nothing to announce
+        let announce = !matches!(resumed, Some((_, true)));
+        let resuming = resumed.is_some();
+        if let Some((session, live)) = &resumed {
+          info!("'{}' resumed their session from {}", session.username, addr);
+          if *live && session.addr != addr {
+            self.sessions.lock().unwrap().remove(&session.addr);
+            self.links.lock().unwrap().remove(&session.addr);
+            self.outbound.lock().unwrap().remove(&session.addr);
+          }
+        } else if user.is_some() {
           error!("Connection from {} already exists", addr);
           return;
         }
         let mut users = self.users.lock().unwrap();
-        let user = User {
-          id: Uuid::new_v4(),
-          username: username.clone(),
-          addr,
-          last_reply: Instant::now(),
-        };
-        info!("'{}' ({}) connected", &username, users.len());
-        // TODO: change response from pong to something more important
-        self.send(addr, ServerMessage::Pong).unwrap();
-        for u in users.values() {
-          self.send(user.addr, ServerMessage::Connected(u.info())).unwrap();
+        // someone taking back their own session doesn't need a new place
+        if resumed.is_none() && users.len() >= self.config.max_users {
+          info!("Refusing {}: server is full ({} users)", addr, users.len());
+          self.reject(addr, "the server is full");
+          return;
         }
+        let exchange = KeyExchange::new();
+        let agreed = match (exchange.agree(identity), self.identity.agree(public_key)) {
+          (Some(theirs), Some(ours)) => [theirs, ours],
+          _ => {
+            info!("Refusing {}: invalid identity", addr);
+            self.reject(addr, "invalid identity");
+            return;
+          },
+        };
+        let public_keys = (exchange.public_key(), public_key);
+        let (server_muted, saved_role) = {
+          let saved = self.saved.lock().unwrap();
+          let identity = crypto::fingerprint(identity);
+          (saved.server_muted.contains(&identity), saved.roles.get(&identity).copied())
+        };
+        let user = match resumed {
+          Some((session, _)) => User {
+            addr,
+            public_keys,
+            connection_id: rand::random(),
+            resume_token: rand::random(),
+            last_reply: Instant::now(),
+            loudness: Loudness::silent(),
+            dead_air: DeadAirDetector::new(),
+            ..session
+          },
+          None => User {
+            id: Uuid::new_v4(),
+            username: username.clone(),
+            role: saved_role.unwrap_or(role),
+            addr,
+            connection_id: rand::random(),
+            resume_token: rand::random(),
+            last_reply: Instant::now(),
+            loudness: Loudness::silent(),
+            dead_air: DeadAirDetector::new(),
+            room: self.config.rooms.first().cloned(),
+            state: UserState::default(),
+            server_muted,
+            accepted_rules: self.config.rules.is_none() || self.accepted_rules.lock().unwrap().contains(&crypto::fingerprint(identity)),
+            public_keys,
+            identity,
+          },
+        };
+        info!("'{}' ({}) connected", &user.username, users.len());
+        self.send_plain(addr, &ServerMessage::ConnectAck {
+          server_version: packets::PROTOCOL_VERSION,
+          accepted: true,
+          reason: None,
+          public_key: exchange.public_key(),
+          client_key: public_key,
+          identity: self.identity.public_key(),
+        });
+        self.sessions.lock().unwrap().insert(addr, exchange.finish(public_key, Side::Server, &agreed));
+        // the client numbers its messages from the start again
+        self.links.lock().unwrap().remove(&addr);
+        self.outbound.lock().unwrap().remove(&addr);
+        self.send_handshake(&user, &users);
         users.insert(addr, user.clone());
         info!("{} users connected", users.len());
         drop(users);
-        self.broadcast(ServerMessage::Connected (user.info()), Some(addr));
+        self.emit(ServerEvent::UserJoined { user: user.info(), addr, resumed: resuming });
+        if announce {
+          self.broadcast(ServerMessage::Connected (user.info()), Some(addr));
+        }
+        if let Some(room) = &user.room {
+          self.send_room_state(room);
+        }
+        if user.server_muted {
+          self.send(addr, ServerMessage::ServerMuted { muted: true });
+        }
       },
       ClientMessage::Disconnect => {
         if let Some(user) = user {
@@ -92,52 +397,914 @@ impl Server {
           info!("'{}' ({}) disconnected", &user.username, users.len());
           drop(users);
           self.broadcast(ServerMessage::Disconnected(user.info(), LeaveReason::Disconnect), None);
+          self.emit(ServerEvent::UserLeft { user: user.info(), reason: LeaveReason::Disconnect });
+          if let Some(room) = &user.room {
+            self.send_room_state(room);
+          }
         }
       },
-      ClientMessage::Ping => {
+      ClientMessage::Ping { id } => {
+        if user.is_none() {
+          self.challenge(addr);
+          return;
+        }
+        self.send(addr, ServerMessage::Pong { id });
+      },
+      ClientMessage::Voice { seq, samples, level } => self.handle_voice(addr, user, ClientVoice { seq, level, samples: &samples }),
+      ClientMessage::Migrate { connection_id, challenge } => {
+        if user.is_some() {return;}
+        // the echoed challenge proves the new address is reachable, not just spoofed
+        match self.challenges.lock().unwrap().remove(&addr) {
+          Some((expected, _)) if expected == challenge => {},
+          _ => {
+            warn!("Invalid migration challenge from {}", addr);
+            return;
+          }
+        }
+        let mut users = self.users.lock().unwrap();
+        let old_addr = users.values().find(|u| u.connection_id == connection_id).map(|u| u.addr);
+        match old_addr.and_then(|old_addr| users.remove(&old_addr)) {
+          Some(mut user) => {
+            info!("'{}' moved from {} to {}", &user.username, user.addr, addr);
+            let mut sessions = self.sessions.lock().unwrap();
+            if let Some(session) = sessions.remove(&user.addr) {
+              sessions.insert(addr, session);
+            }
+            drop(sessions);
+            let mut links = self.links.lock().unwrap();
+            if let Some(link) = links.remove(&user.addr) {
+              links.insert(addr, link);
+            }
+            drop(links);
+            let from = std::mem::replace(&mut user.addr, addr);
+            user.last_reply = Instant::now();
+            let info = user.info();
+            users.insert(addr, user);
+            drop(users);
+            self.emit(ServerEvent::UserMigrated { user: info, from, to: addr });
+          },
+          None => warn!("Migration from {} to unknown connection", addr),
+        }
+      },
+      ClientMessage::JoinRoom { room } => {
         if user.is_none() {return;}
-        self.send(addr, ServerMessage::Pong).unwrap();
+        self.move_to_room(addr, Some(room));
       },
-      ClientMessage::Voice { samples } => {
+      ClientMessage::LeaveRoom => {
         if user.is_none() {return;}
-        self.broadcast(ServerMessage::Voice { user: user.unwrap().id, samples }, Some(addr));
-        // self.broadcast(ServerMessage::Voice { user: user.unwrap().id, samples }, None);
+        self.move_to_room(addr, None);
+      },
+      ClientMessage::ListRooms => {
+        if user.is_none() {return;}
+        self.send(addr, ServerMessage::RoomList(self.room_list()));
+      },
+      ClientMessage::SetState(state) => {
+        let id = {
+          let mut users = self.users.lock().unwrap();
+          match users.get_mut(&addr) {
+            Some(user) if user.state != state => {
+              user.state = state;
+              user.id
+            },
+            _ => return,
+          }
+        };
+        self.broadcast(ServerMessage::StateChanged { user: id, state }, Some(addr));
+      },
+      ClientMessage::ListUsers => {
+        if user.is_none() {return;}
+        self.send_user_list(addr, &self.users.lock().unwrap());
+      },
+      ClientMessage::Chat { text } => {
+        let user = match user {
+          Some(user) => user,
+          None => return,
+        };
+        if text.len() > packets::MAX_CHAT_LEN {
+          warn!("Dropped oversized chat message from '{}'", user.username);
+          return;
+        }
+        if !user.accepted_rules {
+          debug!("Dropped chat from '{}', who hasn't accepted the rules", user.username);
+          return;
+        }
+        if let Some(room) = &user.room {
+          self.send_to_room(room, ServerMessage::Chat { from: user.info(), text }, Some(addr));
+        }
+      },
+      ClientMessage::Report { user: reported, reason } => {
+        let user = match user {
+          Some(user) => user,
+          None => return,
+        };
+        self.emit(ServerEvent::Reported { by: user.info(), user: reported, reason: reason.clone() });
+        let users = self.users.lock().unwrap();
+        match users.values().find(|u| u.id == reported) {
+          // no audio is kept, so note what the reported user was doing instead
+          Some(reported) => warn!(target: "audit",
+            "'{}' ({}) reported '{}' ({}) in room {:?}: {:?} [loudness {:.0} dB, last spoke {:.1}s ago]",
+            user.username, user.id, reported.username, reported.id, reported.room, reason,
+            reported.loudness.current(), reported.loudness.updated().elapsed().as_secs_f32(),
+          ),
+          None => warn!(target: "audit",
+            "'{}' ({}) reported {}, who isn't connected: {:?}",
+            user.username, user.id, reported, reason,
+          ),
+        }
+      },
+      ClientMessage::Reliable { seq, message } => {
+        if user.is_none() {
+          self.challenge(addr);
+          return;
+        }
+        let ready = match self.links.lock().unwrap().entry(addr).or_default().receive(seq, *message) {
+          Some(ready) => ready,
+          // not acked, so they'll send it again once there's room
+          None => {
+            debug!("Refusing reliable message {} from {}, too far ahead", seq, addr);
+            return;
+          },
+        };
+        // acked even if we've seen it, our last ack may have been lost
+        self.send(addr, ServerMessage::Ack { seq });
+        for message in ready {
+          if !message.is_reliable() {
+            warn!("Ignoring {:?} from {}, it can't be sent reliably", message, addr);
+            continue;
+          }
+          self.handle_command(addr, message);
+        }
+      },
+      ClientMessage::Ack { seq } => {
+        if let Some(link) = self.links.lock().unwrap().get_mut(&addr) {
+          link.ack(seq);
+        }
+      },
+      ClientMessage::AcceptRules => {
+        let user = match self.users.lock().unwrap().get_mut(&addr) {
+          Some(user) if !user.accepted_rules => {
+            user.accepted_rules = true;
+            user.clone()
+          },
+          _ => return,
+        };
+        info!("'{}' ({}) accepted the rules", user.username, addr);
+        self.remember_accepted_rules(&user);
+        self.emit(ServerEvent::RulesAccepted { user: user.info() });
+      },
+      ClientMessage::Admin { token, command } => {
+        let user = match user {
+          Some(user) => user,
+          None => return,
+        };
+        if !self.config.admin_token.as_deref().is_some_and(|expected| tokens_match(expected, &token)) {
+          warn!(target: "audit", "'{}' ({}) sent an admin command with the wrong token", user.username, user.id);
+          self.send(addr, ServerMessage::AdminResult { ok: false, message: "wrong admin token".to_string() });
+          return;
+        }
+        warn!(target: "audit", "'{}' ({}) ran {:?}", user.username, user.id, command);
+        self.emit(ServerEvent::Admin { by: user.info(), command: command.clone() });
+        self.admin(addr, command);
+      },
+      // opened and put back together in `service`, and never nested
+      ClientMessage::Sealed { .. } | ClientMessage::Fragment(_) => {},
+    }
+  }
+
+  /// Relays voice from `addr` to their room, if they're allowed to be heard.
+  fn handle_voice(&self, addr: SocketAddr, user: Option<User>, voice: ClientVoice) {
+    let user = match user {
+      Some(user) => user,
+      None => return self.challenge(addr),
+    };
+    if user.role == Role::Audience {
+      debug!("Ignoring voice from audience member {}", addr);
+      return;
+    }
+    if user.server_muted || !user.accepted_rules {return;}
+    // too big for the room to get in one datagram
+    if voice.samples.len() > packets::MAX_VOICE_PAYLOAD {
+      debug!("Dropping {} bytes of voice from {}, over the limit of {}", voice.samples.len(), addr, packets::MAX_VOICE_PAYLOAD);
+      return;
+    }
+    if self.limits.lock().unwrap().get(&addr).is_some_and(RateLimiter::is_muted) {
+      return;
+    }
+    self.metrics.voice(user.id, voice.seq);
+    let room = match &user.room {
+      Some(room) => room,
+      None => return,
+    };
+    if !self.check_dead_air(addr, voice.level) {return;}
+    if !self.update_speaker(addr, room, voice.level) {return;}
+    self.relay(ServerVoice { user: user.id, seq: voice.seq, samples: voice.samples }, room, Some(addr));
+  }
+
+  /// Carries out an operator's command, answering `addr` with how it went.
+  fn admin(&self, addr: SocketAddr, command: AdminCommand) {
+    let result = match command {
+      AdminCommand::ListUsers => {
+        let users = {
+          let users = self.users.lock().unwrap();
+          let outbound = self.outbound.lock().unwrap();
+          users.values()
+            .map(|user| AdminUserInfo {
+              info: user.info(),
+              addr: user.addr,
+              room: user.room.clone(),
+              server_muted: user.server_muted,
+              downlink: outbound.get(&user.addr).map(|queue| queue.stats).unwrap_or_default(),
+            })
+            .collect()
+        };
+        self.send(addr, ServerMessage::AdminUsers(users));
+        return;
+      },
+      AdminCommand::Kick { user } => {
+        let target = self.users.lock().unwrap().values().find(|u| u.id == user).map(|u| u.addr);
+        match target {
+          Some(target) => {
+            self.kick(target, "an operator's request");
+            Ok(format!("kicked {}", user))
+          },
+          None => Err(format!("{} isn't connected", user)),
+        }
+      },
+      AdminCommand::Mute { user, muted } => {
+        let target = self.users.lock().unwrap().values_mut().find(|u| u.id == user).map(|u| {
+          u.server_muted = muted;
+          (u.addr, crypto::fingerprint(u.identity))
+        });
+        match target {
+          Some((target, identity)) => {
+            // kept by identity, so reconnecting under another name doesn't get around it,
+            // though a client that doesn't keep its identity between runs starts afresh
+            self.update_saved(|saved| if muted {saved.server_muted.insert(identity)} else {saved.server_muted.remove(&identity)});
+            self.send(target, ServerMessage::ServerMuted { muted });
+            Ok(format!("{} {}", if muted {"muted"} else {"unmuted"}, user))
+          },
+          None => Err(format!("{} isn't connected", user)),
+        }
+      },
+      AdminCommand::SetRole { user, role } => {
+        let target = self.users.lock().unwrap().values_mut().find(|u| u.id == user).map(|u| {
+          u.role = role;
+          (u.room.clone(), crypto::fingerprint(u.identity))
+        });
+        match target {
+          Some((room, identity)) => {
+            self.update_saved(|saved| saved.roles.insert(identity, role) != Some(role));
+            if let Some(room) = room {
+              self.send_room_state(&room);
+            }
+            Ok(format!("{} is now {:?}", user, role))
+          },
+          None => Err(format!("{} isn't connected", user)),
+        }
+      },
+      AdminCommand::Announce { text } => {
+        if text.len() > packets::MAX_CHAT_LEN {
+          Err(format!("announcements can be at most {} bytes", packets::MAX_CHAT_LEN))
+        } else {
+          self.broadcast(ServerMessage::Announcement { text }, None);
+          Ok("announced".to_string())
+        }
+      },
+      AdminCommand::ForgetRoom { room } => {
+        if self.update_saved(|saved| {
+          let before = saved.rooms.len();
+          saved.rooms.retain(|r| *r != room);
+          saved.rooms.len() != before
+        }) {
+          Ok(format!("forgot room {:?}", room))
+        } else {
+          Err(format!("{:?} isn't a saved room", room))
+        }
+      },
+      AdminCommand::SaveRoom { room } => {
+        if self.config.rooms.contains(&room) {
+          Err(format!("{:?} is already in the config", room))
+        } else if self.config.state_file.is_none() {
+          Err("there's no state_file to save rooms in".to_string())
+        } else if self.remember_room(&room) {
+          Ok(format!("saved room {:?}", room))
+        } else {
+          Err(format!("{:?} wasn't saved, there are already {} saved rooms", room, MAX_SAVED_ROOMS))
+        }
+      },
+    };
+    let (ok, message) = match result {
+      Ok(message) => (true, message),
+      Err(message) => (false, message),
+    };
+    self.send(addr, ServerMessage::AdminResult { ok, message });
+  }
+
+  /// Moves a user into a room (or out of their room), letting everyone in the rooms involved know.
+  fn move_to_room(&self, addr: SocketAddr, room: Option<String>) {
+    let (info, old_room) = {
+      let mut users = self.users.lock().unwrap();
+      let user = match users.get_mut(&addr) {
+        Some(user) => user,
+        None => return,
+      };
+      if user.room == room {return;}
+      info!("'{}' moved from room {:?} to {:?}", user.username, user.room, room);
+      (user.info(), std::mem::replace(&mut user.room, room.clone()))
+    };
+    if let Some(room) = room.as_ref().filter(|room| self.config.save_user_rooms && !self.config.rooms.contains(room)) {
+      self.remember_room(room);
+    }
+    self.emit(ServerEvent::UserMoved { user: info, from: old_room.clone(), to: room.clone() });
+    if let Some(old_room) = old_room {
+      self.send_room_state(&old_room);
+    }
+    if let Some(room) = room {
+      self.send_room_state(&room);
+    }
+  }
+
+  /// Saves that `user` has agreed to the rules, so they aren't asked again.
+  /// Kept by identity, so taking the name of someone who has doesn't get around them.
+  fn remember_accepted_rules(&self, user: &User) {
+    let identity = crypto::fingerprint(user.identity);
+    if !self.accepted_rules.lock().unwrap().insert(identity.clone()) {return;}
+    let path = match &self.config.rules_accepted_file {
+      Some(path) => path,
+      None => return,
+    };
+    let appended = std::fs::OpenOptions::new().create(true).append(true).open(path)
+      .and_then(|mut file| writeln!(file, "{}", identity));
+    if let Err(e) = appended {
+      error!("Failed to save that '{}' accepted the rules to {:?}: {}", user.username, path, e);
+    }
+  }
+
+  /// Saves a room, so it's still listed once everyone leaves and after a restart.
+  /// Returns whether it's saved, having been already or not.
+  fn remember_room(&self, room: &str) -> bool {
+    if self.config.state_file.is_none() {return false;}
+    let mut full = false;
+    self.update_saved(|saved| {
+      if saved.has_room(room) {return false;}
+      if saved.rooms.len() >= MAX_SAVED_ROOMS {
+        warn!("Not saving room {:?}, there are already {} saved rooms", room, MAX_SAVED_ROOMS);
+        full = true;
+        return false;
+      }
+      saved.rooms.push(room.to_string());
+      true
+    });
+    !full
+  }
+
+  /// Changes what's in the state file, saving it in the background if `change` says it changed anything.
+  fn update_saved(&self, change: impl FnOnce(&mut Snapshot) -> bool) -> bool {
+    let mut saved = self.saved.lock().unwrap();
+    if !change(&mut saved) {return false;}
+    if let Some(saver) = &self.saver {
+      if saver.send(saved.clone()).is_err() {
+        error!("Failed to save rooms and mutes, the saving thread has stopped");
+      }
+    }
+    true
+  }
+
+  /// Tells a newly accepted user their id and who's already here.
+  fn send_handshake(&self, user: &User, users: &HashMap<SocketAddr, User>) {
+    let roster = users.values().filter(|u| u.addr != user.addr).map(User::info).collect::<Vec<_>>();
+    let (first, rest) = roster.split_at(roster.len().min(USER_LIST_CHUNK));
+    self.send(user.addr, ServerMessage::Handshake {
+      your_id: user.id,
+      users: first.to_vec(),
+      server_name: self.config.name.clone(),
+      motd: self.config.motd.clone(),
+      rules: if user.accepted_rules {None} else {self.config.rules.clone()},
+      connection_id: user.connection_id,
+      resume_token: user.resume_token,
+      join_defaults: self.config.join_defaults(user.room.as_deref()),
+    });
+    self.send_roster(user.addr, rest);
+  }
+
+  /// Sends someone everyone else on the server, in as many packets as it takes.
+  fn send_user_list(&self, addr: SocketAddr, users: &HashMap<SocketAddr, User>) {
+    let roster = users.values().filter(|u| u.addr != addr).map(User::info).collect::<Vec<_>>();
+    self.send_roster(addr, &roster);
+  }
+
+  /// Takes the session a resume token belongs to, if it has the same identity, and whether it was still live.
+  fn take_session(&self, token: u64, identity: PublicKeyBytes) -> Option<(User, bool)> {
+    let mut users = self.users.lock().unwrap();
+    if let Some(addr) = users.values().find(|u| u.resume_token == token && u.identity == identity).map(|u| u.addr) {
+      return users.remove(&addr).map(|user| (user, true));
+    }
+    drop(users);
+    let mut suspended = self.suspended.lock().unwrap();
+    if suspended.get(&token).is_none_or(|(user, _)| user.identity != identity) {return None;}
+    suspended.remove(&token)
+      .filter(|(_, since)| since.elapsed() < self.config.resume_window)
+      .map(|(user, _)| (user, false))
+  }
+
+  /// Sends a roster as [`ServerMessage::UserList`]s, always ending with a `complete` one.
+  fn send_roster(&self, addr: SocketAddr, roster: &[UserInfo]) {
+    let mut chunks = roster.chunks(USER_LIST_CHUNK).peekable();
+    if chunks.peek().is_none() {
+      self.send(addr, ServerMessage::UserList { users: Vec::new(), complete: true });
+    }
+    while let Some(chunk) = chunks.next() {
+      self.send(addr, ServerMessage::UserList { users: chunk.to_vec(), complete: chunks.peek().is_none() });
+    }
+  }
+
+  /// Sends everyone in a room its current member list.
+  fn send_room_state(&self, room: &str) {
+    let members = self.users.lock().unwrap().values()
+      .filter(|u| u.room.as_deref() == Some(room))
+      .map(User::info)
+      .collect();
+    let defaults = self.config.join_defaults(Some(room));
+    self.send_to_room(room, ServerMessage::RoomState { room: room.to_string(), users: members, defaults }, None);
+  }
+
+  /// Sends a message straight to everyone in a room, unlike [`Server::relay`] which queues it.
+  fn send_to_room(&self, room: &str, command: ServerMessage, ignore: Option<SocketAddr>) {
+    let users = self.users.lock().unwrap();
+    for member in users.values().filter(|u| u.room.as_deref() == Some(room)) {
+      if Some(member.addr) == ignore {continue;}
+      self.send(member.addr, command.clone());
+    }
+  }
+
+  /// Configured rooms, plus any that users have made, with how many people are in them.
+  fn room_list(&self) -> Vec<RoomInfo> {
+    let users = self.users.lock().unwrap();
+    let saved = self.saved.lock().unwrap();
+    let mut rooms = self.config.rooms.iter().chain(&saved.rooms).map(|room| (room.as_str(), 0)).collect::<BTreeMap<_, _>>();
+    for room in users.values().filter_map(|u| u.room.as_deref()) {
+      *rooms.entry(room).or_default() += 1;
+    }
+    rooms.into_iter().map(|(name, users)| RoomInfo { name: name.to_string(), users }).collect()
+  }
+
+  /// Records how loud a user is talking, returning whether they're loud enough to be relayed.
+  ///
+  /// In large rooms only the loudest few speakers are relayed, to keep everyone's downlink manageable.
+  fn update_speaker(&self, addr: SocketAddr, room: &str, level: u8) -> bool {
+    let loudness = match self.users.lock().unwrap().get_mut(&addr) {
+      Some(user) => {
+        user.loudness.update(level);
+        user.loudness
+      },
+      None => return false,
+    };
+    self.speakers.lock().unwrap().update(addr, room, loudness, self.config.max_relayed_speakers)
+  }
+
+  /// Works out again which rooms are large and who's loudest in them.
+  fn recount_speakers(&self) {
+    let users = self.users.lock().unwrap();
+    let members = users.values().filter_map(|user| Some((user.addr, user.room.as_deref()?, user.loudness)));
+    self.speakers.lock().unwrap().recount(members, self.config.large_room_threshold, self.config.max_relayed_speakers);
+  }
+
+  /// Tells a user when their voice has stopped changing, as from a broken mic,
+  /// and whether it should still be relayed.
+  fn check_dead_air(&self, addr: SocketAddr, level: u8) -> bool {
+    let (change, dead, info) = match self.users.lock().unwrap().get_mut(&addr) {
+      Some(user) => (user.dead_air.update(level, self.config.dead_air), user.dead_air.dead, user.info()),
+      None => return false,
+    };
+    let suppressed = self.config.suppress_dead_air;
+    match change {
+      Change::None => {},
+      Change::Dead => {
+        info!("'{}' ({}) has been sending the same level for {:?}{}", info.username, addr, self.config.dead_air,
+          if suppressed {", no longer relaying them"} else {""});
+        self.send(addr, ServerMessage::DeadAir { active: true, suppressed });
+        self.emit(ServerEvent::DeadAir { user: info, active: true });
+      },
+      Change::Alive => {
+        info!("'{}' ({}) is sending live audio again", info.username, addr);
+        self.send(addr, ServerMessage::DeadAir { active: false, suppressed: false });
+        self.emit(ServerEvent::DeadAir { user: info, active: false });
       },
-      _ => {}
     }
+    !(suppressed && dead)
+  }
+
+  /// Asks an unknown address to prove it owns an existing session.
+  fn challenge(&self, addr: SocketAddr) {
+    let mut challenges = self.challenges.lock().unwrap();
+    // only one challenge per address at a time, so we don't answer every voice packet
+    if challenges.contains_key(&addr) {return;}
+    let challenge = rand::random();
+    challenges.insert(addr, (challenge, Instant::now()));
+    drop(challenges);
+    self.send(addr, ServerMessage::Challenge { challenge });
   }
 
-  fn send(&self, addr: SocketAddr, command: ServerMessage) -> Result<usize, std::io::Error>{
-    self.socket.as_ref().unwrap().send_to(&command.to_bytes(), addr)
+  /// Sends a message to `addr`.
+  ///
+  /// Failures are logged and counted rather than returned, so that one
+  /// unreachable client can't take down the service loop.
+  /// Messages are sealed if `addr` has a session, see [`common::crypto`],
+  /// and control messages are sent until acknowledged, see [`common::reliable`].
+  fn send(&self, addr: SocketAddr, command: ServerMessage) {
+    let sessions = self.sessions.lock().unwrap();
+    let session = match sessions.get(&addr) {
+      Some(session) => session,
+      None => {
+        drop(sessions);
+        return self.send_plain(addr, &command);
+      },
+    };
+    let command = if command.is_reliable() {
+      let seq = self.links.lock().unwrap().entry(addr).or_default().send(command.clone());
+      ServerMessage::Reliable { seq, message: Box::new(command) }
+    } else {
+      command
+    };
+    let sealed = command.seal(session);
+    drop(sessions);
+    self.send_plain(addr, &sealed);
+  }
+
+  /// Sends again whatever reliable messages haven't been acknowledged in time,
+  /// timing out anyone who's stopped acknowledging them.
+  fn retransmit(&self) {
+    let (due, broken) = {
+      let sessions = self.sessions.lock().unwrap();
+      let mut links = self.links.lock().unwrap();
+      let due = links.iter_mut()
+        .flat_map(|(addr, link)| link.due().into_iter().map(move |(seq, message)| (*addr, seq, message)))
+        .filter_map(|(addr, seq, message)| {
+          let session = sessions.get(&addr)?;
+          Some((addr, ServerMessage::Reliable { seq, message: Box::new(message) }.seal(session)))
+        })
+        .collect::<Vec<_>>();
+      let broken = links.iter().filter(|(_, link)| link.is_broken()).map(|(addr, _)| *addr).collect::<Vec<_>>();
+      links.retain(|_, link| !link.is_broken());
+      (due, broken)
+    };
+    if !broken.is_empty() {
+      let users = {
+        let mut users = self.users.lock().unwrap();
+        broken.iter().filter_map(|addr| users.remove(addr)).collect()
+      };
+      self.time_out(users);
+    }
+    // sending can take the outbound lock, so only once the others are released
+    for (addr, sealed) in due {
+      self.outbound.lock().unwrap().entry(addr).or_default().stats.retransmits += 1;
+      self.send_plain(addr, &sealed);
+    }
+  }
+
+  /// Sends a message without encrypting it, only for before a session exists.
+  /// Messages too big for one datagram go in pieces, see [`common::fragment`].
+  fn send_plain(&self, addr: SocketAddr, command: &ServerMessage) {
+    let datagrams = command.to_datagrams(self.next_fragment.fetch_add(1, Ordering::Relaxed));
+    if datagrams.is_empty() {
+      error!("Not sending a message to {}, it's too big even in pieces", addr);
+    }
+    for bytes in datagrams {
+      match self.socket.as_ref().unwrap().send_to(&bytes, addr) {
+        Ok(_) => self.metrics.sent(bytes.len()),
+        Err(e) => {
+          self.metrics.send_failed();
+          self.outbound.lock().unwrap().entry(addr).or_default().stats.send_failures += 1;
+          warn!("Failed to send packet to {}: {}", addr, e);
+        },
+      }
+    }
+  }
+
+  /// Adds a piece of a message from `addr`, returning the message once it's whole.
+  /// Only users can send in pieces, so strangers can't have us hold on to their junk.
+  fn reassemble(&self, addr: SocketAddr, fragment: Fragment) -> Option<ClientMessage> {
+    if !self.sessions.lock().unwrap().contains_key(&addr) {
+      debug!("Ignoring a fragment from {}, who has no session", addr);
+      return None;
+    }
+    ClientMessage::reassemble(self.fragments.lock().unwrap().entry(addr).or_default(), fragment)
+  }
+
+  /// Decrypts a packet, with the keys for the session it names.
+  /// The session is usually the one at `addr`, unless the client's address just changed.
+  fn open(&self, addr: SocketAddr, id: u64, counter: u64, payload: &[u8]) -> Option<Vec<u8>> {
+    let opened = self.sessions.lock().unwrap().get(&addr)
+      .filter(|session| session.id() == id)
+      .map(|session| session.open(counter, payload));
+    match opened {
+      Some(Some(plaintext)) => return Some(plaintext),
+      // junk, however it's addressed, costs the sender like anything else we can't place
+      Some(None) => {
+        self.rate_limit_unknown(addr);
+        return None;
+      },
+      None => {},
+    }
+    // a client that moved, or junk, either way only worth a search within the limits
+    if !self.rate_limit_unknown(addr) {return None;}
+    let sessions = self.sessions.lock().unwrap();
+    sessions.values().find(|session| session.id() == id)?.open(counter, payload)
+  }
+
+  fn reject_version(&self, addr: SocketAddr, version: u16) {
+    info!("Refusing {}: protocol version {} (we speak {})", addr, version, packets::PROTOCOL_VERSION);
+    self.reject(addr, &format!(
+      "incompatible protocol version {}, this server requires version {}",
+      version, packets::PROTOCOL_VERSION,
+    ));
+  }
+
+  /// Refuses a [`ClientMessage::Connect`].
+  fn reject(&self, addr: SocketAddr, reason: &str) {
+    self.emit(ServerEvent::Refused { addr, reason: reason.to_string() });
+    self.send_plain(addr, &ServerMessage::ConnectAck {
+      server_version: packets::PROTOCOL_VERSION,
+      accepted: false,
+      reason: Some(reason.to_string()),
+      public_key: [0; 32],
+      client_key: [0; 32],
+      identity: self.identity.public_key(),
+    });
   }
 
   fn broadcast(&self, command: ServerMessage, ignore: Option<SocketAddr>) {
-    self.users.lock().unwrap().iter().for_each(|(addr, user)| {
+    let start = Instant::now();
+    self.users.lock().unwrap().keys().for_each(|addr| {
       if Some(addr) == ignore.as_ref() {return;}
-      self.send(*addr, command.clone()).unwrap();
-    })
+      self.send(*addr, command.clone());
+    });
+    self.metrics.broadcast(start.elapsed());
+  }
+
+  /// Queues voice for everyone in a room, dropping their oldest queued packet if they've fallen behind.
+  fn relay(&self, voice: ServerVoice, room: &str, ignore: Option<SocketAddr>) {
+    let start = Instant::now();
+    let voice = voice.encode();
+    let (mut recipients, mut dropped) = (0, 0);
+    let users = self.users.lock().unwrap();
+    let mut outbound = self.outbound.lock().unwrap();
+    let sessions = self.sessions.lock().unwrap();
+    for (addr, user) in users.iter() {
+      if Some(addr) == ignore.as_ref() || user.room.as_deref() != Some(room) {continue;}
+      let packet = match sessions.get(addr) {
+        Some(session) => ServerMessage::seal_bytes(session, &voice).to_bytes(),
+        None => continue,
+      };
+      let queue = outbound.entry(*addr).or_default();
+      if queue.packets.len() >= self.config.outbound_queue_len {
+        queue.packets.pop_front();
+        queue.stats.queue_drops += 1;
+        dropped += 1;
+      }
+      queue.packets.push_back(packet);
+      recipients += 1;
+    }
+    self.metrics.relayed(recipients, dropped, start.elapsed());
+  }
+
+  /// Sends as many queued packets as the socket will take.
+  fn flush_outbound(&self) {
+    let socket = self.socket.as_ref().unwrap();
+    let users = self.users.lock().unwrap();
+    let mut outbound = self.outbound.lock().unwrap();
+    outbound.retain(|addr, _| users.contains_key(addr));
+    // start with someone new each pass, so when the socket fills up it isn't always the same people left waiting
+    let start = self.next_flush.fetch_add(1, Ordering::Relaxed) % outbound.len().max(1);
+    for (addr, queue) in outbound.iter_mut().skip(start) {
+      if !self.flush_queue(socket, addr, queue) {return;}
+    }
+    for (addr, queue) in outbound.iter_mut().take(start) {
+      if !self.flush_queue(socket, addr, queue) {return;}
+    }
+  }
+
+  /// Sends one recipient's queued packets, returning `false` if the socket was too full to take them all.
+  fn flush_queue(&self, socket: &UdpSocket, addr: &SocketAddr, queue: &mut OutboundQueue) -> bool {
+    while let Some(packet) = queue.packets.front() {
+      match socket.send_to(packet, addr) {
+        Ok(_) => self.metrics.sent(packet.len()),
+        // the socket buffer is full, try again on the next pass
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return false,
+        Err(e) => {
+          self.metrics.send_failed();
+          queue.stats.send_failures += 1;
+          warn!("Failed to send packet to {}: {}", addr, e);
+        }
+      }
+      queue.packets.pop_front();
+    }
+    true
+  }
+
+  /// Counts an authenticated packet against its sender's limits, kicking them if they keep flooding.
+  fn rate_limit(&self, addr: SocketAddr, size: usize) -> bool {
+    let verdict = self.limits.lock().unwrap()
+      .entry(addr)
+      .or_insert_with(|| RateLimiter::new(self.config.max_packets_per_sec, self.config.max_bytes_per_sec))
+      .check(size, self.config.flood_mute, self.config.max_flood_mutes);
+    if verdict != Verdict::Allow {
+      self.metrics.rate_limited();
+    }
+    match verdict {
+      Verdict::Allow => true,
+      Verdict::Drop => false,
+      Verdict::Mute => {
+        self.metrics.flood_muted();
+        let user = self.users.lock().unwrap().get(&addr).map(User::info);
+        self.emit(ServerEvent::RateLimited { addr, user });
+        false
+      },
+      Verdict::Kick => {
+        self.kick(addr, "flooding");
+        false
+      },
+    }
+  }
+
+  /// Tells everyone that `users`, already removed, timed out, keeping their sessions to be resumed.
+  fn time_out(&self, users: Vec<User>) {
+    // broadcast takes the users lock, so this must happen after it is released
+    for user in users {
+      info!("'{}' timed out.", user.username);
+      self.broadcast(ServerMessage::Disconnected(user.info(), LeaveReason::Timeout), None);
+      self.emit(ServerEvent::UserTimedOut { user: user.info() });
+      if let Some(room) = &user.room {
+        self.send_room_state(room);
+      }
+      self.suspended.lock().unwrap().insert(user.resume_token, (user, Instant::now()));
+    }
+  }
+
+  fn kick(&self, addr: SocketAddr, reason: &str) {
+    let user = match self.users.lock().unwrap().remove(&addr) {
+      Some(user) => user,
+      None => return,
+    };
+    warn!("Kicked '{}' ({}) for {}", user.username, addr, reason);
+    // still has a session, so they can be told why
+    self.send(addr, ServerMessage::Disconnected(user.info(), LeaveReason::Kicked));
+    self.broadcast(ServerMessage::Disconnected(user.info(), LeaveReason::Kicked), None);
+    self.emit(ServerEvent::UserLeft { user: user.info(), reason: LeaveReason::Kicked });
+    if let Some(room) = &user.room {
+      self.send_room_state(room);
+    }
+  }
+
+  /// Counts a packet not (yet) known to be from a session against its source's limit.
+  fn rate_limit_unknown(&self, addr: SocketAddr) -> bool {
+    if self.unknown_limits.lock().unwrap().check(addr.ip()) {return true;}
+    self.metrics.rate_limited();
+    debug!("Dropped a packet from {}, over the limit for unauthenticated packets", addr);
+    false
+  }
+
+  /// Logs senders that went over their limits since the last report, and forgets quiet ones.
+  fn report_flooding(&self) {
+    let users = self.users.lock().unwrap();
+    self.unknown_limits.lock().unwrap().forget_idle();
+    let mut limits = self.limits.lock().unwrap();
+    limits.retain(|addr, limit| users.contains_key(addr) || limit.last_packet.elapsed() < self.config.timeout);
+    for (addr, limit) in limits.iter_mut() {
+      if limit.dropped == limit.reported {continue;}
+      let username = users.get(addr).map(|u| u.username.as_str()).unwrap_or("?");
+      warn!("Dropped {} packets from '{}' ({}) over their rate limit ({} total, muted {} times)",
+        limit.dropped - limit.reported, username, addr, limit.dropped, limit.mutes);
+      limit.reported = limit.dropped;
+    }
+  }
+
+  /// Logs recipients we've failed to get packets to since the last report,
+  /// calling out those whose downlink looks broken rather than just slow.
+  fn report_downlinks(&self) {
+    let users = self.users.lock().unwrap();
+    let mut outbound = self.outbound.lock().unwrap();
+    let links = self.links.lock().unwrap();
+    for (addr, queue) in outbound.iter_mut() {
+      if queue.stats == queue.reported {continue;}
+      let (stats, since) = (queue.stats, queue.reported);
+      queue.reported = stats;
+      let username = match users.get(addr) {
+        Some(user) => user.username.as_str(),
+        None => continue,
+      };
+      let unacked = links.get(addr).map_or(0, |link| link.unacked());
+      let (dropped, failed, resent) = (
+        stats.queue_drops - since.queue_drops,
+        stats.send_failures - since.send_failures,
+        stats.retransmits - since.retransmits,
+      );
+      // nothing we send is getting through, as opposed to a link that can't keep up
+      if failed > 0 || (resent > 0 && unacked > 0) {
+        warn!(
+          "Downlink to '{}' ({}) looks broken: {} sends failed, {} control messages resent with {} still unacknowledged, {} voice packets dropped",
+          username, addr, failed, resent, unacked, dropped,
+        );
+      } else if dropped > 0 {
+        warn!("Dropped {} voice packets for '{}' ({} total), their link can't keep up", dropped, username, stats.queue_drops);
+      }
+    }
+  }
+
+  fn serve_metrics(&self) {
+    let addr = match self.config.metrics_addr {
+      Some(addr) => addr,
+      None => return,
+    };
+    #[cfg(feature = "metrics")]
+    if let Err(e) = crate::metrics::serve(addr, self.metrics.clone(), self.users.clone()) {
+      error!("Failed to serve metrics on {}: {}", addr, e);
+    }
+    #[cfg(not(feature = "metrics"))]
+    warn!("Not serving metrics on {}, the server was built without the `metrics` feature", addr);
+  }
+
+  /// Binds the configured address, falling back to IPv4 when the default
+  /// `::` isn't available because the system has no IPv6.
+  fn bind(&self) -> std::io::Result<UdpSocket> {
+    let addr = SocketAddr::new(self.config.bind, self.config.port);
+    match bind_udp(addr) {
+      Err(e) if self.config.bind == ServerConfig::new().bind => {
+        warn!("Failed to bind {} ({}), listening on IPv4 only", addr, e);
+        bind_udp(SocketAddr::new(std::net::Ipv4Addr::UNSPECIFIED.into(), self.config.port))
+      },
+      result => result,
+    }
   }
 
   fn service(&mut self) {
-    self.socket = Some(UdpSocket::bind(format!("0.0.0.0:{}", self.config.port))
-      .expect("Failed to bind socket"));
-    info!("Listening on port {}", self.config.port);
+    self.socket = Some(self.bind().expect("Failed to bind socket"));
+    info!("Listening on {}", self.socket.as_ref().unwrap().local_addr().expect("Failed to get socket address"));
+    info!("Server key is {}", crypto::fingerprint(self.identity.public_key()));
+    if self.config.key_file.is_none() {
+      warn!("No key_file is set, so the server key changes every start and clients that pinned it will refuse the new one");
+    }
+    self.serve_metrics();
 
     let mut last_heartbeat = Instant::now();
+    let mut last_retransmit = Instant::now();
 
-    let socket = self.socket.as_ref().unwrap();
+    // a handle of our own, so the config can change while we hold it
+    let socket = self.socket.as_ref().unwrap().try_clone().expect("Failed to clone socket");
     socket.set_nonblocking(true).expect("Failed to set socket to non-blocking");
 
     loop {
+      self.flush_outbound();
+      if last_retransmit.elapsed() >= RETRANSMIT_CHECK {
+        last_retransmit = Instant::now();
+        self.retransmit();
+      }
       let mut buf = [0; packets::PACKET_MAX_SIZE];
       match socket.recv_from(&mut buf) {
         Ok((bytes, addr)) => {
-          match packets::ClientMessage::from_bytes(&buf[..bytes]) {
-            Some(command) => {
+          self.metrics.received(bytes);
+          let message = match packets::ClientMessage::from_bytes(&buf[..bytes]) {
+            // a piece of a sealed message, which is only counted once it's whole and opened
+            Some(ClientMessage::Fragment(fragment)) => match self.reassemble(addr, fragment) {
+              Some(message) => Some(message),
+              None => continue,
+            },
+            message => message,
+          };
+          if !matches!(message, Some(ClientMessage::Sealed { .. })) && !self.rate_limit_unknown(addr) {continue;}
+          match message {
+            Some(ClientMessage::Sealed { session, counter, payload }) => {
+              let plaintext = match self.open(addr, session, counter, &payload) {
+                Some(plaintext) => plaintext,
+                None => {
+                  debug!("Dropped a packet from {} that failed to decrypt", addr);
+                  continue;
+                },
+              };
+              if !self.rate_limit(addr, payload.len()) {continue;}
+              // most of what we get, so it's relayed straight from the packet
+              if let Some(voice) = ClientVoice::decode(&plaintext) {
+                self.handle_voice(addr, self.heard_from(addr), voice);
+                continue;
+              }
+              match ClientMessage::from_bytes(&plaintext) {
+                Some(ClientMessage::Sealed { .. }) | None => debug!("Dropped a malformed packet from {}", addr),
+                Some(command) => self.handle_command(addr, command),
+              }
+            }
+            // everything else has to come through a session
+            Some(command @ ClientMessage::Connect { .. }) => {
               self.handle_command(addr, command);
             }
+            Some(_) => {
+              warn!("Ignoring unencrypted packet from {}", addr);
+            }
             None => {
-              error!("Failed to parse packet");
+              // a client too old or new for us to parse still deserves to know why it can't connect
+              match packets::ClientMessage::connect_version(&buf[..bytes]) {
+                Some(version) if version != packets::PROTOCOL_VERSION => self.reject_version(addr, version),
+                _ => error!("Failed to parse packet"),
+              }
             }
           }
         }
@@ -146,19 +1313,30 @@ impl Server {
             std::io::ErrorKind::WouldBlock => {
               if Instant::now().duration_since(last_heartbeat) <= self.config.heartbeat_interval { continue; }
               last_heartbeat = Instant::now();
-              let mut users = self.users.lock().unwrap();
-
-              let mut to_remove = Vec::new();
-              for (addr, user) in users.iter() {
-                if user.last_reply.elapsed() >= self.config.timeout {
-                  info!("'{}' timed out.", user.username);
-                  self.broadcast(ServerMessage::Disconnected(user.info(), LeaveReason::Timeout), None);
-                  to_remove.push(*addr);
-                }
-              }
-              for addr in to_remove {
-                users.remove(&addr);
+              let timed_out = {
+                let mut users = self.users.lock().unwrap();
+                let timed_out = users.values()
+                  .filter(|user| user.last_reply.elapsed() >= self.config.timeout)
+                  .map(|user| user.addr)
+                  .collect::<Vec<_>>();
+                timed_out.iter().filter_map(|addr| users.remove(addr)).collect::<Vec<_>>()
+              };
+              self.time_out(timed_out);
+              {
+                let users = self.users.lock().unwrap();
+                self.sessions.lock().unwrap().retain(|addr, _| users.contains_key(addr));
+                self.links.lock().unwrap().retain(|addr, _| users.contains_key(addr));
+                let mut fragments = self.fragments.lock().unwrap();
+                fragments.retain(|addr, _| users.contains_key(addr));
+                fragments.values_mut().for_each(Reassembler::expire);
+                self.metrics.retain_users(|id| users.values().any(|user| &user.id == id));
               }
+              self.suspended.lock().unwrap().retain(|_, (_, since)| since.elapsed() < self.config.resume_window);
+              self.challenges.lock().unwrap().retain(|_, (_, sent)| sent.elapsed() < self.config.timeout);
+              self.recount_speakers();
+              self.report_downlinks();
+              self.report_flooding();
+              self.reload_config();
             }
             _ => {
               error!("Failed to receive packet: {}", e);
@@ -168,4 +1346,351 @@ impl Server {
       }
     }
   }
-}
\ No newline at end of file
+}
+
+/// Identities saved by [`Server::remember_accepted_rules`], one fingerprint per line.
+/// Anything else, like the usernames older servers saved, is skipped.
+fn load_accepted_rules(path: &Path) -> HashSet<String> {
+  match std::fs::read_to_string(path) {
+    Ok(lines) => lines.lines().filter(|line| crypto::parse_fingerprint(line).is_some()).map(str::to_string).collect(),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+    Err(e) => {
+      error!("Failed to read who accepted the rules from {:?}, everyone will be asked again: {}", path, e);
+      HashSet::new()
+    },
+  }
+}
+
+/// The server's identity from `path`, made and saved there if there isn't one yet.
+fn load_identity(path: &Path) -> Result<Identity, anyhow::Error> {
+  match std::fs::read(path) {
+    Ok(bytes) => {
+      let secret = bytes.try_into().map_err(|_| anyhow::anyhow!("{:?} isn't a key", path))?;
+      Ok(Identity::from_bytes(secret))
+    },
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+      let identity = Identity::generate();
+      let mut options = std::fs::OpenOptions::new();
+      options.write(true).create_new(true);
+      #[cfg(unix)]
+      std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+      options.open(path)?.write_all(&identity.to_bytes())?;
+      info!("Made a new server key in {:?}", path);
+      Ok(identity)
+    },
+    Err(e) => Err(e.into()),
+  }
+}
+
+/// Writes each snapshot sent to it to `path`, skipping any that are already out of date.
+fn spawn_saver(path: PathBuf) -> Sender<Snapshot> {
+  let (tx, rx) = mpsc::channel::<Snapshot>();
+  std::thread::spawn(move || {
+    while let Ok(mut snapshot) = rx.recv() {
+      while let Ok(newer) = rx.try_recv() {
+        snapshot = newer;
+      }
+      if let Err(e) = snapshot.save(&path) {
+        error!("Failed to save rooms and mutes to {:?}: {}", path, e);
+      }
+    }
+  });
+  tx
+}
+
+/// Compares secrets in time that doesn't depend on where they differ.
+fn tokens_match(expected: &str, given: &str) -> bool {
+  expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Binds `addr`, taking IPv4 as well on IPv6 whatever the system's default for
+/// `IPV6_V6ONLY` is, as some (e.g. the BSDs, or Linux with `bindv6only` set) default to IPv6 only.
+fn bind_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+  let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+  if addr.is_ipv6() {
+    socket.set_only_v6(false)?;
+  }
+  socket.bind(&addr.into())?;
+  Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An IPv6 address, which an IPv4 socket always fails to send to.
+  fn unreachable() -> SocketAddr {
+    "[::1]:9".parse().unwrap()
+  }
+
+  fn server() -> Server {
+    let mut server = Server::new(ServerConfig::new());
+    server.socket = Some(UdpSocket::bind("127.0.0.1:0").unwrap());
+    server
+  }
+
+  fn listener() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    socket
+  }
+
+  fn user(addr: SocketAddr) -> User {
+    User {
+      id: Uuid::new_v4(),
+      username: addr.to_string(),
+      role: Role::Speaker,
+      addr,
+      connection_id: 0,
+      resume_token: 0,
+      last_reply: Instant::now(),
+      loudness: Loudness::silent(),
+      dead_air: DeadAirDetector::new(),
+      room: None,
+      state: UserState::default(),
+      server_muted: false,
+      accepted_rules: true,
+      public_keys: ([0; 32], [0; 32]),
+      identity: [0; 32],
+    }
+  }
+
+  fn send_failures(server: &Server, addr: SocketAddr) -> u64 {
+    server.outbound.lock().unwrap().get(&addr).map_or(0, |queue| queue.stats.send_failures)
+  }
+
+  #[test]
+  fn failed_sends_are_counted() {
+    let server = server();
+    server.send(unreachable(), ServerMessage::Pong { id: 1 });
+    server.send(unreachable(), ServerMessage::Pong { id: 2 });
+    assert_eq!(send_failures(&server, unreachable()), 2);
+  }
+
+  #[test]
+  fn broadcast_goes_on_after_a_failed_send() {
+    let server = server();
+    let listener = listener();
+    let reachable = listener.local_addr().unwrap();
+    {
+      let mut users = server.users.lock().unwrap();
+      users.insert(unreachable(), user(unreachable()));
+      users.insert(reachable, user(reachable));
+    }
+    server.broadcast(ServerMessage::Pong { id: 1 }, None);
+    let mut buf = [0; 1500];
+    let (len, _) = listener.recv_from(&mut buf).unwrap();
+    assert!(matches!(ServerMessage::from_bytes(&buf[..len]), Some(ServerMessage::Pong { id: 1 })));
+    assert_eq!(send_failures(&server, unreachable()), 1);
+    assert_eq!(send_failures(&server, reachable), 0);
+  }
+
+  #[test]
+  fn flush_goes_on_after_a_failed_send() {
+    let server = server();
+    let listener = listener();
+    let reachable = listener.local_addr().unwrap();
+    {
+      let mut users = server.users.lock().unwrap();
+      let mut outbound = server.outbound.lock().unwrap();
+      for addr in [unreachable(), reachable] {
+        users.insert(addr, user(addr));
+        outbound.entry(addr).or_default().packets.extend([vec![1], vec![2]]);
+      }
+    }
+    server.flush_outbound();
+    let mut buf = [0; 16];
+    for expected in [1, 2] {
+      let (len, _) = listener.recv_from(&mut buf).unwrap();
+      assert_eq!(&buf[..len], &[expected]);
+    }
+    assert_eq!(send_failures(&server, unreachable()), 2);
+    assert!(server.outbound.lock().unwrap().values().all(|queue| queue.packets.is_empty()));
+  }
+
+  #[test]
+  fn broken_links_time_out_without_hanging() {
+    let server = Arc::new(server());
+    let listener = listener();
+    let addr = listener.local_addr().unwrap();
+    server.users.lock().unwrap().insert(addr, user(addr));
+    {
+      // more unacknowledged than any client could still be catching up on
+      let mut links = server.links.lock().unwrap();
+      let link = links.entry(addr).or_default();
+      for id in 0..=common::reliable::WINDOW * 4 {
+        link.send(ServerMessage::Pong { id });
+      }
+    }
+    let (done, finished) = mpsc::channel();
+    let retransmitting = server.clone();
+    std::thread::spawn(move || {
+      retransmitting.retransmit();
+      done.send(()).unwrap();
+    });
+    finished.recv_timeout(Duration::from_secs(5)).expect("retransmit hung on a broken link");
+    assert!(server.users.lock().unwrap().is_empty());
+    assert_eq!(server.suspended.lock().unwrap().len(), 1);
+    assert!(server.links.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn junk_sealed_packets_count_against_their_source() {
+    let server = server();
+    let flooder = listener().local_addr().unwrap();
+    let other = SocketAddr::from(([127, 0, 0, 2], 4000));
+    for counter in 0..UNKNOWN_PACKETS_PER_SOURCE as u64 {
+      assert!(server.open(flooder, 42, counter, &[0; 64]).is_none());
+    }
+    assert!(!server.rate_limit_unknown(flooder));
+    assert!(server.rate_limit_unknown(other));
+  }
+
+  #[test]
+  fn rules_are_remembered_by_identity() {
+    let path = std::env::temp_dir().join(format!("rust-voice-accepted-{}", std::process::id()));
+    std::fs::write(&path, "someone\n").unwrap();
+    let mut config = ServerConfig::new();
+    config.rules = Some("Be nice".to_string());
+    config.rules_accepted_file = Some(path.clone());
+    let server = Server::new(config);
+    let mut accepted = user(unreachable());
+    accepted.identity = [7; 32];
+    server.remember_accepted_rules(&accepted);
+    let loaded = load_accepted_rules(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, HashSet::from([crypto::fingerprint([7; 32])]));
+  }
+
+  #[test]
+  fn usernames_that_break_lines_are_refused() {
+    let server = server();
+    let listener = listener();
+    let addr = listener.local_addr().unwrap();
+    server.handle_command(addr, ClientMessage::Connect {
+      version: packets::PROTOCOL_VERSION,
+      username: "someone\nelse".to_string(),
+      role: Role::Speaker,
+      resume_token: None,
+      public_key: [1; 32],
+      identity: [2; 32],
+      credential: None,
+    });
+    let mut buf = [0; 1500];
+    let (len, _) = listener.recv_from(&mut buf).unwrap();
+    assert!(matches!(ServerMessage::from_bytes(&buf[..len]), Some(ServerMessage::ConnectAck { accepted: false, .. })));
+    assert!(server.users.lock().unwrap().is_empty());
+  }
+
+  fn bound(bind: &str) -> std::io::Result<UdpSocket> {
+    let mut config = ServerConfig::new();
+    config.bind = bind.parse().unwrap();
+    config.port = 0;
+    Server::new(config).bind()
+  }
+
+  #[test]
+  fn listens_on_both_families_by_default() {
+    let socket = bound("::").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let mut buf = [0; 16];
+    for client in ["127.0.0.1", "::1"] {
+      let from = UdpSocket::bind((client, 0)).unwrap();
+      from.send_to(&[1], (client, port)).unwrap();
+      let (_, addr) = socket.recv_from(&mut buf).unwrap();
+      assert_eq!(addr.port(), from.local_addr().unwrap().port());
+    }
+  }
+
+  #[test]
+  fn takes_ipv4_whatever_the_system_default() {
+    let socket = bound("::").unwrap();
+    assert!(!socket2::SockRef::from(&socket).only_v6().unwrap());
+  }
+
+  #[test]
+  fn listens_on_only_the_configured_family() {
+    assert!(bound("127.0.0.1").unwrap().local_addr().unwrap().is_ipv4());
+    assert!(bound("::1").unwrap().local_addr().unwrap().is_ipv6());
+  }
+
+  #[test]
+  fn only_the_default_falls_back_to_ipv4() {
+    // a documentation address, which no interface has
+    assert!(bound("2001:db8::1").is_err());
+  }
+
+  /// A server that keeps its state in a file of its own.
+  fn saving_server(save_user_rooms: bool) -> (Server, PathBuf) {
+    let path = std::env::temp_dir().join(format!("rust-voice-state-{}-{}.toml", std::process::id(), rand::random::<u32>()));
+    let mut config = ServerConfig::new();
+    config.state_file = Some(path.clone());
+    config.save_user_rooms = save_user_rooms;
+    let mut server = Server::new(config);
+    server.socket = Some(UdpSocket::bind("127.0.0.1:0").unwrap());
+    (server, path)
+  }
+
+  /// Waits for the state file to say what's expected, as it's written in the background.
+  fn eventually_saved(path: &Path, expected: impl Fn(&Snapshot) -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(1) {
+      if Snapshot::load(path).is_ok_and(|saved| expected(&saved)) {
+        let _ = std::fs::remove_file(path);
+        return true;
+      }
+      std::thread::sleep(Duration::from_millis(10));
+    }
+    false
+  }
+
+  #[test]
+  fn rooms_users_make_arent_saved_by_default() {
+    let (server, path) = saving_server(false);
+    server.users.lock().unwrap().insert(unreachable(), user(unreachable()));
+    server.move_to_room(unreachable(), Some("Secret".to_string()));
+    assert!(!server.saved.lock().unwrap().has_room("Secret"));
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn rooms_users_make_are_saved_if_allowed() {
+    let (server, path) = saving_server(true);
+    server.users.lock().unwrap().insert(unreachable(), user(unreachable()));
+    server.move_to_room(unreachable(), Some("Music".to_string()));
+    assert!(eventually_saved(&path, |saved| saved.has_room("Music")));
+  }
+
+  #[test]
+  fn operators_save_rooms() {
+    let (server, path) = saving_server(false);
+    server.admin(unreachable(), AdminCommand::SaveRoom { room: "Lobby".to_string() });
+    server.admin(unreachable(), AdminCommand::SaveRoom { room: "Music".to_string() });
+    assert!(eventually_saved(&path, |saved| saved.rooms == ["Music"]));
+  }
+
+  #[test]
+  fn mutes_are_saved_by_identity() {
+    let (server, path) = saving_server(false);
+    let mut muted = user(unreachable());
+    muted.identity = [7; 32];
+    let id = muted.id;
+    server.users.lock().unwrap().insert(unreachable(), muted);
+    server.admin(unreachable(), AdminCommand::Mute { user: id, muted: true });
+    assert!(eventually_saved(&path, |saved| saved.server_muted.contains(&crypto::fingerprint([7; 32]))));
+  }
+
+  #[test]
+  fn roles_are_saved_by_identity() {
+    let (server, path) = saving_server(false);
+    let mut speaker = user(unreachable());
+    speaker.identity = [7; 32];
+    let id = speaker.id;
+    server.users.lock().unwrap().insert(unreachable(), speaker);
+    server.admin(unreachable(), AdminCommand::SetRole { user: id, role: Role::Audience });
+    assert_eq!(server.users.lock().unwrap()[&unreachable()].role, Role::Audience);
+    assert!(eventually_saved(&path, |saved| saved.roles.get(&crypto::fingerprint([7; 32])) == Some(&Role::Audience)));
+  }
+}
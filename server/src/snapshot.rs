@@ -0,0 +1,78 @@
+use std::{collections::{BTreeMap, BTreeSet}, path::Path};
+
+use common::Role;
+use serde::{Serialize, Deserialize};
+
+use crate::config::ServerConfig;
+
+/// Most rooms that are saved, so a state file can't grow without end.
+pub const MAX_SAVED_ROOMS: usize = 256;
+
+/// What changes on a running server that isn't in its config, saved so it survives
+/// a restart and can be moved to another server.
+///
+/// ```toml
+/// rooms = ["Music", "Late night"]
+/// server_muted = ["3b6a27bcceb6a42d62a3a8d02a6f0d73653215771de243a63ac048a18b59da29"]
+///
+/// [roles]
+/// 9f2c0d41b8e7a3562c1d94e08f7b6a25d3c8e1f04a9b72d6e5c3f8a1b0d4e729 = "Audience"
+/// ```
+///
+/// Mutes and roles are kept by identity, which a client only keeps across runs if it
+/// saves it (see the client's `set_identity`), so one that doesn't starts afresh.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Snapshot {
+  /// Rooms that aren't in the config, in the order they were saved.
+  pub rooms: Vec<String>,
+  /// Identities an operator has muted, see [`common::crypto::fingerprint`].
+  pub server_muted: BTreeSet<String>,
+  /// Roles operators gave, by identity, which win over the one a client connects as.
+  pub roles: BTreeMap<String, Role>,
+}
+
+impl Snapshot {
+  /// Nothing saved yet if the file doesn't exist.
+  pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+    match std::fs::read_to_string(path) {
+      Ok(text) => Ok(toml::from_str(&text)?),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Writes the whole file anew, so a crash part way leaves the old one.
+  pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, toml::to_string(self)?)?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+  }
+
+  /// Whether `room` is a saved one, rather than one from the config.
+  pub fn has_room(&self, room: &str) -> bool {
+    self.rooms.iter().any(|r| r == room)
+  }
+
+  /// Everything a server with `config` has, configured rooms included, to set up another with [`Snapshot::import`].
+  pub fn export(config: &ServerConfig) -> Result<Self, anyhow::Error> {
+    let saved = match &config.state_file {
+      Some(path) => Self::load(path)?,
+      None => Self::default(),
+    };
+    let mut rooms = config.rooms.clone();
+    rooms.extend(saved.rooms.into_iter().filter(|room| !config.rooms.contains(room)));
+    Ok(Self { rooms, ..saved })
+  }
+
+  /// Replaces what a server with `config` has saved with an exported snapshot,
+  /// keeping the rooms it doesn't already have configured.
+  pub fn import(mut self, config: &ServerConfig) -> Result<(), anyhow::Error> {
+    let path = config.state_file.as_deref().ok_or_else(|| anyhow::anyhow!("no state_file is configured to import into"))?;
+    self.rooms.retain(|room| !config.rooms.contains(room));
+    self.rooms.truncate(MAX_SAVED_ROOMS);
+    self.save(path)
+  }
+}
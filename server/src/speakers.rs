@@ -0,0 +1,118 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use crate::server::Loudness;
+
+/// The loudest few speakers in each large room, the only ones relayed there, so
+/// everyone's downlink stays manageable however many people talk at once.
+///
+/// Which rooms are large, and who's in them, is only worked out again on the
+/// heartbeat (see [`LoudestSpeakers::recount`]), so a voice packet costs a lookup
+/// and a look at the few speakers already relayed, rather than a scan of everyone.
+/// Until then, someone who left keeps their place only until they've gone quiet.
+#[derive(Debug, Default)]
+pub struct LoudestSpeakers {
+  /// Who's relayed in each large room, and how loud they were last.
+  rooms: HashMap<String, Vec<(SocketAddr, Loudness)>>,
+}
+
+impl LoudestSpeakers {
+  /// Starts over from where everyone is and how loud, keeping the `max` loudest in each
+  /// room with more than `threshold` members.
+  pub fn recount<'a>(&mut self, members: impl IntoIterator<Item = (SocketAddr, &'a str, Loudness)>, threshold: usize, max: usize) {
+    let mut rooms = HashMap::<&str, Vec<(SocketAddr, Loudness)>>::new();
+    for (addr, room, loudness) in members {
+      rooms.entry(room).or_default().push((addr, loudness));
+    }
+    self.rooms = rooms.into_iter()
+      .filter(|(_, members)| members.len() > threshold)
+      .map(|(room, mut members)| {
+        members.sort_by(|(_, a), (_, b)| b.current().total_cmp(&a.current()));
+        members.truncate(max);
+        (room.to_string(), members)
+      })
+      .collect();
+  }
+
+  /// Takes how loud a speaker is now, returning whether they're to be relayed: if they're
+  /// among the `max` loudest in `room`, or it isn't a large room.
+  pub fn update(&mut self, addr: SocketAddr, room: &str, loudness: Loudness, max: usize) -> bool {
+    let speakers = match self.rooms.get_mut(room) {
+      Some(speakers) => speakers,
+      None => return true,
+    };
+    if let Some((_, known)) = speakers.iter_mut().find(|(speaker, _)| *speaker == addr) {
+      *known = loudness;
+      return true;
+    }
+    if speakers.len() < max {
+      speakers.push((addr, loudness));
+      return true;
+    }
+    let quietest = speakers.iter_mut().min_by(|(_, a), (_, b)| a.current().total_cmp(&b.current()));
+    match quietest {
+      Some(quietest) if loudness.current() > quietest.1.current() => {
+        *quietest = (addr, loudness);
+        true
+      },
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn addr(n: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], n))
+  }
+
+  /// Someone talking at `level` -dBov.
+  fn loud(level: u8) -> Loudness {
+    let mut loudness = Loudness::silent();
+    loudness.update(level);
+    loudness
+  }
+
+  /// A room of `size`, all silent.
+  fn room(size: u16) -> LoudestSpeakers {
+    let mut speakers = LoudestSpeakers::default();
+    speakers.recount((0..size).map(|n| (addr(n), "Lobby", Loudness::silent())), 4, 2);
+    speakers
+  }
+
+  #[test]
+  fn small_rooms_relay_everyone() {
+    let mut speakers = room(4);
+    assert!((0..4).all(|n| speakers.update(addr(n), "Lobby", loud(30), 2)));
+  }
+
+  #[test]
+  fn large_rooms_relay_the_loudest() {
+    let mut speakers = room(5);
+    assert!(speakers.update(addr(0), "Lobby", loud(30), 2));
+    assert!(speakers.update(addr(1), "Lobby", loud(20), 2));
+    // quieter than both
+    assert!(!speakers.update(addr(2), "Lobby", loud(40), 2));
+    // louder than the quietest, who loses their place
+    assert!(speakers.update(addr(3), "Lobby", loud(10), 2));
+    assert!(!speakers.update(addr(0), "Lobby", loud(30), 2));
+    assert!(speakers.update(addr(1), "Lobby", loud(20), 2));
+  }
+
+  #[test]
+  fn recount_finds_the_loudest() {
+    let mut speakers = LoudestSpeakers::default();
+    let levels = [50, 10, 40, 20, 30];
+    speakers.recount(levels.iter().enumerate().map(|(n, level)| (addr(n as u16), "Lobby", loud(*level))), 4, 2);
+    let relayed = (0..5).filter(|n| speakers.update(addr(*n), "Lobby", loud(levels[*n as usize]), 2)).collect::<Vec<_>>();
+    assert_eq!(relayed, [1, 3]);
+  }
+
+  #[test]
+  fn rooms_that_shrink_relay_everyone_again() {
+    let mut speakers = room(5);
+    speakers.recount((0..4).map(|n| (addr(n), "Lobby", Loudness::silent())), 4, 2);
+    assert!((0..4).all(|n| speakers.update(addr(n), "Lobby", loud(30), 2)));
+  }
+}
@@ -0,0 +1,139 @@
+//! Renders packet traces recorded by the client (see [`common::trace`]) as a
+//! timeline of each peer's packets, to see what "robot voice" really was.
+
+use std::{collections::HashMap, fmt::Write as _, path::PathBuf};
+
+use anyhow::anyhow;
+use clap::Parser;
+use common::trace::TraceEvent;
+use uuid::Uuid;
+
+mod timeline;
+use timeline::{Frame, Timeline};
+
+#[derive(Parser, Debug)]
+#[clap(name="Rust Voice Trace")]
+struct Args {
+  /// Trace file, as written by the client's --trace
+  #[clap(value_parser)]
+  trace: PathBuf,
+  /// Also draw the timelines to this SVG file
+  #[clap(value_parser, long="svg")]
+  svg: Option<PathBuf>,
+  /// Frames per line of terminal output
+  #[clap(value_parser, long="width", default_value_t=100)]
+  width: usize,
+  /// Length of audio in each packet
+  #[clap(value_parser, long="frame-ms", default_value_t=20)]
+  frame_ms: u64,
+}
+
+fn main() -> Result<(), anyhow::Error> {
+  let args = Args::parse();
+
+  let mut users: Vec<Uuid> = Vec::new();
+  let mut events: HashMap<Uuid, Vec<TraceEvent>> = HashMap::new();
+  for (number, line) in std::fs::read_to_string(&args.trace)?.lines().enumerate() {
+    if line.trim().is_empty() {continue;}
+    let event: TraceEvent = line.parse().map_err(|e| anyhow!("line {}: {}", number + 1, e))?;
+    if !events.contains_key(&event.user) {
+      users.push(event.user);
+    }
+    events.entry(event.user).or_default().push(event);
+  }
+
+  let timelines: Vec<(Uuid, Timeline)> = users.iter()
+    .flat_map(|user| Timeline::build(&events[user]).into_iter().map(move |timeline| (*user, timeline)))
+    .collect();
+  for (user, timeline) in &timelines {
+    print_timeline(user, timeline, args.width, args.frame_ms);
+  }
+  if let Some(path) = &args.svg {
+    std::fs::write(path, render_svg(&timelines, args.frame_ms))?;
+    println!("Wrote {}", path.display());
+  }
+  Ok(())
+}
+
+fn print_timeline(user: &Uuid, timeline: &Timeline, width: usize, frame_ms: u64) {
+  let lost = timeline.count(|f| *f == Frame::Lost);
+  let reordered = timeline.count(|f| matches!(f, Frame::Reordered { .. }));
+  let lateness = timeline.lateness(frame_ms);
+  let worst = lateness.iter().flatten().max().copied().unwrap_or(0);
+  println!("{} from seq {}:", user, timeline.first_seq);
+  println!(
+    "  {} frames, {} lost ({:.1}%), {} reordered, {} duplicates, {} bursts, up to {} ms late",
+    timeline.frames.len(), lost, 100.0 * lost as f32 / timeline.frames.len() as f32,
+    reordered, timeline.duplicates, timeline.bursts.len(), worst,
+  );
+  let in_burst = burst_frames(timeline);
+  for (line, frames) in timeline.frames.chunks(width.max(1)).enumerate() {
+    let start = line * width.max(1);
+    let strip: String = frames.iter().enumerate().map(|(i, frame)| match frame {
+      Frame::Lost => 'x',
+      Frame::Reordered { .. } => 'r',
+      Frame::OnTime { .. } if in_burst[start + i] => 'b',
+      Frame::OnTime { .. } => '.',
+    }).collect();
+    println!("  {:>6} {}", timeline.first_seq.wrapping_add(start as u16), strip);
+  }
+  println!("  (. on time, r reordered, x lost, b arrived in a burst)");
+}
+
+/// Whether each frame arrived as part of a burst.
+fn burst_frames(timeline: &Timeline) -> Vec<bool> {
+  let mut in_burst = vec![false; timeline.frames.len()];
+  for (start, len) in &timeline.bursts {
+    // a burst's packets are in arrival order, which is mostly frame order
+    for flag in in_burst.iter_mut().skip(*start).take(*len) {
+      *flag = true;
+    }
+  }
+  in_burst
+}
+
+const FRAME_WIDTH: usize = 2;
+const ROW_HEIGHT: usize = 16;
+const PLOT_HEIGHT: usize = 80;
+const MARGIN: usize = 10;
+
+/// Each timeline as a strip of frames, coloured by what happened to them,
+/// over a plot of how late each one arrived.
+fn render_svg(timelines: &[(Uuid, Timeline)], frame_ms: u64) -> String {
+  let longest = timelines.iter().map(|(_, t)| t.frames.len()).max().unwrap_or(0);
+  let section = 20 + ROW_HEIGHT + PLOT_HEIGHT + MARGIN;
+  let width = MARGIN * 2 + longest * FRAME_WIDTH;
+  let height = MARGIN + timelines.len() * section;
+  let mut svg = String::new();
+  writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="12">"#, width, height).unwrap();
+  writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+  for (row, (user, timeline)) in timelines.iter().enumerate() {
+    let top = MARGIN + row * section;
+    writeln!(svg, r#"<text x="{}" y="{}">{} from seq {}</text>"#, MARGIN, top + 12, user, timeline.first_seq).unwrap();
+    let strip = top + 20;
+    let in_burst = burst_frames(timeline);
+    for (i, frame) in timeline.frames.iter().enumerate() {
+      let colour = match frame {
+        Frame::Lost => "#d62728",
+        Frame::Reordered { .. } => "#ff7f0e",
+        Frame::OnTime { .. } if in_burst[i] => "#1f77b4",
+        Frame::OnTime { .. } => "#2ca02c",
+      };
+      writeln!(svg, r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}"/>"#, MARGIN + i * FRAME_WIDTH, strip, FRAME_WIDTH, ROW_HEIGHT, colour).unwrap();
+    }
+    let lateness = timeline.lateness(frame_ms);
+    let worst = lateness.iter().flatten().max().copied().unwrap_or(0).max(1);
+    let plot = strip + ROW_HEIGHT;
+    let points: Vec<String> = lateness.iter().enumerate()
+      .filter_map(|(i, late)| late.map(|late| format!(
+        "{},{}",
+        MARGIN + i * FRAME_WIDTH + FRAME_WIDTH / 2,
+        plot + PLOT_HEIGHT - (late as usize * PLOT_HEIGHT / worst as usize),
+      )))
+      .collect();
+    writeln!(svg, r#"<polyline points="{}" fill="none" stroke="black" stroke-width="1"/>"#, points.join(" ")).unwrap();
+    writeln!(svg, r#"<text x="{}" y="{}">{} ms late</text>"#, MARGIN, plot + 12, worst).unwrap();
+  }
+  svg.push_str("</svg>\n");
+  svg
+}
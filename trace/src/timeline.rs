@@ -0,0 +1,107 @@
+use common::{packets::SeqNum, trace::TraceEvent};
+
+/// Jumps in sequence numbers bigger than this are the peer restarting, which starts a new timeline.
+const MAX_GAP: i64 = 1000;
+/// Packets arriving closer together than this are bunched up.
+const BURST_SPACING_MS: u64 = 2;
+/// Bunched up packets in a row needed to count as a burst.
+const BURST_LEN: usize = 3;
+
+/// What happened to one sequence number.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+  /// Arrived after everything before it, at `at_ms`.
+  OnTime { at_ms: u64 },
+  /// Arrived after a later packet.
+  Reordered { at_ms: u64 },
+  /// Never arrived.
+  Lost,
+}
+
+/// One peer's packets, by sequence number.
+#[derive(Debug, Default)]
+pub struct Timeline {
+  /// Frames from the first sequence number seen onwards.
+  pub frames: Vec<Frame>,
+  pub first_seq: SeqNum,
+  pub duplicates: usize,
+  /// Runs of packets that arrived bunched together, as (first frame, length).
+  pub bursts: Vec<(usize, usize)>,
+}
+
+impl Timeline {
+  /// Builds timelines from one peer's events, in the order they arrived.
+  /// A peer that restarts mid-trace gets a new timeline from then on.
+  pub fn build(events: &[TraceEvent]) -> Vec<Self> {
+    let mut timelines: Vec<Self> = Vec::new();
+    // index of the highest frame in the current timeline, so wrapping around doesn't restart it
+    let mut highest: i64 = 0;
+    let mut run: Vec<(usize, u64)> = Vec::new();
+    for event in events {
+      let diff = match timelines.last() {
+        Some(current) => event.seq.wrapping_sub(current.first_seq.wrapping_add(highest as SeqNum)) as i16 as i64,
+        None => i64::MAX,
+      };
+      if diff.abs() > MAX_GAP {
+        if let Some(current) = timelines.last_mut() {
+          current.close_run(&mut run);
+        }
+        timelines.push(Timeline {
+          frames: vec![Frame::OnTime { at_ms: event.at_ms }],
+          first_seq: event.seq,
+          ..Default::default()
+        });
+        highest = 0;
+        run.push((0, event.at_ms));
+        continue;
+      }
+      let current = timelines.last_mut().unwrap();
+      let index = highest + diff;
+      if index < 0 {
+        // from before the start of the timeline
+        continue;
+      }
+      let index = index as usize;
+      if diff > 0 {
+        current.frames.resize(index, Frame::Lost);
+        current.frames.push(Frame::OnTime { at_ms: event.at_ms });
+        highest = index as i64;
+      } else if current.frames[index] == Frame::Lost {
+        current.frames[index] = Frame::Reordered { at_ms: event.at_ms };
+      } else {
+        current.duplicates += 1;
+        continue;
+      }
+      if run.last().is_some_and(|(_, at)| event.at_ms.saturating_sub(*at) > BURST_SPACING_MS) {
+        current.close_run(&mut run);
+      }
+      run.push((index, event.at_ms));
+    }
+    if let Some(current) = timelines.last_mut() {
+      current.close_run(&mut run);
+    }
+    timelines
+  }
+
+  fn close_run(&mut self, run: &mut Vec<(usize, u64)>) {
+    if run.len() >= BURST_LEN {
+      self.bursts.push((run[0].0, run.len()));
+    }
+    run.clear();
+  }
+
+  pub fn count(&self, matches: impl Fn(&Frame) -> bool) -> usize {
+    self.frames.iter().filter(|frame| matches(frame)).count()
+  }
+
+  /// How late each frame arrived compared to the earliest one, in ms, if it arrived.
+  /// Frames are due `frame_ms` apart, so this is the delay the network added.
+  pub fn lateness(&self, frame_ms: u64) -> Vec<Option<u64>> {
+    let arrivals: Vec<Option<i64>> = self.frames.iter().enumerate().map(|(i, frame)| match frame {
+      Frame::OnTime { at_ms } | Frame::Reordered { at_ms } => Some(*at_ms as i64 - (i as u64 * frame_ms) as i64),
+      Frame::Lost => None,
+    }).collect();
+    let earliest = arrivals.iter().flatten().min().copied().unwrap_or(0);
+    arrivals.into_iter().map(|a| a.map(|a| (a - earliest) as u64)).collect()
+  }
+}